@@ -0,0 +1,222 @@
+use std::time::Duration;
+
+use crate::{
+    error::ConvertError,
+    file_io::{decode_key_value, encode_key_value},
+    value::{self, Value},
+};
+
+/// A batch of puts and deletes that is applied to a `KVS` atomically.
+///
+/// ```no_run
+/// use kvsd::{WriteBatch, KVS};
+///
+/// let kvs = KVS::new().unwrap();
+/// let mut batch = WriteBatch::new();
+/// batch.put("from", "90");
+/// batch.put("to", "110");
+/// batch.delete("pending");
+/// kvs.write(batch).unwrap();
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteBatch {
+    /// The keys and values in the order they were added.
+    entries: Vec<(Vec<u8>, Value)>,
+}
+
+impl WriteBatch {
+    /// Creates an empty `WriteBatch`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a put of a key-value pair to the batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    pub fn put(&mut self, k: &str, v: &str) -> &mut Self {
+        self.put_bytes(k.as_bytes(), v.as_bytes())
+    }
+
+    /// Adds a put of a binary key-value pair to the batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    pub fn put_bytes(&mut self, k: &[u8], v: &[u8]) -> &mut Self {
+        self.entries.push((k.to_vec(), Value::new(v, false)));
+        self
+    }
+
+    /// Adds a put of a key-value pair that expires after a time to live.
+    ///
+    /// The expiry time is fixed when the put is added, and is persisted with the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl(&mut self, k: &str, v: &str, ttl: Duration) -> &mut Self {
+        self.put_with_ttl_bytes(k.as_bytes(), v.as_bytes(), ttl)
+    }
+
+    /// Adds a put of a binary key-value pair that expires after a time to live.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl_bytes(&mut self, k: &[u8], v: &[u8], ttl: Duration) -> &mut Self {
+        let ttl_millis: u64 = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at: u64 = value::now_millis().saturating_add(ttl_millis);
+        self.entries
+            .push((k.to_vec(), Value::with_expiry(v, expires_at)));
+        self
+    }
+
+    /// Adds a delete of a key to the batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key to delete.
+    pub fn delete(&mut self, k: &str) -> &mut Self {
+        self.delete_bytes(k.as_bytes())
+    }
+
+    /// Adds a delete of a binary key to the batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key to delete.
+    pub fn delete_bytes(&mut self, k: &[u8]) -> &mut Self {
+        self.entries.push((k.to_vec(), Value::new("", true)));
+        self
+    }
+
+    /// Returns the number of puts and deletes in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the batch contains no puts or deletes.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all puts and deletes from the batch.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the keys and values in the order they were added.
+    pub(crate) fn entries(&self) -> &[(Vec<u8>, Value)] {
+        &self.entries
+    }
+
+    /// Converts the `WriteBatch` to a byte vector.
+    ///
+    /// The format is: sequence number (8 bytes) | count (8 bytes) | key-value pair | key-value pair | ...
+    ///
+    /// The entries get consecutive sequence numbers starting at `seq`.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The sequence number of the first entry.
+    pub(crate) fn to_bytes(&self, seq: u64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = seq.to_be_bytes().to_vec();
+        bytes.extend(self.entries.len().to_be_bytes());
+        for (key, value) in self.entries.iter() {
+            bytes.extend(encode_key_value(key, value));
+        }
+        bytes
+    }
+
+    /// Creates a `WriteBatch` from a byte vector.
+    ///
+    /// Returns the sequence number of the first entry and the batch.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<(u64, Self), ConvertError> {
+        let seq: u64 = match bytes.get(0..8) {
+            Some(b) => u64::from_be_bytes(b.try_into().unwrap()),
+            None => {
+                return Err(ConvertError::InvalidBatch(
+                    "The sequence number is missing.".to_string(),
+                ))
+            }
+        };
+        let count: usize = match bytes.get(8..16) {
+            Some(b) => usize::from_be_bytes(b.try_into().unwrap()),
+            None => {
+                return Err(ConvertError::InvalidBatch(
+                    "The count of entries is missing.".to_string(),
+                ))
+            }
+        };
+
+        let mut offset: usize = 16;
+        let mut entries: Vec<(Vec<u8>, Value)> = Vec::new();
+        for i in 0..count {
+            let (key_bytes, value_bytes, next) = match decode_key_value(bytes, offset) {
+                Some(decoded) => decoded,
+                None => {
+                    return Err(ConvertError::InvalidBatch(format!(
+                        "The entry {i} of {count} is truncated."
+                    )))
+                }
+            };
+
+            entries.push((key_bytes, Value::from_bytes(value_bytes)?));
+            offset = next;
+        }
+
+        Ok((seq, WriteBatch { entries }))
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::*;
+
+    #[test]
+    fn test_put_delete() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.put("k1", "v1").delete("k2");
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch.entries(),
+            &[
+                (b"k1".to_vec(), Value::new("v1", false)),
+                (b"k2".to_vec(), Value::new("", true)),
+            ]
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_to_bytes_from_bytes() {
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1").delete("k2").put("k1", "v3");
+        batch.put_with_ttl("k3", "v3", Duration::from_secs(10));
+        batch
+            .put_bytes(&[0xFF, 0x00], &[0x80, 0xFE])
+            .delete_bytes(&[0xFF]);
+
+        let bytes: Vec<u8> = batch.to_bytes(42);
+        assert_eq!(WriteBatch::from_bytes(&bytes).unwrap(), (42, batch));
+
+        // 途中で切れたバイト列はエラーになる
+        assert!(WriteBatch::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(WriteBatch::from_bytes(&bytes[..12]).is_err());
+        assert!(WriteBatch::from_bytes(&bytes[..4]).is_err());
+    }
+}
@@ -0,0 +1,839 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    ops::Bound,
+    time::Duration,
+};
+
+use kvsd::{KVSError, KVS, MAX_FRAME_SIZE};
+
+use crate::{get_now, pool::Connection};
+
+/// The largest size of the request line and of each header line in bytes.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// The largest number of headers of a request.
+const MAX_HEADERS: usize = 100;
+
+/// The number of pairs a listing returns when `limit` is not given.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The largest number of pairs a listing returns.
+const MAX_LIMIT: usize = 1000;
+
+/// An HTTP request.
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+    /// The method, such as `GET`.
+    method: String,
+    /// The path, percent-decoded.
+    path: Vec<u8>,
+    /// The query parameters, percent-decoded, in the order they were given.
+    query: Vec<(String, Vec<u8>)>,
+    /// Whether the connection is kept open after the response.
+    keep_alive: bool,
+    /// The body.
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of a query parameter.
+    fn param(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// An HTTP response.
+#[derive(Debug, PartialEq)]
+struct HttpResponse {
+    /// The status code.
+    status: u16,
+    /// The content type of the body, or `None` if there is no body.
+    content_type: Option<&'static str>,
+    /// The body.
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Creates a response with a JSON body.
+    fn json(status: u16, body: String) -> Self {
+        HttpResponse {
+            status,
+            content_type: Some("application/json"),
+            body: body.into_bytes(),
+        }
+    }
+
+    /// Creates a response with a JSON error body: `{"error": "<message>"}`.
+    fn error(status: u16, msg: &str) -> Self {
+        HttpResponse::json(
+            status,
+            format!("{{\"error\":{}}}", json_string(msg.as_bytes())),
+        )
+    }
+
+    /// Creates a response without a body.
+    fn no_content() -> Self {
+        HttpResponse {
+            status: 204,
+            content_type: None,
+            body: Vec::new(),
+        }
+    }
+
+    /// Encodes the response with its status line and headers.
+    ///
+    /// A 204 response has neither `Content-Type` nor `Content-Length`, as it cannot have a body.
+    fn encode(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head: String = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if let Some(content_type) = self.content_type {
+            head.push_str(&format!("Content-Type: {content_type}\r\n"));
+        }
+        if self.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        let connection: &str = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {connection}\r\n\r\n"));
+        [head.as_bytes(), &self.body].concat()
+    }
+}
+
+/// Handles an HTTP client connection until it is closed.
+///
+/// Requests are answered one after another while the client keeps the connection alive.
+pub fn handle(connection: &Connection, kvs: &KVS) {
+    let mut reader: BufReader<&Connection> = BufReader::new(connection);
+    let mut writer: &Connection = connection;
+    if let Err(e) = serve(&mut reader, &mut writer, kvs) {
+        eprintln!("{} [ERROR] {}", get_now(), e)
+    }
+}
+
+/// Reads requests and writes their responses until the connection is closed.
+fn serve<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, kvs: &KVS) -> Result<(), String> {
+    loop {
+        let (response, keep_alive): (HttpResponse, bool) = match read_request(reader, writer) {
+            Ok(Some(request)) => {
+                println!(
+                    "{} [INFO] Recieved HTTP request '{} {}'",
+                    get_now(),
+                    request.method,
+                    String::from_utf8_lossy(&request.path)
+                );
+                (route(kvs, &request), request.keep_alive)
+            }
+            Ok(None) => return Ok(()),
+            // 壊れたリクエストの後は区切りが分からないので、エラーを返して閉じる
+            Err(response) => (response, false),
+        };
+        writer
+            .write_all(&response.encode(keep_alive))
+            .and_then(|_| writer.flush())
+            .map_err(|e| e.to_string())?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a request.
+///
+/// Returns `None` if the connection was closed before a new request started,
+/// and the error response if the request cannot be read.
+/// A client that sent `Expect: 100-continue` is told to go on before the body is read.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<HttpRequest>, HttpResponse> {
+    let line: String = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let (method, target, version): (&str, &str, &str) = match parts[..] {
+        [method, target, version] if version.starts_with("HTTP/1.") => (method, target, version),
+        _ => return Err(HttpResponse::error(400, "The request line is invalid.")),
+    };
+
+    let mut keep_alive: bool = version != "HTTP/1.0";
+    let mut content_length: usize = 0;
+    let mut expect_continue: bool = false;
+    for i in 0.. {
+        let header: String = match read_line(reader)? {
+            Some(header) => header,
+            None => return Err(HttpResponse::error(400, "The headers are truncated.")),
+        };
+        if header.is_empty() {
+            break;
+        }
+        if i == MAX_HEADERS {
+            return Err(HttpResponse::error(
+                431,
+                "The request has too many headers.",
+            ));
+        }
+        let (name, value): (&str, &str) = match header.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(HttpResponse::error(400, "A header is invalid.")),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) if len <= MAX_FRAME_SIZE => content_length = len,
+                Ok(_) => return Err(HttpResponse::error(413, "The body is too large.")),
+                Err(_) => return Err(HttpResponse::error(400, "Content-Length is invalid.")),
+            },
+            "transfer-encoding" => {
+                return Err(HttpResponse::error(
+                    501,
+                    "Transfer-Encoding is not supported.",
+                ))
+            }
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+            "expect" => {
+                return Err(HttpResponse::error(
+                    417,
+                    "Only Expect: 100-continue is supported.",
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+
+    // HTTP/1.0 のクライアントには 100 Continue を送らない
+    if expect_continue && version != "HTTP/1.0" && 0 < content_length {
+        let sent: std::io::Result<()> = writer
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .and_then(|_| writer.flush());
+        if sent.is_err() {
+            return Err(HttpResponse::error(400, "The connection was closed."));
+        }
+    }
+
+    // Content-Length だけ大きく本文を送って来ない相手のために、届いた分だけ伸ばす
+    let mut body: Vec<u8> = Vec::new();
+    let read: std::io::Result<usize> = reader.take(content_length as u64).read_to_end(&mut body);
+    if read.is_err() || body.len() < content_length {
+        return Err(HttpResponse::error(400, "The body is truncated."));
+    }
+
+    let (path, query): (&str, &str) = target.split_once('?').unwrap_or((target, ""));
+    let mut params: Vec<(String, Vec<u8>)> = Vec::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value): (&str, &str) = pair.split_once('=').unwrap_or((pair, ""));
+        let name: String = String::from_utf8_lossy(&percent_decode(name, true)?).to_string();
+        params.push((name, percent_decode(value, true)?));
+    }
+
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        path: percent_decode(path, false)?,
+        query: params,
+        keep_alive,
+        body,
+    }))
+}
+
+/// Reads a line without its line ending.
+///
+/// Returns `None` if the connection was closed before the line started.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, HttpResponse> {
+    let mut line: Vec<u8> = Vec::new();
+    let read: usize =
+        match Read::take(&mut *reader, MAX_LINE_SIZE as u64).read_until(b'\n', &mut line) {
+            Ok(read) => read,
+            // 読み込みのタイムアウトを過ぎたケース
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(HttpResponse::error(408, "The request timed out."))
+            }
+            Err(e) => return Err(HttpResponse::error(400, &e.to_string())),
+        };
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(HttpResponse::error(431, "A line is too long or truncated."));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(HttpResponse::error(400, "A line is not UTF-8.")),
+    }
+}
+
+/// Executes a request on the store and returns the response.
+///
+/// - `GET /keys/{key}`: returns the value as it is, or 404 if the key does not exist.
+/// - `PUT /keys/{key}?ttl=<seconds>`: puts the body as the value, with an optional time to live.
+/// - `DELETE /keys/{key}`: deletes the key.
+/// - `GET /keys?start=&after=&end=&prefix=&limit=&encoding=`: lists the pairs in key order.
+fn route(kvs: &KVS, request: &HttpRequest) -> HttpResponse {
+    let key: &[u8] = match request.path.strip_prefix(b"/keys") {
+        Some(b"") | Some(b"/") => {
+            return match request.method.as_str() {
+                "GET" => list(kvs, request).unwrap_or_else(|response| response),
+                _ => HttpResponse::error(405, "The method is not allowed for /keys."),
+            }
+        }
+        Some(rest) if rest.starts_with(b"/") => &rest[1..],
+        _ => return HttpResponse::error(404, "The path is not found."),
+    };
+
+    let result: Result<HttpResponse, KVSError> = match request.method.as_str() {
+        "GET" => kvs.get_bytes(key).map(|value| match value {
+            Some(value) => HttpResponse {
+                status: 200,
+                content_type: Some("application/octet-stream"),
+                body: value.into_bytes(),
+            },
+            None => HttpResponse::error(
+                404,
+                &format!("The key '{}' is not found.", String::from_utf8_lossy(key)),
+            ),
+        }),
+        "PUT" => match request.param("ttl").map(parse_number) {
+            None => kvs.put_bytes(key, &request.body),
+            Some(Some(seconds)) => {
+                kvs.put_with_ttl_bytes(key, &request.body, Duration::from_secs(seconds as u64))
+            }
+            Some(None) => return HttpResponse::error(400, "ttl must be a number of seconds."),
+        }
+        .map(|_| HttpResponse::no_content()),
+        "DELETE" => kvs.delete_bytes(key).map(|_| HttpResponse::no_content()),
+        _ => return HttpResponse::error(405, "The method is not allowed for /keys/{key}."),
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => kvs_error(&e),
+    }
+}
+
+/// Lists the pairs in a range of keys as JSON.
+///
+/// The body is `{"items": [{"key": ..., "value": ...}, ...], "next": ...}`, where `next` is the
+/// key to pass as `after` to get the following pairs, or `null` if there are no more.
+/// Keys and values are strings, or base64 with `encoding=base64` for binary data.
+/// The keys given in `start`, `after`, `end` and `prefix` are read in the same encoding.
+/// A key that is not UTF-8 cannot be listed as a string, so it needs `encoding=base64`.
+fn list(kvs: &KVS, request: &HttpRequest) -> Result<HttpResponse, HttpResponse> {
+    let limit: usize = match request.param("limit").map(parse_number) {
+        None => DEFAULT_LIMIT,
+        Some(Some(limit)) if 0 < limit => limit.min(MAX_LIMIT),
+        Some(_) => return Err(HttpResponse::error(400, "limit must be a positive number.")),
+    };
+    let base64_encoded: bool = match request.param("encoding") {
+        None | Some(b"utf8") => false,
+        Some(b"base64") => true,
+        Some(_) => return Err(HttpResponse::error(400, "encoding must be utf8 or base64.")),
+    };
+    let encode = |bytes: &[u8]| match base64_encoded {
+        true => format!("\"{}\"", base64(bytes)),
+        false => json_string(bytes),
+    };
+
+    let prefix: Vec<u8> = key_param(request, "prefix", base64_encoded)?.unwrap_or_default();
+    let after: Option<Vec<u8>> = key_param(request, "after", base64_encoded)?;
+    let start: Option<Vec<u8>> = key_param(request, "start", base64_encoded)?;
+    let end: Option<Vec<u8>> = key_param(request, "end", base64_encoded)?;
+    let lower: Bound<&[u8]> = match (&after, &start) {
+        (Some(after), _) => Bound::Excluded(after),
+        (None, Some(start)) => Bound::Included(start),
+        (None, None) => Bound::Included(&prefix),
+    };
+    let upper: Bound<&[u8]> = match &end {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+
+    let iter = kvs.scan_bytes((lower, upper)).map_err(|e| kvs_error(&e))?;
+    let mut items: Vec<String> = Vec::new();
+    let mut last: Option<Vec<u8>> = None;
+    let mut more: bool = false;
+    for entry in iter {
+        let (key, value) = entry.map_err(|e| kvs_error(&e))?;
+        // キーは順に並ぶので、接頭辞の範囲を過ぎたら終わり
+        if !key.starts_with(&prefix) {
+            match key.as_slice() < prefix.as_slice() {
+                true => continue,
+                false => break,
+            }
+        }
+        if items.len() == limit {
+            more = true;
+            break;
+        }
+        // 置き換えた文字列を after に渡しても続きを取れないので、UTF-8 でないキーは返さない
+        if !base64_encoded && std::str::from_utf8(&key).is_err() {
+            return Err(HttpResponse::error(
+                400,
+                "A key is not UTF-8. List the keys with encoding=base64.",
+            ));
+        }
+        items.push(format!(
+            "{{\"key\":{},\"value\":{}}}",
+            encode(&key),
+            encode(value.as_bytes())
+        ));
+        last = Some(key);
+    }
+
+    let next: String = match last {
+        Some(key) if more => encode(&key),
+        _ => "null".to_string(),
+    };
+    Ok(HttpResponse::json(
+        200,
+        format!("{{\"items\":[{}],\"next\":{}}}", items.join(","), next),
+    ))
+}
+
+/// Returns a query parameter that holds a key, decoded from base64 if `base64_encoded` is set.
+///
+/// # Arguments
+///
+/// * `request` - The request.
+/// * `name` - The name of the parameter.
+/// * `base64_encoded` - Whether the key is given in base64.
+fn key_param(
+    request: &HttpRequest,
+    name: &str,
+    base64_encoded: bool,
+) -> Result<Option<Vec<u8>>, HttpResponse> {
+    match request.param(name) {
+        None => Ok(None),
+        Some(value) if !base64_encoded => Ok(Some(value.to_vec())),
+        Some(value) => match decode_base64(value) {
+            Some(key) => Ok(Some(key)),
+            None => Err(HttpResponse::error(400, &format!("{name} must be base64."))),
+        },
+    }
+}
+
+/// Creates the error response of a store error, with the message of its `Display`.
+fn kvs_error(e: &KVSError) -> HttpResponse {
+    let status: u16 = match e {
+        KVSError::ReadOnly => 403,
+        KVSError::TransactionConflict(_) => 409,
+        KVSError::WriteStalled(_) => 503,
+        _ => 500,
+    };
+    eprintln!("{} [ERROR] {}", get_now(), e);
+    HttpResponse::error(status, &e.to_string())
+}
+
+/// Parses a query parameter as a number.
+fn parse_number(bytes: &[u8]) -> Option<usize> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space if `plus_as_space` is set.
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<Vec<u8>, HttpResponse> {
+    let bytes: &[u8] = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex: Option<u8> = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => decoded.push(b),
+                    None => return Err(HttpResponse::error(400, "A percent escape is invalid.")),
+                }
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+/// Encodes bytes as a JSON string, replacing the bytes that are not UTF-8.
+fn json_string(bytes: &[u8]) -> String {
+    let mut json: String = String::from("\"");
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Encodes bytes in base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded: String = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n: u32 = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes base64 with or without padding, or returns `None` if it is not base64.
+///
+/// A space is read as `+`, because a `+` that is not percent-encoded in a query means a space.
+fn decode_base64(encoded: &[u8]) -> Option<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b' ' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let encoded: &[u8] = match encoded {
+        [rest @ .., b'=', b'='] | [rest @ .., b'='] if encoded.len().is_multiple_of(4) => rest,
+        _ => encoded,
+    };
+    if encoded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded: Vec<u8> = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        let n: u32 = chunk
+            .iter()
+            .enumerate()
+            .try_fold(0, |n, (i, c)| Some(n | (digit(*c)? as u32) << (18 - 6 * i)))?;
+        decoded.extend(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(decoded)
+}
+
+/// Returns the reason phrase of a status code.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::{http::*, test_util};
+
+    /// Sends requests to a new connection and returns everything written back.
+    fn run(kvs: &KVS, input: &str) -> String {
+        let mut output: Vec<u8> = Vec::new();
+        serve(&mut input.as_bytes(), &mut output, kvs).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Sends a request without a body and returns the response.
+    fn request(kvs: &KVS, method: &str, target: &str) -> HttpResponse {
+        let input: String = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request: HttpRequest = read_request(&mut input.as_bytes(), &mut std::io::sink())
+            .unwrap()
+            .unwrap();
+        route(kvs, &request)
+    }
+
+    #[test]
+    fn test_read_request() {
+        let input: &str = "PUT /keys/a%20b?ttl=60&x=1+2 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /keys HTTP/1.0\r\n\r\n";
+        let mut reader: &[u8] = input.as_bytes();
+        let request: HttpRequest = read_request(&mut reader, &mut std::io::sink())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            request,
+            HttpRequest {
+                method: "PUT".to_string(),
+                path: b"/keys/a b".to_vec(),
+                query: vec![
+                    ("ttl".to_string(), b"60".to_vec()),
+                    ("x".to_string(), b"1 2".to_vec())
+                ],
+                keep_alive: true,
+                body: b"hello".to_vec(),
+            }
+        );
+        let request: HttpRequest = read_request(&mut reader, &mut std::io::sink())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path, b"/keys".to_vec());
+        assert!(!request.keep_alive);
+        assert_eq!(
+            read_request(&mut reader, &mut std::io::sink()).unwrap(),
+            None
+        );
+
+        // 壊れたリクエストのケース
+        for (input, status) in [
+            ("GET /keys\r\n\r\n", 400),
+            ("GET /keys/%zz HTTP/1.1\r\n\r\n", 400),
+            ("PUT /keys/k HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc", 400),
+            (
+                "PUT /keys/k HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n",
+                413,
+            ),
+            (
+                "PUT /keys/k HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                501,
+            ),
+            ("PUT /keys/k HTTP/1.1\r\nExpect: something\r\n\r\n", 417),
+        ] {
+            assert_eq!(
+                read_request(&mut input.as_bytes(), &mut std::io::sink())
+                    .unwrap_err()
+                    .status,
+                status
+            );
+        }
+
+        // Content-Length だけ大きく、本文が届かないケース
+        struct Recorder<'a> {
+            bytes: &'a [u8],
+            largest_buf: usize,
+        }
+        impl Read for Recorder<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.largest_buf = self.largest_buf.max(buf.len());
+                self.bytes.read(buf)
+            }
+        }
+        let input: String =
+            format!("PUT /keys/k HTTP/1.1\r\nContent-Length: {MAX_FRAME_SIZE}\r\n\r\nabc");
+        let mut reader: BufReader<Recorder> = BufReader::new(Recorder {
+            bytes: input.as_bytes(),
+            largest_buf: 0,
+        });
+        assert_eq!(
+            read_request(&mut reader, &mut std::io::sink())
+                .unwrap_err()
+                .status,
+            400
+        );
+        assert!(reader.get_ref().largest_buf < 1024 * 1024);
+    }
+
+    #[test]
+    fn test_keys() {
+        let kvs: KVS = test_util::temp_store("http_keys");
+
+        // 1 つの接続で続けて処理するケース
+        let output: String = run(
+            &kvs,
+            "PUT /keys/k1 HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET /keys/k1 HTTP/1.1\r\nConnection: close\r\n\r\nGET /keys/k1 HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(
+            output,
+            [
+                "HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+            ]
+            .concat()
+        );
+
+        // 100 Continue を返してから本文を読むケース (HTTP/1.0 には返さない)
+        let output: String = run(
+            &kvs,
+            "PUT /keys/k3 HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nv3PUT /keys/k4 HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nv4",
+        );
+        assert_eq!(
+            output,
+            [
+                "HTTP/1.1 100 Continue\r\n\r\n",
+                "HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n",
+                "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+            ]
+            .concat()
+        );
+        assert_eq!(kvs.get("k4").unwrap().unwrap().as_bytes(), b"v4");
+
+        // 存在しないキーは 404 と JSON のエラーになるケース
+        let response: HttpResponse = request(&kvs, "GET", "/keys/missing");
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            r#"{"error":"The key 'missing' is not found."}"#
+        );
+
+        // 有効期限つきの書き込みと削除のケース
+        let response: HttpResponse = request(&kvs, "PUT", "/keys/session?ttl=60");
+        assert_eq!(response.status, 204);
+        assert!(kvs.get("session").unwrap().unwrap().ttl().is_some());
+        assert_eq!(request(&kvs, "PUT", "/keys/k2?ttl=abc").status, 400);
+        assert_eq!(request(&kvs, "DELETE", "/keys/k1").status, 204);
+        assert_eq!(request(&kvs, "GET", "/keys/k1").status, 404);
+
+        // 未定義のパスとメソッドのケース
+        assert_eq!(request(&kvs, "GET", "/other").status, 404);
+        assert_eq!(request(&kvs, "POST", "/keys/k1").status, 405);
+        assert_eq!(request(&kvs, "DELETE", "/keys").status, 405);
+    }
+
+    #[test]
+    fn test_list() {
+        let kvs: KVS = test_util::temp_store("http_list");
+        for k in ["a1", "a2", "a3", "b1", "b2", "c1"] {
+            kvs.put(k, &format!("v{k}")).unwrap();
+        }
+        let list = |target: &str| {
+            let response: HttpResponse = request(&kvs, "GET", target);
+            assert_eq!(response.status, 200);
+            String::from_utf8(response.body).unwrap()
+        };
+
+        assert_eq!(
+            list("/keys?prefix=a&limit=2"),
+            r#"{"items":[{"key":"a1","value":"va1"},{"key":"a2","value":"va2"}],"next":"a2"}"#
+        );
+        assert_eq!(
+            list("/keys?prefix=a&limit=2&after=a2"),
+            r#"{"items":[{"key":"a3","value":"va3"}],"next":null}"#
+        );
+        assert_eq!(
+            list("/keys?start=a3&end=c1"),
+            r#"{"items":[{"key":"a3","value":"va3"},{"key":"b1","value":"vb1"},{"key":"b2","value":"vb2"}],"next":null}"#
+        );
+        assert_eq!(
+            list("/keys/?prefix=Yw==&encoding=base64"),
+            r#"{"items":[{"key":"YzE=","value":"dmMx"}],"next":null}"#
+        );
+        assert_eq!(list("/keys?prefix=d"), r#"{"items":[],"next":null}"#);
+
+        // 不正なパラメータのケース
+        assert_eq!(request(&kvs, "GET", "/keys?limit=0").status, 400);
+        assert_eq!(request(&kvs, "GET", "/keys?encoding=hex").status, 400);
+        assert_eq!(
+            request(&kvs, "GET", "/keys?encoding=base64&after=%2A").status,
+            400
+        );
+    }
+
+    #[test]
+    fn test_list_binary_keys() {
+        let kvs: KVS = test_util::temp_store("http_list_binary");
+        // base64 にすると + や / を含むキーと、UTF-8 でないキー
+        let keys: Vec<Vec<u8>> = vec![
+            b"a".to_vec(),
+            vec![0xfb, 0xff],
+            vec![0xfb, 0xff, 0x01],
+            vec![0xfb, 0xff, 0xfe],
+            vec![0xff],
+        ];
+        for key in &keys {
+            kvs.put_bytes(key, b"v").unwrap();
+        }
+        let next = |body: &str| -> Option<String> {
+            let (_, next) = body.split_once(r#""next":"#)?;
+            next.strip_prefix('"')
+                .map(|n| n.split('"').next().unwrap().to_string())
+        };
+        let items = |body: &str| -> Vec<Vec<u8>> {
+            body.split(r#"{"key":""#)
+                .skip(1)
+                .map(|item| decode_base64(item.split('"').next().unwrap().as_bytes()).unwrap())
+                .collect()
+        };
+
+        // 返された next をそのまま after に渡して、全てのキーを順に読むケース
+        let mut listed: Vec<Vec<u8>> = Vec::new();
+        let mut target: String = "/keys?encoding=base64&limit=2".to_string();
+        loop {
+            let response: HttpResponse = request(&kvs, "GET", &target);
+            assert_eq!(response.status, 200);
+            let body: String = String::from_utf8(response.body).unwrap();
+            listed.extend(items(&body));
+            match next(&body) {
+                Some(next) => target = format!("/keys?encoding=base64&limit=2&after={next}"),
+                None => break,
+            }
+        }
+        assert_eq!(listed, keys);
+
+        // パーセントエンコードした next と、base64 の接頭辞と範囲のケース
+        let after: String = base64(&[0xfb, 0xff])
+            .replace('+', "%2B")
+            .replace('/', "%2F");
+        let response: HttpResponse = request(
+            &kvs,
+            "GET",
+            &format!("/keys?encoding=base64&after={after}&end=/w"),
+        );
+        let body: String = String::from_utf8(response.body).unwrap();
+        assert_eq!(
+            items(&body),
+            vec![vec![0xfb, 0xff, 0x01], vec![0xfb, 0xff, 0xfe]]
+        );
+        let response: HttpResponse = request(&kvs, "GET", "/keys?encoding=base64&prefix=+/8");
+        let body: String = String::from_utf8(response.body).unwrap();
+        assert_eq!(items(&body), keys[1..4].to_vec());
+
+        // UTF-8 でないキーは文字列では返さないケース
+        assert_eq!(request(&kvs, "GET", "/keys?prefix=a").status, 200);
+        assert_eq!(request(&kvs, "GET", "/keys").status, 400);
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(json_string(b"a\"b\\c\n\x01"), r#""a\"b\\c\n\u0001""#);
+        assert_eq!(json_string(&[0xff]), "\"\u{fffd}\"");
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        for bytes in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foobar",
+            &[0xfb, 0xff, 0xfe],
+        ] {
+            assert_eq!(
+                decode_base64(base64(bytes).as_bytes()).as_deref(),
+                Some(bytes)
+            );
+        }
+        assert_eq!(decode_base64(b"Zm8"), Some(b"fo".to_vec()));
+        assert_eq!(decode_base64(b" //+"), Some(vec![0xfb, 0xff, 0xfe]));
+        assert_eq!(decode_base64(b"Zm9vY"), None);
+        assert_eq!(decode_base64(b"Zm9v*A=="), None);
+    }
+}
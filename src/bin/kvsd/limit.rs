@@ -0,0 +1,86 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Counts the open connections and refuses new ones over a maximum.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    /// The number of open connections.
+    active: Arc<AtomicUsize>,
+    /// The maximum number of open connections.
+    max: usize,
+}
+
+impl ConnectionLimit {
+    /// Creates a limit with no open connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The maximum number of open connections.
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Counts a new connection, or returns `None` if the maximum is already open.
+    ///
+    /// The connection is counted until the returned permit is dropped.
+    pub fn acquire(&self) -> Option<ConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| ConnectionPermit {
+                active: self.active.clone(),
+            })
+    }
+
+    /// Returns the number of open connections.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+/// An open connection counted by a `ConnectionLimit`.
+pub struct ConnectionPermit {
+    /// The number of open connections.
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::limit::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let limit: ConnectionLimit = ConnectionLimit::new(2);
+        let first: Option<ConnectionPermit> = limit.acquire();
+        let second: Option<ConnectionPermit> = limit.acquire();
+        assert!(first.is_some() && second.is_some());
+        assert_eq!(limit.active(), 2);
+
+        // 上限を超える接続は拒否するケース
+        assert!(limit.acquire().is_none());
+
+        // 接続を閉じると、また受け付けるケース
+        drop(first);
+        assert_eq!(limit.active(), 1);
+        assert!(limit.acquire().is_some());
+        assert_eq!(limit.active(), 1);
+
+        // 上限が 0 のケース
+        assert!(ConnectionLimit::new(0).acquire().is_none());
+    }
+}
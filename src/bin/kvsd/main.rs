@@ -0,0 +1,761 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+mod http;
+mod limit;
+mod pool;
+mod resp;
+#[cfg(test)]
+mod test_util;
+
+use kvsd::{Compression, Durability, KVSError, KVSOptions, ProtocolError, Request, Response, KVS};
+use limit::ConnectionLimit;
+use pool::{Connection, ThreadPool};
+
+const DEFAULT_PORT: &str = "54321";
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_WORKERS: usize = 64;
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A function that serves a client connection in one of the protocols.
+type Handler = fn(&Connection, &KVS);
+
+/// The server configuration given by the command line arguments.
+#[derive(Debug, PartialEq)]
+struct Config {
+    /// The options used to open the store.
+    options: KVSOptions,
+    /// Whether to upgrade a data directory of the earlier release before opening it.
+    upgrade: bool,
+    /// The port to serve the Redis protocol (RESP) on, if any.
+    resp_port: Option<u16>,
+    /// The port to serve the HTTP/JSON API on, if any.
+    http_port: Option<u16>,
+    /// The number of threads that serve connections.
+    workers: usize,
+    /// The maximum number of open connections over all the ports.
+    max_connections: usize,
+    /// How long to wait for a client to send data, or `None` to wait forever.
+    read_timeout: Option<Duration>,
+    /// How long to wait for a client to receive data, or `None` to wait forever.
+    write_timeout: Option<Duration>,
+}
+
+/// The resources shared by the listeners of the server.
+struct Server {
+    /// The store.
+    kvs: KVS,
+    /// The threads that serve connections.
+    pool: ThreadPool,
+    /// The count of open connections.
+    connections: ConnectionLimit,
+    /// How long to wait for a client to send data.
+    read_timeout: Option<Duration>,
+    /// How long to wait for a client to receive data.
+    write_timeout: Option<Duration>,
+}
+
+/// The main function for the key-value store server.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config: Config = match parse_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} [ERROR] {}", get_now(), e);
+            return;
+        }
+    };
+
+    if config.upgrade {
+        match config.options.upgrade() {
+            Ok(true) => eprintln!(
+                "{} [INFO] Upgraded the data directory to the current format.",
+                get_now()
+            ),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{} [ERROR] {}", get_now(), e);
+                return;
+            }
+        }
+    }
+
+    let kvs: KVS = match config.options.open() {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("{} [ERROR] {}", get_now(), e);
+            return;
+        }
+    };
+
+    if kvs.wal_dropped_bytes() > 0 {
+        eprintln!(
+            "{} [WARN] Dropped {} bytes of torn or corrupt records from the tail of the WAL.",
+            get_now(),
+            kvs.wal_dropped_bytes()
+        );
+    }
+
+    // 接続はすべてのポートで同じワーカーと上限を共有する
+    let server: Arc<Server> = Arc::new(Server {
+        kvs,
+        pool: ThreadPool::new(config.workers),
+        connections: ConnectionLimit::new(config.max_connections),
+        read_timeout: config.read_timeout,
+        write_timeout: config.write_timeout,
+    });
+
+    let address: String = format!("{DEFAULT_HOST}:{DEFAULT_PORT}");
+    let listner: TcpListener = match bind(&address) {
+        Some(tl) => tl,
+        None => return,
+    };
+
+    let extra: [(Option<u16>, Handler); 2] = [
+        (config.resp_port, resp::handle),
+        (config.http_port, http::handle),
+    ];
+    for (port, handle) in extra {
+        let Some(port) = port else { continue };
+        let address: String = format!("{DEFAULT_HOST}:{port}");
+        let listener: TcpListener = match bind(&address) {
+            Some(tl) => tl,
+            None => return,
+        };
+        let server: Arc<Server> = server.clone();
+        thread::spawn(move || serve(listener, server, handle));
+    }
+
+    serve(listner, server, handle);
+}
+
+/// Binds a listener to an address, logging the error if it fails.
+fn bind(address: &str) -> Option<TcpListener> {
+    match TcpListener::bind(address) {
+        Ok(tl) => Some(tl),
+        Err(e) => {
+            eprintln!(
+                "{} [ERROR] Failed to bind IP address '{}', because the following error is occured.\n{}",
+                get_now(),
+                address,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Accepts connections on a listener and hands each of them to a worker of the server.
+fn serve(listner: TcpListener, server: Arc<Server>, handle: Handler) {
+    for stream_result in listner.incoming() {
+        let result: io::Result<()> =
+            stream_result.and_then(|stream| accept(&server, stream, handle));
+        if let Err(e) = result {
+            eprintln!("{} [ERROR] {}", get_now(), e)
+        }
+    }
+}
+
+/// Hands a connection to a worker, or closes it if the maximum number of connections is open.
+///
+/// A connection waits in the queue while every worker is busy. A worker whose client
+/// sends nothing for a while closes that connection and takes the next one,
+/// so clients that keep their connections open never keep the waiting ones from being served.
+///
+/// # Arguments
+///
+/// * `server` - The server.
+/// * `stream` - The accepted connection.
+/// * `handle` - The function that serves the connection in its protocol.
+fn accept(server: &Arc<Server>, stream: TcpStream, handle: Handler) -> io::Result<()> {
+    let Some(permit) = server.connections.acquire() else {
+        eprintln!(
+            "{} [WARN] Refused a connection from {}, because {} connections are already open.",
+            get_now(),
+            stream.peer_addr()?,
+            server.connections.active()
+        );
+        return Ok(());
+    };
+
+    // 何も送らないクライアントや受け取らないクライアントがワーカーを占有し続けないようにする
+    let connection: Connection = Connection::new(
+        stream,
+        server.read_timeout,
+        server.write_timeout,
+        server.pool.clone(),
+    )?;
+    let shared: Arc<Server> = server.clone();
+    server.pool.execute(move || {
+        handle(&connection, &shared.kvs);
+        drop(permit);
+    });
+    Ok(())
+}
+
+/// Parses the command line arguments.
+///
+/// Supported arguments:
+/// - `--data-dir <DIR>`: the data directory (default: `./data/`).
+/// - `--memtable-entries <N>`: the maximum number of entries in the memtable.
+/// - `--memtable-bytes <N>`: the maximum size of the memtable in bytes.
+/// - `--create`: create the data directory if it does not exist.
+/// - `--durability <always|never|MILLISECONDS>`: when the WAL is synced (default: `always`).
+/// - `--read-only`: open the store read-only, sharing the data directory with other read-only servers.
+/// - `--upgrade`: rewrite a data directory of the earlier release in the current format before opening it.
+/// - `--compression <none|lz|lz-huffman>`: how new SSTables are compressed (default: `none`).
+/// - `--compression-level <1-9>`: the compression level, from fastest to smallest (default: 6).
+/// - `--resp-port <PORT>`: also serve the Redis protocol (RESP2/RESP3) on the port.
+/// - `--http-port <PORT>`: also serve the HTTP/JSON API on the port.
+/// - `--workers <N>`: the number of threads that serve connections (default: 64).
+/// - `--max-connections <N>`: the maximum number of open connections (default: 256).
+/// - `--read-timeout <SECONDS>`: how long to wait for a client to send data, 0 for ever (default: 300).
+/// - `--write-timeout <SECONDS>`: how long to wait for a client to receive data, 0 for ever (default: 30).
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut options: KVSOptions = KVSOptions::new();
+    let mut upgrade: bool = false;
+    let mut resp_port: Option<u16> = None;
+    let mut http_port: Option<u16> = None;
+    let mut workers: usize = DEFAULT_WORKERS;
+    let mut max_connections: usize = DEFAULT_MAX_CONNECTIONS;
+    let mut read_timeout: Option<Duration> = Some(DEFAULT_READ_TIMEOUT);
+    let mut write_timeout: Option<Duration> = Some(DEFAULT_WRITE_TIMEOUT);
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--data-dir" => options = options.data_dir(next_value(&mut iter, arg)?),
+            "--memtable-entries" => {
+                options = options.memtable_max_entries(parse_number(&mut iter, arg)?)
+            }
+            "--memtable-bytes" => {
+                options = options.memtable_max_bytes(parse_number(&mut iter, arg)?)
+            }
+            "--create" => options = options.create_if_missing(true),
+            "--durability" => options = options.durability(parse_durability(&mut iter, arg)?),
+            "--read-only" => options = options.read_only(true),
+            "--upgrade" => upgrade = true,
+            "--compression" => options = options.compression(parse_compression(&mut iter, arg)?),
+            "--compression-level" => {
+                let level: usize = parse_number(&mut iter, arg)?;
+                options = options.compression_level(level.try_into().unwrap_or(u32::MAX))
+            }
+            "--resp-port" => resp_port = Some(parse_port(&mut iter, arg)?),
+            "--http-port" => http_port = Some(parse_port(&mut iter, arg)?),
+            "--workers" => workers = parse_number(&mut iter, arg)?,
+            "--max-connections" => max_connections = parse_number(&mut iter, arg)?,
+            "--read-timeout" => read_timeout = parse_timeout(&mut iter, arg)?,
+            "--write-timeout" => write_timeout = parse_timeout(&mut iter, arg)?,
+            _ => return Err(format!("Unknown argument '{arg}'.")),
+        }
+    }
+
+    Ok(Config {
+        options,
+        upgrade,
+        resp_port,
+        http_port,
+        workers,
+        max_connections,
+        read_timeout,
+        write_timeout,
+    })
+}
+
+/// Returns the value that follows an argument.
+fn next_value<'a>(
+    iter: &mut std::slice::Iter<'a, String>,
+    arg: &str,
+) -> Result<&'a String, String> {
+    iter.next()
+        .ok_or_else(|| format!("The argument '{arg}' requires a value."))
+}
+
+/// Parses the value that follows an argument as a number.
+fn parse_number(iter: &mut std::slice::Iter<'_, String>, arg: &str) -> Result<usize, String> {
+    let value: &String = next_value(iter, arg)?;
+    value
+        .parse::<usize>()
+        .map_err(|e| format!("Invalid value '{value}' for '{arg}'. {e}"))
+}
+
+/// Parses the value that follows an argument as a port number.
+fn parse_port(iter: &mut std::slice::Iter<'_, String>, arg: &str) -> Result<u16, String> {
+    let value: &String = next_value(iter, arg)?;
+    value
+        .parse::<u16>()
+        .map_err(|e| format!("Invalid value '{value}' for '{arg}'. {e}"))
+}
+
+/// Parses the value that follows an argument as a timeout in seconds, where 0 means no timeout.
+fn parse_timeout(
+    iter: &mut std::slice::Iter<'_, String>,
+    arg: &str,
+) -> Result<Option<Duration>, String> {
+    let seconds: usize = parse_number(iter, arg)?;
+    Ok((seconds > 0).then(|| Duration::from_secs(seconds as u64)))
+}
+
+/// Parses the value that follows an argument as a `Compression`.
+fn parse_compression(
+    iter: &mut std::slice::Iter<'_, String>,
+    arg: &str,
+) -> Result<Compression, String> {
+    let value: &String = next_value(iter, arg)?;
+    match value.as_str() {
+        "none" => Ok(Compression::None),
+        "lz" => Ok(Compression::Lz),
+        "lz-huffman" => Ok(Compression::LzHuffman),
+        _ => Err(format!("Invalid value '{value}' for '{arg}'.")),
+    }
+}
+
+/// Parses the value that follows an argument as a `Durability`.
+fn parse_durability(
+    iter: &mut std::slice::Iter<'_, String>,
+    arg: &str,
+) -> Result<Durability, String> {
+    let value: &String = next_value(iter, arg)?;
+    match value.as_str() {
+        "always" => Ok(Durability::Always),
+        "never" => Ok(Durability::Never),
+        millis => match millis.parse::<u64>() {
+            // 間隔が 0 だと同期のスレッドが休まずに回り続ける
+            Ok(0) => Err(format!(
+                "Invalid value '{value}' for '{arg}'. The interval must be at least 1 millisecond."
+            )),
+            Ok(ms) => Ok(Durability::Periodic(Duration::from_millis(ms))),
+            Err(e) => Err(format!("Invalid value '{value}' for '{arg}'. {e}")),
+        },
+    }
+}
+
+/// Handles a client connection until it is closed.
+///
+/// Requests are read and answered one after another on the same connection.
+fn handle(connection: &Connection, kvs: &KVS) {
+    let mut reader: BufReader<&Connection> = BufReader::new(connection);
+    let mut writer: BufWriter<&Connection> = BufWriter::new(connection);
+    if let Err(e) = serve_connection(&mut reader, &mut writer, kvs) {
+        eprintln!("{} [ERROR] {}", get_now(), e)
+    }
+}
+
+/// Reads requests and writes their responses in order until the client closes the connection.
+///
+/// Responses are flushed when no more requests have arrived, so the responses to pipelined
+/// requests are sent together.
+fn serve_connection<R: Read, W: Write>(
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+    kvs: &KVS,
+) -> Result<(), ProtocolError> {
+    loop {
+        let request: Request = match Request::read_from(reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // 接続が切れたかタイムアウトしたので、応答せずに閉じる
+            Err(e @ ProtocolError::FailedIO(_)) => return Err(e),
+            Err(e) => {
+                // 壊れたフレームの後は区切りが分からないので、エラーを返して閉じる
+                Response::Error(e.to_string()).write_to(writer)?;
+                flush(writer)?;
+                return Err(e);
+            }
+        };
+        println!(
+            "{} [INFO] Recieved request '{}'",
+            get_now(),
+            request.command()
+        );
+
+        let response: Response = execute(kvs, request);
+        if let Response::Error(msg) = &response {
+            eprintln!("{} [ERROR] {}", get_now(), msg)
+        }
+        response.write_to(writer)?;
+        if reader.buffer().is_empty() {
+            flush(writer)?;
+        }
+    }
+}
+
+/// Flushes the responses written to a connection.
+fn flush<W: Write>(writer: &mut W) -> Result<(), ProtocolError> {
+    writer
+        .flush()
+        .map_err(|e| ProtocolError::FailedIO(e.to_string()))
+}
+
+/// Executes a request on the store and returns the response.
+fn execute(kvs: &KVS, request: Request) -> Response {
+    let result: Result<Response, KVSError> = match request {
+        Request::Get { key } => kvs.get_bytes(&key).map(|value| match value {
+            Some(value) => Response::Ok(value.into_bytes()),
+            None => Response::NotFound,
+        }),
+        Request::Put { key, value } => kvs.put_bytes(&key, &value).map(|_| ok()),
+        Request::PutWithTtl { key, value, ttl } => {
+            kvs.put_with_ttl_bytes(&key, &value, ttl).map(|_| ok())
+        }
+        Request::Delete { key } => kvs.delete_bytes(&key).map(|_| ok()),
+        // 有効期限のない値は -1 を返す
+        Request::Ttl { key } => kvs.get_bytes(&key).map(|value| match value {
+            Some(value) => {
+                let ttl: String = match value.ttl() {
+                    Some(ttl) => ttl.as_secs().to_string(),
+                    None => "-1".to_string(),
+                };
+                Response::Ok(ttl.into_bytes())
+            }
+            None => Response::NotFound,
+        }),
+        Request::CompareAndSwap {
+            key,
+            expected,
+            value,
+        } => kvs
+            .compare_and_swap_bytes(&key, Some(&expected), &value)
+            .map(applied),
+        Request::PutIfAbsent { key, value } => kvs.put_if_absent_bytes(&key, &value).map(applied),
+        Request::DeleteIfEquals { key, expected } => {
+            kvs.delete_if_equals_bytes(&key, &expected).map(applied)
+        }
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+/// Returns the response of a write without a value.
+fn ok() -> Response {
+    Response::Ok(Vec::new())
+}
+
+/// Returns the response of a conditional write, `1` if it was applied and `0` if not.
+fn applied(applied: bool) -> Response {
+    let body: &[u8] = if applied { b"1" } else { b"0" };
+    Response::Ok(body.to_vec())
+}
+
+/// Gets the current time as a formatted string.
+fn get_now() -> String {
+    let now = chrono::Local::now();
+    now.format("%F %T%.3f").to_string()
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_parse_args() {
+        // 引数なしのケース
+        let config: Config = parse_args(&[]).unwrap();
+        assert_eq!(config.options, KVSOptions::new());
+
+        // 引数ありのケース
+        let args: Vec<String> = [
+            "--data-dir",
+            "/tmp/kvsd",
+            "--memtable-entries",
+            "10",
+            "--create",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let config: Config = parse_args(&args).unwrap();
+        let expected: KVSOptions = KVSOptions::new()
+            .data_dir("/tmp/kvsd")
+            .memtable_max_entries(10)
+            .create_if_missing(true);
+        assert_eq!(config.options, expected);
+
+        // durability のケース
+        let args: Vec<String> = vec!["--durability".to_string(), "100".to_string()];
+        let config: Config = parse_args(&args).unwrap();
+        let expected: KVSOptions =
+            KVSOptions::new().durability(Durability::Periodic(Duration::from_millis(100)));
+        assert_eq!(config.options, expected);
+        let args: Vec<String> = vec!["--durability".to_string(), "never".to_string()];
+        let config: Config = parse_args(&args).unwrap();
+        assert_eq!(
+            config.options,
+            KVSOptions::new().durability(Durability::Never)
+        );
+        let args: Vec<String> = vec!["--durability".to_string(), "0".to_string()];
+        assert!(parse_args(&args).is_err());
+
+        // 読み取り専用のケース
+        let args: Vec<String> = vec!["--read-only".to_string()];
+        let config: Config = parse_args(&args).unwrap();
+        assert_eq!(config.options, KVSOptions::new().read_only(true));
+
+        // 以前のリリースのデータディレクトリを書き換えるケース
+        assert!(!parse_args(&[]).unwrap().upgrade);
+        let args: Vec<String> = vec!["--upgrade".to_string()];
+        assert!(parse_args(&args).unwrap().upgrade);
+
+        // 圧縮のケース
+        let args: Vec<String> = ["--compression", "lz-huffman", "--compression-level", "9"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let config: Config = parse_args(&args).unwrap();
+        let expected: KVSOptions = KVSOptions::new()
+            .compression(Compression::LzHuffman)
+            .compression_level(9);
+        assert_eq!(config.options, expected);
+        let args: Vec<String> = vec!["--compression".to_string(), "zip".to_string()];
+        assert!(parse_args(&args).is_err());
+
+        // RESP のポートのケース
+        assert_eq!(parse_args(&[]).unwrap().resp_port, None);
+        let args: Vec<String> = vec!["--resp-port".to_string(), "6379".to_string()];
+        assert_eq!(parse_args(&args).unwrap().resp_port, Some(6379));
+        let args: Vec<String> = vec!["--resp-port".to_string(), "70000".to_string()];
+        assert!(parse_args(&args).is_err());
+
+        // HTTP のポートのケース
+        assert_eq!(parse_args(&[]).unwrap().http_port, None);
+        let args: Vec<String> = vec!["--http-port".to_string(), "8080".to_string()];
+        assert_eq!(parse_args(&args).unwrap().http_port, Some(8080));
+
+        // 接続数・タイムアウトのケース
+        let config: Config = parse_args(&[]).unwrap();
+        assert_eq!(config.workers, DEFAULT_WORKERS);
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.read_timeout, Some(DEFAULT_READ_TIMEOUT));
+        assert_eq!(config.write_timeout, Some(DEFAULT_WRITE_TIMEOUT));
+        let args: Vec<String> = [
+            "--workers",
+            "8",
+            "--max-connections",
+            "100",
+            "--read-timeout",
+            "10",
+            "--write-timeout",
+            "0",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let config: Config = parse_args(&args).unwrap();
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.max_connections, 100);
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(config.write_timeout, None);
+
+        // 不正な引数のケース
+        let args: Vec<String> = vec!["--memtable-bytes".to_string(), "abc".to_string()];
+        assert!(parse_args(&args).is_err());
+        let args: Vec<String> = vec!["--data-dir".to_string()];
+        assert!(parse_args(&args).is_err());
+        let args: Vec<String> = vec!["--unknown".to_string()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let kvs: KVS = test_util::temp_store("execute");
+        let key: Vec<u8> = b"k1".to_vec();
+
+        // 空白を含む値を書いて読むケース
+        let value: Vec<u8> = b"a value with spaces".to_vec();
+        let request: Request = Request::Put {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        assert_eq!(execute(&kvs, request), Response::Ok(Vec::new()));
+        let request: Request = Request::Get { key: key.clone() };
+        assert_eq!(execute(&kvs, request), Response::Ok(value.clone()));
+        let request: Request = Request::Ttl { key: key.clone() };
+        assert_eq!(execute(&kvs, request), Response::Ok(b"-1".to_vec()));
+
+        // 条件つきの書き込みのケース
+        let request: Request = Request::CompareAndSwap {
+            key: key.clone(),
+            expected: b"other".to_vec(),
+            value: b"v2".to_vec(),
+        };
+        assert_eq!(execute(&kvs, request), Response::Ok(b"0".to_vec()));
+        let request: Request = Request::PutIfAbsent {
+            key: b"k2".to_vec(),
+            value: b"v2".to_vec(),
+        };
+        assert_eq!(execute(&kvs, request), Response::Ok(b"1".to_vec()));
+        let request: Request = Request::DeleteIfEquals {
+            key: b"k2".to_vec(),
+            expected: b"v2".to_vec(),
+        };
+        assert_eq!(execute(&kvs, request), Response::Ok(b"1".to_vec()));
+
+        // 有効期限つきの値のケース
+        let request: Request = Request::PutWithTtl {
+            key: b"session".to_vec(),
+            value: b"token".to_vec(),
+            ttl: Duration::from_secs(60),
+        };
+        assert_eq!(execute(&kvs, request), Response::Ok(Vec::new()));
+        let request: Request = Request::Ttl {
+            key: b"session".to_vec(),
+        };
+        assert!(
+            matches!(execute(&kvs, request), Response::Ok(ttl) if ttl == b"59" || ttl == b"60")
+        );
+
+        // 存在しないキーは NOT_FOUND になるケース
+        let request: Request = Request::Delete { key: key.clone() };
+        assert_eq!(execute(&kvs, request), Response::Ok(Vec::new()));
+        let request: Request = Request::Get { key: key.clone() };
+        assert_eq!(execute(&kvs, request), Response::NotFound);
+        let request: Request = Request::Ttl { key };
+        assert_eq!(execute(&kvs, request), Response::NotFound);
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let kvs: KVS = test_util::temp_store("serve_connection");
+
+        // パイプラインで送ったリクエストに、順に応答するケース
+        let requests: Vec<Request> = (0..100)
+            .flat_map(|i| {
+                let key: Vec<u8> = format!("k{i}").into_bytes();
+                [
+                    Request::Put {
+                        key: key.clone(),
+                        value: format!("v{i}").into_bytes(),
+                    },
+                    Request::Get { key },
+                ]
+            })
+            .collect();
+        let mut input: Vec<u8> = Vec::new();
+        for request in &requests {
+            request.write_to(&mut input).unwrap();
+        }
+        let mut reader: BufReader<&[u8]> = BufReader::new(&input[..]);
+        let mut output: Vec<u8> = Vec::new();
+        serve_connection(&mut reader, &mut output, &kvs).unwrap();
+
+        let mut responses: &[u8] = &output;
+        for i in 0..100 {
+            assert_eq!(
+                Response::read_from(&mut responses).unwrap(),
+                Response::Ok(Vec::new())
+            );
+            assert_eq!(
+                Response::read_from(&mut responses).unwrap(),
+                Response::Ok(format!("v{i}").into_bytes())
+            );
+        }
+        assert!(responses.is_empty());
+
+        // 壊れたフレームにはエラーを返して閉じるケース
+        let mut input: Vec<u8> = Vec::new();
+        Request::Get {
+            key: b"k1".to_vec(),
+        }
+        .write_to(&mut input)
+        .unwrap();
+        input.extend_from_slice(&[0, 0, 0, 2, 9, 9]);
+        let mut reader: BufReader<&[u8]> = BufReader::new(&input[..]);
+        let mut output: Vec<u8> = Vec::new();
+        assert!(serve_connection(&mut reader, &mut output, &kvs).is_err());
+        let mut responses: &[u8] = &output;
+        assert_eq!(
+            Response::read_from(&mut responses).unwrap(),
+            Response::Ok(b"v1".to_vec())
+        );
+        assert!(matches!(
+            Response::read_from(&mut responses).unwrap(),
+            Response::Error(msg) if msg.contains("version 9")
+        ));
+    }
+
+    #[test]
+    fn test_serve() {
+        let server: Arc<Server> = Arc::new(Server {
+            kvs: test_util::temp_store("serve"),
+            pool: ThreadPool::new(2),
+            connections: ConnectionLimit::new(2),
+            read_timeout: Some(Duration::from_millis(500)),
+            write_timeout: Some(Duration::from_secs(5)),
+        });
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let shared: Arc<Server> = server.clone();
+        thread::spawn(move || serve(listener, shared, handle));
+
+        // 何も送らないクライアントがいても、他のクライアントに応答するケース
+        let mut idle: TcpStream = TcpStream::connect(&address).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client: kvsd::Client = kvsd::Client::connect(&address).unwrap();
+        let request: Request = Request::Put {
+            key: b"k1".to_vec(),
+            value: b"v1".to_vec(),
+        };
+        assert_eq!(client.send(&request).unwrap(), Response::Ok(Vec::new()));
+        assert_eq!(server.connections.active(), 2);
+
+        // 上限を超える接続は閉じるケース
+        let mut refused: kvsd::Client = kvsd::Client::connect(&address).unwrap();
+        let request: Request = Request::Get {
+            key: b"k1".to_vec(),
+        };
+        assert!(refused.send(&request).is_err());
+
+        // 何も送らないクライアントはタイムアウトで閉じるケース
+        let mut buf: Vec<u8> = Vec::new();
+        assert_eq!(idle.read_to_end(&mut buf).unwrap(), 0);
+
+        // 接続が閉じられた後は、また受け付けるケース
+        while server.connections.active() > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut client: kvsd::Client = kvsd::Client::connect(&address).unwrap();
+        assert_eq!(client.send(&request).unwrap(), Response::Ok(b"v1".to_vec()));
+        drop(client);
+
+        // 開いたままの接続がワーカーの数より多くても、後の接続がすべて処理されるケース
+        let server: Arc<Server> = Arc::new(Server {
+            kvs: test_util::temp_store("serve_many"),
+            pool: ThreadPool::new(2),
+            connections: ConnectionLimit::new(10),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(5)),
+        });
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let shared: Arc<Server> = server.clone();
+        thread::spawn(move || serve(listener, shared, handle));
+        let mut clients: Vec<kvsd::Client> = Vec::new();
+        for i in 0..4 {
+            let mut client: kvsd::Client = kvsd::Client::connect(&address).unwrap();
+            let request: Request = Request::Put {
+                key: format!("k{i}").into_bytes(),
+                value: format!("v{i}").into_bytes(),
+            };
+            assert_eq!(client.send(&request).unwrap(), Response::Ok(Vec::new()));
+            clients.push(client);
+        }
+        // 後の接続に譲るために閉じられた接続は、接続し直せば続けられる
+        let request: Request = Request::Get {
+            key: b"k0".to_vec(),
+        };
+        assert!(clients[0].send(&request).is_err());
+        let mut client: kvsd::Client = kvsd::Client::connect(&address).unwrap();
+        assert_eq!(client.send(&request).unwrap(), Response::Ok(b"v0".to_vec()));
+    }
+}
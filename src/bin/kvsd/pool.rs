@@ -0,0 +1,225 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a read waits on the socket before it checks whether to give way.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a connection may send nothing while other connections wait for a worker.
+const YIELD_AFTER: Duration = Duration::from_secs(1);
+
+/// A job run by a worker of a `ThreadPool`.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of worker threads that run jobs in the order they were given.
+///
+/// When every worker is busy, the jobs wait in a queue until one of them becomes free.
+#[derive(Clone)]
+pub struct ThreadPool {
+    /// The queue of jobs shared by the workers.
+    sender: Sender<Job>,
+    /// The number of jobs in the queue.
+    queued: Arc<AtomicUsize>,
+}
+
+impl ThreadPool {
+    /// Starts a pool of worker threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers` - The number of worker threads, at least 1.
+    pub fn new(workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+        let queued: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers.max(1) {
+            let receiver: Arc<Mutex<Receiver<Job>>> = receiver.clone();
+            let queued: Arc<AtomicUsize> = queued.clone();
+            thread::spawn(move || loop {
+                // ロックは次のジョブを受け取る間だけ保持する
+                let job: Job = match receiver.lock() {
+                    Ok(receiver) => match receiver.recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    },
+                    Err(_) => return,
+                };
+                queued.fetch_sub(1, Ordering::AcqRel);
+                // ジョブが panic してもワーカーは減らさない
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            });
+        }
+        ThreadPool { sender, queued }
+    }
+
+    /// Queues a job to run on one of the workers.
+    ///
+    /// # Arguments
+    ///
+    /// * `job` - The job.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        // ワーカーはプールより先に終了しないので、送信は失敗しない
+        let _ = self.sender.send(Box::new(job));
+    }
+
+    /// Returns the number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+/// A client connection served by a worker of a `ThreadPool`.
+///
+/// A connection that sends nothing for `YIELD_AFTER` while other connections wait in the
+/// pool's queue reads as closed, so that its worker serves the next connection.
+/// Idle keep-alive clients therefore cannot hold every worker.
+pub struct Connection {
+    /// The socket.
+    stream: TcpStream,
+    /// How long to wait for the client to send data, or `None` to wait forever.
+    read_timeout: Option<Duration>,
+    /// The pool whose queue the connection gives way to.
+    pool: ThreadPool,
+}
+
+impl Connection {
+    /// Creates a new `Connection`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The socket.
+    /// * `read_timeout` - How long to wait for the client to send data, or `None` to wait forever.
+    /// * `write_timeout` - How long to wait for the client to receive data, or `None` to wait forever.
+    /// * `pool` - The pool that serves the connection.
+    pub fn new(
+        stream: TcpStream,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+        pool: ThreadPool,
+    ) -> io::Result<Self> {
+        // 一定の間隔で読み込みを中断して、待っている接続がないか確かめる
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(write_timeout)?;
+        Ok(Connection {
+            stream,
+            read_timeout,
+            pool,
+        })
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started: Instant = Instant::now();
+        loop {
+            match (&self.stream).read(buf) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let idle: Duration = started.elapsed();
+                    if self.read_timeout.is_some_and(|timeout| timeout <= idle) {
+                        return Err(e);
+                    }
+                    if YIELD_AFTER <= idle && 0 < self.pool.queued() {
+                        return Ok(0);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.stream).flush()
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Barrier};
+
+    use crate::pool::*;
+
+    #[test]
+    fn test_thread_pool() {
+        // ワーカーの数だけ同時に実行するケース
+        let pool: ThreadPool = ThreadPool::new(4);
+        let barrier: Arc<Barrier> = Arc::new(Barrier::new(5));
+        for _ in 0..4 {
+            let barrier: Arc<Barrier> = barrier.clone();
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+
+        // panic したジョブの後もワーカーが残るケース
+        let pool: ThreadPool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel::<usize>();
+        for i in 0..10 {
+            let sender: Sender<usize> = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        let received: Vec<usize> = (0..10)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(received, (0..10).collect::<Vec<usize>>());
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let pool: ThreadPool = ThreadPool::new(1);
+        let read_timeout: Option<Duration> = Some(Duration::from_millis(1500));
+        let connection: Connection =
+            Connection::new(stream, read_timeout, None, pool.clone()).unwrap();
+        let mut buf: [u8; 4] = [0; 4];
+
+        // 送られたデータは間隔より長く待っても読めるケース
+        let writer = thread::spawn(move || {
+            thread::sleep(POLL_INTERVAL * 3);
+            client.write_all(b"ping").unwrap();
+            client
+        });
+        assert_eq!((&connection).read(&mut buf).unwrap(), 4);
+        let client: TcpStream = writer.join().unwrap();
+
+        // 待っている接続があれば、何も送らない接続は閉じたものとして読むケース
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move || receiver.recv().unwrap());
+        pool.execute(|| {});
+        let started: Instant = Instant::now();
+        assert_eq!((&connection).read(&mut buf).unwrap(), 0);
+        assert!(YIELD_AFTER <= started.elapsed());
+        sender.send(()).unwrap();
+
+        // 待っている接続がなければ、タイムアウトまで待つケース
+        while pool.queued() > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let started: Instant = Instant::now();
+        assert!((&connection).read(&mut buf).is_err());
+        assert!(Duration::from_millis(1500) <= started.elapsed());
+        drop(client);
+    }
+}
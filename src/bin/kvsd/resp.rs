@@ -0,0 +1,849 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    ops::Bound,
+    time::Duration,
+};
+
+use kvsd::{KVSError, Transaction, WriteBatch, KVS, MAX_FRAME_SIZE};
+
+use crate::{get_now, pool::Connection};
+
+/// The largest number of elements of a command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// The largest total size of the arguments of a command in bytes.
+const MAX_COMMAND_SIZE: usize = MAX_FRAME_SIZE;
+
+/// The number of keys a `SCAN` visits when `COUNT` is not given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply to a RESP command.
+///
+/// Replies are written in RESP2 or RESP3, whichever the connection chose with `HELLO`.
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    /// A simple string, such as `OK`.
+    Simple(&'static str),
+    /// An error, whose message starts with an error code such as `ERR`.
+    Error(String),
+    /// An integer.
+    Integer(i64),
+    /// A binary-safe string.
+    Bulk(Vec<u8>),
+    /// The absence of a value.
+    Null,
+    /// An array of replies.
+    Array(Vec<Reply>),
+    /// A map of replies, written as a flat array of keys and values in RESP2.
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    /// Creates an error reply with the `ERR` code.
+    fn err(msg: &str) -> Reply {
+        Reply::Error(format!("ERR {msg}"))
+    }
+
+    /// Encodes the reply in a protocol version.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - 2 for RESP2, 3 for RESP3.
+    /// * `out` - The buffer the reply is appended to.
+    fn encode(&self, version: u8, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{s}\r\n").as_bytes()),
+            // エラーメッセージは 1 行でなければならない
+            Reply::Error(msg) => {
+                let line: String = msg.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{line}\r\n").as_bytes());
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{n}\r\n").as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if version == 3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(version, out);
+                }
+            }
+            Reply::Map(entries) => {
+                let header: String = match version {
+                    3 => format!("%{}\r\n", entries.len()),
+                    _ => format!("*{}\r\n", entries.len() * 2),
+                };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in entries {
+                    key.encode(version, out);
+                    value.encode(version, out);
+                }
+            }
+        }
+    }
+}
+
+/// The state of a RESP connection.
+struct Session {
+    /// The protocol version of the replies, 2 or 3.
+    version: u8,
+}
+
+impl Session {
+    /// Creates a session that speaks RESP2 until the client sends `HELLO 3`.
+    fn new() -> Self {
+        Session { version: 2 }
+    }
+}
+
+/// Handles a RESP client connection until it is closed.
+///
+/// Commands are read and answered one after another on the same connection.
+pub fn handle(connection: &Connection, kvs: &KVS) {
+    let mut reader: BufReader<&Connection> = BufReader::new(connection);
+    let mut writer: &Connection = connection;
+    if let Err(e) = serve(&mut reader, &mut writer, kvs) {
+        eprintln!("{} [ERROR] {}", get_now(), e)
+    }
+}
+
+/// Reads commands and writes their replies until the client closes the connection or quits.
+fn serve<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, kvs: &KVS) -> Result<(), String> {
+    let mut session: Session = Session::new();
+    loop {
+        let args: Vec<Vec<u8>> = match read_command(reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // 壊れたコマンドの後は区切りが分からないので、エラーを返して閉じる
+                let mut out: Vec<u8> = Vec::new();
+                Reply::err(&format!("Protocol error: {e}")).encode(session.version, &mut out);
+                let _ = writer.write_all(&out);
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let name: String = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        println!("{} [INFO] Recieved RESP command '{}'", get_now(), name);
+        let reply: Reply = execute(kvs, &mut session, &name, &args[1..]);
+        let mut out: Vec<u8> = Vec::new();
+        reply.encode(session.version, &mut out);
+        writer
+            .write_all(&out)
+            .and_then(|_| writer.flush())
+            .map_err(|e| e.to_string())?;
+        if name == "quit" {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a command as its arguments.
+///
+/// A command is an array of bulk strings, or an inline command of words separated by spaces.
+/// Returns `None` if the connection was closed before a new command started.
+///
+/// Nothing is allocated from the lengths the client claims: the arguments grow as their
+/// bytes arrive, and their total size is limited to `MAX_COMMAND_SIZE`.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, String> {
+    let line: Vec<u8> = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args: Vec<Vec<u8>> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count: usize = parse_length(&line[1..], MAX_ARGS)?;
+    let mut args: Vec<Vec<u8>> = Vec::new();
+    let mut total: usize = 0;
+    for _ in 0..count {
+        let header: Vec<u8> = match read_line(reader)? {
+            Some(header) if header.first() == Some(&b'$') => header,
+            _ => return Err("expected a bulk string".to_string()),
+        };
+        let len: usize = parse_length(&header[1..], MAX_COMMAND_SIZE - total)?;
+        total += len;
+        let mut arg: Vec<u8> = Vec::new();
+        Read::take(&mut *reader, len as u64 + 2)
+            .read_to_end(&mut arg)
+            .map_err(|e| e.to_string())?;
+        if arg.len() < len + 2 {
+            return Err("a bulk string is truncated".to_string());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err("a bulk string is not followed by CRLF".to_string());
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line ending.
+///
+/// Returns `None` if the connection was closed before the line started.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let mut line: Vec<u8> = Vec::new();
+    // 行の長さにも上限を設け、改行のないデータでメモリを使い切らないようにする
+    let read: usize = Read::take(&mut *reader, MAX_FRAME_SIZE as u64)
+        .read_until(b'\n', &mut line)
+        .map_err(|e| e.to_string())?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err("a line is too long or truncated".to_string());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parses the length of an array or a bulk string.
+fn parse_length(bytes: &[u8], max: usize) -> Result<usize, String> {
+    let len: usize = match std::str::from_utf8(bytes).ok().and_then(|s| s.parse().ok()) {
+        Some(len) => len,
+        None => return Err("invalid length".to_string()),
+    };
+    if len > max {
+        return Err("the length is too large".to_string());
+    }
+    Ok(len)
+}
+
+/// Executes a command on the store and returns its reply.
+///
+/// # Arguments
+///
+/// * `kvs` - The store.
+/// * `session` - The state of the connection.
+/// * `name` - The name of the command in lowercase.
+/// * `args` - The arguments of the command.
+fn execute(kvs: &KVS, session: &mut Session, name: &str, args: &[Vec<u8>]) -> Reply {
+    let arity_ok: bool = match name {
+        "ping" => args.len() <= 1,
+        "echo" | "get" | "ttl" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" => !args.is_empty(),
+        "mset" => !args.is_empty() && args.len().is_multiple_of(2),
+        "expire" => args.len() >= 2,
+        "scan" => !args.is_empty(),
+        "quit" => args.is_empty(),
+        _ => true,
+    };
+    if !arity_ok {
+        return Reply::err(&format!("wrong number of arguments for '{name}' command"));
+    }
+
+    let result: Result<Reply, KVSError> = match name {
+        "ping" => Ok(match args.first() {
+            Some(msg) => Reply::Bulk(msg.clone()),
+            None => Reply::Simple("PONG"),
+        }),
+        "echo" => Ok(Reply::Bulk(args[0].clone())),
+        "hello" => Ok(hello(session, args)),
+        // redis-cli やクライアントライブラリが接続時に送るコマンドには、空の応答を返す
+        "command" => Ok(Reply::Array(Vec::new())),
+        "client" => Ok(Reply::Simple("OK")),
+        "select" if args.len() == 1 && args[0] == b"0" => Ok(Reply::Simple("OK")),
+        "select" => Ok(Reply::err("only the database 0 is supported")),
+        "quit" => Ok(Reply::Simple("OK")),
+        "get" => kvs.get_bytes(&args[0]).map(|value| match value {
+            Some(value) => Reply::Bulk(value.into_bytes()),
+            None => Reply::Null,
+        }),
+        "set" => set(kvs, args),
+        "del" => del(kvs, args),
+        "exists" => exists(kvs, args),
+        "mget" => args
+            .iter()
+            .map(|key| {
+                kvs.get_bytes(key).map(|value| match value {
+                    Some(value) => Reply::Bulk(value.into_bytes()),
+                    None => Reply::Null,
+                })
+            })
+            .collect::<Result<Vec<Reply>, KVSError>>()
+            .map(Reply::Array),
+        "mset" => {
+            let mut batch: WriteBatch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                batch.put_bytes(&pair[0], &pair[1]);
+            }
+            kvs.write(batch).map(|_| Reply::Simple("OK"))
+        }
+        "expire" => expire(kvs, args),
+        // 存在しないキーは -2、有効期限のない値は -1 を返す
+        "ttl" => kvs.get_bytes(&args[0]).map(|value| match value {
+            Some(value) => match value.ttl() {
+                Some(ttl) => Reply::Integer(ttl.as_secs() as i64),
+                None => Reply::Integer(-1),
+            },
+            None => Reply::Integer(-2),
+        }),
+        "scan" => scan(kvs, args),
+        _ => Ok(Reply::err(&format!("unknown command '{name}'"))),
+    };
+    match result {
+        Ok(reply) => reply,
+        Err(e) => Reply::err(&e.to_string()),
+    }
+}
+
+/// Executes `HELLO [protover]`, switching the protocol version if one is given.
+fn hello(session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    if let Some(version) = args.first() {
+        match version.as_slice() {
+            b"2" => session.version = 2,
+            b"3" => session.version = 3,
+            _ => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+        }
+    }
+    let entry = |key: &'static str, value: Reply| (Reply::Bulk(key.as_bytes().to_vec()), value);
+    Reply::Map(vec![
+        entry("server", Reply::Bulk(b"kvsd".to_vec())),
+        entry(
+            "version",
+            Reply::Bulk(env!("CARGO_PKG_VERSION").as_bytes().to_vec()),
+        ),
+        entry("proto", Reply::Integer(session.version as i64)),
+        entry("mode", Reply::Bulk(b"standalone".to_vec())),
+        entry("role", Reply::Bulk(b"master".to_vec())),
+        entry("modules", Reply::Array(Vec::new())),
+    ])
+}
+
+/// Executes `SET key value [EX seconds | PX milliseconds] [NX | XX]`.
+fn set(kvs: &KVS, args: &[Vec<u8>]) -> Result<Reply, KVSError> {
+    let (key, value): (&[u8], &[u8]) = (&args[0], &args[1]);
+    let mut ttl: Option<Duration> = None;
+    let mut condition: Option<bool> = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"ex" | b"px" if ttl.is_none() => {
+                let n: u64 = match options.next().and_then(|n| parse_integer(n)) {
+                    Some(n) if n > 0 => n as u64,
+                    _ => return Ok(Reply::err("invalid expire time in 'set' command")),
+                };
+                ttl = Some(match option.eq_ignore_ascii_case(b"ex") {
+                    true => Duration::from_secs(n),
+                    false => Duration::from_millis(n),
+                });
+            }
+            b"nx" if condition.is_none() => condition = Some(false),
+            b"xx" if condition.is_none() => condition = Some(true),
+            _ => return Ok(Reply::err("syntax error")),
+        }
+    }
+
+    // NX と XX は、キーの有無を読んだトランザクションで確かめてから書く
+    let applied: bool = retry(|| {
+        let mut txn: Transaction = kvs.transaction();
+        if let Some(must_exist) = condition {
+            if txn.get_bytes(key)?.is_some() != must_exist {
+                return Ok(false);
+            }
+        }
+        match ttl {
+            Some(ttl) => txn.put_with_ttl_bytes(key, value, ttl),
+            None => txn.put_bytes(key, value),
+        };
+        kvs.commit(txn)?;
+        Ok(true)
+    })?;
+    Ok(match applied {
+        true => Reply::Simple("OK"),
+        false => Reply::Null,
+    })
+}
+
+/// Executes `DEL key [key ...]` and replies with the number of keys deleted.
+fn del(kvs: &KVS, args: &[Vec<u8>]) -> Result<Reply, KVSError> {
+    let deleted: i64 = retry(|| {
+        let mut txn: Transaction = kvs.transaction();
+        let mut deleted: i64 = 0;
+        for key in args {
+            if txn.get_bytes(key)?.is_some() {
+                txn.delete_bytes(key);
+                deleted += 1;
+            }
+        }
+        kvs.commit(txn)?;
+        Ok(deleted)
+    })?;
+    Ok(Reply::Integer(deleted))
+}
+
+/// Executes `EXISTS key [key ...]` and replies with the number of the keys that exist.
+///
+/// A key given more than once is counted each time.
+fn exists(kvs: &KVS, args: &[Vec<u8>]) -> Result<Reply, KVSError> {
+    let mut count: i64 = 0;
+    for key in args {
+        if kvs.get_bytes(key)?.is_some() {
+            count += 1;
+        }
+    }
+    Ok(Reply::Integer(count))
+}
+
+/// Executes `EXPIRE key seconds` and replies with 1 if the key exists and 0 if not.
+///
+/// A time to live that is not positive deletes the key.
+fn expire(kvs: &KVS, args: &[Vec<u8>]) -> Result<Reply, KVSError> {
+    let key: &[u8] = &args[0];
+    let seconds: i64 = match parse_integer(&args[1]) {
+        Some(seconds) => seconds,
+        None => return Ok(Reply::err("value is not an integer or out of range")),
+    };
+    if args.len() > 2 {
+        return Ok(Reply::err("unsupported option for 'expire' command"));
+    }
+
+    let applied: bool = retry(|| {
+        let mut txn: Transaction = kvs.transaction();
+        let value: Vec<u8> = match txn.get_bytes(key)? {
+            Some(value) => value.into_bytes(),
+            None => return Ok(false),
+        };
+        match seconds {
+            ..=0 => txn.delete_bytes(key),
+            _ => txn.put_with_ttl_bytes(key, &value, Duration::from_secs(seconds as u64)),
+        };
+        kvs.commit(txn)?;
+        Ok(true)
+    })?;
+    Ok(Reply::Integer(applied as i64))
+}
+
+/// Executes `SCAN cursor [MATCH pattern] [COUNT count]`.
+///
+/// Keys are visited in key order. The cursor is `0` to start, and otherwise the last key
+/// visited in hex, so a key that exists for the whole scan is returned exactly once,
+/// and a cursor can be continued on any connection.
+fn scan(kvs: &KVS, args: &[Vec<u8>]) -> Result<Reply, KVSError> {
+    let start: Option<Vec<u8>> = match args[0].as_slice() {
+        b"0" => None,
+        cursor => match decode_hex(cursor) {
+            Some(last) => Some(last),
+            None => return Ok(Reply::err("invalid cursor")),
+        },
+    };
+    let mut pattern: Option<&[u8]> = None;
+    let mut count: usize = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value: Option<&Vec<u8>> = options.next();
+        match (option.to_ascii_lowercase().as_slice(), value) {
+            (b"match", Some(p)) => pattern = Some(p),
+            (b"count", Some(n)) => match parse_integer(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::err("value is not an integer or out of range")),
+            },
+            _ => return Ok(Reply::err("syntax error")),
+        }
+    }
+
+    let lower: Bound<&[u8]> = match &start {
+        None => Bound::Unbounded,
+        Some(last) => Bound::Excluded(last),
+    };
+
+    let mut keys: Vec<Reply> = Vec::new();
+    let mut last: Option<Vec<u8>> = None;
+    let mut iter = kvs.scan_bytes((lower, Bound::Unbounded))?;
+    for _ in 0..count {
+        let key: Vec<u8> = match iter.next() {
+            Some(entry) => entry?.0,
+            None => {
+                last = None;
+                break;
+            }
+        };
+        if pattern.is_none_or(|p| glob_match(p, &key)) {
+            keys.push(Reply::Bulk(key.clone()));
+        }
+        last = Some(key);
+    }
+    // 最後に見たキーの後にキーが残っていなければ、走査は終わり
+    if last.is_some() && iter.next().is_none() {
+        last = None;
+    }
+
+    let next: Vec<u8> = match last {
+        Some(last) => encode_hex(&last),
+        None => b"0".to_vec(),
+    };
+    Ok(Reply::Array(vec![Reply::Bulk(next), Reply::Array(keys)]))
+}
+
+/// Encodes bytes as lowercase hex.
+fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    bytes
+        .iter()
+        .flat_map(|b| [DIGITS[(b >> 4) as usize], DIGITS[(b & 0x0F) as usize]])
+        .collect()
+}
+
+/// Decodes hex, or returns `None` if it is not hex of whole bytes.
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let digit = |d: u8| (d as char).to_digit(16).map(|d| d as u8);
+    hex.chunks(2)
+        .map(|pair| Some((digit(pair[0])? << 4) | digit(pair[1])?))
+        .collect()
+}
+
+/// Runs a transaction again while it conflicts with other writes.
+fn retry<T>(mut f: impl FnMut() -> Result<T, KVSError>) -> Result<T, KVSError> {
+    loop {
+        match f() {
+            Err(KVSError::TransactionConflict(_)) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Parses an argument as a decimal integer.
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Returns `true` if a key matches a glob-style pattern.
+///
+/// `*` matches any bytes, `?` matches one byte, `[abc]`, `[a-z]` and `[^a]` match one byte
+/// of a set, and `\` escapes the next byte.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k): (usize, usize) = (0, 0);
+    // 最後の * の位置と、そこから読み直すキーの位置
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, k));
+            p += 1;
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, key[k]) {
+            p = next;
+            k += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p + 1;
+                k = star_k + 1;
+                star = Some((star_p, star_k + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|b| *b == b'*')
+}
+
+/// Matches one byte against the pattern element at `p`, other than `*`.
+///
+/// Returns the position after the element if it matches.
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        b'[' => {
+            let mut i: usize = p + 1;
+            let negate: bool = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched: bool = false;
+            loop {
+                let c: u8 = match pattern.get(i) {
+                    Some(b']') => break,
+                    Some(b'\\') if i + 1 < pattern.len() => {
+                        i += 1;
+                        pattern[i]
+                    }
+                    Some(c) => *c,
+                    // 閉じていない [ は文字として扱う
+                    None => return (byte == b'[').then_some(p + 1),
+                };
+                if pattern.get(i + 1) == Some(&b'-') && i + 2 < pattern.len() {
+                    let end: u8 = pattern[i + 2];
+                    matched |= c.min(end) <= byte && byte <= c.max(end);
+                    i += 3;
+                } else {
+                    matched |= c == byte;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some(i + 1)
+        }
+        c => (c == byte).then_some(p + 1),
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::{resp::*, test_util};
+
+    /// Sends commands to a new connection and returns everything written back.
+    fn run(kvs: &KVS, input: &[u8]) -> String {
+        let mut output: Vec<u8> = Vec::new();
+        serve(&mut &input[..], &mut output, kvs).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Encodes a command as an array of bulk strings.
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let bulks: Vec<Reply> = args
+            .iter()
+            .map(|a| Reply::Bulk(a.as_bytes().to_vec()))
+            .collect();
+        Reply::Array(bulks).encode(2, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_read_command() {
+        let input: &[u8] = b"*2\r\n$3\r\nGET\r\n$5\r\na b\r\n\r\nPING hello\r\n";
+        let mut reader: &[u8] = input;
+        assert_eq!(
+            read_command(&mut reader).unwrap(),
+            Some(vec![b"GET".to_vec(), b"a b\r\n".to_vec()])
+        );
+        assert_eq!(
+            read_command(&mut reader).unwrap(),
+            Some(vec![b"PING".to_vec(), b"hello".to_vec()])
+        );
+        assert_eq!(read_command(&mut reader).unwrap(), None);
+
+        // 壊れたコマンドのケース
+        for input in [
+            &b"*1\r\n:1\r\n"[..],
+            b"*1\r\n$3\r\nab",
+            b"*x\r\n",
+            b"*1\r\n$2\r\nabcd",
+        ] {
+            assert!(read_command(&mut &input[..]).is_err());
+        }
+
+        // 引数の合計が上限を超えるケース
+        let half: usize = MAX_COMMAND_SIZE / 2;
+        let first: String = format!("*3\r\n${half}\r\n");
+        let second: String = format!("\r\n${half}\r\n");
+        let input = first
+            .as_bytes()
+            .chain(std::io::repeat(b'a').take(half as u64))
+            .chain(second.as_bytes())
+            .chain(std::io::repeat(b'a').take(half as u64))
+            .chain(&b"\r\n$1\r\na\r\n"[..]);
+        let mut reader = BufReader::new(input);
+        assert_eq!(
+            read_command(&mut reader),
+            Err("the length is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let kvs: KVS = test_util::temp_store("resp_commands");
+        let input: Vec<u8> = [
+            command(&["PING"]),
+            command(&["SET", "k1", "hello world"]),
+            command(&["GET", "k1"]),
+            command(&["GET", "missing"]),
+            command(&["MSET", "k2", "v2", "k3", "v3"]),
+            command(&["MGET", "k1", "missing", "k3"]),
+            command(&["EXISTS", "k1", "k2", "missing", "k1"]),
+            command(&["DEL", "k2", "missing"]),
+            command(&["SET", "k1", "v", "NX"]),
+            command(&["SET", "k9", "v", "XX"]),
+            command(&["SET", "lock", "token", "NX", "EX", "30"]),
+            command(&["TTL", "lock"]),
+            command(&["EXPIRE", "k3", "100"]),
+            command(&["EXPIRE", "missing", "100"]),
+            command(&["TTL", "k1"]),
+            command(&["TTL", "missing"]),
+            command(&["GET"]),
+            command(&["FOO"]),
+            command(&["QUIT"]),
+            command(&["PING"]),
+        ]
+        .concat();
+        let expected: String = [
+            "+PONG\r\n",
+            "+OK\r\n",
+            "$11\r\nhello world\r\n",
+            "$-1\r\n",
+            "+OK\r\n",
+            "*3\r\n$11\r\nhello world\r\n$-1\r\n$2\r\nv3\r\n",
+            ":3\r\n",
+            ":1\r\n",
+            "$-1\r\n",
+            "$-1\r\n",
+            "+OK\r\n",
+            ":30\r\n",
+            ":1\r\n",
+            ":0\r\n",
+            ":-1\r\n",
+            ":-2\r\n",
+            "-ERR wrong number of arguments for 'get' command\r\n",
+            "-ERR unknown command 'foo'\r\n",
+            "+OK\r\n",
+        ]
+        .concat();
+        let output: String = run(&kvs, &input);
+        // TTL は秒の境目をまたぐと 1 少なくなる
+        assert_eq!(output.replace(":29\r\n", ":30\r\n"), expected);
+        let ttl: Duration = kvs.get("k3").unwrap().unwrap().ttl().unwrap();
+        assert!(Duration::from_secs(99) <= ttl);
+        assert_eq!(kvs.get("k2").unwrap(), None);
+
+        // EXPIRE に 0 以下を渡すとキーが消えるケース
+        assert_eq!(run(&kvs, &command(&["EXPIRE", "k3", "0"])), ":1\r\n");
+        assert_eq!(kvs.get("k3").unwrap(), None);
+
+        // 不正な引数のケース
+        let output: String = run(&kvs, &command(&["SET", "k1", "v", "EX", "abc"]));
+        assert!(output.starts_with("-ERR invalid expire time"));
+        let output: String = run(&kvs, &command(&["MSET", "k1"]));
+        assert!(output.starts_with("-ERR wrong number of arguments"));
+    }
+
+    #[test]
+    fn test_resp3() {
+        let kvs: KVS = test_util::temp_store("resp3");
+        let input: Vec<u8> = [
+            command(&["GET", "missing"]),
+            command(&["HELLO", "3"]),
+            command(&["GET", "missing"]),
+            command(&["HELLO", "4"]),
+        ]
+        .concat();
+        let output: String = run(&kvs, &input);
+        let mut hello: Vec<u8> = Vec::new();
+        hello.extend_from_slice(b"%6\r\n$6\r\nserver\r\n$4\r\nkvsd\r\n");
+        let expected: String = format!(
+            "$-1\r\n{}$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n_\r\n-NOPROTO unsupported protocol version\r\n",
+            String::from_utf8(hello).unwrap(),
+            env!("CARGO_PKG_VERSION").len(),
+            env!("CARGO_PKG_VERSION"),
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_scan() {
+        let kvs: KVS = test_util::temp_store("resp_scan");
+        for i in 0..25 {
+            kvs.put(&format!("user:{i:02}"), "v").unwrap();
+            kvs.put(&format!("item:{i:02}"), "v").unwrap();
+        }
+
+        // カーソルを辿って全てのキーを 1 度ずつ返すケース
+        let mut session: Session = Session::new();
+        let mut cursor: String = "0".to_string();
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        let mut calls: usize = 0;
+        loop {
+            let args: Vec<Vec<u8>> = [cursor.as_str(), "MATCH", "user:*", "COUNT", "7"]
+                .iter()
+                .map(|a| a.as_bytes().to_vec())
+                .collect();
+            let reply: Reply = execute(&kvs, &mut session, "scan", &args);
+            let (next, page) = match reply {
+                Reply::Array(items) => match &items[..] {
+                    [Reply::Bulk(next), Reply::Array(page)] => (next.clone(), page.clone()),
+                    _ => panic!("unexpected reply {items:?}"),
+                },
+                reply => panic!("unexpected reply {reply:?}"),
+            };
+            for key in page {
+                match key {
+                    Reply::Bulk(key) => keys.push(key),
+                    key => panic!("unexpected key {key:?}"),
+                }
+            }
+            // 走査中の書き込みは結果を壊さない
+            kvs.put("item:zz", "v").unwrap();
+            calls += 1;
+            cursor = String::from_utf8(next).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<Vec<u8>> = (0..25)
+            .map(|i| format!("user:{i:02}").into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(calls, 8);
+
+        // カーソルは最後のキーの hex なので、別の接続でも続きから走査できるケース
+        let args: Vec<Vec<u8>> = vec![b"0".to_vec(), b"COUNT".to_vec(), b"2".to_vec()];
+        let reply: Reply = execute(&kvs, &mut session, "scan", &args);
+        let cursor: Vec<u8> = match &reply {
+            Reply::Array(items) => match &items[..] {
+                [Reply::Bulk(next), _] => next.clone(),
+                _ => panic!("unexpected reply {items:?}"),
+            },
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        assert_eq!(cursor, encode_hex(b"item:01"));
+        let args: Vec<Vec<u8>> = vec![cursor, b"COUNT".to_vec(), b"1".to_vec()];
+        assert_eq!(
+            execute(&kvs, &mut Session::new(), "scan", &args),
+            Reply::Array(vec![
+                Reply::Bulk(encode_hex(b"item:02")),
+                Reply::Array(vec![Reply::Bulk(b"item:02".to_vec())])
+            ])
+        );
+
+        // 不正なカーソルのケース
+        for cursor in ["12345", "zz", "+f"] {
+            let args: Vec<Vec<u8>> = vec![cursor.as_bytes().to_vec()];
+            assert_eq!(
+                execute(&kvs, &mut session, "scan", &args),
+                Reply::err("invalid cursor")
+            );
+        }
+        assert_eq!(
+            decode_hex(&encode_hex(&[0, 0x7f, 0xff])),
+            Some(vec![0, 0x7f, 0xff])
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"item:42"));
+        assert!(glob_match(b"*:4?", b"user:42"));
+        assert!(glob_match(b"*a*b*c", b"xxaxxbxxbxc"));
+        assert!(!glob_match(b"*a*b*c", b"xxaxxbxx"));
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-f]llo", b"hello"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"a[", b"a["));
+    }
+}
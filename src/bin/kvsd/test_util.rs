@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+
+use kvsd::{KVSOptions, KVS};
+
+/// Opens a store in an empty directory for a test under the system temporary directory.
+pub fn temp_store(name: &str) -> KVS {
+    let mut path: PathBuf = std::env::temp_dir();
+    path.push(format!("kvsd-bin-test-{}-{name}", std::process::id()));
+
+    if path.exists() {
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+    KVSOptions::new()
+        .data_dir(&path)
+        .create_if_missing(true)
+        .open()
+        .unwrap()
+}
@@ -1,10 +1,11 @@
 use std::{
     fmt::{self, Display},
-    io::{self, Read, Write},
-    net::TcpStream,
-    usize,
+    io::{self, Write},
+    time::Duration,
 };
 
+use kvsd::{Client, ProtocolError, Request, Response};
+
 /// Represents an error that can occur when parsing a command.
 #[derive(Debug, PartialEq)]
 pub enum CommandError {
@@ -27,6 +28,8 @@ const DEFAULT_HOST: &str = "localhost";
 
 /// The main function for the key-value store shell.
 fn main() {
+    // 接続はコマンドをまたいで使い回す
+    let mut client: Option<Client> = None;
     loop {
         print!("> ");
 
@@ -60,21 +63,26 @@ fn main() {
                 if b {
                     match oper.as_str() {
                         "exit" => return,
-                        "put" | "delete" => {
-                            if let Err(e) = send_request(DEFAULT_HOST, DEFAULT_PORT, &input) {
-                                eprintln!("{e}")
-                            }
-                        }
-                        "get" => match send_request(DEFAULT_HOST, DEFAULT_PORT, &input) {
-                            Ok(res) => match String::from_utf8(res.to_vec()) {
-                                Ok(value) => {
-                                    println!("{}", value.replace("\0", "").trim());
+                        _ => {
+                            let request: Request = match build_request(&split_args(&input)) {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    eprintln!("{e}");
+                                    continue;
+                                }
+                            };
+                            match send_request(&mut client, DEFAULT_HOST, DEFAULT_PORT, &request) {
+                                // 書き込みの応答は空なので何も表示しない
+                                Ok(Response::Ok(value)) if value.is_empty() => {}
+                                Ok(Response::Ok(value)) => {
+                                    // 値は任意のバイト列なので、表示できないバイトは置き換える
+                                    println!("{}", String::from_utf8_lossy(&value));
                                 }
+                                Ok(Response::NotFound) => println!("(not found)"),
+                                Ok(Response::Error(msg)) => eprintln!("{msg}"),
                                 Err(e) => eprintln!("{e}"),
-                            },
-                            Err(e) => eprintln!("{e}"),
-                        },
-                        _ => unreachable!(),
+                            }
+                        }
                     }
                 } else {
                     eprintln!("Invalid arguments.")
@@ -85,23 +93,137 @@ fn main() {
     }
 }
 
-/// Sends a request to the key-value store server.
-fn send_request(host: &str, port: usize, request: &str) -> Result<[u8; 1024], io::Error> {
-    let address: String = format!("{host}:{port}");
-    let mut stream: TcpStream = TcpStream::connect(address)?;
+/// Sends a request to the key-value store server and reads its response.
+///
+/// The connection is opened on the first request and kept for the following ones.
+/// When a request fails, the connection is dropped, so the next request opens a new one.
+/// A kept connection the server has closed, e.g. after its read timeout, is replaced before
+/// the request is sent. If the connection fails after the request is sent, the request is
+/// sent once more on a new connection only if it is idempotent, since the server may
+/// already have executed it.
+///
+/// # Arguments
+///
+/// * `client` - The connection kept between requests.
+/// * `host` - The host of the server.
+/// * `port` - The port of the server.
+/// * `request` - The request.
+fn send_request(
+    client: &mut Option<Client>,
+    host: &str,
+    port: usize,
+    request: &Request,
+) -> Result<Response, ProtocolError> {
+    if client
+        .as_ref()
+        .is_some_and(|connection| connection.is_closed())
+    {
+        *client = None;
+    }
+    if let Some(connection) = client {
+        match connection.send(request) {
+            // 送った後に切れた場合は実行されたか分からないので、何度実行しても同じリクエストだけ送り直す
+            Err(ProtocolError::FailedIO(_)) if is_idempotent(request) => *client = None,
+            Err(e) => {
+                *client = None;
+                return Err(e);
+            }
+            Ok(response) => return Ok(response),
+        }
+    }
 
-    stream.write(request.as_bytes())?;
+    let connection: &mut Client = client.insert(Client::connect(format!("{host}:{port}"))?);
+    let result: Result<Response, ProtocolError> = connection.send(request);
+    if result.is_err() {
+        *client = None;
+    }
+    result
+}
 
-    // response を read する
-    let mut response: [u8; 1024] = [0u8; 1024];
-    stream.read(&mut response)?;
+/// Returns `true` if executing a request twice has the same effect and response as once.
+fn is_idempotent(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::Get { .. } | Request::Ttl { .. } | Request::Put { .. }
+    )
+}
+
+/// Builds a request from a command and its arguments, already checked by `check_input`.
+fn build_request(args: &[String]) -> Result<Request, String> {
+    let arg = |i: usize| args[i].as_bytes().to_vec();
+    let request: Request = match args[0].as_str() {
+        "get" => Request::Get { key: arg(1) },
+        "put" => Request::Put {
+            key: arg(1),
+            value: arg(2),
+        },
+        "setex" => {
+            let seconds: u64 = match args[2].parse::<u64>() {
+                Ok(seconds) => seconds,
+                Err(e) => return Err(format!("Invalid TTL '{}'. {e}", args[2])),
+            };
+            Request::PutWithTtl {
+                key: arg(1),
+                value: arg(3),
+                ttl: Duration::from_secs(seconds),
+            }
+        }
+        "delete" => Request::Delete { key: arg(1) },
+        "ttl" => Request::Ttl { key: arg(1) },
+        "cas" => Request::CompareAndSwap {
+            key: arg(1),
+            expected: arg(2),
+            value: arg(3),
+        },
+        "setnx" => Request::PutIfAbsent {
+            key: arg(1),
+            value: arg(2),
+        },
+        "delifeq" => Request::DeleteIfEquals {
+            key: arg(1),
+            expected: arg(2),
+        },
+        _ => unreachable!(),
+    };
+    Ok(request)
+}
 
-    Ok(response)
+/// Splits the user's input into arguments at whitespace.
+///
+/// An argument in double quotes can contain whitespace, and `\"` and `\\` in it
+/// stand for `"` and `\`.
+fn split_args(input: &str) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first: char = match chars.next() {
+            Some(c) => c,
+            None => return args,
+        };
+
+        let mut arg: String = String::new();
+        if first == '"' {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => arg.extend(chars.next()),
+                    c => arg.push(c),
+                }
+            }
+        } else {
+            arg.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
 }
 
 /// Checks the validity of the user's input.
 fn check_input(input: &str) -> Result<(String, Option<bool>), CommandError> {
-    let input_vec: Vec<&str> = input.split_whitespace().collect();
+    let input_vec: Vec<String> = split_args(input);
     match input_vec.len() {
         0 => Ok((String::new(), None)),
         1 => {
@@ -109,7 +231,7 @@ fn check_input(input: &str) -> Result<(String, Option<bool>), CommandError> {
             let check_result: bool = check_args(&operation, 0)?;
             Ok((operation, Some(check_result)))
         }
-        2 | 3 => {
+        2..=4 => {
             let operation: String = input_vec[0].to_string();
             let args: Vec<String> = input_vec[1..].iter().map(|s| s.to_string()).collect();
             let check_result: bool = check_args(&operation, args.len())?;
@@ -127,7 +249,9 @@ fn check_input(input: &str) -> Result<(String, Option<bool>), CommandError> {
 fn check_args(operation: &str, args_len: usize) -> Result<bool, CommandError> {
     let check_res: bool = match operation {
         "put" => args_len == 2,
-        "get" | "delete" => args_len == 1,
+        "setex" | "cas" => args_len == 3,
+        "setnx" | "delifeq" => args_len == 2,
+        "get" | "delete" | "ttl" => args_len == 1,
         "exit" => args_len == 0,
         _ => return Err(CommandError::CommandNotDefine(operation.to_string())),
     };
@@ -169,6 +293,80 @@ mod tests {
         let (opr, check_res) = crate::check_input(&input5).unwrap();
         assert_eq!(opr, String::from("put"));
         assert_eq!(check_res, Some(false));
+
+        // コマンドと引数3つを受け取るケース
+        let input6: String = String::from("setex k1 60 value1");
+        let (opr, check_res) = crate::check_input(&input6).unwrap();
+        assert_eq!(opr, String::from("setex"));
+        assert_eq!(check_res, Some(true));
+    }
+
+    #[test]
+    fn test_split_args() {
+        // 空白で区切るケース
+        assert_eq!(crate::split_args("  put k1   v1 "), vec!["put", "k1", "v1"]);
+
+        // 引用符で囲んだ値は空白を含められるケース
+        assert_eq!(
+            crate::split_args(r#"put k1 "hello world""#),
+            vec!["put", "k1", "hello world"]
+        );
+        assert_eq!(
+            crate::split_args(r#"put k1 "say \"hi\" \\ bye" """#),
+            vec!["put", "k1", r#"say "hi" \ bye"#, ""]
+        );
+
+        // 引用符で囲んだ値は 1 つの引数として数えるケース
+        let (opr, check_res) = crate::check_input(r#"put k1 "value with spaces""#).unwrap();
+        assert_eq!(opr, String::from("put"));
+        assert_eq!(check_res, Some(true));
+    }
+
+    #[test]
+    fn test_build_request() {
+        let args = |input: &str| crate::split_args(input);
+
+        assert_eq!(
+            crate::build_request(&args(r#"put k1 "a b""#)),
+            Ok(crate::Request::Put {
+                key: b"k1".to_vec(),
+                value: b"a b".to_vec(),
+            })
+        );
+        assert_eq!(
+            crate::build_request(&args("setex session 60 token")),
+            Ok(crate::Request::PutWithTtl {
+                key: b"session".to_vec(),
+                value: b"token".to_vec(),
+                ttl: crate::Duration::from_secs(60),
+            })
+        );
+        assert_eq!(
+            crate::build_request(&args("cas k1 v1 v2")),
+            Ok(crate::Request::CompareAndSwap {
+                key: b"k1".to_vec(),
+                expected: b"v1".to_vec(),
+                value: b"v2".to_vec(),
+            })
+        );
+
+        // TTL が数値でないケース(エラー)
+        assert!(crate::build_request(&args("setex session abc token")).is_err());
+
+        // 送り直してよいリクエストのケース
+        for (input, idempotent) in [
+            ("get k1", true),
+            ("ttl k1", true),
+            ("put k1 v1", true),
+            ("setex k1 60 v1", false),
+            ("delete k1", false),
+            ("cas k1 v1 v2", false),
+            ("setnx k1 v1", false),
+            ("delifeq k1 v1", false),
+        ] {
+            let request: crate::Request = crate::build_request(&args(input)).unwrap();
+            assert_eq!(crate::is_idempotent(&request), idempotent);
+        }
     }
 
     #[test]
@@ -184,6 +382,19 @@ mod tests {
         assert_eq!(crate::check_args("delete", 1), Ok(true));
         assert_eq!(crate::check_args("delete", 2), Ok(false));
 
+        // setex, ttl のケース
+        assert_eq!(crate::check_args("setex", 3), Ok(true));
+        assert_eq!(crate::check_args("setex", 2), Ok(false));
+        assert_eq!(crate::check_args("ttl", 1), Ok(true));
+        assert_eq!(crate::check_args("ttl", 0), Ok(false));
+
+        // cas, setnx, delifeq のケース
+        assert_eq!(crate::check_args("cas", 3), Ok(true));
+        assert_eq!(crate::check_args("cas", 2), Ok(false));
+        assert_eq!(crate::check_args("setnx", 2), Ok(true));
+        assert_eq!(crate::check_args("delifeq", 2), Ok(true));
+        assert_eq!(crate::check_args("delifeq", 1), Ok(false));
+
         // exit のケース
         assert_eq!(crate::check_args("exit", 0), Ok(true));
         assert_eq!(crate::check_args("exit", 1), Ok(false));
@@ -0,0 +1,125 @@
+/// A bloom filter over the keys of an SSTable.
+///
+/// The format is: bits | hash count (1 byte)
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    /// The bit array.
+    bits: Vec<u8>,
+    /// The number of bits set for each key.
+    hash_count: u8,
+}
+
+impl BloomFilter {
+    /// Builds a bloom filter from the hashes of keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The hashes of the keys, computed by `hash`.
+    /// * `bits_per_key` - The number of bits used per key. 10 bits give about 1% false positives.
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        // k = bits_per_key * ln(2) で偽陽性率が最小になる
+        let hash_count: u8 = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u8;
+        let bit_count: usize = (hashes.len() * bits_per_key).max(64);
+
+        let mut bits: Vec<u8> = vec![0; bit_count.div_ceil(8)];
+        let bit_count: u64 = (bits.len() * 8) as u64;
+        for hash in hashes {
+            for position in positions(*hash, hash_count, bit_count) {
+                bits[(position / 8) as usize] |= 1 << (position % 8);
+            }
+        }
+
+        BloomFilter { bits, hash_count }
+    }
+
+    /// Returns `false` if the key is definitely not in the filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to check.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let bit_count: u64 = (self.bits.len() * 8) as u64;
+        if bit_count == 0 {
+            return true;
+        }
+
+        positions(hash(key), self.hash_count, bit_count)
+            .all(|position| self.bits[(position / 8) as usize] & (1 << (position % 8)) != 0)
+    }
+
+    /// Converts the `BloomFilter` to a byte vector.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.bits.as_slice(), &[self.hash_count]].concat()
+    }
+
+    /// Creates a `BloomFilter` from a byte vector.
+    ///
+    /// Returns `None` if the bytes are empty.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (hash_count, bits) = bytes.split_last()?;
+        Some(BloomFilter {
+            bits: bits.to_vec(),
+            hash_count: *hash_count,
+        })
+    }
+}
+
+/// Computes the 64-bit hash of a key used by the bloom filter.
+///
+/// FNV-1a followed by the finalizer of MurmurHash3 to spread the bits.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xCBF2_9CE4_8422_2325;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xC4CE_B9FE_1A85_EC53);
+    h ^ (h >> 33)
+}
+
+/// Returns the bit positions of a hash by double hashing.
+fn positions(hash: u64, hash_count: u8, bit_count: u64) -> impl Iterator<Item = u64> {
+    let h1: u64 = hash & 0xFFFF_FFFF;
+    let h2: u64 = hash >> 32;
+    (0..hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::bloom::*;
+
+    #[test]
+    fn test_may_contain() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{i}")).collect();
+        let hashes: Vec<u64> = keys.iter().map(|k| hash(k.as_bytes())).collect();
+        let filter: BloomFilter = BloomFilter::build(&hashes, 10);
+
+        // 追加したキーは必ず含まれる
+        for key in keys.iter() {
+            assert!(filter.may_contain(key.as_bytes()));
+        }
+
+        // 偽陽性率はおよそ 1%
+        let false_positives: usize = (0..10000)
+            .filter(|i| filter.may_contain(format!("other{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_to_bytes_from_bytes() {
+        let hashes: Vec<u64> = [b"a", b"b", b"c"].iter().map(|k| hash(*k)).collect();
+        let filter: BloomFilter = BloomFilter::build(&hashes, 10);
+
+        let bytes: Vec<u8> = filter.to_bytes();
+        assert_eq!(bytes.len(), 8 + 1);
+        assert_eq!(BloomFilter::from_bytes(&bytes), Some(filter));
+        assert_eq!(BloomFilter::from_bytes(&[]), None);
+    }
+}
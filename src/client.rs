@@ -0,0 +1,200 @@
+use std::{
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+};
+
+use crate::{
+    error::ProtocolError,
+    protocol::{Request, Response},
+};
+
+/// A client of a `kvsd` server that keeps one connection open for many requests.
+///
+/// ```no_run
+/// use kvsd::{Client, Request, Response};
+///
+/// let mut client = Client::connect("localhost:54321").unwrap();
+/// client
+///     .send(&Request::Put { key: b"k1".to_vec(), value: b"v1".to_vec() })
+///     .unwrap();
+/// let responses: Vec<Response> = client
+///     .pipeline(&[
+///         Request::Get { key: b"k1".to_vec() },
+///         Request::Get { key: b"k2".to_vec() },
+///     ])
+///     .unwrap();
+/// ```
+pub struct Client {
+    /// The connection to read responses from.
+    reader: BufReader<TcpStream>,
+    /// The connection to write requests to.
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    /// Connects to a server.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the server, e.g. `"localhost:54321"`.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Self, ProtocolError> {
+        let stream: TcpStream = TcpStream::connect(address).map_err(failed_io)?;
+        // 小さなリクエストを待たせずに送る
+        stream.set_nodelay(true).map_err(failed_io)?;
+        let reader: TcpStream = stream.try_clone().map_err(failed_io)?;
+        Ok(Client {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends a request and waits for its response.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request.
+    pub fn send(&mut self, request: &Request) -> Result<Response, ProtocolError> {
+        request.write_to(&mut self.writer)?;
+        self.writer.flush().map_err(failed_io)?;
+        Response::read_from(&mut self.reader)
+    }
+
+    /// Returns `true` if the server has closed the connection, e.g. after its read timeout.
+    ///
+    /// This looks at the connection without waiting, so a request that must not be sent twice
+    /// can be sent on a new connection instead of failing on a closed one.
+    pub fn is_closed(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream: &TcpStream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed: bool = match stream.peek(&mut [0; 1]) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() != ErrorKind::WouldBlock,
+        };
+        closed || stream.set_nonblocking(false).is_err()
+    }
+
+    /// Sends requests without waiting for each response, and returns the responses in order.
+    ///
+    /// The requests are written on another thread while the responses are read,
+    /// so neither side waits for the other however many requests there are.
+    /// The requests are executed one after another, not atomically.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The requests.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>, ProtocolError> {
+        let (reader, writer) = (&mut self.reader, &mut self.writer);
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || -> Result<(), ProtocolError> {
+                for request in requests {
+                    request.write_to(writer)?;
+                }
+                writer.flush().map_err(failed_io)
+            });
+
+            let mut responses: Vec<Response> = Vec::with_capacity(requests.len());
+            for _ in requests {
+                match Response::read_from(reader) {
+                    Ok(response) => responses.push(response),
+                    // 送信に失敗していれば、そちらのエラーを返す
+                    Err(e) => {
+                        return match sender.join() {
+                            Ok(Err(send_error)) => Err(send_error),
+                            _ => Err(e),
+                        }
+                    }
+                }
+            }
+            match sender.join() {
+                Ok(result) => result.map(|_| responses),
+                Err(_) => Err(ProtocolError::FailedIO(
+                    "The thread sending the requests panicked.".to_string(),
+                )),
+            }
+        })
+    }
+}
+
+/// Converts an I/O error of the connection.
+fn failed_io(e: std::io::Error) -> ProtocolError {
+    ProtocolError::FailedIO(e.to_string())
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use crate::client::*;
+
+    /// Starts a server that answers every request with its key until the client disconnects.
+    fn echo_server() -> String {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let stream: TcpStream = listener.accept().unwrap().0;
+            let mut reader: BufReader<&TcpStream> = BufReader::new(&stream);
+            let mut writer: BufWriter<&TcpStream> = BufWriter::new(&stream);
+            while let Some(request) = Request::read_from(&mut reader).unwrap() {
+                let response: Response = match request {
+                    Request::Get { key } => Response::Ok(key),
+                    _ => Response::NotFound,
+                };
+                response.write_to(&mut writer).unwrap();
+                if reader.buffer().is_empty() {
+                    writer.flush().unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_client() {
+        let mut client: Client = Client::connect(echo_server()).unwrap();
+
+        // 1 つの接続で何度も送るケース
+        for i in 0..10 {
+            let key: Vec<u8> = format!("k{i}").into_bytes();
+            let response: Response = client.send(&Request::Get { key: key.clone() }).unwrap();
+            assert_eq!(response, Response::Ok(key));
+        }
+        let request: Request = Request::Delete {
+            key: b"k1".to_vec(),
+        };
+        assert_eq!(client.send(&request).unwrap(), Response::NotFound);
+
+        // 大量のリクエストをパイプラインで送り、順に応答を受け取るケース
+        let requests: Vec<Request> = (0..5000)
+            .map(|i| Request::Get {
+                key: format!("{i:01000}").into_bytes(),
+            })
+            .collect();
+        let responses: Vec<Response> = client.pipeline(&requests).unwrap();
+        assert_eq!(responses.len(), requests.len());
+        for (i, response) in responses.into_iter().enumerate() {
+            assert_eq!(response, Response::Ok(format!("{i:01000}").into_bytes()));
+        }
+        assert_eq!(client.pipeline(&[]).unwrap(), Vec::new());
+        assert!(!client.is_closed());
+        assert_eq!(client.send(&request).unwrap(), Response::NotFound);
+
+        // サーバが閉じた接続を、送る前に見分けるケース
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client: Client = Client::connect(listener.local_addr().unwrap()).unwrap();
+        drop(listener.accept().unwrap());
+        let deadline: std::time::Instant = std::time::Instant::now() + Duration::from_secs(5);
+        while !client.is_closed() {
+            assert!(std::time::Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
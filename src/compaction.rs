@@ -0,0 +1,626 @@
+use std::{
+    borrow::Borrow,
+    ops::Bound,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    error::KVSError,
+    iterator::{EntryIterator, MergeIterator},
+    options::KVSOptions,
+    snapshot,
+    sstable::{self, SSTable, SSTableBuilder},
+    value::{self, Value},
+};
+
+/// A compaction of some tables of a level into the next level.
+#[derive(Debug, PartialEq)]
+pub struct Compaction {
+    /// The level the input tables are taken from.
+    pub level: usize,
+    /// The indices of the input tables in `level`.
+    pub inputs: Vec<usize>,
+    /// The indices of the tables in `level + 1` whose keys overlap the inputs.
+    pub overlaps: Vec<usize>,
+}
+
+/// How much a compaction reclaimed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompactionResult {
+    /// The number of entries dropped: deleted keys and the older values they or newer values hide.
+    pub reclaimed_entries: u64,
+    /// The number of bytes the SSTables shrank by.
+    pub reclaimed_bytes: u64,
+}
+
+impl CompactionResult {
+    /// Compares the input tables of a compaction with the tables it wrote.
+    pub(crate) fn between(inputs: &[&SSTable], outputs: &[SSTable]) -> Self {
+        let input_entries: u64 = inputs.iter().map(|t| t.meta().entry_count).sum();
+        let output_entries: u64 = outputs.iter().map(|t| t.meta().entry_count).sum();
+        let input_bytes: u64 = inputs.iter().map(|t| t.file_size()).sum();
+        CompactionResult {
+            reclaimed_entries: input_entries.saturating_sub(output_entries),
+            reclaimed_bytes: input_bytes.saturating_sub(total_size(outputs)),
+        }
+    }
+}
+
+/// Picks the next compaction, or returns `None` if every level is within its target.
+///
+/// Every level except the last one gets a score:
+/// the number of tables divided by `level0_compaction_trigger` for level 0,
+/// and the total size divided by the target size for the other levels.
+/// The level with the highest score of at least 1 is compacted.
+///
+/// All tables of level 0 are compacted at once because their keys overlap.
+/// In the other levels one table is picked round-robin, starting after the key
+/// in `pointers` where the previous compaction of the level stopped.
+///
+/// # Arguments
+///
+/// * `levels` - The tables of each level. Level 0 is ordered from oldest to newest,
+///   the other levels are ordered by key.
+/// * `pointers` - The largest key compacted last time in each level.
+/// * `options` - The options of the store.
+pub fn pick<T: Borrow<SSTable>>(
+    levels: &[Vec<T>],
+    pointers: &[Option<Vec<u8>>],
+    options: &KVSOptions,
+) -> Option<Compaction> {
+    let mut best: Option<(usize, f64)> = None;
+    for level in 0..levels.len().saturating_sub(1) {
+        let score: f64 = match level {
+            0 => levels[0].len() as f64 / options.level0_compaction_trigger as f64,
+            _ => total_size(&levels[level]) as f64 / max_bytes_for_level(options, level) as f64,
+        };
+        if 1.0 <= score && best.is_none_or(|(_, best_score)| best_score < score) {
+            best = Some((level, score));
+        }
+    }
+    let (level, _) = best?;
+
+    let inputs: Vec<usize> = match level {
+        0 => (0..levels[0].len()).collect(),
+        _ => {
+            let tables: &[T] = &levels[level];
+            let next: usize = match &pointers[level] {
+                Some(pointer) => tables
+                    .iter()
+                    .position(|t| t.borrow().meta().min_key > *pointer)
+                    .unwrap_or(0),
+                None => 0,
+            };
+            vec![next]
+        }
+    };
+
+    let (min_key, max_key) = key_range(inputs.iter().map(|i| levels[level][*i].borrow()))?;
+    let overlaps: Vec<usize> = overlapping(&levels[level + 1], &min_key, &max_key);
+
+    Some(Compaction {
+        level,
+        inputs,
+        overlaps,
+    })
+}
+
+/// Returns the target total size of the tables in a level in bytes.
+///
+/// # Arguments
+///
+/// * `options` - The options of the store.
+/// * `level` - The level, which must be 1 or more.
+pub fn max_bytes_for_level(options: &KVSOptions, level: usize) -> u64 {
+    let mut bytes: u64 = options.level1_max_bytes;
+    for _ in 1..level {
+        bytes = bytes.saturating_mul(options.level_size_multiplier);
+    }
+    bytes
+}
+
+/// Returns the indices of the tables whose keys overlap `min_key..=max_key`.
+///
+/// # Arguments
+///
+/// * `tables` - The tables of a level.
+/// * `min_key` - The smallest key of the range.
+/// * `max_key` - The largest key of the range.
+pub fn overlapping<T: Borrow<SSTable>>(tables: &[T], min_key: &[u8], max_key: &[u8]) -> Vec<usize> {
+    tables
+        .iter()
+        .map(|t| t.borrow())
+        .enumerate()
+        .filter(|(_, t)| 0 < t.meta().entry_count)
+        .filter(|(_, t)| {
+            t.meta().min_key.as_slice() <= max_key && min_key <= t.meta().max_key.as_slice()
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Returns the smallest and largest keys of the tables, or `None` if all of them are empty.
+pub fn key_range<'a, I>(tables: I) -> Option<(Vec<u8>, Vec<u8>)>
+where
+    I: Iterator<Item = &'a SSTable>,
+{
+    let mut range: Option<(Vec<u8>, Vec<u8>)> = None;
+    for table in tables.filter(|t| 0 < t.meta().entry_count) {
+        let meta = table.meta();
+        range = match range {
+            Some((min, max)) => {
+                Some((min.min(meta.min_key.clone()), max.max(meta.max_key.clone())))
+            }
+            None => Some((meta.min_key.clone(), meta.max_key.clone())),
+        };
+    }
+    range
+}
+
+/// Merges tables into new tables in `output_level`.
+///
+/// The entries are streamed through a k-way merge, so only one data block of each
+/// input and the output table being built are held in memory.
+/// Of the versions of a key, the newest one and those a live snapshot can read are kept.
+/// Expired values are written as deletions.
+/// If `drop_tombstones` is `true`, deletions with no older version left are not written at all,
+/// which is only correct when no older value of them remains below `output_level`.
+/// A new output table is started once the current one reaches `target_file_size`,
+/// but never between the versions of a key.
+///
+/// # Arguments
+///
+/// * `inputs` - The tables to merge, ordered from newest to oldest.
+/// * `data_dir` - The directory to store the new tables in.
+/// * `output_level` - The level of the new tables.
+/// * `drop_tombstones` - Whether to drop deleted keys.
+/// * `snapshots` - The sequence numbers of the live snapshots in ascending order.
+/// * `options` - The options of the store.
+/// * `last_seq` - The sequence of the store, from which the numbers of the new files are taken.
+///   Other threads can take numbers from it at the same time.
+pub fn merge(
+    inputs: &[&SSTable],
+    data_dir: &Path,
+    output_level: usize,
+    drop_tombstones: bool,
+    snapshots: &[u64],
+    options: &KVSOptions,
+    last_seq: &AtomicU64,
+) -> Result<Vec<SSTable>, KVSError> {
+    let mut sources: Vec<EntryIterator<'_>> = Vec::new();
+    for sstable in inputs {
+        sources.push(Box::new(
+            sstable.range((Bound::Unbounded, Bound::Unbounded))?,
+        ));
+    }
+
+    let mut outputs: Vec<SSTable> = Vec::new();
+    let mut builder: Option<SSTableBuilder> = None;
+    let now: u64 = value::now_millis();
+    let mut merged = MergeIterator::new(sources).peekable();
+    while let Some(entry) = merged.next() {
+        let (key, seq, value) = entry?;
+
+        // 同じキーのバージョンを新しい順に集める
+        let mut versions: Vec<(u64, Value)> = vec![(seq, value)];
+        while let Some(Ok((next_key, _, _))) = merged.peek() {
+            if *next_key != key {
+                break;
+            }
+            if let Some(Ok((_, seq, value))) = merged.next() {
+                versions.push((seq, value));
+            }
+        }
+        let versions: Vec<(u64, Value)> = live_versions(versions, drop_tombstones, snapshots, now);
+        if versions.is_empty() {
+            continue;
+        }
+
+        let current: &mut SSTableBuilder = match builder {
+            Some(ref mut current) => current,
+            None => {
+                let number: u64 = last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+                builder.insert(SSTableBuilder::new(
+                    data_dir,
+                    &sstable::filename_for(number),
+                    output_level as u32,
+                    options,
+                )?)
+            }
+        };
+        for (seq, value) in versions.iter() {
+            current.add(&key, *seq, value)?;
+        }
+
+        if options.target_file_size <= current.file_size() {
+            if let Some(full) = builder.take() {
+                outputs.push(full.finish()?);
+            }
+        }
+    }
+
+    if let Some(last) = builder.take() {
+        outputs.push(last.finish()?);
+    }
+    Ok(outputs)
+}
+
+/// Returns the versions of a key that must be written, newest first.
+///
+/// # Arguments
+///
+/// * `versions` - The versions of the key, newest first.
+/// * `drop_tombstones` - Whether to drop deletions with no older version left.
+/// * `snapshots` - The sequence numbers of the live snapshots in ascending order.
+/// * `now` - The time at which expiry is checked, in milliseconds since the UNIX epoch.
+fn live_versions(
+    versions: Vec<(u64, Value)>,
+    drop_tombstones: bool,
+    snapshots: &[u64],
+    now: u64,
+) -> Vec<(u64, Value)> {
+    let mut live: Vec<(u64, Value)> = Vec::new();
+    let mut newer_seq: Option<u64> = None;
+    for (seq, value) in versions {
+        if snapshot::is_visible(seq, newer_seq, snapshots) {
+            // 期限切れの値は削除として書き出し、値の中身を捨てる
+            match value.is_expired(now) {
+                true => live.push((seq, Value::new("", true))),
+                false => live.push((seq, value)),
+            }
+        }
+        newer_seq = Some(seq);
+    }
+
+    // 最も古いバージョンが削除なら、それより古い値は残っていないので書き出さない
+    if drop_tombstones {
+        while live.last().is_some_and(|(_, value)| value.is_deleted()) {
+            live.pop();
+        }
+    }
+    live
+}
+
+/// Returns the total size of the tables in bytes.
+pub fn total_size<T: Borrow<SSTable>>(tables: &[T]) -> u64 {
+    tables.iter().map(|t| t.borrow().file_size()).sum()
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        path::PathBuf,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use crate::{compaction::*, test_util, value::Value};
+
+    fn next_filename() -> String {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        sstable::filename_for(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn create_table(data_dir: &Path, keys: &[&str], value: &str, level: u32) -> SSTable {
+        let mut builder =
+            SSTableBuilder::new(data_dir, &next_filename(), level, &KVSOptions::new()).unwrap();
+        for key in keys {
+            builder
+                .add(key.as_bytes(), 1, &Value::new(value, false))
+                .unwrap();
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_max_bytes_for_level() {
+        let options = KVSOptions::new()
+            .level1_max_bytes(100)
+            .level_size_multiplier(10);
+        assert_eq!(max_bytes_for_level(&options, 1), 100);
+        assert_eq!(max_bytes_for_level(&options, 2), 1000);
+        assert_eq!(max_bytes_for_level(&options, 3), 10000);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_overlapping");
+        let tables: Vec<SSTable> = vec![
+            create_table(&data_dir, &["a", "c"], "v", 1),
+            create_table(&data_dir, &["d", "f"], "v", 1),
+            create_table(&data_dir, &["g", "i"], "v", 1),
+        ];
+
+        assert_eq!(overlapping(&tables, b"b", b"b"), vec![0]);
+        assert_eq!(overlapping(&tables, b"c", b"d"), vec![0, 1]);
+        assert_eq!(overlapping(&tables, b"e", b"z"), vec![1, 2]);
+        // どのテーブルとも重ならないケース
+        assert_eq!(overlapping(&tables, b"j", b"z"), Vec::<usize>::new());
+        assert_eq!(
+            key_range(tables.iter()),
+            Some((b"a".to_vec(), b"i".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_pick() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_pick");
+        let options = KVSOptions::new()
+            .max_levels(3)
+            .level0_compaction_trigger(2)
+            .level1_max_bytes(1);
+        let pointers: Vec<Option<Vec<u8>>> = vec![None; 3];
+
+        // 全てのレベルが目標以内のケース
+        let mut levels: Vec<Vec<SSTable>> = vec![Vec::new(), Vec::new(), Vec::new()];
+        levels[0].push(create_table(&data_dir, &["b", "c"], "v", 0));
+        assert_eq!(pick(&levels, &pointers, &KVSOptions::new()), None);
+
+        // レベル0のテーブル数が閾値に達したケース
+        levels[0].push(create_table(&data_dir, &["a", "b"], "v", 0));
+        levels[1].push(create_table(&data_dir, &["c", "d"], "v", 1));
+        levels[1].push(create_table(&data_dir, &["x", "y"], "v", 1));
+        let picked = pick(
+            &levels,
+            &pointers,
+            &options.clone().level1_max_bytes(u64::MAX),
+        );
+        assert_eq!(
+            picked,
+            Some(Compaction {
+                level: 0,
+                inputs: vec![0, 1],
+                overlaps: vec![0],
+            })
+        );
+
+        // レベル1のサイズが目標を超えたケース (ポインタの次のテーブルを選ぶ)
+        levels[0].clear();
+        levels[2].push(create_table(&data_dir, &["w", "z"], "v", 2));
+        let pointers: Vec<Option<Vec<u8>>> = vec![None, Some(b"d".to_vec()), None];
+        assert_eq!(
+            pick(&levels, &pointers, &options),
+            Some(Compaction {
+                level: 1,
+                inputs: vec![1],
+                overlaps: vec![0],
+            })
+        );
+
+        // ポインタが最後のテーブルを指しているケースは先頭に戻る
+        let pointers: Vec<Option<Vec<u8>>> = vec![None, Some(b"y".to_vec()), None];
+        assert_eq!(
+            pick(&levels, &pointers, &options),
+            Some(Compaction {
+                level: 1,
+                inputs: vec![0],
+                overlaps: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_merge");
+        let newer: SSTable = create_table(&data_dir, &["b", "d"], "new", 0);
+        let older: SSTable = create_table(&data_dir, &["a", "b", "c"], "old", 0);
+
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            false,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].meta().level, 1);
+
+        let entries: BTreeMap<Vec<u8>, Value> = outputs[0]
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .unwrap()
+            .map(|e| e.map(|(k, _, v)| (k, v)).unwrap())
+            .collect();
+        let expected: BTreeMap<Vec<u8>, Value> =
+            [("a", "old"), ("b", "new"), ("c", "old"), ("d", "new")]
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), Value::new(v, false)))
+                .collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_merge_drop_tombstones() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_tombstones");
+        let mut builder =
+            SSTableBuilder::new(&data_dir, &next_filename(), 0, &KVSOptions::new()).unwrap();
+        builder.add(b"a", 2, &Value::new("", true)).unwrap();
+        builder.add(b"c", 2, &Value::new("new", false)).unwrap();
+        let newer: SSTable = builder.finish().unwrap();
+        let older: SSTable = create_table(&data_dir, &["a", "b", "c"], "old", 1);
+
+        // 最下層でないケースは削除済みのキーを残す
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            false,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert_eq!(
+            outputs[0].get(b"a", u64::MAX).unwrap(),
+            Some((2, Value::new("", true)))
+        );
+        assert_eq!(
+            CompactionResult::between(&[&newer, &older], &outputs).reclaimed_entries,
+            2
+        );
+
+        // 最下層のケースは削除済みのキーと古い値を捨てる
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            true,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert_eq!(outputs[0].meta().entry_count, 2);
+        assert_eq!(outputs[0].get(b"a", u64::MAX).unwrap(), None);
+        let result = CompactionResult::between(&[&newer, &older], &outputs);
+        assert_eq!(result.reclaimed_entries, 3);
+        assert!(0 < result.reclaimed_bytes);
+
+        // 全て削除済みのケースはテーブルを作らない
+        let mut builder =
+            SSTableBuilder::new(&data_dir, &next_filename(), 0, &KVSOptions::new()).unwrap();
+        builder.add(b"b", 2, &Value::new("", true)).unwrap();
+        let deleted: SSTable = builder.finish().unwrap();
+        let outputs: Vec<SSTable> = merge(
+            &[&deleted],
+            &data_dir,
+            1,
+            true,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn test_merge_snapshots() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_snapshots");
+        let mut builder =
+            SSTableBuilder::new(&data_dir, &next_filename(), 0, &KVSOptions::new()).unwrap();
+        builder.add(b"a", 5, &Value::new("", true)).unwrap();
+        builder.add(b"b", 6, &Value::new("b6", false)).unwrap();
+        builder.add(b"b", 4, &Value::new("b4", false)).unwrap();
+        let newer: SSTable = builder.finish().unwrap();
+        let older: SSTable = create_table(&data_dir, &["a", "b"], "old", 1);
+
+        // スナップショットが読めるバージョンは最下層でも残す
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            true,
+            &[3],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        let entries: Vec<(Vec<u8>, u64)> = outputs[0]
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .unwrap()
+            .map(|e| e.map(|(k, seq, _)| (k, seq)).unwrap())
+            .collect();
+        let expected: Vec<(Vec<u8>, u64)> = vec![
+            (b"a".to_vec(), 5),
+            (b"a".to_vec(), 1),
+            (b"b".to_vec(), 6),
+            (b"b".to_vec(), 1),
+        ];
+        assert_eq!(entries, expected);
+        assert_eq!(
+            outputs[0].get(b"a", 3).unwrap(),
+            Some((1, Value::new("old", false)))
+        );
+        assert_eq!(
+            outputs[0].get(b"a", u64::MAX).unwrap(),
+            Some((5, Value::new("", true)))
+        );
+        assert_eq!(
+            outputs[0].get(b"b", 5).unwrap(),
+            Some((1, Value::new("old", false)))
+        );
+    }
+
+    #[test]
+    fn test_merge_expired() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_expired");
+        let mut builder =
+            SSTableBuilder::new(&data_dir, &next_filename(), 0, &KVSOptions::new()).unwrap();
+        builder
+            .add(b"a", 2, &Value::with_expiry("gone", 1))
+            .unwrap();
+        builder
+            .add(b"b", 2, &Value::with_expiry("alive", u64::MAX))
+            .unwrap();
+        let newer: SSTable = builder.finish().unwrap();
+        let older: SSTable = create_table(&data_dir, &["a", "b"], "old", 1);
+
+        // 最下層でないケースは期限切れの値を削除に置き換える
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            false,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert_eq!(
+            outputs[0].get(b"a", u64::MAX).unwrap(),
+            Some((2, Value::new("", true)))
+        );
+
+        // 最下層のケースは期限切れの値を捨てる
+        let outputs: Vec<SSTable> = merge(
+            &[&newer, &older],
+            &data_dir,
+            1,
+            true,
+            &[],
+            &KVSOptions::new(),
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert_eq!(outputs[0].meta().entry_count, 1);
+        assert_eq!(outputs[0].get(b"a", u64::MAX).unwrap(), None);
+        assert_eq!(
+            outputs[0].get(b"b", u64::MAX).unwrap(),
+            Some((2, Value::with_expiry("alive", u64::MAX)))
+        );
+    }
+
+    #[test]
+    fn test_merge_split() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_merge_split");
+        let keys: Vec<String> = (0..100).map(|i| format!("key{i:03}")).collect();
+        let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+        let input: SSTable = create_table(&data_dir, &keys, "value", 0);
+
+        // 出力テーブルが target_file_size で分割されるケース
+        let options = KVSOptions::new().block_size(64).target_file_size(512);
+        let outputs: Vec<SSTable> = merge(
+            &[&input],
+            &data_dir,
+            1,
+            false,
+            &[],
+            &options,
+            &AtomicU64::new(1000),
+        )
+        .unwrap();
+        assert!(1 < outputs.len());
+
+        let total: u64 = outputs.iter().map(|t| t.meta().entry_count).sum();
+        assert_eq!(total, 100);
+        for pair in outputs.windows(2) {
+            assert!(pair[0].meta().max_key < pair[1].meta().min_key);
+        }
+    }
+}
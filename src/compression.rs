@@ -0,0 +1,573 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// The default compression level.
+pub const DEFAULT_LEVEL: u32 = 6;
+/// The smallest length of a match copied from earlier bytes.
+const MIN_MATCH: usize = 4;
+/// The largest distance back to a match.
+const MAX_OFFSET: usize = u16::MAX as usize;
+/// The number of bits of the hash of 4 bytes used to find matches.
+const HASH_BITS: u32 = 14;
+/// The longest Huffman code in bits.
+const MAX_CODE_LENGTH: u8 = 15;
+
+/// The algorithm used to compress the data blocks of an SSTable.
+///
+/// Each algorithm is recorded by its id, so tables written with different algorithms
+/// can be read by the same store.
+/// The algorithms are implemented in this module, so that the crate depends on no compression library.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// The blocks are stored as they are.
+    None,
+    /// LZ77: repeated byte sequences are replaced by references to earlier ones.
+    /// Fast to compress and to decompress.
+    Lz,
+    /// LZ77 followed by Huffman coding of the bytes. Smaller output than `Lz`, but slower.
+    LzHuffman,
+}
+
+impl Compression {
+    /// Returns the id of the algorithm stored in the files.
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz => 1,
+            Compression::LzHuffman => 2,
+        }
+    }
+
+    /// Returns the algorithm with the given id, or `None` if the id is unknown.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz),
+            2 => Some(Compression::LzHuffman),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses a data block.
+///
+/// The format is: algorithm id (1 byte) | compressed bytes
+///
+/// Data that compresses by less than 1/8, such as data that is already compressed,
+/// is stored as it is with the id of `Compression::None`.
+///
+/// # Arguments
+///
+/// * `compression` - The algorithm.
+/// * `level` - The compression level from 1 (fastest) to 9 (smallest).
+/// * `block` - The bytes of the block.
+pub fn compress_block(compression: Compression, level: u32, block: &[u8]) -> Vec<u8> {
+    let compressed: Option<Vec<u8>> = match compression {
+        Compression::None => None,
+        Compression::Lz => Some(lz_compress(block, level)),
+        Compression::LzHuffman => Some(huffman_encode(&lz_compress(block, level))),
+    };
+    match compressed {
+        Some(bytes) if bytes.len() <= block.len() - block.len() / 8 => {
+            [&[compression.id()][..], &bytes].concat()
+        }
+        _ => [&[Compression::None.id()][..], block].concat(),
+    }
+}
+
+/// Decompresses a data block written by `compress_block`.
+///
+/// Returns `None` if the bytes are invalid. Corrupt bytes never cause a panic,
+/// and the output grows only as the input is decoded, not by the lengths the input claims.
+pub fn decompress_block(bytes: &[u8]) -> Option<Vec<u8>> {
+    let (id, body) = bytes.split_first()?;
+    match Compression::from_id(*id)? {
+        Compression::None => Some(body.to_vec()),
+        Compression::Lz => lz_decompress(body),
+        Compression::LzHuffman => lz_decompress(&huffman_decode(body)?),
+    }
+}
+
+/// Compresses bytes with LZ77.
+///
+/// The format is: length (8 bytes) | sequence | sequence | ...
+///
+/// A sequence is: token (1 byte) | literal length | literals | offset (2 bytes) | match length
+/// The upper 4 bits of the token hold the number of literals and the lower 4 bits
+/// the length of the match minus 4. A value of 15 continues in the following bytes,
+/// each adding up to 255. The last sequence has only literals.
+///
+/// The level sets how many earlier positions are tried for each match: 1 at level 1
+/// and twice as many at each level up to 256 at level 9.
+fn lz_compress(input: &[u8], level: u32) -> Vec<u8> {
+    let mut output: Vec<u8> = input.len().to_be_bytes().to_vec();
+    let max_chain: usize = 1 << (level.clamp(1, 9) - 1);
+
+    // 同じハッシュを持つ直前の位置をたどれるようにしておく
+    let mut head: Vec<usize> = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev: Vec<usize> = vec![usize::MAX; input.len()];
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        let h: usize = hash(&input[pos..pos + MIN_MATCH]);
+        prev[pos] = head[h];
+        head[h] = pos;
+    };
+
+    let mut literal_start: usize = 0;
+    let mut pos: usize = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate: usize = head[hash(&input[pos..pos + MIN_MATCH])];
+        let mut tried: usize = 0;
+        while candidate != usize::MAX && pos - candidate <= MAX_OFFSET && tried < max_chain {
+            let length: usize = common_prefix(&input[candidate..], &input[pos..]);
+            if MIN_MATCH <= length && best.is_none_or(|(_, best_length)| best_length < length) {
+                best = Some((pos - candidate, length));
+            }
+            candidate = prev[candidate];
+            tried += 1;
+        }
+        insert(pos, &mut head, &mut prev);
+
+        match best {
+            Some((offset, length)) => {
+                write_sequence(
+                    &mut output,
+                    &input[literal_start..pos],
+                    Some((offset, length)),
+                );
+                for p in pos + 1..(pos + length).min(input.len() + 1 - MIN_MATCH) {
+                    insert(p, &mut head, &mut prev);
+                }
+                pos += length;
+                literal_start = pos;
+            }
+            None => pos += 1,
+        }
+    }
+    write_sequence(&mut output, &input[literal_start..], None);
+    output
+}
+
+/// Decompresses bytes compressed by `lz_compress`.
+///
+/// Returns `None` if the bytes are invalid.
+fn lz_decompress(bytes: &[u8]) -> Option<Vec<u8>> {
+    let length: usize = usize::from_be_bytes(bytes.get(0..8)?.try_into().ok()?);
+    let mut output: Vec<u8> = Vec::new();
+    let mut pos: usize = 8;
+    loop {
+        let token: u8 = *bytes.get(pos)?;
+        pos += 1;
+
+        let (literal_length, next) = read_length(bytes, pos, (token >> 4) as usize)?;
+        let end: usize = next.checked_add(literal_length)?;
+        output.extend(bytes.get(next..end)?);
+        pos = end;
+        if length < output.len() {
+            return None;
+        }
+        if pos == bytes.len() {
+            break;
+        }
+
+        let offset: usize = u16::from_be_bytes(bytes.get(pos..pos + 2)?.try_into().ok()?) as usize;
+        let (match_length, next) = read_length(bytes, pos + 2, (token & 0x0F) as usize)?;
+        let match_length: usize = match_length + MIN_MATCH;
+        pos = next;
+        if offset == 0 || output.len() < offset || length < output.len() + match_length {
+            return None;
+        }
+        // 重なったコピーもあるので 1 バイトずつ写す
+        let start: usize = output.len() - offset;
+        for i in 0..match_length {
+            output.push(output[start + i]);
+        }
+    }
+
+    match output.len() == length {
+        true => Some(output),
+        false => None,
+    }
+}
+
+/// Writes a sequence of literals and an optional match of `(offset, length)`.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_length: usize = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+    let token: u8 = ((literals.len().min(15) as u8) << 4) | match_length.min(15) as u8;
+    output.push(token);
+    write_length(output, literals.len());
+    output.extend(literals);
+    if let Some((offset, _)) = matched {
+        output.extend((offset as u16).to_be_bytes());
+        write_length(output, match_length);
+    }
+}
+
+/// Writes the bytes that continue a length of 15 or more in a token.
+fn write_length(output: &mut Vec<u8>, length: usize) {
+    if length < 15 {
+        return;
+    }
+    let mut rest: usize = length - 15;
+    while 255 <= rest {
+        output.push(255);
+        rest -= 255;
+    }
+    output.push(rest as u8);
+}
+
+/// Reads a length from a token and the bytes that continue it.
+///
+/// Returns the length and the offset after it.
+fn read_length(bytes: &[u8], offset: usize, length: usize) -> Option<(usize, usize)> {
+    if length < 15 {
+        return Some((length, offset));
+    }
+    let mut length: usize = length;
+    let mut offset: usize = offset;
+    loop {
+        let b: u8 = *bytes.get(offset)?;
+        offset += 1;
+        length = length.checked_add(b as usize)?;
+        if b < 255 {
+            return Some((length, offset));
+        }
+    }
+}
+
+/// Hashes the first 4 bytes into `HASH_BITS` bits.
+fn hash(bytes: &[u8]) -> usize {
+    let v: u32 = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Returns the length of the common prefix of two byte slices.
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Encodes bytes with canonical Huffman codes.
+///
+/// The format is: length (8 bytes) | code lengths (128 bytes) | codes
+///
+/// The code lengths of the 256 byte values are packed two per byte, 0 for unused values.
+/// The codes are written from the most significant bit.
+fn huffman_encode(input: &[u8]) -> Vec<u8> {
+    let mut frequencies: [u64; 256] = [0; 256];
+    for b in input {
+        frequencies[*b as usize] += 1;
+    }
+    let lengths: [u8; 256] = code_lengths(&frequencies);
+    let codes: [u16; 256] = canonical_codes(&lengths);
+
+    let mut output: Vec<u8> = input.len().to_be_bytes().to_vec();
+    for pair in lengths.chunks(2) {
+        output.push((pair[0] << 4) | pair[1]);
+    }
+
+    let mut bits: u64 = 0;
+    let mut bit_count: u32 = 0;
+    for b in input {
+        bits = (bits << lengths[*b as usize]) | codes[*b as usize] as u64;
+        bit_count += lengths[*b as usize] as u32;
+        while 8 <= bit_count {
+            bit_count -= 8;
+            output.push((bits >> bit_count) as u8);
+        }
+    }
+    if 0 < bit_count {
+        output.push((bits << (8 - bit_count)) as u8);
+    }
+    output
+}
+
+/// Decodes bytes encoded by `huffman_encode`.
+///
+/// Returns `None` if the bytes are invalid.
+fn huffman_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let length: usize = usize::from_be_bytes(bytes.get(0..8)?.try_into().ok()?);
+    let packed: &[u8] = bytes.get(8..136)?;
+    let lengths: Vec<u8> = packed.iter().flat_map(|b| [b >> 4, b & 0x0F]).collect();
+
+    // 符号長ごとの数と、符号順に並べたバイト値
+    let mut counts: [usize; MAX_CODE_LENGTH as usize + 1] = [0; MAX_CODE_LENGTH as usize + 1];
+    for l in lengths.iter().filter(|l| 0 < **l) {
+        counts[*l as usize] += 1;
+    }
+    let mut symbols: Vec<u8> = (0..=255u8).filter(|s| 0 < lengths[*s as usize]).collect();
+    symbols.sort_by_key(|s| lengths[*s as usize]);
+
+    let data: &[u8] = &bytes[136..];
+    let mut output: Vec<u8> = Vec::new();
+    let mut bit: usize = 0;
+    while output.len() < length {
+        // 各長さの最初の符号と比べて、どの長さの符号かを決める
+        let mut code: usize = 0;
+        let mut first: usize = 0;
+        let mut index: usize = 0;
+        let mut decoded: Option<u8> = None;
+        for count in counts.iter().skip(1) {
+            let b: u8 = *data.get(bit / 8)?;
+            code |= ((b >> (7 - bit % 8)) & 1) as usize;
+            bit += 1;
+            if code < first + count {
+                decoded = Some(symbols[index + code - first]);
+                break;
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        output.push(decoded?);
+    }
+    Some(output)
+}
+
+/// Computes the Huffman code length of each byte value, at most `MAX_CODE_LENGTH` bits.
+fn code_lengths(frequencies: &[u64; 256]) -> [u8; 256] {
+    let mut frequencies: [u64; 256] = *frequencies;
+    loop {
+        let lengths: [u8; 256] = tree_depths(&frequencies);
+        if lengths.iter().all(|l| *l <= MAX_CODE_LENGTH) {
+            return lengths;
+        }
+        // 符号が長すぎるときは、頻度の差を縮めて作り直す
+        for f in frequencies.iter_mut().filter(|f| 0 < **f) {
+            *f = (*f >> 1) | 1;
+        }
+    }
+}
+
+/// Builds a Huffman tree and returns the depth of each byte value in it.
+fn tree_depths(frequencies: &[u64; 256]) -> [u8; 256] {
+    let mut depths: [u8; 256] = [0; 256];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+    // 0..256 は葉、それ以降は内部ノード
+    let mut parents: Vec<usize> = vec![usize::MAX; 256];
+    for (symbol, frequency) in frequencies.iter().enumerate() {
+        if 0 < *frequency {
+            heap.push(Reverse((*frequency, symbol)));
+        }
+    }
+    if heap.len() == 1 {
+        let Reverse((_, symbol)) = heap.pop().unwrap();
+        depths[symbol] = 1;
+        return depths;
+    }
+
+    while let (Some(Reverse((f1, n1))), Some(Reverse((f2, n2)))) = (heap.pop(), heap.pop()) {
+        let node: usize = parents.len();
+        parents.push(usize::MAX);
+        parents[n1] = node;
+        parents[n2] = node;
+        heap.push(Reverse((f1 + f2, node)));
+    }
+
+    for (symbol, depth) in depths.iter_mut().enumerate() {
+        let mut node: usize = symbol;
+        while parents[node] != usize::MAX {
+            node = parents[node];
+            *depth += 1;
+        }
+    }
+    depths
+}
+
+/// Assigns canonical Huffman codes from the code lengths.
+fn canonical_codes(lengths: &[u8; 256]) -> [u16; 256] {
+    let mut counts: [u16; MAX_CODE_LENGTH as usize + 1] = [0; MAX_CODE_LENGTH as usize + 1];
+    for l in lengths.iter().filter(|l| 0 < **l) {
+        counts[*l as usize] += 1;
+    }
+    let mut next: [u16; MAX_CODE_LENGTH as usize + 1] = [0; MAX_CODE_LENGTH as usize + 1];
+    let mut code: u16 = 0;
+    for l in 1..=MAX_CODE_LENGTH as usize {
+        code = (code + counts[l - 1]) << 1;
+        next[l] = code;
+    }
+
+    let mut codes: [u16; 256] = [0; 256];
+    for (symbol, l) in lengths.iter().enumerate() {
+        if 0 < *l {
+            codes[symbol] = next[*l as usize];
+            next[*l as usize] += 1;
+        }
+    }
+    codes
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::*;
+
+    fn json(n: usize) -> Vec<u8> {
+        (0..n)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{i},"name":"user{i}","email":"user{i}@example.com","active":true}}"#
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_lz() {
+        for input in [
+            Vec::new(),
+            b"abc".to_vec(),
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+            json(100),
+            (0..=255u8).cycle().take(70000).collect(),
+        ] {
+            for level in [1, DEFAULT_LEVEL, 9] {
+                let compressed: Vec<u8> = lz_compress(&input, level);
+                assert_eq!(lz_decompress(&compressed), Some(input.clone()));
+            }
+        }
+
+        // レベルが高いほど小さくなる
+        let input: Vec<u8> = json(100);
+        assert!(lz_compress(&input, 9).len() <= lz_compress(&input, 1).len());
+        assert!(lz_compress(&input, 1).len() < input.len() / 2);
+
+        // 壊れたバイト列
+        let compressed: Vec<u8> = lz_compress(&input, DEFAULT_LEVEL);
+        assert_eq!(lz_decompress(&compressed[..compressed.len() - 1]), None);
+        assert_eq!(lz_decompress(&[]), None);
+    }
+
+    #[test]
+    fn test_huffman() {
+        for input in [Vec::new(), b"a".to_vec(), b"aaaa".to_vec(), json(100)] {
+            assert_eq!(huffman_decode(&huffman_encode(&input)), Some(input.clone()));
+        }
+
+        // 偏った頻度でも符号長が上限を超えない
+        let mut frequencies: [u64; 256] = [0; 256];
+        for (i, f) in frequencies.iter_mut().enumerate().take(40) {
+            *f = 1 << i;
+        }
+        let lengths: [u8; 256] = code_lengths(&frequencies);
+        assert!(lengths.iter().all(|l| *l <= MAX_CODE_LENGTH));
+        let input: Vec<u8> = (0..40u8).flat_map(|b| vec![b; 1 << (b / 4)]).collect();
+        assert_eq!(huffman_decode(&huffman_encode(&input)), Some(input));
+    }
+
+    #[test]
+    fn test_compress_block() {
+        let block: Vec<u8> = json(50);
+        for compression in [Compression::None, Compression::Lz, Compression::LzHuffman] {
+            let compressed: Vec<u8> = compress_block(compression, DEFAULT_LEVEL, &block);
+            assert_eq!(compressed[0], compression.id());
+            assert_eq!(decompress_block(&compressed), Some(block.clone()));
+        }
+        let lz: usize = compress_block(Compression::Lz, DEFAULT_LEVEL, &block).len();
+        let huffman: usize = compress_block(Compression::LzHuffman, DEFAULT_LEVEL, &block).len();
+        assert!(huffman < lz);
+
+        // 圧縮しても小さくならないデータはそのまま保存するケース
+        let mut state: u64 = 1;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+        let compressed: Vec<u8> = compress_block(Compression::LzHuffman, DEFAULT_LEVEL, &random);
+        assert_eq!(compressed[0], Compression::None.id());
+        assert_eq!(decompress_block(&compressed), Some(random));
+
+        // 不明なアルゴリズム
+        assert_eq!(decompress_block(&[9, 1, 2, 3]), None);
+        assert_eq!(decompress_block(&[]), None);
+    }
+
+    #[test]
+    fn test_compress_block_levels() {
+        let mut state: u64 = 7;
+        let mut random = move |n: usize| -> Vec<u8> {
+            (0..n)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 56) as u8
+                })
+                .collect()
+        };
+
+        // SSTable のブロックの大きさ (既定の 4096 バイト前後と、大きめのブロック)
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        for size in [1, 4096, 4096 + 100, 16 * 1024] {
+            let text: Vec<u8> = json(size / 60 + 1).into_iter().take(size).collect();
+            let noise: Vec<u8> = random(size);
+            let repeated: Vec<u8> = [noise.clone(), noise.clone()].concat();
+            let mixed: Vec<u8> = text
+                .chunks(100)
+                .zip(noise.chunks(20))
+                .flat_map(|(t, n)| [t, n].concat())
+                .collect();
+            blocks.extend([vec![0; size], text, noise, repeated, mixed]);
+        }
+        // 同じ乱数列が一致を探す距離より遠くで繰り返されるケース
+        let noise: Vec<u8> = random(MAX_OFFSET + 1);
+        blocks.push([noise.clone(), noise].concat());
+
+        for block in &blocks {
+            for compression in [Compression::None, Compression::Lz, Compression::LzHuffman] {
+                for level in 0..=10 {
+                    let compressed: Vec<u8> = compress_block(compression, level, block);
+                    assert!(compressed.len() <= block.len() + 1);
+                    assert_eq!(
+                        decompress_block(&compressed).as_ref(),
+                        Some(block),
+                        "{compression:?} level {level} size {}",
+                        block.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress_block_arbitrary_bytes() {
+        let mut state: u64 = 42;
+        let mut next = move |bound: usize| -> usize {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) as usize) % bound
+        };
+
+        // でたらめなバイト列でも panic せず、None か何かしらの値を返すケース
+        for _ in 0..10000 {
+            let length: usize = next(300);
+            let mut bytes: Vec<u8> = (0..length).map(|_| next(256) as u8).collect();
+            if let Some(id) = bytes.first_mut() {
+                *id = next(4) as u8;
+            }
+            let _ = decompress_block(&bytes);
+        }
+
+        // 正しく圧縮したブロックの一部を書き換えたり切り詰めたりしたケース
+        let blocks: Vec<Vec<u8>> = [Compression::Lz, Compression::LzHuffman]
+            .into_iter()
+            .map(|compression| compress_block(compression, DEFAULT_LEVEL, &json(20)))
+            .collect();
+        for _ in 0..10000 {
+            let mut bytes: Vec<u8> = blocks[next(blocks.len())].clone();
+            for _ in 0..1 + next(4) {
+                let at: usize = 1 + next(bytes.len() - 1);
+                match next(3) {
+                    0 => bytes[at] = next(256) as u8,
+                    1 => bytes[at] ^= 1 << next(8),
+                    _ => bytes.truncate(at.max(2)),
+                }
+            }
+            if let Some(output) = decompress_block(&bytes) {
+                // 展開した大きさは、入力の大きさに比例する範囲に収まる
+                assert!(output.len() <= bytes.len() * 8 * 255);
+            }
+        }
+    }
+}
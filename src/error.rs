@@ -13,6 +13,19 @@ pub enum KVSError {
     FailedIO(IOError),
     /// Represents an error during data conversion.
     FailedConvert(ConvertError),
+    /// A key read by a transaction was written by someone else before the transaction committed.
+    ///
+    /// Nothing of the transaction was applied, so it can be retried from the start.
+    TransactionConflict(Vec<u8>),
+    /// Another process has opened the data directory.
+    StoreLocked(PathBuf),
+    /// The store was opened read-only and cannot be written.
+    ReadOnly,
+    /// The memtable is full and the previous memtable cannot be flushed,
+    /// because the background threads are paused or stopped by an error.
+    WriteStalled(String),
+    /// The data directory was written by the earlier release, and must be upgraded before it is opened.
+    UpgradeRequired(PathBuf),
 }
 
 impl Display for KVSError {
@@ -20,6 +33,27 @@ impl Display for KVSError {
         match self {
             Self::FailedIO(e) => write!(f, "{e}"),
             Self::FailedConvert(e) => write!(f, "{e}"),
+            Self::TransactionConflict(key) => write!(
+                f,
+                "TransactionError: The key '{}' was changed after the transaction read it.",
+                String::from_utf8_lossy(key)
+            ),
+            Self::StoreLocked(path) => write!(
+                f,
+                "LockError: The data directory '{path:?}' is already opened by another process."
+            ),
+            Self::ReadOnly => write!(
+                f,
+                "ReadOnlyError: The store is opened read-only and cannot be written."
+            ),
+            Self::WriteStalled(msg) => write!(
+                f,
+                "WriteStallError: The memtable is full and waits for the previous memtable to be flushed.\n{msg}"
+            ),
+            Self::UpgradeRequired(path) => write!(
+                f,
+                "UpgradeError: The data directory '{path:?}' was written by an earlier release. Upgrade it with `KVSOptions::upgrade` or `kvsd --upgrade` first."
+            ),
         }
     }
 }
@@ -49,6 +83,20 @@ pub enum IOError {
     FailedSeek(String),
     /// The specified directory was not found.
     DirectoryNotFound(PathBuf),
+    /// Failed to create a directory.
+    FailedCreateDirectory(PathBuf, String),
+    /// A store already exists in the specified directory.
+    StoreAlreadyExists(PathBuf),
+    /// Failed to sync a file to the disk.
+    FailedSync(String),
+    /// Failed to rename a file.
+    FailedRenameFile(PathBuf, String),
+    /// Failed to lock a file.
+    FailedLockFile(PathBuf, String),
+    /// An earlier write or sync of the WAL failed, so the WAL refuses every later write.
+    WALPoisoned(String),
+    /// An earlier write or sync of the manifest failed, so the manifest refuses every later edit.
+    ManifestPoisoned(String),
 }
 
 impl Display for IOError {
@@ -63,7 +111,14 @@ impl Display for IOError {
             IOError::FailedGetFileSize(path, msg) => write!(f, "IOError: Failed to get file size of '{path:?}' because the following error occurred.\n{msg}"),
             IOError::FailedGetFilePath(dir_path, msg) => write!(f, "IOError: Failed to get file path in directory '{dir_path:?}' because the following error occurred.\n{msg}"),
             IOError::FailedSeek(msg) => write!(f, "IOError: Failed to seek file because the following error occurred.\n{msg}"),
-            IOError::DirectoryNotFound(path) => write!(f, "IOError: The directory '{path:?}' is not found or is not directory.")
+            IOError::DirectoryNotFound(path) => write!(f, "IOError: The directory '{path:?}' is not found or is not directory."),
+            IOError::FailedCreateDirectory(path, msg) => write!(f, "IOError: Failed to create directory '{path:?}' because the following error occurred.\n{msg}"),
+            IOError::StoreAlreadyExists(path) => write!(f, "IOError: A store already exists in the directory '{path:?}'."),
+            IOError::FailedSync(msg) => write!(f, "IOError: Failed to sync file because the following error occurred.\n{msg}"),
+            IOError::FailedRenameFile(path, msg) => write!(f, "IOError: Failed to rename '{path:?}' because the following error occurred.\n{msg}"),
+            IOError::FailedLockFile(path, msg) => write!(f, "IOError: Failed to lock '{path:?}' because the following error occurred.\n{msg}"),
+            IOError::WALPoisoned(msg) => write!(f, "IOError: The WAL cannot be written until the store is reopened, because the following error occurred earlier.\n{msg}"),
+            IOError::ManifestPoisoned(msg) => write!(f, "IOError: The manifest cannot be written until the store is reopened, because the following error occurred earlier.\n{msg}"),
         }
     }
 }
@@ -83,6 +138,14 @@ pub enum ConvertError {
     FailedBytesToValue(String),
     /// Failed to convert bytes to a `String`.
     FailedBytesToString(String),
+    /// The bytes of a `WriteBatch` are invalid.
+    InvalidBatch(String),
+    /// The bytes of an SSTable file are invalid.
+    InvalidSSTable(String),
+    /// The WAL file is invalid, or was written in a format that is not supported.
+    InvalidWAL(String),
+    /// The manifest file is corrupt before its last record.
+    InvalidManifest(String),
 }
 
 impl Display for ConvertError {
@@ -90,6 +153,10 @@ impl Display for ConvertError {
         match self {
             ConvertError::FailedBytesToValue(msg) => write!(f, "ConvertError: Failed to convert bytes to Value because the following error occurred.\n{msg}"),
             ConvertError::FailedBytesToString(msg) => write!(f, "ConvertError: Failed to convert bytes to String because the following error occurred.\n{msg}"),
+            ConvertError::InvalidBatch(msg) => write!(f, "ConvertError: The bytes of WriteBatch are invalid.\n{msg}"),
+            ConvertError::InvalidSSTable(msg) => write!(f, "ConvertError: The SSTable file is invalid.\n{msg}"),
+            ConvertError::InvalidWAL(msg) => write!(f, "ConvertError: The WAL file is invalid.\n{msg}"),
+            ConvertError::InvalidManifest(msg) => write!(f, "ConvertError: The manifest file is invalid.\n{msg}"),
         }
     }
 }
@@ -101,3 +168,29 @@ impl From<ConvertError> for KVSError {
         KVSError::FailedConvert(value)
     }
 }
+
+/// Represents an error that can occur while reading or writing a protocol frame.
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    /// Failed to read or write a frame on the connection.
+    FailedIO(String),
+    /// The frame was written in a version of the protocol that is not supported.
+    UnsupportedVersion(u8),
+    /// The frame is larger than a frame can be.
+    FrameTooLarge(usize),
+    /// The message in the frame is invalid.
+    InvalidMessage(String),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::FailedIO(msg) => write!(f, "ProtocolError: Failed to read or write a frame because the following error occurred.\n{msg}"),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "ProtocolError: The protocol version {version} is not supported."),
+            ProtocolError::FrameTooLarge(size) => write!(f, "ProtocolError: The frame of {size} bytes is too large."),
+            ProtocolError::InvalidMessage(msg) => write!(f, "ProtocolError: The message is invalid.\n{msg}"),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
@@ -1,77 +1,33 @@
-    use crate::{error::IOError, value::Value};
-    use std::{
-        collections::HashMap,
-        fs::{self, File},
-        io::{BufReader, BufWriter, Read, Seek, Write},
-        path::PathBuf,
-    };
-
-    /// Writes a key-value pair to a buffered writer.
-    ///
-    /// The format is: key_length (8 bytes) | key | value_bytes | is_deleted (1 byte)
-    pub fn write_key_value(
-        buf_writer: &mut BufWriter<File>,
-        key: &str,
-        value: &Value,
-    ) -> Result<usize, IOError> {
-        let key_bytes: Vec<u8> = [&key.len().to_be_bytes(), key.as_bytes()].concat();
-        let value_bytes: Vec<u8> = value.clone().to_bytes();
-        let bytes: Vec<u8> = [key_bytes, value_bytes, vec![value.is_deleted() as u8]].concat();
-
-        match buf_writer.write(&bytes) {
-            Ok(u) => Ok(u),
-            Err(e) => Err(IOError::FailedWriteBytes(e.to_string())),
-        }
-    }
-
-    /// Reads a specified number of bytes from a buffered reader.
-    fn read(buf_reader: &mut BufReader<File>, length: usize) -> Result<Vec<u8>, IOError> {
-        let mut bytes: Vec<u8> = vec![0; length];
-        match buf_reader.read(&mut bytes) {
-            Ok(_) => Ok(bytes),
-            Err(e) => Err(IOError::FailedReadFile(e.to_string())),
-        }
-    }
-
-    /// Reads an 8-byte length prefix from a buffered reader.
-    fn read_length(buf_reader: &mut BufReader<File>) -> Result<usize, IOError> {
-        let mut bytes: [u8; 8] = [0; 8];
-        match buf_reader.read(&mut bytes) {
-            Ok(_) => Ok(usize::from_be_bytes(bytes)),
-            Err(e) => Err(IOError::FailedReadFile(e.to_string())),
-        }
-    }
-
-    /// Reads a key-value pair from a buffered reader at a given offset.
-    pub fn read_key_value(
-        buf_reader: &mut BufReader<File>,
-        offset: usize,
-    ) -> Result<(Vec<u8>, Vec<u8>), IOError> {
-        if let Err(e) = buf_reader.seek(std::io::SeekFrom::Start(offset as u64)) {
-            return Err(IOError::FailedSeek(e.to_string()));
-        };
-
-        let mut map: HashMap<&str, Vec<u8>> = HashMap::new();
-
-        for k in ["key", "value"] {
-            let length = read_length(buf_reader)?;
-
-            match read(buf_reader, length) {
-                Ok(v) => map.insert(k, v),
-                Err(e) => return Err(e),
-            };
-        }
-
-        let key = map.get("key").cloned().unwrap();
-        let value = map.get("value").cloned().unwrap();
-
-        Ok((key, value))
-    }
-
-    /// Gets the size of a file in bytes.
-    pub fn get_filesize(path: &PathBuf) -> Result<usize, IOError> {
-        match fs::metadata(path) {
-            Ok(metadata) => Ok(metadata.len() as usize),
-            Err(e) => Err(IOError::FailedGetFileSize(path.clone(), e.to_string())),
-        }
+use crate::value::Value;
+
+/// Encodes a key-value pair into bytes.
+///
+/// The format is: key_length (8 bytes) | key | value_bytes | is_deleted (1 byte)
+pub fn encode_key_value(key: &[u8], value: &Value) -> Vec<u8> {
+    let key_bytes: Vec<u8> = [&key.len().to_be_bytes(), key].concat();
+    let value_bytes: Vec<u8> = value.clone().to_bytes();
+    [key_bytes, value_bytes, vec![value.is_deleted() as u8]].concat()
+}
+
+/// Decodes a key-value pair encoded by `encode_key_value` at a given offset.
+///
+/// Returns the key bytes, the value bytes and the offset of the next pair,
+/// or `None` if the bytes end before the pair does.
+pub fn decode_key_value(bytes: &[u8], offset: usize) -> Option<(Vec<u8>, Vec<u8>, usize)> {
+    let (key, offset) = decode_length_prefixed(bytes, offset)?;
+    let (value, offset) = decode_length_prefixed(bytes, offset)?;
+
+    // 末尾の is_deleted (1 byte) を読み飛ばす
+    match offset < bytes.len() {
+        true => Some((key, value, offset + 1)),
+        false => None,
     }
+}
+
+/// Decodes an 8-byte length prefix and the bytes that follow it.
+fn decode_length_prefixed(bytes: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+    let length_bytes: [u8; 8] = bytes.get(offset..offset.checked_add(8)?)?.try_into().ok()?;
+    let start: usize = offset + 8;
+    let end: usize = start.checked_add(usize::from_be_bytes(length_bytes))?;
+    Some((bytes.get(start..end)?.to_vec(), end))
+}
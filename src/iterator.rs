@@ -0,0 +1,236 @@
+use std::iter::Peekable;
+
+use crate::{
+    error::KVSError,
+    snapshot::Snapshot,
+    value::{self, Value},
+};
+
+/// An entry read from the memtable or an SSTable: the key, the sequence number and the value.
+pub type Entry = Result<(Vec<u8>, u64, Value), KVSError>;
+
+/// An iterator over entries ordered by key and then by sequence number, newest first.
+pub type EntryIterator<'a> = Box<dyn Iterator<Item = Entry> + 'a>;
+
+/// Merges several iterators into one iterator ordered by key and then by sequence number,
+/// newest first.
+///
+/// Every version of a key is returned. Every write gets its own sequence number,
+/// so two sources hold a key with the same sequence number only if they hold copies of
+/// the same entry. Then only the entry of the first source is returned.
+pub struct MergeIterator<'a> {
+    /// The sources, newest first.
+    sources: Vec<Peekable<EntryIterator<'a>>>,
+}
+
+impl<'a> MergeIterator<'a> {
+    /// Creates a new `MergeIterator`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sources` - The iterators to merge, newest first.
+    pub fn new(sources: Vec<EntryIterator<'a>>) -> Self {
+        MergeIterator {
+            sources: sources.into_iter().map(|s| s.peekable()).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIterator<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 先頭のキーが最小のソースを探す (同じキーならシーケンス番号が大きいソースを優先する)
+        let mut newest: Option<(usize, Vec<u8>, u64)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, seq, _)))
+                    if newest
+                        .as_ref()
+                        .is_none_or(|(_, k, s)| key < k || (key == k && s < seq)) =>
+                {
+                    newest = Some((i, key.clone(), *seq));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => {}
+            }
+        }
+
+        let (i, _, _) = newest?;
+        let (key, seq, value) = match self.sources[i].next() {
+            Some(Ok(entry)) => entry,
+            _ => unreachable!(),
+        };
+
+        // 他のソースにある同じキー・同じシーケンス番号のエントリを読み飛ばす
+        for source in self.sources.iter_mut() {
+            if let Some(Ok((k, s, _))) = source.peek() {
+                if *k == key && *s == seq {
+                    source.next();
+                }
+            }
+        }
+
+        Some(Ok((key, seq, value)))
+    }
+}
+
+/// An iterator over the live key-value pairs of a `KVS` in key order.
+///
+/// For each key, the newest version whose sequence number is at most the read sequence
+/// number is returned. Deleted and expired keys are skipped.
+///
+/// The iterator reads through a snapshot taken when it was created, so it does not borrow
+/// the store and is not affected by writes, flushes or compactions after it was created.
+pub struct KVSIterator {
+    /// The merged entries of the memtable and the SSTables.
+    merge: MergeIterator<'static>,
+    /// When set, iteration stops at the first key without this prefix.
+    prefix: Option<Vec<u8>>,
+    /// The largest sequence number to read.
+    seq: u64,
+    /// The last key whose version was decided, so that its older versions are skipped.
+    last_key: Option<Vec<u8>>,
+    /// The time the iterator was created, at which expiry is checked.
+    now: u64,
+    /// The snapshot that keeps the versions the iterator reads from the memtables.
+    snapshot: Option<Snapshot>,
+}
+
+impl KVSIterator {
+    /// Creates a new `KVSIterator`.
+    pub fn new(merge: MergeIterator<'static>, prefix: Option<Vec<u8>>, seq: u64) -> Self {
+        KVSIterator {
+            merge,
+            prefix,
+            seq,
+            last_key: None,
+            now: value::now_millis(),
+            snapshot: None,
+        }
+    }
+
+    /// Keeps a snapshot until the iterator is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The snapshot the iterator reads.
+    pub(crate) fn hold(mut self, snapshot: Snapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+}
+
+impl Iterator for KVSIterator {
+    type Item = Result<(Vec<u8>, Value), KVSError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, seq, value) = match self.merge.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    return None;
+                }
+            }
+
+            if self.seq < seq || self.last_key.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_key = Some(key.clone());
+
+            if !value.is_deleted() && !value.is_expired(self.now) {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::iterator::*;
+
+    fn source(entries: &[(&str, u64, &str, bool)]) -> EntryIterator<'static> {
+        let entries: Vec<Entry> = entries
+            .iter()
+            .map(|(k, seq, v, d)| Ok((k.as_bytes().to_vec(), *seq, Value::new(v, *d))))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn test_merge_iterator() {
+        let newer = source(&[("a", 5, "new", false), ("c", 6, "", true)]);
+        let older = source(&[
+            ("a", 1, "old", false),
+            ("b", 2, "old", false),
+            ("c", 3, "old", false),
+        ]);
+
+        let merged: Vec<(Vec<u8>, u64, Value)> = MergeIterator::new(vec![newer, older])
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(
+            merged,
+            vec![
+                (b"a".to_vec(), 5, Value::new("new", false)),
+                (b"a".to_vec(), 1, Value::new("old", false)),
+                (b"b".to_vec(), 2, Value::new("old", false)),
+                (b"c".to_vec(), 6, Value::new("", true)),
+                (b"c".to_vec(), 3, Value::new("old", false)),
+            ]
+        );
+
+        // ソースの順番に関係なく、シーケンス番号が大きいエントリが先に来る
+        let older = source(&[("a", 1, "old", false)]);
+        let newer = source(&[("a", 5, "new", false)]);
+        let seqs: Vec<u64> = MergeIterator::new(vec![older, newer])
+            .map(|e| e.unwrap().1)
+            .collect();
+        assert_eq!(seqs, vec![5, 1]);
+
+        // シーケンス番号が同じ場合は先のソースが勝つ
+        let first = source(&[("a", 0, "first", false)]);
+        let second = source(&[("a", 0, "second", false)]);
+        let merged: Vec<(Vec<u8>, u64, Value)> = MergeIterator::new(vec![first, second])
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(merged, vec![(b"a".to_vec(), 0, Value::new("first", false))]);
+    }
+
+    #[test]
+    fn test_kvs_iterator() {
+        let newer = source(&[("p1", 4, "", true), ("p2", 5, "v2", false)]);
+        let older = source(&[
+            ("p1", 1, "v1", false),
+            ("p3", 2, "v3", false),
+            ("q1", 3, "v4", false),
+        ]);
+        let merge = MergeIterator::new(vec![newer, older]);
+
+        let keys: Vec<Vec<u8>> = KVSIterator::new(merge, Some(b"p".to_vec()), u64::MAX)
+            .map(|e| e.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"p2".to_vec(), b"p3".to_vec()]);
+
+        // 読み取るシーケンス番号より新しいバージョンは見えない
+        let newer = source(&[("p1", 4, "", true), ("p2", 5, "v2", false)]);
+        let older = source(&[("p1", 1, "v1", false), ("p3", 2, "v3", false)]);
+        let merge = MergeIterator::new(vec![newer, older]);
+        let entries: Vec<(Vec<u8>, String)> = KVSIterator::new(merge, None, 3)
+            .map(|e| e.map(|(k, v)| (k, v.to_string())).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"p1".to_vec(), "v1".to_string()),
+                (b"p3".to_vec(), "v3".to_string()),
+            ]
+        );
+    }
+}
@@ -1,69 +1,403 @@
+mod batch;
+mod bloom;
+mod client;
+mod compaction;
+mod compression;
+mod crc;
 mod error;
 mod file_io;
+mod iterator;
+mod log;
+mod manifest;
+mod memtable;
+mod options;
+mod protocol;
+mod snapshot;
 mod sstable;
+mod stats;
+#[cfg(test)]
+mod test_util;
+mod transaction;
+mod upgrade;
 mod value;
 mod wal;
 
-use std::{collections::BTreeMap, fs, path::PathBuf};
+use std::{
+    fs::{self, File, TryLockError},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use error::{IOError, KVSError};
-use sstable::SSTable;
+pub use batch::WriteBatch;
+pub use client::Client;
+use compaction::Compaction;
+pub use compaction::CompactionResult;
+pub use compression::Compression;
+pub use error::{ConvertError, IOError, KVSError, ProtocolError};
+pub use iterator::KVSIterator;
+use iterator::{EntryIterator, MergeIterator};
+use manifest::{Manifest, Replay, VersionEdit};
+use memtable::{Memtable, MemtableIterator};
+pub use options::{Durability, KVSOptions};
+pub use protocol::{Request, Response, Status, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+pub use snapshot::Snapshot;
+use snapshot::SnapshotList;
+use sstable::{Metadata, SSTable};
+pub use stats::KVSStats;
+use stats::Statistics;
+pub use transaction::Transaction;
 use value::Value;
-use wal::WriteAheadLog;
+use wal::{Recovery, WriteAheadLog};
 
 /// A key-value store.
+///
+/// A `KVS` is a handle to a store: clones of it share the same store and can be sent to
+/// other threads. Any number of threads can read at once, and reads never wait for a flush
+/// or a compaction. Writes are applied one at a time.
+///
+/// A full memtable is handed over to background threads, which flush it and compact the
+/// levels while writers go on with a new memtable.
+/// The background threads stop when the last handle is dropped.
+#[derive(Clone)]
 pub struct KVS {
-    /// In-memory key-value store.
-    memtable: BTreeMap<String, Value>,
-    /// The maximum number of key-value pairs to store in the memtable.
-    limit: usize,
+    /// The state shared by all handles of the store and its background threads.
+    inner: Arc<Inner>,
+    /// The background threads, stopped when the last handle is dropped.
+    _workers: Arc<Workers>,
+}
+
+/// The state of a store shared by all its handles and its background threads.
+struct Inner {
+    /// The options the store was opened with.
+    options: KVSOptions,
     /// The directory where the data files are stored.
     data_dir: PathBuf,
-    /// The write-ahead log for durability.
-    wal: WriteAheadLog,
-    /// The list of SSTables.
-    sstables: Vec<SSTable>,
+    /// The number of bytes dropped from the tail of the WALs when they were recovered.
+    wal_dropped_bytes: usize,
+    /// The data directory opened to lock it, unlocked when it is closed with the store.
+    _lock: File,
+    /// The state only writers use, locked so that writes are applied one at a time.
+    writer: Mutex<Writer>,
+    /// The memtables and the SSTables reads see.
+    ///
+    /// Flushes and compactions build their tables first and then replace the version,
+    /// so reads only hold the lock while they clone the `Arc`.
+    current: RwLock<Arc<Version>>,
+    /// The last number taken from the sequence.
+    ///
+    /// Every write gets the next number, and so does every new SSTable file.
+    last_seq: AtomicU64,
+    /// The sequence number of the last write reads can see.
+    ///
+    /// A write counts only once all of it is in the memtable, so snapshots are taken at it.
+    visible_seq: AtomicU64,
+    /// The sequence numbers of the live snapshots.
+    snapshots: Arc<SnapshotList>,
+    /// The counters updated while the store is used.
+    stats: Statistics,
+    /// The state of the flushes and compactions.
+    background: Mutex<Background>,
+    /// Notified when there may be new background work, when a job finishes,
+    /// and when the background threads are paused, resumed or stopped.
+    background_changed: Condvar,
+}
+
+/// The state of a store only writers use.
+struct Writer {
+    /// The write-ahead log of the memtable, or `None` if the store is read-only.
+    wal: Option<WriteAheadLog>,
+}
+
+/// The state of the flushes and compactions of a store.
+struct Background {
+    /// The largest key compacted last time in each level, where the next compaction starts.
+    compact_pointers: Vec<Option<Vec<u8>>>,
+    /// The log of which SSTables are live, or `None` if the store is read-only.
+    manifest: Option<Manifest>,
+    /// Whether the immutable memtable is being flushed.
+    flushing: bool,
+    /// The names of the SSTables being compacted.
+    compacting: Vec<String>,
+    /// The number of jobs running on the background threads.
+    running: usize,
+    /// Whether the background threads are paused.
+    paused: bool,
+    /// The error of the last background job that failed.
+    ///
+    /// The background threads start no new job until it is cleared by resuming them.
+    error: Option<String>,
+    /// Whether the background threads must stop.
+    shutdown: bool,
+}
+
+/// The memtables and the SSTables of a store at one moment.
+#[derive(Clone)]
+struct Version {
+    /// In-memory key-value store of the most recent writes.
+    memtable: Arc<RwLock<Memtable>>,
+    /// The previous memtable, which is no longer written and is waiting to be flushed.
+    immutable: Option<Arc<RwLock<Memtable>>>,
+    /// The SSTables of each level.
+    ///
+    /// Level 0 is ordered from oldest to newest and its tables can overlap.
+    /// The other levels are ordered by key and their tables do not overlap.
+    levels: Vec<Vec<Arc<SSTable>>>,
+}
+
+/// A job of the background threads.
+enum Job {
+    /// Flushes the immutable memtable.
+    Flush,
+    /// Runs a compaction picked from a version.
+    Compaction(Compaction, Arc<Version>),
+}
+
+/// The background threads of a store.
+struct Workers {
+    /// The state shared with the threads.
+    inner: Arc<Inner>,
+    /// The threads.
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        lock(&self.inner.background).shutdown = true;
+        self.inner.background_changed.notify_all();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Writer {
+    /// Returns the WAL, which a read-only store does not have.
+    fn wal(&mut self) -> Result<&mut WriteAheadLog, KVSError> {
+        self.wal.as_mut().ok_or(KVSError::ReadOnly)
+    }
 }
 
-const DEFAULT_DATA_DIR: &str = "./data/";
-const DEFAULT_WAL_FILENAME: &str = "wal";
+impl Background {
+    /// Returns the manifest, which a read-only store does not have.
+    fn manifest(&mut self) -> Result<&mut Manifest, KVSError> {
+        self.manifest.as_mut().ok_or(KVSError::ReadOnly)
+    }
+}
 
 impl KVS {
-    /// Creates a new `KVS` instance.
+    /// Creates a new `KVS` instance with the default options.
+    ///
+    /// See [`KVSOptions`] to change the data directory or the memtable limits.
+    pub fn new() -> Result<Self, KVSError> {
+        KVS::open(&KVSOptions::default())
+    }
+
+    /// Opens a `KVS` instance with the given options.
     ///
     /// This function initializes the `KVS` by:
     /// - Setting the data directory.
-    /// - Loading existing SSTables.
+    /// - Locking the data directory, so that no other process can open it for writing.
+    /// - Loading the live SSTables recorded in the manifest.
     /// - Initializing the write-ahead log.
-    /// - Recovering the memtable from the WAL.
-    pub fn new() -> Result<Self, KVSError> {
-        let data_dir: PathBuf = PathBuf::from(DEFAULT_DATA_DIR);
+    /// - Recovering the memtable from the WAL, and the immutable memtable from its WAL if
+    ///   it was not flushed.
+    /// - Starting the background threads.
+    ///
+    /// A read-only store leaves the data directory as it is and starts no background threads.
+    /// Fails with `KVSError::UpgradeRequired` if the data directory was written by the earlier
+    /// release, and with `KVSError::StoreLocked` if another process has opened the data directory,
+    /// unless both are read-only.
+    pub fn open(options: &KVSOptions) -> Result<Self, KVSError> {
+        let data_dir: PathBuf = options.data_dir.clone();
         if !data_dir.is_dir() {
-            return Err(KVSError::FailedIO(IOError::DirectoryNotFound(data_dir)));
+            if !options.create_if_missing || options.read_only {
+                return Err(KVSError::FailedIO(IOError::DirectoryNotFound(data_dir)));
+            }
+            if let Err(e) = fs::create_dir_all(&data_dir) {
+                return Err(KVSError::FailedIO(IOError::FailedCreateDirectory(
+                    data_dir,
+                    e.to_string(),
+                )));
+            }
         }
 
-        let sstables: Vec<SSTable> = get_sstables(&data_dir)?;
-        let mut wal: WriteAheadLog = WriteAheadLog::new(&data_dir, DEFAULT_WAL_FILENAME)?;
-        let memtable: BTreeMap<String, Value> = wal.recovery()?;
+        let lock_file: File = lock_data_dir(&data_dir, options.read_only)?;
+        if upgrade::needs_upgrade(&data_dir, &options.wal_filename)? {
+            return Err(KVSError::UpgradeRequired(data_dir));
+        }
 
-        Ok(KVS {
+        if options.error_if_exists && data_dir.join(&options.wal_filename).exists() {
+            return Err(KVSError::FailedIO(IOError::StoreAlreadyExists(data_dir)));
+        }
+
+        let replay: Option<Replay> = Manifest::replay(&data_dir)?;
+        let (levels, table_seq) = load_sstables(&data_dir, options.max_levels, replay.as_ref())?;
+        let removed: &[String] = replay.as_ref().map_or(&[], |replay| &replay.removed);
+        let manifest: Option<Manifest> = match options.read_only {
+            true => None,
+            false => Some(write_manifest(&data_dir, &levels, table_seq, removed)?),
+        };
+
+        // 前回 flush されずに終わった immutable memtable があれば、その WAL から復元する
+        let old_wal_filename: String = old_wal_filename(&options.wal_filename);
+        let mut immutable: Option<Memtable> = None;
+        let mut old_wal_seq: u64 = 0;
+        let mut old_wal_dropped_bytes: usize = 0;
+        if data_dir.join(&old_wal_filename).is_file() {
+            let recovery: Recovery = if options.read_only {
+                WriteAheadLog::replay(&data_dir, &old_wal_filename)?
+            } else {
+                WriteAheadLog::new(&data_dir, &old_wal_filename, Durability::Never)?.recovery()?
+            };
+            immutable = Some(recovery.memtable);
+            old_wal_seq = recovery.last_seq;
+            old_wal_dropped_bytes = recovery.dropped_bytes;
+        }
+
+        let (wal, recovery): (Option<WriteAheadLog>, Recovery) = if options.read_only {
+            let recovery: Recovery = WriteAheadLog::replay(&data_dir, &options.wal_filename)?;
+            (None, recovery)
+        } else {
+            let mut wal: WriteAheadLog =
+                WriteAheadLog::new(&data_dir, &options.wal_filename, options.durability)?;
+            let recovery: Recovery = wal.recovery()?;
+            (Some(wal), recovery)
+        };
+        let Recovery {
             memtable,
-            limit: 1024,
-            wal,
+            last_seq: wal_seq,
+            dropped_bytes: wal_dropped_bytes,
+        } = recovery;
+
+        let last_seq: u64 = table_seq.max(old_wal_seq).max(wal_seq);
+        let version: Version = Version {
+            memtable: Arc::new(RwLock::new(memtable)),
+            immutable: immutable.map(|memtable| Arc::new(RwLock::new(memtable))),
+            levels: levels
+                .into_iter()
+                .map(|tables| tables.into_iter().map(Arc::new).collect())
+                .collect(),
+        };
+        let inner: Arc<Inner> = Arc::new(Inner {
+            options: options.clone(),
             data_dir,
-            sstables,
+            wal_dropped_bytes: old_wal_dropped_bytes + wal_dropped_bytes,
+            _lock: lock_file,
+            writer: Mutex::new(Writer { wal }),
+            current: RwLock::new(Arc::new(version)),
+            last_seq: AtomicU64::new(last_seq),
+            visible_seq: AtomicU64::new(last_seq),
+            snapshots: Arc::new(SnapshotList::default()),
+            stats: Statistics::default(),
+            background: Mutex::new(Background {
+                compact_pointers: vec![None; options.max_levels],
+                manifest,
+                flushing: false,
+                compacting: Vec::new(),
+                running: 0,
+                paused: false,
+                error: None,
+                shutdown: false,
+            }),
+            background_changed: Condvar::new(),
+        });
+
+        // バックグラウンドスレッドがなければ、復元した immutable memtable はここで flush する
+        if options.background_threads == 0 && !options.read_only {
+            inner.flush_immutable_memtable()?;
+        }
+
+        let threads: usize = match options.read_only {
+            true => 0,
+            false => options.background_threads,
+        };
+        let handles: Vec<JoinHandle<()>> = (0..threads)
+            .map(|_| {
+                let inner: Arc<Inner> = Arc::clone(&inner);
+                thread::spawn(move || inner.run_background_jobs())
+            })
+            .collect();
+        let workers: Workers = Workers {
+            inner: Arc::clone(&inner),
+            handles,
+        };
+        Ok(KVS {
+            inner,
+            _workers: Arc::new(workers),
         })
     }
 
+    /// Returns the number of bytes dropped from the tail of the WAL when the store was opened.
+    ///
+    /// A non-zero value means the last writes before a crash were torn or corrupt
+    /// and were not recovered.
+    pub fn wal_dropped_bytes(&self) -> usize {
+        self.inner.wal_dropped_bytes
+    }
+
+    /// Syncs all writes in the WAL to the disk.
+    ///
+    /// This is useful with `Durability::Never` or `Durability::Periodic`
+    /// to make the writes so far durable at a chosen point.
+    pub fn sync(&self) -> Result<(), IOError> {
+        match &lock(&self.inner.writer).wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
     /// Inserts a key-value pair into the store.
     ///
     /// # Arguments
     ///
     /// * `k` - The key.
     /// * `v` - The value.
-    pub fn put(&mut self, k: &str, v: &str) -> Result<(), IOError> {
-        let value: Value = Value::new(v, false);
-        self.put_key_value(k, value)
+    pub fn put(&self, k: &str, v: &str) -> Result<(), KVSError> {
+        self.put_bytes(k.as_bytes(), v.as_bytes())
+    }
+
+    /// Inserts a binary key-value pair into the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    pub fn put_bytes(&self, k: &[u8], v: &[u8]) -> Result<(), KVSError> {
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.put_bytes(k, v);
+        self.write(batch)
+    }
+
+    /// Inserts a key-value pair that expires after a time to live.
+    ///
+    /// Once expired, the pair is hidden from reads and is removed by compaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl(&self, k: &str, v: &str, ttl: Duration) -> Result<(), KVSError> {
+        self.put_with_ttl_bytes(k.as_bytes(), v.as_bytes(), ttl)
+    }
+
+    /// Inserts a binary key-value pair that expires after a time to live.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl_bytes(&self, k: &[u8], v: &[u8], ttl: Duration) -> Result<(), KVSError> {
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.put_with_ttl_bytes(k, v, ttl);
+        self.write(batch)
     }
 
     /// Deletes a key-value pair from the store.
@@ -71,145 +405,2243 @@ impl KVS {
     /// # Arguments
     ///
     /// * `k` - The key to delete.
-    pub fn delete(&mut self, k: &str) -> Result<(), IOError> {
-        let value: Value = Value::new("", true);
-        self.put_key_value(k, value)
+    pub fn delete(&self, k: &str) -> Result<(), KVSError> {
+        self.delete_bytes(k.as_bytes())
+    }
+
+    /// Deletes a binary key from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key to delete.
+    pub fn delete_bytes(&self, k: &[u8]) -> Result<(), KVSError> {
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.delete_bytes(k);
+        self.write(batch)
     }
 
-    /// A helper function to put a key-value pair into the memtable and WAL.
-    fn put_key_value(&mut self, key: &str, value: Value) -> Result<(), IOError> {
-        self.wal.write(key, &value)?;
-        self.memtable.insert(key.to_string(), value);
+    /// Sets a key to a new value only if its current value is the expected one.
+    ///
+    /// The check and the write happen atomically. Returns whether the value was set.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    /// * `expected` - The expected current value, or `None` if the key is expected to be absent.
+    /// * `new` - The new value.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> Result<bool, KVSError> {
+        self.compare_and_swap_bytes(key.as_bytes(), expected.map(str::as_bytes), new.as_bytes())
+    }
 
-        if self.limit < self.memtable.len() {
-            self.flush()?;
+    /// Sets a binary key to a new value only if its current value is the expected one.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    /// * `expected` - The expected current value, or `None` if the key is expected to be absent.
+    /// * `new` - The new value.
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, KVSError> {
+        let writer: MutexGuard<Writer> = lock(&self.inner.writer);
+        if !self.current_equals(key, expected)? {
+            return Ok(false);
         }
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.put_bytes(key, new);
+        self.write_locked(writer, batch)?;
+        Ok(true)
+    }
 
-        Ok(())
+    /// Inserts a key-value pair only if the key is absent.
+    ///
+    /// Returns whether the pair was inserted.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    /// * `value` - The value.
+    pub fn put_if_absent(&self, key: &str, value: &str) -> Result<bool, KVSError> {
+        self.compare_and_swap_bytes(key.as_bytes(), None, value.as_bytes())
     }
 
-    /// Retrieves a value from the store by its key.
+    /// Inserts a binary key-value pair only if the key is absent.
+    ///
+    /// # Arguments
     ///
-    /// It first searches the memtable, then the SSTables.
+    /// * `key` - The key.
+    /// * `value` - The value.
+    pub fn put_if_absent_bytes(&self, key: &[u8], value: &[u8]) -> Result<bool, KVSError> {
+        self.compare_and_swap_bytes(key, None, value)
+    }
+
+    /// Deletes a key only if its current value is the expected one.
+    ///
+    /// Returns whether the key was deleted.
     ///
     /// # Arguments
     ///
-    /// * `key` - The key to retrieve.
-    pub fn get(&mut self, key: &str) -> Result<Option<Value>, KVSError> {
-        if let Some(value) = self.memtable.get(key) {
-            return match value.is_deleted() {
-                true => Ok(None),
-                false => Ok(Some(value.clone())),
-            };
+    /// * `key` - The key to delete.
+    /// * `expected` - The expected current value.
+    pub fn delete_if_equals(&self, key: &str, expected: &str) -> Result<bool, KVSError> {
+        self.delete_if_equals_bytes(key.as_bytes(), expected.as_bytes())
+    }
+
+    /// Deletes a binary key only if its current value is the expected one.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to delete.
+    /// * `expected` - The expected current value.
+    pub fn delete_if_equals_bytes(&self, key: &[u8], expected: &[u8]) -> Result<bool, KVSError> {
+        let writer: MutexGuard<Writer> = lock(&self.inner.writer);
+        if !self.current_equals(key, Some(expected))? {
+            return Ok(false);
         }
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.delete_bytes(key);
+        self.write_locked(writer, batch)?;
+        Ok(true)
+    }
 
-        if let Some(value) = self.get_from_sstable(key)? {
-            return match value.is_deleted() {
-                true => Ok(None),
-                false => Ok(Some(value)),
-            };
+    /// Returns `true` if the current value of a key is the expected one.
+    fn current_equals(&self, key: &[u8], expected: Option<&[u8]>) -> Result<bool, KVSError> {
+        let current: Option<Value> = self.get_at(key, u64::MAX)?;
+        Ok(current.as_ref().map(|v| v.as_bytes()) == expected)
+    }
+
+    /// Applies all puts and deletes in a batch atomically.
+    ///
+    /// The batch is written to the WAL as one record, so after a crash
+    /// either every change in the batch is recovered or none of them is.
+    /// The changes get consecutive sequence numbers in the order they were added.
+    ///
+    /// If syncing the WAL fails, the batch is not applied but may still be recovered
+    /// when the store is reopened, and every later write fails until then.
+    ///
+    /// When the memtable is full and the previous memtable is still waiting to be flushed,
+    /// this waits until the flush finishes, so that memory does not grow without limit.
+    /// Fails with `KVSError::WriteStalled` instead if the background threads are paused
+    /// or stopped by an error, since the flush would not come.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The batch to apply.
+    pub fn write(&self, batch: WriteBatch) -> Result<(), KVSError> {
+        let writer: MutexGuard<Writer> = lock(&self.inner.writer);
+        self.write_locked(writer, batch)
+    }
+
+    /// Applies a batch while the writer lock is held, then releases the lock.
+    ///
+    /// The entries are inserted into the memtable and become visible together,
+    /// so reads see either the whole batch or none of it.
+    /// A full memtable is handed over to the background threads, or is flushed
+    /// by this thread if there are none.
+    fn write_locked(
+        &self,
+        mut writer: MutexGuard<Writer>,
+        batch: WriteBatch,
+    ) -> Result<(), KVSError> {
+        if self.inner.options.read_only {
+            return Err(KVSError::ReadOnly);
         }
-        Ok(None)
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.wait_for_flush()?;
+
+        let count: u64 = batch.len() as u64;
+        let seq: u64 = self.inner.last_seq.fetch_add(count, Ordering::SeqCst) + 1;
+        writer.wal()?.write(seq, &batch)?;
+
+        let current: Arc<Version> = self.inner.current();
+        let is_full: bool = self.inner.snapshots.write(|snapshots| {
+            let mut memtable: RwLockWriteGuard<Memtable> = write(&current.memtable);
+            for (i, (key, value)) in batch.entries().iter().enumerate() {
+                memtable.insert(key, seq + i as u64, value.clone(), snapshots);
+            }
+            self.inner
+                .visible_seq
+                .store(seq + count - 1, Ordering::SeqCst);
+            self.inner.is_memtable_full(&memtable)
+        });
+
+        // 前の memtable の flush が終わるまでは、今の memtable に書き続ける
+        if is_full && current.immutable.is_none() {
+            self.inner.switch_memtable(&mut writer)?;
+            drop(writer);
+            if self.inner.options.background_threads == 0 {
+                self.inner.flush_immutable_memtable()?;
+                self.compaction()?;
+            }
+        }
+
+        Ok(())
     }
 
-    /// Retrieves a value from the SSTables by its key.
-    fn get_from_sstable(&mut self, key: &str) -> Result<Option<Value>, KVSError> {
-        for sstable in self.sstables.iter().rev() {
-            if let Some(value) = sstable.get(key)? {
-                return Ok(Some(value));
+    /// Waits while the memtable is full and the previous memtable is waiting to be flushed.
+    ///
+    /// The writer lock must be held, so that the memtable does not fill up again meanwhile.
+    fn wait_for_flush(&self) -> Result<(), KVSError> {
+        // バックグラウンドスレッドがなければ、前の書き込みで flush に失敗した memtable をここで flush する
+        if self.inner.options.background_threads == 0 {
+            return self.inner.flush_immutable_memtable();
+        }
+
+        // flush は background のロックを取ってから immutable memtable を外すので、通知を取りこぼさない
+        let mut background: MutexGuard<Background> = lock(&self.inner.background);
+        loop {
+            let current: Arc<Version> = self.inner.current();
+            if current.immutable.is_none() || !self.inner.is_memtable_full(&read(&current.memtable))
+            {
+                return Ok(());
             }
+            if let Some(e) = &background.error {
+                return Err(KVSError::WriteStalled(e.clone()));
+            }
+            if background.paused {
+                return Err(KVSError::WriteStalled(
+                    "The background work is paused.".to_string(),
+                ));
+            }
+            background = wait(&self.inner.background_changed, background);
         }
-        Ok(None)
     }
 
-    /// Flushes the memtable to an SSTable.
-    pub fn flush(&mut self) -> Result<(), IOError> {
-        let timestamp = chrono::Local::now().timestamp();
-        match SSTable::create(&self.data_dir, &self.memtable, &timestamp.to_string()) {
-            Ok(sst) => self.sstables.push(sst),
-            Err(e) => return Err(e),
-        };
+    /// Retrieves a value from the store by its key.
+    ///
+    /// It first searches the memtable, then the SSTables from level 0 down.
+    /// Deleted and expired values are not returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get(&self, key: &str) -> Result<Option<Value>, KVSError> {
+        self.get_at(key.as_bytes(), u64::MAX)
+    }
 
-        match self.wal.clear() {
-            Ok(_) => self.memtable.clear(),
-            Err(e) => return Err(e),
-        };
+    /// Retrieves a value from the store by its binary key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Value>, KVSError> {
+        self.get_at(key, u64::MAX)
+    }
 
-        Ok(())
+    /// Retrieves the value of a key as of a sequence number.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    /// * `seq` - The largest sequence number to read.
+    pub(crate) fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Value>, KVSError> {
+        match self.get_version(key, seq)? {
+            Some((_, value)) if !value.is_deleted() && !value.is_expired(value::now_millis()) => {
+                Ok(Some(value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Retrieves the newest version of a key as of a sequence number, including a deletion,
+    /// together with its sequence number.
+    ///
+    /// The memtable is searched first, then the immutable memtable, then the SSTables.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    /// * `seq` - The largest sequence number to read.
+    fn get_version(&self, key: &[u8], seq: u64) -> Result<Option<(u64, Value)>, KVSError> {
+        let current: Arc<Version> = self.inner.current();
+        let memtables = std::iter::once(&current.memtable).chain(current.immutable.as_ref());
+        for memtable in memtables {
+            let found: Option<(u64, Value)> = read(memtable)
+                .get(key, seq)
+                .map(|(version, value)| (version, value.clone()));
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        self.get_from_sstable(&current.levels, key, seq)
     }
 
-    /// Compacts the SSTables into a single SSTable.
-    pub fn compaction(&mut self) -> Result<(), KVSError> {
-        let mut btm: BTreeMap<String, Value> = BTreeMap::new();
-        for sstable in self.sstables.iter() {
-            for key in sstable.keys() {
-                if let Some(value) = sstable.get(key)? {
-                    btm.insert(key.to_string(), value);
+    /// Retrieves a version from the SSTables by its key.
+    ///
+    /// Every table of level 0 can hold the key, so they are searched from newest to oldest.
+    /// In the other levels only the one table whose key range covers the key is searched.
+    /// SSTables whose bloom filter rules the key out are skipped without any I/O.
+    fn get_from_sstable(
+        &self,
+        levels: &[Vec<Arc<SSTable>>],
+        key: &[u8],
+        seq: u64,
+    ) -> Result<Option<(u64, Value)>, KVSError> {
+        let mut candidates: Vec<&SSTable> = levels[0].iter().rev().map(|t| t.as_ref()).collect();
+        for tables in levels[1..].iter() {
+            let i: usize = tables.partition_point(|t| t.meta().max_key.as_slice() < key);
+            if let Some(sstable) = tables.get(i) {
+                if sstable.meta().min_key.as_slice() <= key {
+                    candidates.push(sstable);
                 }
             }
         }
 
-        clear_sstables(&self.data_dir)?;
-        self.sstables.clear();
+        for sstable in candidates {
+            let filter = match sstable.filter() {
+                Some(filter) => filter,
+                None => match sstable.get(key, seq)? {
+                    Some(version) => return Ok(Some(version)),
+                    None => continue,
+                },
+            };
 
-        let timestamp = chrono::Local::now().timestamp();
-        let sstable: SSTable = SSTable::create(&self.data_dir, &btm, &timestamp.to_string())?;
-        self.sstables.push(sstable);
+            if !filter.may_contain(key) {
+                self.inner.stats.record_bloom_filter_miss();
+                continue;
+            }
 
-        Ok(())
+            self.inner.stats.record_bloom_filter_hit();
+            match sstable.get(key, seq)? {
+                Some(version) => return Ok(Some(version)),
+                None => self.inner.stats.record_bloom_filter_false_positive(),
+            }
+        }
+        Ok(None)
     }
-}
 
-/// Gets a list of SSTables from the data directory.
-fn get_sstables(data_dir: &PathBuf) -> Result<Vec<SSTable>, KVSError> {
-    let data_files: Vec<PathBuf> = get_data_files(data_dir)?;
-    let mut sstables: Vec<SSTable> = Vec::new();
+    /// Returns the current statistics of the store.
+    ///
+    /// The sizes of the data blocks are summed over the SSTables that are live now.
+    pub fn stats(&self) -> KVSStats {
+        let mut stats: KVSStats = self.inner.stats.snapshot();
+        for table in self.inner.current().levels.iter().flatten() {
+            stats.uncompressed_bytes += table.meta().raw_data_size;
+            stats.compressed_bytes += table.data_size();
+        }
+        stats
+    }
 
-    for file in data_files {
-        let sstable = SSTable::from_file(file)?;
-        sstables.push(sstable)
+    /// Returns an iterator over the key-value pairs whose keys are in the range, in key order.
+    ///
+    /// The memtable and all SSTables are merged, and deleted keys are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of keys, e.g. `"a".."c"` or `b"a".to_vec()..`.
+    pub fn scan<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<KVSIterator, KVSError> {
+        self.iter(key_range(&range), None, self.snapshot())
     }
-    Ok(sstables)
-}
 
-/// Gets a list of data files from the data directory.
-fn get_data_files(data_dir: &PathBuf) -> Result<Vec<PathBuf>, IOError> {
-    let files: fs::ReadDir = match fs::read_dir(data_dir) {
-        Ok(read_dir) => read_dir,
-        Err(e) => return Err(IOError::FailedGetFilePath(data_dir.clone(), e.to_string())),
-    };
+    /// Returns an iterator over all the key-value pairs, in key order.
+    pub fn scan_all(&self) -> Result<KVSIterator, KVSError> {
+        self.iter((Bound::Unbounded, Bound::Unbounded), None, self.snapshot())
+    }
 
-    let mut data_files: Vec<PathBuf> = Vec::new();
+    /// Returns an iterator over the key-value pairs whose binary keys are in the range, in key order.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of keys, e.g. `&b"a"[..]..&b"c"[..]`.
+    pub fn scan_bytes<'k, R>(&self, range: R) -> Result<KVSIterator, KVSError>
+    where
+        R: RangeBounds<&'k [u8]>,
+    {
+        self.iter(key_range(&range), None, self.snapshot())
+    }
 
-    for result in files {
-        let data_file: PathBuf = match result {
-            Ok(dir_entry) => dir_entry.path(),
-            Err(e) => return Err(IOError::FailedGetFilePath(data_dir.clone(), e.to_string())),
-        };
+    /// Returns an iterator over the key-value pairs whose keys start with the prefix, in key order.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The prefix of the keys.
+    pub fn prefix(&self, prefix: &str) -> Result<KVSIterator, KVSError> {
+        self.prefix_bytes(prefix.as_bytes())
+    }
 
-        let mut extention: &str = "";
-        if let Some(ext_os_str) = data_file.extension() {
-            if let Some(ext_str) = ext_os_str.to_str() {
-                extention = ext_str
+    /// Returns an iterator over the key-value pairs whose binary keys start with the prefix.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The prefix of the keys.
+    pub fn prefix_bytes(&self, prefix: &[u8]) -> Result<KVSIterator, KVSError> {
+        self.iter(prefix_range(prefix), Some(prefix.to_vec()), self.snapshot())
+    }
+
+    /// Takes a snapshot of the store.
+    ///
+    /// Reads through the snapshot see the store as it is now, whatever is written afterwards.
+    /// The versions the snapshot can read are kept, and the store is kept open, until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::latest(self)
+    }
+
+    /// Begins a transaction that reads the store as it is now.
+    ///
+    /// The transaction's writes are applied by [`KVS::commit`].
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.snapshot())
+    }
+
+    /// Commits a transaction.
+    ///
+    /// If any key the transaction read has been written since it began,
+    /// nothing is applied and `KVSError::TransactionConflict` is returned.
+    /// Otherwise the transaction's writes are applied atomically like a `WriteBatch`.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction` - The transaction to commit.
+    pub fn commit(&self, transaction: Transaction) -> Result<(), KVSError> {
+        let writer: MutexGuard<Writer> = lock(&self.inner.writer);
+        for key in transaction.reads() {
+            if let Some((seq, _)) = self.get_version(key, u64::MAX)? {
+                if transaction.seq() < seq {
+                    return Err(KVSError::TransactionConflict(key.clone()));
+                }
             }
-        };
+        }
+        self.write_locked(writer, transaction.into_writes())
+    }
 
-        if extention == "dat" {
-            data_files.push(data_file)
-        };
+    /// A helper function to create an iterator over the memtable and the SSTables.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The start and end bounds of the keys.
+    /// * `prefix` - When set, iteration stops at the first key without this prefix.
+    /// * `snapshot` - The snapshot to read, which the iterator keeps until it is dropped.
+    pub(crate) fn iter(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        prefix: Option<Vec<u8>>,
+        snapshot: Snapshot,
+    ) -> Result<KVSIterator, KVSError> {
+        let current: Arc<Version> = self.inner.current();
+        let seq: u64 = snapshot.seq();
+        let mut sources: Vec<EntryIterator<'static>> = Vec::new();
+
+        if is_valid_range(&range) {
+            // memtable は書き込みで変わるので、スナップショットで古いバージョンを残しながら少しずつ読む
+            let memtables = std::iter::once(&current.memtable).chain(current.immutable.as_ref());
+            for memtable in memtables {
+                let iter = MemtableIterator::new(Arc::clone(memtable), range.clone(), seq);
+                sources.push(Box::new(iter));
+            }
+
+            for sstable in current.levels[0].iter().rev() {
+                sources.push(Box::new(sstable.range(range.clone())?));
+            }
+            // 各レベルのテーブルはキーが重ならないので、範囲と重なるものだけを読む
+            for tables in current.levels[1..].iter() {
+                for sstable in tables.iter().filter(|t| overlaps_range(t.meta(), &range)) {
+                    sources.push(Box::new(sstable.range(range.clone())?));
+                }
+            }
+        }
+
+        Ok(KVSIterator::new(MergeIterator::new(sources), prefix, seq).hold(snapshot))
     }
-    Ok(data_files)
-}
 
-/// Clears all SSTables from the data directory.
-fn clear_sstables(data_dir: &PathBuf) -> Result<(), IOError> {
-    let data_files: Vec<PathBuf> = get_data_files(data_dir)?;
+    /// Flushes the memtable to an SSTable in level 0 and waits until it is done.
+    ///
+    /// Levels that grow beyond their targets are compacted afterwards.
+    /// The work runs on the calling thread, even while the background threads are paused.
+    pub fn flush(&self) -> Result<(), KVSError> {
+        if self.inner.options.read_only {
+            return Err(KVSError::ReadOnly);
+        }
+        loop {
+            // 前の memtable が残っていれば先に flush する
+            self.inner.flush_immutable_memtable()?;
 
-    for file in data_files {
-        if let Err(e) = fs::remove_file(&file) {
-            return Err(IOError::FailedRemoveFile(file, e.to_string()));
+            let mut writer: MutexGuard<Writer> = lock(&self.inner.writer);
+            let current: Arc<Version> = self.inner.current();
+            if current.immutable.is_some() {
+                continue;
+            }
+            if !read(&current.memtable).is_empty() {
+                self.inner.switch_memtable(&mut writer)?;
+            }
+            break;
         }
+        self.inner.flush_immutable_memtable()?;
+
+        self.compaction()?;
+        Ok(())
     }
 
-    Ok(())
+    /// Compacts the levels that exceed their targets into the next level.
+    ///
+    /// Level 0 is compacted when it holds `level0_compaction_trigger` tables,
+    /// and the other levels when their total size exceeds the target size of the level.
+    /// This runs on the calling thread until every level is within its target,
+    /// and waits for the compactions running on the background threads.
+    ///
+    /// Deleted keys are dropped when no older data of them can remain in the levels below,
+    /// and the returned result tells how much was reclaimed.
+    pub fn compaction(&self) -> Result<CompactionResult, KVSError> {
+        if self.inner.options.read_only {
+            return Err(KVSError::ReadOnly);
+        }
+        let mut result: CompactionResult = CompactionResult::default();
+        loop {
+            let started: Option<(Compaction, Arc<Version>)> = {
+                let mut background: MutexGuard<Background> = lock(&self.inner.background);
+                loop {
+                    if let Some(started) = self.inner.start_compaction(&mut background) {
+                        break Some(started);
+                    }
+                    if background.compacting.is_empty() {
+                        break None;
+                    }
+                    background = wait(&self.inner.background_changed, background);
+                }
+            };
+            let (compaction, version) = match started {
+                Some(started) => started,
+                None => return Ok(result),
+            };
+
+            let reclaimed: Result<CompactionResult, KVSError> =
+                self.inner.run_compaction(&compaction, &version);
+            let names: Vec<String> = compaction_table_names(&compaction, &version);
+            // 削除されたテーブルのファイルが消えてから、終わったことを知らせる
+            drop(version);
+            self.inner
+                .finish_compaction(&mut lock(&self.inner.background), &names);
+            self.inner.background_changed.notify_all();
+
+            let reclaimed: CompactionResult = reclaimed?;
+            result.reclaimed_entries += reclaimed.reclaimed_entries;
+            result.reclaimed_bytes += reclaimed.reclaimed_bytes;
+        }
+    }
+
+    /// Pauses the background flushes and compactions.
+    ///
+    /// This waits for the running jobs to finish, and no new job starts until
+    /// [`KVS::resume_background_work`] is called. Writes go on meanwhile until the memtable
+    /// fills up while the previous memtable is waiting to be flushed. Then they fail with
+    /// `KVSError::WriteStalled` until the background work is resumed or [`KVS::flush`] is called.
+    pub fn pause_background_work(&self) {
+        let mut background: MutexGuard<Background> = lock(&self.inner.background);
+        background.paused = true;
+        while 0 < background.running {
+            background = wait(&self.inner.background_changed, background);
+        }
+    }
+
+    /// Resumes the background flushes and compactions.
+    ///
+    /// This also clears the error of a failed background job, so that the job is retried.
+    pub fn resume_background_work(&self) {
+        let mut background: MutexGuard<Background> = lock(&self.inner.background);
+        background.paused = false;
+        background.error = None;
+        drop(background);
+        self.inner.background_changed.notify_all();
+    }
+
+    /// Returns the error of the last background job that failed, if any.
+    ///
+    /// After a failure the background threads start no new job until they are resumed.
+    pub fn background_error(&self) -> Option<String> {
+        lock(&self.inner.background).error.clone()
+    }
+}
+
+impl Inner {
+    /// Returns the memtables and the SSTables reads see now.
+    fn current(&self) -> Arc<Version> {
+        Arc::clone(&read(&self.current))
+    }
+
+    /// Replaces the version reads see with a changed copy of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The change to make to the copy.
+    fn install(&self, change: impl FnOnce(&mut Version)) {
+        let mut current: RwLockWriteGuard<Arc<Version>> = write(&self.current);
+        let mut version: Version = Version::clone(&current);
+        change(&mut version);
+        *current = Arc::new(version);
+    }
+
+    /// Returns `true` if the memtable exceeds either of its limits.
+    fn is_memtable_full(&self, memtable: &Memtable) -> bool {
+        if self.options.memtable_max_entries < memtable.len() {
+            return true;
+        }
+        match self.options.memtable_max_bytes {
+            Some(limit_bytes) => limit_bytes < memtable.size(),
+            None => false,
+        }
+    }
+
+    /// Makes the memtable immutable and starts a new memtable with a new WAL.
+    ///
+    /// The WAL of the immutable memtable is kept under another name until it is flushed.
+    /// The rename and the new WAL are synced to the disk before any write goes to the new WAL.
+    /// There must be no immutable memtable yet.
+    fn switch_memtable(&self, writer: &mut Writer) -> Result<(), KVSError> {
+        if self.options.durability != Durability::Never {
+            writer.wal()?.sync()?;
+        }
+        let wal_path: PathBuf = self.data_dir.join(&self.options.wal_filename);
+        let old_wal_path: PathBuf = self
+            .data_dir
+            .join(old_wal_filename(&self.options.wal_filename));
+        if let Err(e) = fs::rename(&wal_path, old_wal_path) {
+            return Err(KVSError::FailedIO(IOError::FailedRenameFile(
+                wal_path,
+                e.to_string(),
+            )));
+        }
+        // 新しい WAL に書き込む前に、名前の変更をディスクに残す (新しい WAL は作成時に同期される)
+        manifest::sync_dir(&self.data_dir)?;
+        writer.wal = Some(WriteAheadLog::new(
+            &self.data_dir,
+            &self.options.wal_filename,
+            self.options.durability,
+        )?);
+
+        // 待機中のバックグラウンドスレッドが通知を取りこぼさないように、ロックを取ってから切り替える
+        let background: MutexGuard<Background> = lock(&self.background);
+        self.install(|version| {
+            let memtable: Arc<RwLock<Memtable>> = std::mem::replace(
+                &mut version.memtable,
+                Arc::new(RwLock::new(Memtable::new())),
+            );
+            version.immutable = Some(memtable);
+        });
+        drop(background);
+        self.background_changed.notify_all();
+        Ok(())
+    }
+
+    /// Flushes the immutable memtable, if any, and waits until it is done.
+    ///
+    /// If a background thread is already flushing it, this waits for that thread instead.
+    fn flush_immutable_memtable(&self) -> Result<(), KVSError> {
+        let mut background: MutexGuard<Background> = lock(&self.background);
+        loop {
+            if self.current().immutable.is_none() {
+                return Ok(());
+            }
+            if !background.flushing {
+                break;
+            }
+            background = wait(&self.background_changed, background);
+        }
+        background.flushing = true;
+        drop(background);
+
+        let result: Result<(), KVSError> = self.flush();
+        lock(&self.background).flushing = false;
+        self.background_changed.notify_all();
+        result
+    }
+
+    /// Writes the immutable memtable to an SSTable in level 0 and removes its WAL.
+    ///
+    /// The data directory is synced before the table is recorded in the manifest,
+    /// so that the manifest never names a file whose directory entry was lost.
+    /// Reads keep using the immutable memtable until the new table replaces it.
+    /// The caller must have set `Background::flushing`.
+    fn flush(&self) -> Result<(), KVSError> {
+        let immutable: Arc<RwLock<Memtable>> = match &self.current().immutable {
+            Some(immutable) => Arc::clone(immutable),
+            None => return Ok(()),
+        };
+
+        let mut sstable: Option<SSTable> = None;
+        let mut edit: VersionEdit = VersionEdit::new();
+        {
+            let memtable: RwLockReadGuard<Memtable> = read(&immutable);
+            if !memtable.is_empty() {
+                let number: u64 = self.last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+                let filename: String = sstable::filename_for(number);
+                let created: SSTable =
+                    SSTable::create(&self.data_dir, &memtable, &filename, &self.options)?;
+                edit.add(0, &created.filename()).set_last_seq(number);
+                sstable = Some(created);
+            }
+        }
+
+        // マニフェストに記録したテーブルのファイルが、電源断でディレクトリから消えないようにする
+        if sstable.is_some() {
+            manifest::sync_dir(&self.data_dir)?;
+        }
+
+        let mut background: MutexGuard<Background> = lock(&self.background);
+        background.manifest()?.apply(&edit)?;
+
+        // テーブルがマニフェストに記録されてから WAL を消す
+        let old_wal_path: PathBuf = self
+            .data_dir
+            .join(old_wal_filename(&self.options.wal_filename));
+        if let Err(e) = fs::remove_file(&old_wal_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(KVSError::FailedIO(IOError::FailedRemoveFile(
+                    old_wal_path,
+                    e.to_string(),
+                )));
+            }
+        }
+
+        self.install(|version| {
+            version.levels[0].extend(sstable.map(Arc::new));
+            version.immutable = None;
+        });
+        drop(background);
+        self.background_changed.notify_all();
+        Ok(())
+    }
+
+    /// Picks the next compaction and marks its tables as being compacted.
+    ///
+    /// Returns `None` if every level is within its target,
+    /// or if a table of the compaction is already being compacted.
+    fn start_compaction(&self, background: &mut Background) -> Option<(Compaction, Arc<Version>)> {
+        let current: Arc<Version> = self.current();
+        let compaction: Compaction =
+            compaction::pick(&current.levels, &background.compact_pointers, &self.options)?;
+        let names: Vec<String> = compaction_table_names(&compaction, &current);
+        if names
+            .iter()
+            .any(|name| background.compacting.contains(name))
+        {
+            return None;
+        }
+
+        // 次のコンパクションが同じレベルの次のテーブルを選べるように、開始時にポインタを進める
+        let inputs = compaction
+            .inputs
+            .iter()
+            .map(|i| current.levels[compaction.level][*i].as_ref());
+        if let Some((_, max_key)) = compaction::key_range(inputs) {
+            background.compact_pointers[compaction.level] = Some(max_key);
+        }
+        background.compacting.extend(names);
+        Some((compaction, current))
+    }
+
+    /// Unmarks the tables of a compaction started by `start_compaction`.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the tables of the compaction.
+    fn finish_compaction(&self, background: &mut Background, names: &[String]) {
+        background.compacting.retain(|name| !names.contains(name));
+    }
+
+    /// Merges the input tables of a compaction into the next level, then removes the inputs.
+    ///
+    /// The new tables replace the inputs in the manifest with a single edit,
+    /// after the data directory is synced so that their files survive a power failure.
+    /// The files of the inputs are deleted once no read uses them any more.
+    ///
+    /// # Arguments
+    ///
+    /// * `compaction` - The compaction, started by `start_compaction`.
+    /// * `version` - The version the compaction was picked from.
+    fn run_compaction(
+        &self,
+        compaction: &Compaction,
+        version: &Version,
+    ) -> Result<CompactionResult, KVSError> {
+        let level: usize = compaction.level;
+        let levels: &[Vec<Arc<SSTable>>] = &version.levels;
+
+        // 新しいテーブルの値を優先するため、新しい順に並べる
+        let mut sources: Vec<&SSTable> = compaction
+            .inputs
+            .iter()
+            .rev()
+            .map(|i| levels[level][*i].as_ref())
+            .collect();
+        sources.extend(
+            compaction
+                .overlaps
+                .iter()
+                .map(|i| levels[level + 1][*i].as_ref()),
+        );
+
+        // 下のレベルに同じキーの古い値が残っていなければ、削除済みのキーを書き出す必要はない
+        let bottommost: bool = match compaction::key_range(sources.iter().copied()) {
+            Some((min_key, max_key)) => levels[level + 2..]
+                .iter()
+                .all(|tables| compaction::overlapping(tables, &min_key, &max_key).is_empty()),
+            None => true,
+        };
+        let outputs: Vec<SSTable> = compaction::merge(
+            &sources,
+            &self.data_dir,
+            level + 1,
+            bottommost,
+            &self.snapshots.sequences(),
+            &self.options,
+            &self.last_seq,
+        )?;
+        let result: CompactionResult = CompactionResult::between(&sources, &outputs);
+
+        let mut edit: VersionEdit = VersionEdit::new();
+        for sstable in outputs.iter() {
+            edit.add(level as u32 + 1, &sstable.filename());
+        }
+        let removed: Vec<String> = sources.iter().map(|t| t.filename()).collect();
+        for filename in removed.iter() {
+            edit.remove(filename);
+        }
+        edit.set_last_seq(self.last_seq.load(Ordering::SeqCst));
+        if !outputs.is_empty() {
+            manifest::sync_dir(&self.data_dir)?;
+        }
+
+        // 他のスレッドが flush やコンパクションで変えたかもしれないので、今のバージョンを名前で書き換える
+        let mut background: MutexGuard<Background> = lock(&self.background);
+        background.manifest()?.apply(&edit)?;
+        self.install(|version| {
+            for tables in version.levels[level..=level + 1].iter_mut() {
+                tables.retain(|t| !removed.contains(&t.filename()));
+            }
+            let next_level: &mut Vec<Arc<SSTable>> = &mut version.levels[level + 1];
+            next_level.extend(outputs.into_iter().map(Arc::new));
+            next_level.sort_by(|a, b| a.meta().min_key.cmp(&b.meta().min_key));
+        });
+        drop(background);
+
+        for sstable in compaction_tables(compaction, version) {
+            sstable.mark_obsolete();
+        }
+        Ok(result)
+    }
+
+    /// Runs background jobs until the store is closed.
+    fn run_background_jobs(&self) {
+        while let Some(job) = self.next_job() {
+            let (result, compacted): (Result<(), KVSError>, Option<Vec<String>>) = match job {
+                Job::Flush => (self.flush(), None),
+                Job::Compaction(compaction, version) => {
+                    let result: Result<CompactionResult, KVSError> =
+                        self.run_compaction(&compaction, &version);
+                    // ここで version を手放して、削除されたテーブルのファイルを消す
+                    (
+                        result.map(|_| ()),
+                        Some(compaction_table_names(&compaction, &version)),
+                    )
+                }
+            };
+
+            let mut background: MutexGuard<Background> = lock(&self.background);
+            background.running -= 1;
+            match compacted {
+                Some(names) => self.finish_compaction(&mut background, &names),
+                None => background.flushing = false,
+            }
+            if let Err(e) = result {
+                background.error = Some(e.to_string());
+            }
+            drop(background);
+            self.background_changed.notify_all();
+        }
+    }
+
+    /// Waits for the next background job and claims it.
+    ///
+    /// A flush of the immutable memtable comes before compactions.
+    /// Returns `None` when the background threads must stop.
+    fn next_job(&self) -> Option<Job> {
+        let mut background: MutexGuard<Background> = lock(&self.background);
+        loop {
+            if background.shutdown {
+                return None;
+            }
+            if !background.paused && background.error.is_none() {
+                if !background.flushing && self.current().immutable.is_some() {
+                    background.flushing = true;
+                    background.running += 1;
+                    return Some(Job::Flush);
+                }
+                if let Some((compaction, version)) = self.start_compaction(&mut background) {
+                    background.running += 1;
+                    return Some(Job::Compaction(compaction, version));
+                }
+            }
+            background = wait(&self.background_changed, background);
+        }
+    }
+}
+
+/// Returns the input tables and the overlapping tables of a compaction.
+fn compaction_tables<'a>(
+    compaction: &'a Compaction,
+    version: &'a Version,
+) -> impl Iterator<Item = &'a Arc<SSTable>> {
+    let inputs = compaction
+        .inputs
+        .iter()
+        .map(move |i| &version.levels[compaction.level][*i]);
+    let overlaps = compaction
+        .overlaps
+        .iter()
+        .map(move |i| &version.levels[compaction.level + 1][*i]);
+    inputs.chain(overlaps)
+}
+
+/// Returns the names of the input tables and the overlapping tables of a compaction.
+fn compaction_table_names(compaction: &Compaction, version: &Version) -> Vec<String> {
+    compaction_tables(compaction, version)
+        .map(|t| t.filename())
+        .collect()
+}
+
+/// Returns the name of the WAL of the immutable memtable, which is kept until it is flushed.
+fn old_wal_filename(wal_filename: &str) -> String {
+    format!("{wal_filename}.old")
+}
+
+/// Returns `false` if the range would make `BTreeMap::range` panic.
+fn is_valid_range(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match range {
+        (Bound::Included(s), Bound::Included(e)) => s <= e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s < e,
+        _ => true,
+    }
+}
+
+/// Converts a range of keys into a range of owned binary keys.
+pub(crate) fn key_range<K: AsRef<[u8]>>(
+    range: &impl RangeBounds<K>,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start: Bound<Vec<u8>> = range.start_bound().map(|k| k.as_ref().to_vec());
+    let end: Bound<Vec<u8>> = range.end_bound().map(|k| k.as_ref().to_vec());
+    (start, end)
+}
+
+/// Returns the range of the keys that start with a prefix.
+///
+/// The end is the first key after every key with the prefix,
+/// or unbounded if the prefix is empty or consists of 0xFF bytes.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end: Vec<u8> = prefix.to_vec();
+    while end.last() == Some(&0xFF) {
+        end.pop();
+    }
+    let end: Bound<Vec<u8>> = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+/// Returns `true` if the key range of a table overlaps the range.
+fn overlaps_range(meta: &Metadata, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    if meta.entry_count == 0 {
+        return false;
+    }
+    let after_start: bool = match &range.0 {
+        Bound::Included(start) | Bound::Excluded(start) => *start <= meta.max_key,
+        Bound::Unbounded => true,
+    };
+    let before_end: bool = match &range.1 {
+        Bound::Included(end) => meta.min_key <= *end,
+        Bound::Excluded(end) => meta.min_key < *end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Locks a mutex, recovering the data if a thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Waits on a condition variable, recovering the data if a thread panicked while holding the lock.
+fn wait<'a, T>(condvar: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    match condvar.wait(guard) {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Locks a lock for reading, recovering the data if a thread panicked while holding it.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    match lock.read() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Locks a lock for writing, recovering the data if a thread panicked while holding it.
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    match lock.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Locks the data directory for this process.
+///
+/// A store that can write takes an exclusive lock and a read-only store takes a shared lock,
+/// so read-only stores can share the directory only with each other.
+/// The directory itself is locked, so that a read-only store creates no file for the lock.
+/// The lock is released when the returned file is closed.
+///
+/// # Arguments
+///
+/// * `data_dir` - The data directory.
+/// * `read_only` - Whether the store is opened read-only.
+fn lock_data_dir(data_dir: &Path, read_only: bool) -> Result<File, KVSError> {
+    let file: File = match File::open(data_dir) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(KVSError::FailedIO(IOError::FailedOpenFile(
+                data_dir.to_path_buf(),
+                e.to_string(),
+            )))
+        }
+    };
+
+    let locked: Result<(), TryLockError> = match read_only {
+        true => file.try_lock_shared(),
+        false => file.try_lock(),
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KVSError::StoreLocked(data_dir.to_path_buf())),
+        Err(TryLockError::Error(e)) => Err(KVSError::FailedIO(IOError::FailedLockFile(
+            data_dir.to_path_buf(),
+            e.to_string(),
+        ))),
+    }
+}
+
+/// Loads the live SSTables, grouped by level.
+///
+/// The live tables and their order are read from the manifest.
+/// A store without a manifest loads every data file at the level in its meta block instead,
+/// with level 0 ordered by sequence number and then by file name.
+///
+/// Returns the tables and the largest sequence number the manifest and the tables record.
+///
+/// # Arguments
+///
+/// * `data_dir` - The data directory.
+/// * `max_levels` - The number of levels.
+/// * `replay` - The replayed manifest, or `None` if there is no manifest.
+fn load_sstables(
+    data_dir: &Path,
+    max_levels: usize,
+    replay: Option<&Replay>,
+) -> Result<(Vec<Vec<SSTable>>, u64), KVSError> {
+    let mut levels: Vec<Vec<SSTable>> = (0..max_levels).map(|_| Vec::new()).collect();
+    let mut last_seq: u64 = 0;
+    match replay {
+        Some(replay) => {
+            last_seq = replay.last_seq;
+            for (level, filename) in replay.tables.iter() {
+                let sstable: SSTable = SSTable::from_file(data_dir.join(filename))?;
+                levels[(*level as usize).min(max_levels - 1)].push(sstable);
+            }
+        }
+        None => {
+            for file in get_data_files(data_dir)? {
+                let sstable: SSTable = SSTable::from_file(file)?;
+                let level: usize = (sstable.meta().level as usize).min(max_levels - 1);
+                levels[level].push(sstable);
+            }
+            levels[0].sort_by(|a, b| {
+                (a.meta().max_seq, &a.data_path).cmp(&(b.meta().max_seq, &b.data_path))
+            });
+        }
+    }
+    for tables in levels[1..].iter_mut() {
+        tables.sort_by(|a, b| a.meta().min_key.cmp(&b.meta().min_key));
+    }
+
+    for sstable in levels.iter().flatten() {
+        last_seq = last_seq.max(sstable.meta().max_seq);
+    }
+    Ok((levels, last_seq))
+}
+
+/// Removes the data files left by a crash, and writes a new manifest for the loaded SSTables.
+///
+/// Only two kinds of data files are removed: those of the tables the manifest removed,
+/// left by a crash during a compaction, and those numbered after every table the manifest knows,
+/// left by a crash during a flush or a compaction before its edit was recorded.
+/// Any other data file is kept, since the manifest cannot tell that it is not needed.
+/// The files are removed before the new manifest forgets which tables were removed.
+///
+/// # Arguments
+///
+/// * `data_dir` - The data directory.
+/// * `levels` - The live tables of each level.
+/// * `last_seq` - The largest sequence number used so far.
+/// * `removed` - The file names of the tables the manifest removed.
+fn write_manifest(
+    data_dir: &Path,
+    levels: &[Vec<SSTable>],
+    last_seq: u64,
+    removed: &[String],
+) -> Result<Manifest, KVSError> {
+    let mut snapshot: VersionEdit = VersionEdit::new();
+    for (level, tables) in levels.iter().enumerate() {
+        for sstable in tables {
+            snapshot.add(level as u32, &sstable.filename());
+        }
+    }
+    snapshot.set_last_seq(last_seq);
+
+    // マニフェストが知っている中で最も新しいテーブルの番号
+    let confirmed: Option<u64> = snapshot
+        .added
+        .iter()
+        .map(|(_, filename)| filename)
+        .chain(removed.iter())
+        .filter_map(|filename| table_number(Path::new(filename)))
+        .max();
+    for file in get_data_files(data_dir)? {
+        let filename: String = match file.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if snapshot.added.iter().any(|(_, live)| *live == filename) {
+            continue;
+        }
+        let unrecorded: bool = match table_number(&file) {
+            Some(number) => confirmed.is_none_or(|confirmed| confirmed < number),
+            None => false,
+        };
+        if !removed.contains(&filename) && !unrecorded {
+            continue;
+        }
+        if let Err(e) = fs::remove_file(&file) {
+            return Err(KVSError::FailedIO(IOError::FailedRemoveFile(
+                file,
+                e.to_string(),
+            )));
+        }
+    }
+
+    Ok(Manifest::create(data_dir, &snapshot)?)
+}
+
+/// Returns the number a data file was named after by `sstable::filename_for`,
+/// or `None` if the name is not a number.
+fn table_number(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+/// Gets a list of data files from the data directory.
+fn get_data_files(data_dir: &Path) -> Result<Vec<PathBuf>, IOError> {
+    let files: fs::ReadDir = match fs::read_dir(data_dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            return Err(IOError::FailedGetFilePath(
+                data_dir.to_path_buf(),
+                e.to_string(),
+            ))
+        }
+    };
+
+    let mut data_files: Vec<PathBuf> = Vec::new();
+
+    for result in files {
+        let data_file: PathBuf = match result {
+            Ok(dir_entry) => dir_entry.path(),
+            Err(e) => {
+                return Err(IOError::FailedGetFilePath(
+                    data_dir.to_path_buf(),
+                    e.to_string(),
+                ))
+            }
+        };
+
+        let mut extention: &str = "";
+        if let Some(ext_os_str) = data_file.extension() {
+            if let Some(ext_str) = ext_os_str.to_str() {
+                extention = ext_str
+            }
+        };
+
+        if extention == "dat" {
+            data_files.push(data_file)
+        };
+    }
+    Ok(data_files)
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::*;
+
+    #[test]
+    fn test_open_create_if_missing() {
+        let data_dir: PathBuf = test_util::temp_dir("open_create").join("data");
+
+        // データディレクトリが存在しないケース
+        let result = KVSOptions::new().data_dir(&data_dir).open();
+        assert!(matches!(
+            result,
+            Err(KVSError::FailedIO(IOError::DirectoryNotFound(_)))
+        ));
+
+        // create_if_missing を指定したケース
+        KVSOptions::new()
+            .data_dir(&data_dir)
+            .create_if_missing(true)
+            .open()
+            .unwrap();
+        assert!(data_dir.is_dir());
+    }
+
+    #[test]
+    fn test_open_error_if_exists() {
+        let data_dir: PathBuf = test_util::temp_dir("open_exists");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir).error_if_exists(true);
+
+        // 初回はストアが存在しないので成功する
+        drop(options.open().unwrap());
+
+        // 2回目はストアが存在するので失敗する
+        assert!(matches!(
+            options.open(),
+            Err(KVSError::FailedIO(IOError::StoreAlreadyExists(_)))
+        ));
+    }
+
+    #[test]
+    fn test_put_get_delete() {
+        let data_dir: PathBuf = test_util::temp_dir("put_get_delete");
+        let kvs: KVS = KVSOptions::new().data_dir(&data_dir).open().unwrap();
+
+        kvs.put("k1", "v1").unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v1", false)));
+
+        kvs.delete("k1").unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), None);
+        assert_eq!(kvs.get("k2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_memtable_limits() {
+        // エントリ数で flush されるケース
+        let data_dir: PathBuf = test_util::temp_dir("limit_entries");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(2)
+            .background_threads(0)
+            .open()
+            .unwrap();
+        kvs.put("k1", "v1").unwrap();
+        kvs.put("k2", "v2").unwrap();
+        assert!(kvs.inner.current().levels[0].is_empty());
+        kvs.put("k3", "v3").unwrap();
+        assert_eq!(kvs.inner.current().levels[0].len(), 1);
+        assert!(read(&kvs.inner.current().memtable).is_empty());
+
+        // バイト数で flush されるケース
+        let data_dir: PathBuf = test_util::temp_dir("limit_bytes");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_bytes(30)
+            .background_threads(0)
+            .open()
+            .unwrap();
+        kvs.put("k1", "v1").unwrap();
+        assert_eq!(read(&kvs.inner.current().memtable).size(), 13);
+        kvs.put("k1", "value1").unwrap();
+        assert_eq!(read(&kvs.inner.current().memtable).size(), 17);
+        assert!(kvs.inner.current().levels[0].is_empty());
+        kvs.put("k2", "value2").unwrap();
+        assert_eq!(kvs.inner.current().levels[0].len(), 1);
+        assert_eq!(read(&kvs.inner.current().memtable).size(), 0);
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("value1", false)));
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let data_dir: PathBuf = test_util::temp_dir("write_batch");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.put("k2", "v2").delete("k1").put("k3", "v3");
+        kvs.write(batch).unwrap();
+
+        assert_eq!(kvs.get("k1").unwrap(), None);
+        assert_eq!(kvs.get("k2").unwrap(), Some(Value::new("v2", false)));
+        assert_eq!(kvs.get("k3").unwrap(), Some(Value::new("v3", false)));
+
+        // 再オープン後もバッチ全体が復旧される
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), None);
+        assert_eq!(kvs.get("k2").unwrap(), Some(Value::new("v2", false)));
+        assert_eq!(kvs.get("k3").unwrap(), Some(Value::new("v3", false)));
+    }
+
+    #[test]
+    fn test_open_torn_wal() {
+        let data_dir: PathBuf = test_util::temp_dir("torn_wal");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+        kvs.put("k2", "v2").unwrap();
+        drop(kvs);
+
+        // 最後のレコードが途中までしか書き込まれていないケース
+        let wal_path: PathBuf = data_dir.join("wal");
+        let bytes: Vec<u8> = fs::read(&wal_path).unwrap();
+        fs::write(&wal_path, &bytes[..bytes.len() - 3]).unwrap();
+
+        let kvs: KVS = options.open().unwrap();
+        assert!(kvs.wal_dropped_bytes() > 0);
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v1", false)));
+        assert_eq!(kvs.get("k2").unwrap(), None);
+
+        // 切り詰めた後の WAL に続けて書き込める
+        kvs.put("k3", "v3").unwrap();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.wal_dropped_bytes(), 0);
+        assert_eq!(kvs.get("k3").unwrap(), Some(Value::new("v3", false)));
+    }
+
+    #[test]
+    fn test_scan() {
+        let data_dir: PathBuf = test_util::temp_dir("scan");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(3)
+            .background_threads(0)
+            .open()
+            .unwrap();
+
+        // 1つ目の SSTable
+        for k in ["a", "b", "c", "d"] {
+            kvs.put(k, "old").unwrap();
+        }
+        // 2つ目の SSTable
+        kvs.put("b", "new").unwrap();
+        kvs.delete("c").unwrap();
+        kvs.put("e", "new").unwrap();
+        kvs.put("f", "new").unwrap();
+        // memtable
+        kvs.delete("a").unwrap();
+        kvs.put("g", "new").unwrap();
+        assert_eq!(kvs.inner.current().levels[0].len(), 2);
+
+        let entries: Vec<(String, String)> = kvs
+            .scan_all()
+            .unwrap()
+            .map(|e| {
+                e.map(|(k, v)| (String::from_utf8(k).unwrap(), v.to_string()))
+                    .unwrap()
+            })
+            .collect();
+        let expected: Vec<(String, String)> = [
+            ("b", "new"),
+            ("d", "old"),
+            ("e", "new"),
+            ("f", "new"),
+            ("g", "new"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(entries, expected);
+
+        let keys: Vec<String> = kvs
+            .scan("b".."f")
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["b", "d", "e"]);
+
+        let keys: Vec<String> = kvs
+            .scan("c"..="f")
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["d", "e", "f"]);
+
+        // 所有したキーやバイト列のキーで範囲を指定するケース
+        let start: String = String::from("e");
+        assert_eq!(kvs.scan(start..).unwrap().count(), 3);
+        assert_eq!(kvs.scan(..b"d".to_vec()).unwrap().count(), 1);
+
+        // 空の範囲
+        assert_eq!(kvs.scan("f".."b").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let data_dir: PathBuf = test_util::temp_dir("prefix");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(2)
+            .open()
+            .unwrap();
+
+        for k in [
+            "tenant1/a",
+            "tenant2/a",
+            "tenant1/b",
+            "tenant1/c",
+            "tenant10/a",
+        ] {
+            kvs.put(k, "v").unwrap();
+        }
+        kvs.delete("tenant1/b").unwrap();
+
+        let keys: Vec<String> = kvs
+            .prefix("tenant1/")
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["tenant1/a", "tenant1/c"]);
+
+        // 範囲の終わりは接頭辞の次のキーで、0xFF は繰り上げる
+        assert_eq!(
+            prefix_range(b"tenant1/").1,
+            Bound::Excluded(b"tenant10".to_vec())
+        );
+        assert_eq!(prefix_range(b"a\xFF\xFF").1, Bound::Excluded(b"b".to_vec()));
+        assert_eq!(prefix_range(b"\xFF").1, Bound::Unbounded);
+        for k in [&b"a\xFF"[..], b"a\xFF\xFF", b"a\xFF\xFFz", b"b"] {
+            kvs.put_bytes(k, b"v").unwrap();
+        }
+        let keys: Vec<Vec<u8>> = kvs
+            .prefix_bytes(b"a\xFF\xFF")
+            .unwrap()
+            .map(|e| e.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"a\xFF\xFF".to_vec(), b"a\xFF\xFFz".to_vec()]);
+    }
+
+    #[test]
+    fn test_bloom_filter_stats() {
+        let data_dir: PathBuf = test_util::temp_dir("bloom_stats");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(10)
+            .background_threads(0)
+            .open()
+            .unwrap();
+        for i in 0..=10 {
+            kvs.put(&format!("key{i:02}"), "v").unwrap();
+        }
+        assert_eq!(kvs.inner.current().levels[0].len(), 1);
+
+        assert_eq!(kvs.get("key05").unwrap(), Some(Value::new("v", false)));
+        for i in 0..100 {
+            assert_eq!(kvs.get(&format!("missing{i}")).unwrap(), None);
+        }
+
+        let stats: KVSStats = kvs.stats();
+        assert_eq!(stats.bloom_filter_hits + stats.bloom_filter_misses, 101);
+        assert_eq!(
+            stats.bloom_filter_hits - stats.bloom_filter_false_positives,
+            1
+        );
+        assert!(stats.bloom_filter_misses > 90);
+
+        // フィルタを無効にしたケース
+        let data_dir: PathBuf = test_util::temp_dir("bloom_disabled");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(1)
+            .bloom_bits_per_key(0)
+            .open()
+            .unwrap();
+        kvs.put("k1", "v1").unwrap();
+        kvs.put("k2", "v2").unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v1", false)));
+        assert_eq!(kvs.get("k3").unwrap(), None);
+        let stats: KVSStats = kvs.stats();
+        assert_eq!(
+            stats,
+            KVSStats {
+                uncompressed_bytes: stats.uncompressed_bytes,
+                compressed_bytes: stats.compressed_bytes,
+                ..KVSStats::default()
+            }
+        );
+        assert_eq!(stats.compressed_bytes, stats.uncompressed_bytes);
+    }
+
+    #[test]
+    fn test_wal_filename() {
+        let data_dir: PathBuf = test_util::temp_dir("wal_filename");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir).wal_filename("log");
+
+        let kvs: KVS = options.open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+        drop(kvs);
+        assert!(data_dir.join("log").is_file());
+
+        // 再オープン時に WAL から復旧する
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v1", false)));
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let data_dir: PathBuf = test_util::temp_dir("leveled_compaction");
+        let options: KVSOptions = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(9)
+            .level0_compaction_trigger(2)
+            .level1_max_bytes(4096)
+            .block_size(256)
+            .target_file_size(1024);
+        let kvs: KVS = options.open().unwrap();
+
+        for round in 0..20 {
+            for i in 0..10 {
+                kvs.put(
+                    &format!("key{:03}", (i * 37 + round * 11) % 200),
+                    &format!("v{round}"),
+                )
+                .unwrap();
+            }
+        }
+
+        wait_for_background_work(&kvs);
+
+        // レベル0 は閾値未満に保たれ、下のレベルにテーブルがある
+        assert!(kvs.inner.current().levels[0].len() < 2);
+        assert!(kvs.inner.current().levels[1..]
+            .iter()
+            .any(|tables| !tables.is_empty()));
+        // レベル1 以降はキーが重ならない
+        for tables in kvs.inner.current().levels[1..].iter() {
+            for pair in tables.windows(2) {
+                assert!(pair[0].meta().max_key < pair[1].meta().min_key);
+            }
+        }
+        // 削除されたテーブルのファイルは残らない
+        let tables: usize = kvs
+            .inner
+            .current()
+            .levels
+            .iter()
+            .map(|tables| tables.len())
+            .sum();
+        assert_eq!(get_data_files(&data_dir).unwrap().len(), tables);
+
+        let mut expected: BTreeMap<String, String> = BTreeMap::new();
+        for round in 0..20 {
+            for i in 0..10 {
+                expected.insert(
+                    format!("key{:03}", (i * 37 + round * 11) % 200),
+                    format!("v{round}"),
+                );
+            }
+        }
+        for (k, v) in expected.iter() {
+            assert_eq!(kvs.get(k).unwrap(), Some(Value::new(v, false)));
+        }
+        let entries: Vec<(String, String)> = kvs
+            .scan_all()
+            .unwrap()
+            .map(|e| {
+                e.map(|(k, v)| (String::from_utf8(k).unwrap(), v.to_string()))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(entries, expected.clone().into_iter().collect::<Vec<_>>());
+
+        // 再オープン後もレベルが復元される
+        let levels: Vec<usize> = kvs
+            .inner
+            .current()
+            .levels
+            .iter()
+            .map(|tables| tables.len())
+            .collect();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(
+            kvs.inner
+                .current()
+                .levels
+                .iter()
+                .map(|tables| tables.len())
+                .collect::<Vec<_>>(),
+            levels
+        );
+        for (k, v) in expected.iter() {
+            assert_eq!(kvs.get(k).unwrap(), Some(Value::new(v, false)));
+        }
+    }
+
+    #[test]
+    fn test_compaction_drops_tombstones() {
+        let data_dir: PathBuf = test_util::temp_dir("compaction_tombstones");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(100)
+            .level0_compaction_trigger(2)
+            .max_levels(2)
+            .open()
+            .unwrap();
+
+        for i in 0..10 {
+            kvs.put(&format!("key{i}"), "v").unwrap();
+        }
+        kvs.flush().unwrap();
+        for i in 0..5 {
+            kvs.delete(&format!("key{i}")).unwrap();
+        }
+        kvs.flush().unwrap();
+
+        // 最下層に書き出したので、削除済みのキーと隠された値は残らない
+        assert!(kvs.inner.current().levels[0].is_empty());
+        assert_eq!(kvs.inner.current().levels[1].len(), 1);
+        assert_eq!(kvs.inner.current().levels[1][0].meta().entry_count, 5);
+        assert_eq!(kvs.get("key0").unwrap(), None);
+        assert_eq!(kvs.get("key5").unwrap(), Some(Value::new("v", false)));
+
+        // 全て削除した後は、その後に書き込んだキーだけが残る
+        for i in 5..10 {
+            kvs.delete(&format!("key{i}")).unwrap();
+        }
+        kvs.flush().unwrap();
+        kvs.put("key0", "v").unwrap();
+        kvs.flush().unwrap();
+        assert!(kvs.inner.current().levels[0].is_empty());
+        assert_eq!(kvs.inner.current().levels[1][0].meta().entry_count, 1);
+
+        // 目標以内の場合は何もしない
+        assert_eq!(kvs.compaction().unwrap(), CompactionResult::default());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let data_dir: PathBuf = test_util::temp_dir("manifest_recovery");
+        let options: KVSOptions = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(100)
+            .level0_compaction_trigger(2);
+        let kvs: KVS = options.open().unwrap();
+
+        kvs.put("k1", "old").unwrap();
+        kvs.flush().unwrap();
+        let stale_path: PathBuf = kvs.inner.current().levels[0][0].data_path.clone();
+        let stale_bytes: Vec<u8> = fs::read(&stale_path).unwrap();
+        kvs.put("k1", "new").unwrap();
+        kvs.flush().unwrap();
+        assert!(kvs.inner.current().levels[0].is_empty());
+        assert!(!stale_path.exists());
+        drop(kvs);
+
+        // コンパクションでファイルを消す前にクラッシュしたケース
+        fs::write(&stale_path, &stale_bytes).unwrap();
+        // flush でマニフェストに記録する前にクラッシュしたケース
+        let mut builder =
+            sstable::SSTableBuilder::new(&data_dir, &sstable::filename_for(999_999), 0, &options)
+                .unwrap();
+        builder
+            .add(b"k1", 100, &Value::new("orphan", false))
+            .unwrap();
+        let orphan: SSTable = builder.finish().unwrap();
+
+        // マニフェストの記録より古い番号で、削除の記録もないテーブル
+        let unknown_path: PathBuf = data_dir.join(format!("{}.dat", sstable::filename_for(0)));
+        fs::copy(&orphan.data_path, &unknown_path).unwrap();
+
+        // マニフェストの途中が壊れているときは開けず、ファイルも消さない
+        let manifest_path: PathBuf = data_dir.join("MANIFEST");
+        let manifest_bytes: Vec<u8> = fs::read(&manifest_path).unwrap();
+        let mut corrupt: Vec<u8> = manifest_bytes.clone();
+        corrupt[log::HEADER_SIZE] ^= 0xFF;
+        fs::write(&manifest_path, &corrupt).unwrap();
+        assert!(matches!(
+            options.open(),
+            Err(KVSError::FailedConvert(ConvertError::InvalidManifest(_)))
+        ));
+        assert_eq!(get_data_files(&data_dir).unwrap().len(), 4);
+        fs::write(&manifest_path, &manifest_bytes).unwrap();
+
+        // マニフェストで削除したテーブルと、記録される前のテーブルは読み込まれず、削除される
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("new", false)));
+        assert!(!stale_path.exists());
+        assert!(!orphan.data_path.exists());
+        // 不要かどうか分からないテーブルは読み込まないが、残しておく
+        assert!(unknown_path.exists());
+        assert_eq!(get_data_files(&data_dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_manifest_bootstrap() {
+        let data_dir: PathBuf = test_util::temp_dir("manifest_bootstrap");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+        kvs.flush().unwrap();
+        kvs.put("k1", "v2").unwrap();
+        kvs.flush().unwrap();
+        drop(kvs);
+
+        // マニフェストのない古いストアはデータファイルから読み込む
+        fs::remove_file(data_dir.join("MANIFEST")).unwrap();
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.inner.current().levels[0].len(), 2);
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v2", false)));
+        assert!(data_dir.join("MANIFEST").is_file());
+    }
+
+    #[test]
+    fn test_sequence() {
+        let data_dir: PathBuf = test_util::temp_dir("sequence");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+
+        let mut batch: WriteBatch = WriteBatch::new();
+        batch.put("k1", "v1").put("k2", "v2");
+        kvs.write(batch).unwrap();
+        assert_eq!(kvs.inner.last_seq.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            read(&kvs.inner.current().memtable)
+                .get(b"k2", u64::MAX)
+                .unwrap()
+                .0,
+            2
+        );
+
+        // ファイル名もシーケンスから取る
+        kvs.flush().unwrap();
+        assert_eq!(kvs.inner.current().levels[0][0].filename(), "000003.dat");
+        assert_eq!(kvs.inner.current().levels[0][0].meta().max_seq, 2);
+
+        // 再オープン後も WAL からシーケンス番号が続く
+        kvs.put("k1", "v3").unwrap();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.inner.last_seq.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            read(&kvs.inner.current().memtable)
+                .get(b"k1", u64::MAX)
+                .unwrap()
+                .0,
+            4
+        );
+
+        // WAL が空でもマニフェストからシーケンス番号が続く
+        kvs.flush().unwrap();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.inner.last_seq.load(Ordering::SeqCst), 5);
+        kvs.put("k3", "v3").unwrap();
+        assert_eq!(
+            read(&kvs.inner.current().memtable)
+                .get(b"k3", u64::MAX)
+                .unwrap()
+                .0,
+            6
+        );
+
+        // 新しいシーケンス番号の値が勝つ
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v3", false)));
+        let values: Vec<String> = kvs
+            .scan("k1"..="k2")
+            .unwrap()
+            .map(|e| e.unwrap().1.to_string())
+            .collect();
+        assert_eq!(values, vec!["v3", "v2"]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let data_dir: PathBuf = test_util::temp_dir("snapshot");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(100)
+            .level0_compaction_trigger(2)
+            .max_levels(2)
+            .open()
+            .unwrap();
+        kvs.put("k1", "v1").unwrap();
+        kvs.put("k2", "v2").unwrap();
+
+        // スナップショットを取った後の書き込みは見えない
+        let snapshot: Snapshot = kvs.snapshot();
+        kvs.put("k1", "new").unwrap();
+        kvs.delete("k2").unwrap();
+        kvs.put("k3", "v3").unwrap();
+        assert_eq!(read(&kvs.inner.current().memtable).len(), 5);
+        assert_eq!(snapshot.get("k1").unwrap(), Some(Value::new("v1", false)));
+        assert_eq!(snapshot.get("k2").unwrap(), Some(Value::new("v2", false)));
+        assert_eq!(snapshot.get("k3").unwrap(), None);
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("new", false)));
+        assert_eq!(kvs.get("k2").unwrap(), None);
+
+        // flush とコンパクションの後も古いバージョンが読める
+        kvs.flush().unwrap();
+        kvs.put("k1", "newer").unwrap();
+        kvs.flush().unwrap();
+        assert!(kvs.inner.current().levels[0].is_empty());
+        assert_eq!(snapshot.get("k1").unwrap(), Some(Value::new("v1", false)));
+        assert_eq!(snapshot.get("k2").unwrap(), Some(Value::new("v2", false)));
+        let entries: Vec<(String, String)> = snapshot
+            .scan_all()
+            .unwrap()
+            .map(|e| {
+                e.map(|(k, v)| (String::from_utf8(k).unwrap(), v.to_string()))
+                    .unwrap()
+            })
+            .collect();
+        let expected: Vec<(String, String)> = vec![
+            ("k1".to_string(), "v1".to_string()),
+            ("k2".to_string(), "v2".to_string()),
+        ];
+        assert_eq!(entries, expected);
+        assert_eq!(snapshot.prefix("k2").unwrap().count(), 1);
+        let keys: Vec<String> = kvs
+            .scan_all()
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["k1", "k3"]);
+
+        // スナップショットを解放すると、次のコンパクションで古いバージョンが消える
+        drop(snapshot);
+        kvs.put("k1", "newest").unwrap();
+        kvs.flush().unwrap();
+        kvs.put("k3", "newest").unwrap();
+        kvs.flush().unwrap();
+        assert_eq!(kvs.inner.current().levels[1].len(), 1);
+        assert_eq!(kvs.inner.current().levels[1][0].meta().entry_count, 2);
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("newest", false)));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let data_dir: PathBuf = test_util::temp_dir("ttl");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+        kvs.put_with_ttl("k1", "v1", Duration::from_secs(3600))
+            .unwrap();
+        kvs.put_with_ttl("k2", "v2", Duration::ZERO).unwrap();
+        kvs.put("k3", "v3").unwrap();
+
+        // 期限切れの値は get にも scan にも出てこない
+        let value: Value = kvs.get("k1").unwrap().unwrap();
+        assert!(Duration::from_secs(3599) < value.ttl().unwrap());
+        assert_eq!(kvs.get("k2").unwrap(), None);
+        assert_eq!(kvs.get("k3").unwrap().unwrap().ttl(), None);
+        let keys: Vec<String> = kvs
+            .scan_all()
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["k1", "k3"]);
+
+        // 有効期限は WAL と SSTable に残る
+        let expires_at: Option<u64> = value.expires_at();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get("k1").unwrap().unwrap().expires_at(), expires_at);
+        assert_eq!(kvs.get("k2").unwrap(), None);
+        kvs.flush().unwrap();
+        assert_eq!(kvs.get("k1").unwrap().unwrap().expires_at(), expires_at);
+        assert_eq!(kvs.get("k2").unwrap(), None);
+        assert_eq!(kvs.prefix("k").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let data_dir: PathBuf = test_util::temp_dir("conditional_writes");
+        let kvs: KVS = KVSOptions::new().data_dir(&data_dir).open().unwrap();
+
+        // 存在しないキーにだけ書き込むケース
+        assert!(kvs.put_if_absent("k1", "v1").unwrap());
+        assert!(!kvs.put_if_absent("k1", "other").unwrap());
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v1", false)));
+
+        // 期待した値のときだけ書き換えるケース
+        assert!(!kvs.compare_and_swap("k1", Some("wrong"), "v2").unwrap());
+        assert!(!kvs.compare_and_swap("k1", None, "v2").unwrap());
+        assert!(kvs.compare_and_swap("k1", Some("v1"), "v2").unwrap());
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v2", false)));
+
+        // 期待した値のときだけ削除するケース
+        assert!(!kvs.delete_if_equals("k1", "v1").unwrap());
+        assert!(kvs.delete_if_equals("k1", "v2").unwrap());
+        assert_eq!(kvs.get("k1").unwrap(), None);
+        assert!(!kvs.delete_if_equals("k1", "v2").unwrap());
+
+        // 削除済み・期限切れのキーは存在しないものとして扱う
+        assert!(kvs.compare_and_swap("k1", None, "v3").unwrap());
+        kvs.put_with_ttl("k2", "v", Duration::ZERO).unwrap();
+        assert!(kvs.put_if_absent("k2", "v").unwrap());
+    }
+
+    #[test]
+    fn test_binary() {
+        let data_dir: PathBuf = test_util::temp_dir("binary");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        let kvs: KVS = options.open().unwrap();
+
+        // UTF-8 ではないキーと値
+        let k1: &[u8] = &[0x00, 0xFF, 0x80];
+        let k2: &[u8] = &[0x00, 0xFF, 0x81];
+        let v1: &[u8] = &[0xC3, 0x28, 0x00, 0x0A];
+        let v2: &[u8] = &[0xFE, 0xFF];
+        kvs.put_bytes(k1, v1).unwrap();
+        kvs.put_bytes(k2, v2).unwrap();
+        assert_eq!(kvs.get_bytes(k1).unwrap().unwrap().as_bytes(), v1);
+        assert!(kvs.get_bytes(k1).unwrap().unwrap().as_str().is_none());
+
+        // WAL から復元するケース
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get_bytes(k1).unwrap().unwrap().as_bytes(), v1);
+
+        // SSTable から読むケース
+        kvs.flush().unwrap();
+        drop(kvs);
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get_bytes(k2).unwrap().unwrap().as_bytes(), v2);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = kvs
+            .prefix_bytes(&[0x00, 0xFF])
+            .unwrap()
+            .map(|e| e.map(|(k, v)| (k, v.into_bytes())).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(k1.to_vec(), v1.to_vec()), (k2.to_vec(), v2.to_vec())]
+        );
+        let keys: usize = kvs.scan_bytes(k2..).unwrap().count();
+        assert_eq!(keys, 1);
+
+        kvs.delete_bytes(k1).unwrap();
+        assert_eq!(kvs.get_bytes(k1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        fn assert_shared<T: Clone + Send + Sync + 'static>() {}
+        assert_shared::<KVS>();
+
+        let data_dir: PathBuf = test_util::temp_dir("concurrent");
+        let kvs: KVS = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(16)
+            .level0_compaction_trigger(2)
+            .durability(Durability::Never)
+            .open()
+            .unwrap();
+
+        // 書き込みとフラッシュ・コンパクションの最中に、他のスレッドから読む
+        let writers: Vec<std::thread::JoinHandle<()>> = (0..4)
+            .map(|t| {
+                let kvs: KVS = kvs.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        kvs.put(&format!("t{t}_{i:03}"), &format!("v{i}")).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<std::thread::JoinHandle<()>> = (0..4)
+            .map(|t| {
+                let kvs: KVS = kvs.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        // 一度読めたキーは、その後も読めなければならない
+                        let key: String = format!("t{t}_{i:03}");
+                        while kvs.get(&key).unwrap().is_none() {
+                            std::thread::yield_now();
+                        }
+                        let count: usize = kvs.prefix(&format!("t{t}_")).unwrap().count();
+                        assert!(i < count);
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(kvs.scan_all().unwrap().count(), 400);
+
+        // 作成済みのイテレータは、その後の書き込みやフラッシュの影響を受けない
+        let iter: KVSIterator = kvs.prefix("t0_").unwrap();
+        kvs.delete("t0_000").unwrap();
+        kvs.flush().unwrap();
+        kvs.compaction().unwrap();
+        assert_eq!(iter.count(), 100);
+        assert_eq!(kvs.prefix("t0_").unwrap().count(), 99);
+    }
+
+    #[test]
+    fn test_background_work() {
+        let data_dir: PathBuf = test_util::temp_dir("background_work");
+        let options: KVSOptions = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(2);
+        let old_wal_path: PathBuf = data_dir.join(old_wal_filename(&options.wal_filename));
+        let kvs: KVS = options.open().unwrap();
+
+        // バックグラウンドスレッドが immutable memtable を flush するケース
+        for k in ["k1", "k2", "k3"] {
+            kvs.put(k, "v").unwrap();
+        }
+        assert!(read(&kvs.inner.current().memtable).is_empty());
+        wait_for_background_work(&kvs);
+        assert_eq!(kvs.inner.current().levels[0].len(), 1);
+        assert!(!old_wal_path.exists());
+
+        // 一時停止中は flush されず、immutable memtable から読めるケース
+        kvs.pause_background_work();
+        for k in ["k4", "k5", "k6"] {
+            kvs.put(k, "v").unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(kvs.inner.current().immutable.is_some());
+        assert!(old_wal_path.is_file());
+        assert_eq!(kvs.get("k4").unwrap(), Some(Value::new("v", false)));
+        assert_eq!(kvs.scan_all().unwrap().count(), 6);
+
+        // 再開すると flush されるケース
+        kvs.resume_background_work();
+        wait_for_background_work(&kvs);
+        assert_eq!(kvs.inner.current().levels[0].len(), 2);
+        assert_eq!(kvs.background_error(), None);
+
+        // flush されずに閉じた immutable memtable を、再オープン時に WAL から復元するケース
+        kvs.pause_background_work();
+        for k in ["k7", "k8", "k9"] {
+            kvs.put(k, "v").unwrap();
+        }
+        drop(kvs);
+        assert!(old_wal_path.is_file());
+        let kvs: KVS = options.clone().background_threads(0).open().unwrap();
+        assert!(kvs.inner.current().immutable.is_none());
+        assert!(!old_wal_path.exists());
+        for k in ["k1", "k4", "k7", "k9"] {
+            assert_eq!(kvs.get(k).unwrap(), Some(Value::new("v", false)));
+        }
+        assert_eq!(kvs.scan_all().unwrap().count(), 9);
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let data_dir: PathBuf = test_util::temp_dir("write_stall");
+        let options: KVSOptions = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(2);
+        let kvs: KVS = options.open().unwrap();
+
+        // 一時停止中に memtable が 2 つとも埋まると、書き込みは失敗するケース
+        kvs.pause_background_work();
+        for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+            kvs.put(k, "v").unwrap();
+        }
+        assert!(kvs.inner.current().immutable.is_some());
+        assert!(matches!(kvs.put("k7", "v"), Err(KVSError::WriteStalled(_))));
+        assert_eq!(kvs.get("k7").unwrap(), None);
+        assert_eq!(read(&kvs.inner.current().memtable).len(), 3);
+
+        // 再開すると、flush が終わるのを待ってから書き込むケース
+        kvs.resume_background_work();
+        kvs.put("k7", "v").unwrap();
+        assert_eq!(kvs.inner.current().levels[0].len(), 1);
+        assert_eq!(kvs.get("k7").unwrap(), Some(Value::new("v", false)));
+
+        // flush を呼ぶと、一時停止中でも書き込めるようになるケース
+        wait_for_background_work(&kvs);
+        kvs.pause_background_work();
+        for k in ["k08", "k09", "k10", "k11", "k12", "k13"] {
+            kvs.put(k, "v").unwrap();
+        }
+        assert!(kvs.put("k14", "v").is_err());
+        kvs.flush().unwrap();
+        kvs.put("k14", "v").unwrap();
+        assert_eq!(kvs.scan_all().unwrap().count(), 14);
+    }
+
+    #[test]
+    fn test_lock_and_read_only() {
+        let data_dir: PathBuf = test_util::temp_dir("lock");
+        let options: KVSOptions = KVSOptions::new()
+            .data_dir(&data_dir)
+            .memtable_max_entries(2)
+            .background_threads(0);
+        let kvs: KVS = options.open().unwrap();
+        for k in ["k1", "k2", "k3", "k4"] {
+            kvs.put(k, "v").unwrap();
+        }
+
+        // 書き込み可能なストアが開いている間は、読み取り専用でも開けないケース
+        assert!(matches!(options.open(), Err(KVSError::StoreLocked(_))));
+        assert!(matches!(
+            options.clone().read_only(true).open(),
+            Err(KVSError::StoreLocked(_))
+        ));
+        // 複製したハンドルは同じストアを使うので、ロックに関係なく書き込める
+        kvs.clone().put("k5", "v").unwrap();
+        drop(kvs);
+
+        // 読み取り専用のストアは互いに共有できるケース
+        let files: Vec<PathBuf> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        let reader1: KVS = options.clone().read_only(true).open().unwrap();
+        let reader2: KVS = options.clone().read_only(true).open().unwrap();
+        assert!(matches!(options.open(), Err(KVSError::StoreLocked(_))));
+        for k in ["k1", "k3", "k5"] {
+            assert_eq!(reader1.get(k).unwrap(), Some(Value::new("v", false)));
+        }
+        assert_eq!(reader2.scan_all().unwrap().count(), 5);
+
+        // 読み取り専用のストアには書き込めず、データディレクトリも変わらないケース
+        assert!(matches!(reader1.put("k6", "v"), Err(KVSError::ReadOnly)));
+        assert!(matches!(reader1.delete("k1"), Err(KVSError::ReadOnly)));
+        assert!(matches!(reader1.flush(), Err(KVSError::ReadOnly)));
+        assert!(matches!(reader1.compaction(), Err(KVSError::ReadOnly)));
+        assert!(matches!(
+            reader1.commit(reader1.transaction()),
+            Err(KVSError::ReadOnly)
+        ));
+        drop(reader1);
+        drop(reader2);
+        let mut after: Vec<PathBuf> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        let mut before: Vec<PathBuf> = files;
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+
+        // 全て閉じた後は書き込み可能で開けるケース
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(kvs.get("k5").unwrap(), Some(Value::new("v", false)));
+    }
+
+    #[test]
+    fn test_compression() {
+        let data_dir: PathBuf = test_util::temp_dir("compression");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir).background_threads(0);
+        let value = |i: usize| format!(r#"{{"id":{i},"name":"user{i}","tags":["a","b","c"]}}"#);
+
+        // 圧縮なしで書いたテーブル
+        let kvs: KVS = options.open().unwrap();
+        for i in 0..100 {
+            kvs.put(&format!("user{i:03}"), &value(i)).unwrap();
+        }
+        kvs.flush().unwrap();
+        let stats: KVSStats = kvs.stats();
+        assert!(stats.uncompressed_bytes > 0);
+        assert_eq!(stats.compression_ratio(), 1.0);
+        drop(kvs);
+
+        // 圧縮ありで開き直し、古いテーブルと新しいテーブルが混在するケース
+        let kvs: KVS = options
+            .clone()
+            .compression(Compression::LzHuffman)
+            .open()
+            .unwrap();
+        for i in 100..200 {
+            kvs.put(&format!("user{i:03}"), &value(i)).unwrap();
+        }
+        kvs.flush().unwrap();
+        let compressions: Vec<Compression> = kvs.inner.current().levels[0]
+            .iter()
+            .map(|t| t.meta().compression)
+            .collect();
+        assert_eq!(
+            compressions,
+            vec![Compression::None, Compression::LzHuffman]
+        );
+        let mixed: f64 = kvs.stats().compression_ratio();
+        assert!(mixed > 1.0);
+        for i in [0, 99, 100, 199] {
+            assert_eq!(
+                kvs.get(&format!("user{i:03}")).unwrap(),
+                Some(Value::new(value(i), false))
+            );
+        }
+
+        drop(kvs);
+
+        // compaction で古いテーブルも圧縮されるケース
+        let kvs: KVS = options
+            .clone()
+            .compression(Compression::LzHuffman)
+            .level0_compaction_trigger(2)
+            .open()
+            .unwrap();
+        kvs.compaction().unwrap();
+        let current: Arc<Version> = kvs.inner.current();
+        assert!(current.levels[0].is_empty());
+        assert!(current.levels[1]
+            .iter()
+            .all(|t| t.meta().compression == Compression::LzHuffman));
+        assert!(kvs.stats().compression_ratio() > mixed);
+        assert_eq!(kvs.scan_all().unwrap().count(), 200);
+        drop(current);
+        drop(kvs);
+
+        // 圧縮なしで開き直しても、圧縮されたテーブルを読めるケース
+        let kvs: KVS = options.open().unwrap();
+        assert_eq!(
+            kvs.get("user150").unwrap(),
+            Some(Value::new(value(150), false))
+        );
+    }
+
+    /// Waits until the background threads have flushed the immutable memtable
+    /// and compacted every level within its target.
+    fn wait_for_background_work(kvs: &KVS) {
+        let mut background: MutexGuard<Background> = lock(&kvs.inner.background);
+        loop {
+            assert_eq!(background.error, None);
+            let current: Arc<Version> = kvs.inner.current();
+            let pending: bool = current.immutable.is_some()
+                || compaction::pick(
+                    &current.levels,
+                    &background.compact_pointers,
+                    &kvs.inner.options,
+                )
+                .is_some();
+            if !pending && background.running == 0 {
+                return;
+            }
+            background = wait(&kvs.inner.background_changed, background);
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use crate::crc::crc32;
+
+/// The size of the header of a record: length (8 bytes) | crc (4 bytes)
+pub const HEADER_SIZE: usize = 12;
+
+/// Encodes a payload into a record.
+///
+/// The format of a record is: length (8 bytes) | crc (4 bytes) | payload
+///
+/// The CRC-32 covers the length and the payload, so a torn or corrupt record is detected.
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let length: [u8; 8] = payload.len().to_be_bytes();
+    let crc: [u8; 4] = crc32(&[&length, payload].concat()).to_be_bytes();
+    [&length[..], &crc[..], payload].concat()
+}
+
+/// Decodes the record at a given offset.
+///
+/// Returns the payload and the offset of the next record,
+/// or `None` if the record is torn or corrupt.
+pub fn decode_record(bytes: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let header: &[u8] = bytes.get(offset..offset.checked_add(HEADER_SIZE)?)?;
+    let crc: u32 = u32::from_be_bytes(header[8..12].try_into().ok()?);
+    let length: usize = usize::from_be_bytes(header[0..8].try_into().ok()?);
+
+    let start: usize = offset + HEADER_SIZE;
+    let end: usize = start.checked_add(length)?;
+    let payload: &[u8] = bytes.get(start..end)?;
+
+    match crc32(&[&header[0..8], payload].concat()) == crc {
+        true => Some((payload, end)),
+        false => None,
+    }
+}
+
+/// Returns `true` if the record at a given offset runs past the end of the bytes,
+/// as the last record does when its write was cut off.
+pub fn is_torn(bytes: &[u8], offset: usize) -> bool {
+    let header: &[u8] = match bytes.get(offset..offset.saturating_add(HEADER_SIZE)) {
+        Some(header) => header,
+        None => return true,
+    };
+    let length: usize = usize::from_be_bytes(header[0..8].try_into().unwrap_or_default());
+    match (offset + HEADER_SIZE).checked_add(length) {
+        Some(end) => end > bytes.len(),
+        None => true,
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::log::*;
+
+    #[test]
+    fn test_encode_decode_record() {
+        let bytes: Vec<u8> = [encode_record(b"first"), encode_record(b"second")].concat();
+
+        let (payload, next) = decode_record(&bytes, 0).unwrap();
+        assert_eq!(payload, b"first");
+        let (payload, next) = decode_record(&bytes, next).unwrap();
+        assert_eq!(payload, b"second");
+        assert_eq!(next, bytes.len());
+        assert_eq!(decode_record(&bytes, next), None);
+
+        // 途中で切れたレコード
+        assert_eq!(
+            decode_record(&bytes[..bytes.len() - 1], HEADER_SIZE + 5),
+            None
+        );
+
+        // CRC が合わないレコード
+        let mut corrupt: Vec<u8> = bytes.clone();
+        corrupt[HEADER_SIZE] ^= 0xFF;
+        assert_eq!(decode_record(&corrupt, 0), None);
+        assert!(!is_torn(&corrupt, 0));
+
+        // 途中で切れたレコードと CRC が合わないレコードを区別するケース
+        assert!(is_torn(&bytes[..bytes.len() - 1], HEADER_SIZE + 5));
+        assert!(is_torn(&bytes, bytes.len() - 3));
+        assert!(!is_torn(&bytes, 0));
+    }
+}
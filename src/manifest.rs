@@ -0,0 +1,431 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{ConvertError, IOError, KVSError},
+    log,
+};
+
+/// The name of the manifest file in the data directory.
+pub(crate) const MANIFEST_FILENAME: &str = "MANIFEST";
+/// The name of the file a new manifest is written to before it replaces the old one.
+const MANIFEST_TMP_FILENAME: &str = "MANIFEST.tmp";
+
+/// A change to the set of live SSTables.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VersionEdit {
+    /// The levels and file names of the tables added, in the order they were written.
+    pub added: Vec<(u32, String)>,
+    /// The file names of the tables removed.
+    pub removed: Vec<String>,
+    /// The largest sequence number used so far, if the edit records it.
+    pub last_seq: Option<u64>,
+}
+
+/// The live tables and the sequence number read from a manifest.
+#[derive(Debug, PartialEq)]
+pub struct Replay {
+    /// The levels and file names of the live tables in the order they were added.
+    pub tables: Vec<(u32, String)>,
+    /// The file names of the tables removed by the edits, whose files may be left behind.
+    pub removed: Vec<String>,
+    /// The largest sequence number recorded.
+    pub last_seq: u64,
+}
+
+impl VersionEdit {
+    /// Creates an empty `VersionEdit`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a table to a level.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The level of the table.
+    /// * `filename` - The name of the data file.
+    pub fn add(&mut self, level: u32, filename: &str) -> &mut Self {
+        self.added.push((level, filename.to_string()));
+        self
+    }
+
+    /// Removes a table.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the data file.
+    pub fn remove(&mut self, filename: &str) -> &mut Self {
+        self.removed.push(filename.to_string());
+        self
+    }
+
+    /// Records the largest sequence number used so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - The sequence number.
+    pub fn set_last_seq(&mut self, seq: u64) -> &mut Self {
+        self.last_seq = Some(seq);
+        self
+    }
+
+    /// Converts the `VersionEdit` to a byte vector.
+    ///
+    /// The format is:
+    /// added count (8 bytes) | level (4 bytes) | name length (8 bytes) | name | ... |
+    /// removed count (8 bytes) | name length (8 bytes) | name | ... | last seq (8 bytes, optional)
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.added.len().to_be_bytes().to_vec();
+        for (level, filename) in self.added.iter() {
+            bytes.extend(level.to_be_bytes());
+            bytes.extend(encode_string(filename));
+        }
+        bytes.extend(self.removed.len().to_be_bytes());
+        for filename in self.removed.iter() {
+            bytes.extend(encode_string(filename));
+        }
+        if let Some(seq) = self.last_seq {
+            bytes.extend(seq.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Creates a `VersionEdit` from a byte vector.
+    ///
+    /// Returns `None` if the bytes are invalid.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut edit: VersionEdit = VersionEdit::new();
+
+        let (added_count, mut offset) = read_usize(bytes, 0)?;
+        for _ in 0..added_count {
+            let level: u32 = u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?);
+            let (filename, next) = decode_string(bytes, offset + 4)?;
+            edit.added.push((level, filename));
+            offset = next;
+        }
+
+        let (removed_count, mut offset) = read_usize(bytes, offset)?;
+        for _ in 0..removed_count {
+            let (filename, next) = decode_string(bytes, offset)?;
+            edit.removed.push(filename);
+            offset = next;
+        }
+
+        if offset < bytes.len() {
+            let seq: u64 = u64::from_be_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?);
+            edit.last_seq = Some(seq);
+            offset += 8;
+        }
+
+        match offset == bytes.len() {
+            true => Some(edit),
+            false => None,
+        }
+    }
+}
+
+/// The log of version edits that records which SSTables are live, at which level and in what order.
+///
+/// Each record holds one `VersionEdit`, framed by `log::encode_record`.
+/// An edit is synced to the disk before the files it removes are deleted,
+/// so the tables of a flush or a compaction become live, or stop being live, all at once.
+///
+/// An edit that fails to be written is cut off the file, so that the next edit is not
+/// appended after a broken record. If that fails too, or an fsync fails,
+/// the manifest is poisoned and refuses every later edit, like the WAL.
+#[derive(Debug)]
+pub struct Manifest {
+    /// The file opened in append mode.
+    file: File,
+    /// The length of the file up to the end of the last edit written.
+    len: u64,
+    /// The error that poisoned the manifest, if any.
+    error: Option<String>,
+}
+
+impl Manifest {
+    /// Returns `true` if the data directory has a manifest.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` - The data directory.
+    pub fn exists(data_dir: &Path) -> bool {
+        data_dir.join(MANIFEST_FILENAME).is_file()
+    }
+
+    /// Replays the manifest in the data directory.
+    ///
+    /// Returns the live tables and the largest sequence number, or `None` if there is no manifest.
+    /// A torn last record is ignored, since its edit never completed.
+    /// Fails with `ConvertError::InvalidManifest` if a record before it is corrupt,
+    /// since the edits after it would be lost.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` - The directory where the manifest is stored.
+    pub fn replay(data_dir: &Path) -> Result<Option<Replay>, KVSError> {
+        let path: PathBuf = data_dir.join(MANIFEST_FILENAME);
+        let bytes: Vec<u8> = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(KVSError::FailedIO(IOError::FailedReadFile(e.to_string()))),
+        };
+
+        let mut replay: Replay = Replay {
+            tables: Vec::new(),
+            removed: Vec::new(),
+            last_seq: 0,
+        };
+        let mut offset: usize = 0;
+        while offset < bytes.len() {
+            let edit: Option<(VersionEdit, usize)> = log::decode_record(&bytes, offset)
+                .and_then(|(edit_bytes, next)| Some((VersionEdit::from_bytes(edit_bytes)?, next)));
+            let (edit, next) = match edit {
+                Some(decoded) => decoded,
+                None if log::is_torn(&bytes, offset) => break,
+                None => {
+                    return Err(KVSError::FailedConvert(ConvertError::InvalidManifest(
+                        format!("{path:?}: The record at offset {offset} is corrupt."),
+                    )))
+                }
+            };
+            replay
+                .tables
+                .retain(|(_, filename)| !edit.removed.contains(filename));
+            replay.tables.extend(edit.added);
+            replay.removed.extend(edit.removed);
+            replay.last_seq = replay.last_seq.max(edit.last_seq.unwrap_or(0));
+            offset = next;
+        }
+        Ok(Some(replay))
+    }
+
+    /// Creates a new manifest that holds the live tables as a single edit.
+    ///
+    /// The new manifest is written to a temporary file and renamed over the old one,
+    /// so a crash leaves either the old manifest or the new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` - The directory to store the manifest in.
+    /// * `snapshot` - The edit that adds every live table.
+    pub fn create(data_dir: &Path, snapshot: &VersionEdit) -> Result<Self, IOError> {
+        let path: PathBuf = data_dir.join(MANIFEST_FILENAME);
+        let tmp_path: PathBuf = data_dir.join(MANIFEST_TMP_FILENAME);
+
+        let mut tmp_file: File = match File::create(&tmp_path) {
+            Ok(f) => f,
+            Err(e) => return Err(IOError::FailedCreateFile(tmp_path, e.to_string())),
+        };
+        if let Err(e) = tmp_file.write_all(&log::encode_record(&snapshot.to_bytes())) {
+            return Err(IOError::FailedWriteBytes(e.to_string()));
+        }
+        if let Err(e) = tmp_file.sync_all() {
+            return Err(IOError::FailedSync(e.to_string()));
+        }
+        if let Err(e) = fs::rename(&tmp_path, &path) {
+            return Err(IOError::FailedRenameFile(tmp_path, e.to_string()));
+        }
+        sync_dir(data_dir)?;
+
+        let file: File = match OpenOptions::new().append(true).open(&path) {
+            Ok(f) => f,
+            Err(e) => return Err(IOError::FailedOpenFile(path, e.to_string())),
+        };
+        let len: u64 = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(IOError::FailedGetFileSize(path, e.to_string())),
+        };
+        Ok(Manifest {
+            file,
+            len,
+            error: None,
+        })
+    }
+
+    /// Appends an edit to the manifest and syncs it to the disk.
+    ///
+    /// If the sync fails, the edit may or may not be on the disk, and the manifest is poisoned.
+    ///
+    /// # Arguments
+    ///
+    /// * `edit` - The edit to append.
+    pub fn apply(&mut self, edit: &VersionEdit) -> Result<(), IOError> {
+        if let Some(msg) = &self.error {
+            return Err(IOError::ManifestPoisoned(msg.clone()));
+        }
+
+        let record: Vec<u8> = log::encode_record(&edit.to_bytes());
+        if let Err(e) = self.file.write_all(&record) {
+            // 書きかけの編集の後ろに次の編集を書かないように、書く前の長さに戻す
+            if let Err(truncate_error) = self.file.set_len(self.len) {
+                self.error = Some(truncate_error.to_string());
+            }
+            return Err(IOError::FailedWriteBytes(e.to_string()));
+        }
+        self.len += record.len() as u64;
+
+        if let Err(e) = self.file.sync_all() {
+            self.error = Some(e.to_string());
+            return Err(IOError::FailedSync(e.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Syncs a directory so that a file created or renamed in it survives a crash.
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), IOError> {
+    let dir_file: File = match File::open(dir) {
+        Ok(f) => f,
+        Err(e) => return Err(IOError::FailedOpenFile(dir.to_path_buf(), e.to_string())),
+    };
+    match dir_file.sync_all() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(IOError::FailedSync(e.to_string())),
+    }
+}
+
+/// Encodes a string with an 8-byte length prefix.
+fn encode_string(s: &str) -> Vec<u8> {
+    [&s.len().to_be_bytes(), s.as_bytes()].concat()
+}
+
+/// Decodes a string with an 8-byte length prefix at a given offset.
+fn decode_string(bytes: &[u8], offset: usize) -> Option<(String, usize)> {
+    let (length, start) = read_usize(bytes, offset)?;
+    let end: usize = start.checked_add(length)?;
+    let s: String = String::from_utf8(bytes.get(start..end)?.to_vec()).ok()?;
+    Some((s, end))
+}
+
+/// Reads a big-endian `usize` at a given offset and returns it with the offset after it.
+fn read_usize(bytes: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let b: [u8; 8] = bytes.get(offset..offset.checked_add(8)?)?.try_into().ok()?;
+    Some((usize::from_be_bytes(b), offset + 8))
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::{manifest::*, test_util};
+
+    #[test]
+    fn test_version_edit_bytes() {
+        let mut edit = VersionEdit::new();
+        edit.add(0, "1.dat").add(2, "2.dat").remove("0.dat");
+
+        let bytes: Vec<u8> = edit.to_bytes();
+        assert_eq!(VersionEdit::from_bytes(&bytes), Some(edit.clone()));
+        assert_eq!(VersionEdit::from_bytes(&bytes[..bytes.len() - 1]), None);
+
+        // シーケンス番号を記録したケース
+        edit.set_last_seq(42);
+        let bytes: Vec<u8> = edit.to_bytes();
+        assert_eq!(VersionEdit::from_bytes(&bytes), Some(edit));
+        assert_eq!(VersionEdit::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(
+            VersionEdit::from_bytes(&VersionEdit::new().to_bytes()),
+            Some(VersionEdit::new())
+        );
+    }
+
+    #[test]
+    fn test_create_apply_replay() {
+        let data_dir: PathBuf = test_util::temp_dir("manifest");
+
+        // マニフェストがないケース
+        assert_eq!(Manifest::replay(&data_dir).unwrap(), None);
+
+        let mut snapshot = VersionEdit::new();
+        snapshot.add(1, "a.dat").add(0, "b.dat");
+        let mut manifest = Manifest::create(&data_dir, &snapshot).unwrap();
+        assert!(!data_dir.join(MANIFEST_TMP_FILENAME).exists());
+
+        let mut edit = VersionEdit::new();
+        edit.add(0, "c.dat");
+        manifest.apply(&edit).unwrap();
+        let mut edit = VersionEdit::new();
+        edit.add(1, "d.dat")
+            .remove("a.dat")
+            .remove("b.dat")
+            .set_last_seq(10);
+        manifest.apply(&edit).unwrap();
+
+        let live = Replay {
+            tables: vec![(0, "c.dat".to_string()), (1, "d.dat".to_string())],
+            removed: vec!["a.dat".to_string(), "b.dat".to_string()],
+            last_seq: 10,
+        };
+        let replay: Replay = Manifest::replay(&data_dir).unwrap().unwrap();
+        assert_eq!(replay, live);
+
+        // 最後の編集が途中までしか書き込まれていないケースは、その編集がなかったことになる
+        let mut edit = VersionEdit::new();
+        edit.add(2, "e.dat")
+            .remove("c.dat")
+            .remove("d.dat")
+            .set_last_seq(20);
+        manifest.apply(&edit).unwrap();
+        let path: PathBuf = data_dir.join(MANIFEST_FILENAME);
+        let bytes: Vec<u8> = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let replay: Replay = Manifest::replay(&data_dir).unwrap().unwrap();
+        assert_eq!(replay, live);
+
+        // 最後より前の編集が壊れているケースはエラーにする
+        let mut corrupt: Vec<u8> = bytes.clone();
+        corrupt[log::HEADER_SIZE] ^= 0xFF;
+        fs::write(&path, &corrupt).unwrap();
+        assert!(matches!(
+            Manifest::replay(&data_dir),
+            Err(KVSError::FailedConvert(ConvertError::InvalidManifest(_)))
+        ));
+
+        // 作り直したマニフェストは1つの編集にまとめられる
+        let mut snapshot = VersionEdit::new();
+        for (level, filename) in live.tables.iter() {
+            snapshot.add(*level, filename);
+        }
+        snapshot.set_last_seq(live.last_seq);
+        drop(Manifest::create(&data_dir, &snapshot).unwrap());
+        let live = Replay {
+            removed: Vec::new(),
+            ..live
+        };
+        assert_eq!(Manifest::replay(&data_dir).unwrap(), Some(live));
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let data_dir: PathBuf = test_util::temp_dir("manifest_poisoned");
+        let mut snapshot = VersionEdit::new();
+        snapshot.add(0, "a.dat");
+        let mut manifest = Manifest::create(&data_dir, &snapshot).unwrap();
+        let mut edit = VersionEdit::new();
+        edit.add(0, "b.dat");
+
+        // 書き込みに失敗し、書きかけの編集も切り詰められないケース
+        let read_only: File = File::open(data_dir.join(MANIFEST_FILENAME)).unwrap();
+        let file: File = std::mem::replace(&mut manifest.file, read_only);
+        assert!(matches!(
+            manifest.apply(&edit),
+            Err(IOError::FailedWriteBytes(_))
+        ));
+
+        // 以降の編集はすべて失敗する
+        manifest.file = file;
+        assert!(matches!(
+            manifest.apply(&edit),
+            Err(IOError::ManifestPoisoned(_))
+        ));
+
+        // 開き直すと、失敗する前の編集だけが残っている
+        let replay: Replay = Manifest::replay(&data_dir).unwrap().unwrap();
+        assert_eq!(replay.tables, vec![(0, "a.dat".to_string())]);
+    }
+}
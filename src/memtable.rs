@@ -0,0 +1,353 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+    vec,
+};
+
+use crate::{iterator::Entry, snapshot, value::Value};
+
+/// The key of an entry in the memtable: the key and the sequence number, newest first.
+type InternalKey = (Vec<u8>, Reverse<u64>);
+
+/// The number of versions a `MemtableIterator` reads from the memtable under one lock.
+const CHUNK_ENTRIES: usize = 256;
+
+/// The in-memory table of the most recent writes.
+///
+/// Entries are ordered by key and then by sequence number, newest first.
+/// An older version of a key is kept only while a snapshot can still read it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Memtable {
+    /// The versions of every key.
+    entries: BTreeMap<InternalKey, Value>,
+    /// The approximate size of the entries in bytes.
+    size: usize,
+}
+
+impl Memtable {
+    /// Creates an empty `Memtable`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a version of a key and drops the older versions no snapshot can read.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    /// * `seq` - The sequence number of the write.
+    /// * `value` - The value.
+    /// * `snapshots` - The sequence numbers of the live snapshots in ascending order.
+    pub fn insert(&mut self, key: &[u8], seq: u64, value: Value, snapshots: &[u64]) {
+        self.size += entry_size(key, &value);
+        if let Some(old) = self.entries.insert((key.to_vec(), Reverse(seq)), value) {
+            self.size -= entry_size(key, &old);
+        }
+
+        let mut newer_seq: Option<u64> = None;
+        let mut unused: Vec<InternalKey> = Vec::new();
+        for ((_, Reverse(version)), _) in self.entries.range(versions(key)) {
+            if !snapshot::is_visible(*version, newer_seq, snapshots) {
+                unused.push((key.to_vec(), Reverse(*version)));
+            }
+            newer_seq = Some(*version);
+        }
+        for internal_key in unused {
+            if let Some(old) = self.entries.remove(&internal_key) {
+                self.size -= entry_size(key, &old);
+            }
+        }
+    }
+
+    /// Returns the newest version of a key whose sequence number is at most `seq`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key.
+    /// * `seq` - The largest sequence number to read.
+    pub fn get(&self, key: &[u8], seq: u64) -> Option<(u64, &Value)> {
+        let start: InternalKey = (key.to_vec(), Reverse(seq));
+        let end: InternalKey = (key.to_vec(), Reverse(0));
+        self.entries
+            .range(start..=end)
+            .next()
+            .map(|((_, Reverse(version)), value)| (*version, value))
+    }
+
+    /// Returns an iterator over every version of the keys in the range,
+    /// ordered by key and then by sequence number, newest first.
+    ///
+    /// The range must be valid for `BTreeMap::range`.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The start and end bounds of the keys.
+    pub fn range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> impl Iterator<Item = (&[u8], u64, &Value)> {
+        self.entries
+            .range(internal_range(range))
+            .map(|((key, Reverse(seq)), value)| (key.as_slice(), *seq, value))
+    }
+
+    /// Returns an iterator over every version of every key in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64, &Value)> {
+        self.range((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// Returns the number of versions in the memtable.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the memtable holds no versions.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the approximate size of the memtable in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// An iterator over the versions of the keys in a range of a memtable that is still written,
+/// ordered by key and then by sequence number, newest first.
+///
+/// Versions are copied `CHUNK_ENTRIES` at a time under the read lock, so writers are blocked
+/// only for one chunk and memory does not grow with the range.
+/// Only versions whose sequence numbers are at most `seq` are returned, so later writes are not seen.
+/// The caller keeps a snapshot at `seq` alive, so that the versions it reads are not dropped.
+pub struct MemtableIterator {
+    /// The memtable.
+    memtable: Arc<RwLock<Memtable>>,
+    /// The bound after the last version read, or the start of the range.
+    start: Bound<InternalKey>,
+    /// The end of the range.
+    end: Bound<InternalKey>,
+    /// The largest sequence number to read.
+    seq: u64,
+    /// The versions of the current chunk.
+    entries: vec::IntoIter<(Vec<u8>, u64, Value)>,
+    /// Whether the last chunk has been read.
+    done: bool,
+}
+
+impl MemtableIterator {
+    /// Creates a new `MemtableIterator`.
+    ///
+    /// The range must be valid for `BTreeMap::range`.
+    ///
+    /// # Arguments
+    ///
+    /// * `memtable` - The memtable.
+    /// * `range` - The start and end bounds of the keys.
+    /// * `seq` - The largest sequence number to read.
+    pub fn new(
+        memtable: Arc<RwLock<Memtable>>,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        seq: u64,
+    ) -> Self {
+        let (start, end) = internal_range(range);
+        MemtableIterator {
+            memtable,
+            start,
+            end,
+            seq,
+            entries: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Reads the next chunk of versions, and returns `false` if the range has been read to the end.
+    fn read_chunk(&mut self) -> bool {
+        let memtable = crate::read(&self.memtable);
+        let mut entries: Vec<(Vec<u8>, u64, Value)> = Vec::new();
+        let mut last: Option<&InternalKey> = None;
+        let mut count: usize = 0;
+        for (internal_key, value) in memtable
+            .entries
+            .range((self.start.clone(), self.end.clone()))
+            .take(CHUNK_ENTRIES)
+        {
+            let (key, Reverse(seq)) = internal_key;
+            if *seq <= self.seq {
+                entries.push((key.clone(), *seq, value.clone()));
+            }
+            last = Some(internal_key);
+            count += 1;
+        }
+        if let Some(last) = last {
+            self.start = Bound::Excluded(last.clone());
+        }
+        self.done = count < CHUNK_ENTRIES;
+        self.entries = entries.into_iter();
+        count > 0
+    }
+}
+
+impl Iterator for MemtableIterator {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, seq, value)) = self.entries.next() {
+                return Some(Ok((key, seq, value)));
+            }
+            if self.done || !self.read_chunk() {
+                return None;
+            }
+        }
+    }
+}
+
+/// Converts a range of keys into the range of the internal keys of all their versions.
+fn internal_range(
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+) -> (Bound<InternalKey>, Bound<InternalKey>) {
+    // 開始キーは全てのバージョンを含め、除外するキーは全てのバージョンを除く
+    let start: Bound<InternalKey> = match range.0 {
+        Bound::Included(key) => Bound::Included((key, Reverse(u64::MAX))),
+        Bound::Excluded(key) => Bound::Excluded((key, Reverse(0))),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end: Bound<InternalKey> = match range.1 {
+        Bound::Included(key) => Bound::Included((key, Reverse(0))),
+        Bound::Excluded(key) => Bound::Excluded((key, Reverse(u64::MAX))),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+/// Returns the range of the internal keys of every version of a key.
+fn versions(key: &[u8]) -> (Bound<InternalKey>, Bound<InternalKey>) {
+    (
+        Bound::Included((key.to_vec(), Reverse(u64::MAX))),
+        Bound::Included((key.to_vec(), Reverse(0))),
+    )
+}
+
+/// Returns the approximate number of bytes an entry occupies in the memtable.
+fn entry_size(key: &[u8], value: &Value) -> usize {
+    key.len() + value.len()
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::memtable::*;
+
+    #[test]
+    fn test_insert_get() {
+        let mut memtable = Memtable::new();
+        memtable.insert(b"k1", 1, Value::new("v1", false), &[]);
+        assert_eq!(memtable.size(), 13);
+
+        // スナップショットがなければ古いバージョンは捨てる
+        memtable.insert(b"k1", 2, Value::new("value2", false), &[]);
+        assert_eq!(memtable.len(), 1);
+        assert_eq!(memtable.size(), 17);
+        assert_eq!(
+            memtable.get(b"k1", u64::MAX),
+            Some((2, &Value::new("value2", false)))
+        );
+        assert_eq!(memtable.get(b"k1", 1), None);
+        assert_eq!(memtable.get(b"k2", u64::MAX), None);
+
+        // スナップショットが読めるバージョンは残す
+        memtable.insert(b"k1", 5, Value::new("", true), &[3]);
+        memtable.insert(b"k1", 6, Value::new("v6", false), &[3]);
+        assert_eq!(memtable.len(), 2);
+        assert_eq!(
+            memtable.get(b"k1", 3),
+            Some((2, &Value::new("value2", false)))
+        );
+        assert_eq!(
+            memtable.get(b"k1", 5),
+            Some((2, &Value::new("value2", false)))
+        );
+        assert_eq!(
+            memtable.get(b"k1", u64::MAX),
+            Some((6, &Value::new("v6", false)))
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let mut memtable = Memtable::new();
+        memtable.insert(b"a", 1, Value::new("a1", false), &[]);
+        memtable.insert(b"b", 2, Value::new("b2", false), &[]);
+        memtable.insert(b"b", 3, Value::new("b3", false), &[2]);
+        memtable.insert(b"c", 4, Value::new("c4", false), &[]);
+
+        let all: Vec<(&[u8], u64)> = memtable.iter().map(|(k, seq, _)| (k, seq)).collect();
+        assert_eq!(
+            all,
+            vec![
+                (&b"a"[..], 1),
+                (&b"b"[..], 3),
+                (&b"b"[..], 2),
+                (&b"c"[..], 4)
+            ]
+        );
+
+        // 除外するキーは全てのバージョンを除く
+        let range = (
+            Bound::Excluded(b"a".to_vec()),
+            Bound::Excluded(b"c".to_vec()),
+        );
+        let keys: Vec<(&[u8], u64)> = memtable.range(range).map(|(k, seq, _)| (k, seq)).collect();
+        assert_eq!(keys, vec![(&b"b"[..], 3), (&b"b"[..], 2)]);
+
+        let range = (
+            Bound::Included(b"b".to_vec()),
+            Bound::Included(b"b".to_vec()),
+        );
+        assert_eq!(memtable.range(range).count(), 2);
+    }
+
+    #[test]
+    fn test_memtable_iterator() {
+        let memtable: Arc<RwLock<Memtable>> = Arc::new(RwLock::new(Memtable::new()));
+        let n: u64 = CHUNK_ENTRIES as u64 * 2 + 1;
+        for i in 0..n {
+            let key: String = format!("k{i:04}");
+            memtable
+                .write()
+                .unwrap()
+                .insert(key.as_bytes(), i + 1, Value::new("v", false), &[n]);
+        }
+
+        // チャンクをまたいで全てのエントリを読み、途中の書き込みは見えない
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        let mut iter = MemtableIterator::new(Arc::clone(&memtable), range, n);
+        let first: Entry = iter.next().unwrap();
+        assert_eq!(first.unwrap().0, b"k0000".to_vec());
+        memtable
+            .write()
+            .unwrap()
+            .insert(b"k0001", n + 1, Value::new("new", false), &[n]);
+        memtable
+            .write()
+            .unwrap()
+            .insert(b"k9999", n + 2, Value::new("new", false), &[n]);
+        let rest: Vec<(Vec<u8>, u64, Value)> = iter.map(|e| e.unwrap()).collect();
+        assert_eq!(rest.len() as u64, n - 1);
+        assert_eq!(rest[0], (b"k0001".to_vec(), 2, Value::new("v", false)));
+        assert!(rest.iter().all(|(_, seq, _)| *seq <= n));
+
+        // 範囲の終わりで止まる
+        let range = (
+            Bound::Excluded(b"k0255".to_vec()),
+            Bound::Included(b"k0256".to_vec()),
+        );
+        let keys: Vec<Vec<u8>> = MemtableIterator::new(Arc::clone(&memtable), range, n)
+            .map(|e| e.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"k0256".to_vec()]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    compression::{self, Compression},
+    error::KVSError,
+    upgrade, KVS,
+};
+
+const DEFAULT_DATA_DIR: &str = "./data/";
+const DEFAULT_WAL_FILENAME: &str = "wal";
+const DEFAULT_MEMTABLE_MAX_ENTRIES: usize = 1024;
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+const DEFAULT_MAX_LEVELS: usize = 7;
+const DEFAULT_LEVEL0_COMPACTION_TRIGGER: usize = 4;
+const DEFAULT_LEVEL1_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LEVEL_SIZE_MULTIPLIER: u64 = 10;
+const DEFAULT_TARGET_FILE_SIZE: u64 = 2 * 1024 * 1024;
+const DEFAULT_BACKGROUND_THREADS: usize = 1;
+
+/// When writes to the write-ahead log are synced to the disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Every write is synced before it returns.
+    ///
+    /// Writers that arrive while a sync is running share the next sync (group commit).
+    Always,
+    /// The WAL is synced in the background at the given interval.
+    ///
+    /// Writes acknowledged within the last interval can be lost on a power failure.
+    Periodic(Duration),
+    /// The WAL is never synced explicitly and the OS decides when to write it back.
+    Never,
+}
+
+/// Options used to open a `KVS`.
+///
+/// ```no_run
+/// use kvsd::KVSOptions;
+///
+/// let kvs = KVSOptions::new()
+///     .data_dir("/var/lib/kvsd")
+///     .memtable_max_bytes(4 * 1024 * 1024)
+///     .create_if_missing(true)
+///     .open()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KVSOptions {
+    /// The directory where the data files are stored.
+    pub(crate) data_dir: PathBuf,
+    /// The maximum number of entries held in the memtable before it is flushed.
+    pub(crate) memtable_max_entries: usize,
+    /// The maximum size of the memtable in bytes before it is flushed.
+    pub(crate) memtable_max_bytes: Option<usize>,
+    /// The name of the write-ahead log file in the data directory.
+    pub(crate) wal_filename: String,
+    /// Whether to create the data directory if it does not exist.
+    pub(crate) create_if_missing: bool,
+    /// Whether to fail if a store already exists in the data directory.
+    pub(crate) error_if_exists: bool,
+    /// When writes to the WAL are synced to the disk.
+    pub(crate) durability: Durability,
+    /// The target size of an SSTable data block in bytes.
+    pub(crate) block_size: usize,
+    /// The number of bits per key of the bloom filter of an SSTable. 0 disables the filters.
+    pub(crate) bloom_bits_per_key: usize,
+    /// The number of levels of the LSM tree.
+    pub(crate) max_levels: usize,
+    /// The number of SSTables in level 0 that triggers a compaction into level 1.
+    pub(crate) level0_compaction_trigger: usize,
+    /// The target total size of the SSTables in level 1 in bytes.
+    pub(crate) level1_max_bytes: u64,
+    /// The ratio of the target size of a level to the target size of the level above it.
+    pub(crate) level_size_multiplier: u64,
+    /// The size of an SSTable written by a compaction in bytes.
+    pub(crate) target_file_size: u64,
+    /// The number of threads that flush memtables and run compactions in the background.
+    pub(crate) background_threads: usize,
+    /// Whether the store is opened only for reading.
+    pub(crate) read_only: bool,
+    /// The compression of the data blocks of new SSTables.
+    pub(crate) compression: Compression,
+    /// The compression level from 1 (fastest) to 9 (smallest).
+    pub(crate) compression_level: u32,
+}
+
+impl Default for KVSOptions {
+    fn default() -> Self {
+        KVSOptions {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            memtable_max_entries: DEFAULT_MEMTABLE_MAX_ENTRIES,
+            memtable_max_bytes: None,
+            wal_filename: DEFAULT_WAL_FILENAME.to_string(),
+            create_if_missing: false,
+            error_if_exists: false,
+            durability: Durability::Always,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            max_levels: DEFAULT_MAX_LEVELS,
+            level0_compaction_trigger: DEFAULT_LEVEL0_COMPACTION_TRIGGER,
+            level1_max_bytes: DEFAULT_LEVEL1_MAX_BYTES,
+            level_size_multiplier: DEFAULT_LEVEL_SIZE_MULTIPLIER,
+            target_file_size: DEFAULT_TARGET_FILE_SIZE,
+            background_threads: DEFAULT_BACKGROUND_THREADS,
+            read_only: false,
+            compression: Compression::None,
+            compression_level: compression::DEFAULT_LEVEL,
+        }
+    }
+}
+
+impl KVSOptions {
+    /// Creates a new `KVSOptions` with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory where the data files are stored.
+    pub fn data_dir<P: AsRef<Path>>(mut self, data_dir: P) -> Self {
+        self.data_dir = data_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sets the maximum number of entries held in the memtable.
+    pub fn memtable_max_entries(mut self, entries: usize) -> Self {
+        self.memtable_max_entries = entries;
+        self
+    }
+
+    /// Sets the maximum size of the memtable in bytes.
+    ///
+    /// The memtable is flushed when either this limit or the entry limit is exceeded.
+    pub fn memtable_max_bytes(mut self, bytes: usize) -> Self {
+        self.memtable_max_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the name of the write-ahead log file.
+    pub fn wal_filename(mut self, filename: &str) -> Self {
+        self.wal_filename = filename.to_string();
+        self
+    }
+
+    /// Creates the data directory if it does not exist.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Fails to open if a store already exists in the data directory.
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.error_if_exists = error;
+        self
+    }
+
+    /// Sets when writes to the WAL are synced to the disk.
+    ///
+    /// The default is `Durability::Always`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the target size of an SSTable data block in bytes.
+    ///
+    /// A lookup reads one data block, and the index holds one entry per data block.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    /// Sets the number of bits per key of the bloom filter written to each SSTable.
+    ///
+    /// More bits give fewer false positives at the cost of memory:
+    /// 10 bits per key give about 1% false positives. 0 disables the filters.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    /// Sets the number of levels of the LSM tree.
+    ///
+    /// At least 2 levels are used.
+    pub fn max_levels(mut self, levels: usize) -> Self {
+        self.max_levels = levels.max(2);
+        self
+    }
+
+    /// Sets the number of SSTables in level 0 that triggers a compaction into level 1.
+    pub fn level0_compaction_trigger(mut self, tables: usize) -> Self {
+        self.level0_compaction_trigger = tables.max(1);
+        self
+    }
+
+    /// Sets the target total size of the SSTables in level 1 in bytes.
+    ///
+    /// A level is compacted into the next level when it grows beyond its target.
+    pub fn level1_max_bytes(mut self, bytes: u64) -> Self {
+        self.level1_max_bytes = bytes;
+        self
+    }
+
+    /// Sets the ratio of the target size of a level to the target size of the level above it.
+    pub fn level_size_multiplier(mut self, multiplier: u64) -> Self {
+        self.level_size_multiplier = multiplier.max(1);
+        self
+    }
+
+    /// Sets the size of an SSTable written by a compaction in bytes.
+    pub fn target_file_size(mut self, bytes: u64) -> Self {
+        self.target_file_size = bytes;
+        self
+    }
+
+    /// Sets the number of threads that flush memtables and run compactions in the background.
+    ///
+    /// A flush and compactions of different levels can run at the same time on different threads.
+    /// With 0 threads, the writer that fills the memtable flushes and compacts before it returns.
+    pub fn background_threads(mut self, threads: usize) -> Self {
+        self.background_threads = threads;
+        self
+    }
+
+    /// Opens the store only for reading.
+    ///
+    /// Any number of read-only stores can share a data directory, but not with a store
+    /// that can write. Nothing in the data directory is changed, and writes, flushes and
+    /// compactions fail with `KVSError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the compression of the data blocks of new SSTables.
+    ///
+    /// Existing SSTables keep their compression until they are compacted,
+    /// so the compression can be changed between opens.
+    /// Blocks that do not get smaller, such as already compressed values, are stored as they are.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the compression level from 1 (fastest) to 9 (smallest).
+    ///
+    /// Levels out of the range are clamped into it.
+    pub fn compression_level(mut self, level: u32) -> Self {
+        self.compression_level = level.clamp(1, 9);
+        self
+    }
+
+    /// Opens a `KVS` with these options.
+    pub fn open(&self) -> Result<KVS, KVSError> {
+        KVS::open(self)
+    }
+
+    /// Rewrites the data directory in the current format if it was written by the earlier release.
+    ///
+    /// The earlier release kept its data files and WAL without headers or checksums,
+    /// and a store in such a directory fails to open with `KVSError::UpgradeRequired` until
+    /// it is upgraded. Deleted keys are dropped, and the old files are removed once the new
+    /// ones are in place. An upgrade interrupted by a crash resumes when this is called again.
+    ///
+    /// Returns `false` if there was nothing to upgrade.
+    pub fn upgrade(&self) -> Result<bool, KVSError> {
+        upgrade::upgrade(self)
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::options::*;
+
+    #[test]
+    fn test_default() {
+        let options = KVSOptions::new();
+        assert_eq!(options.data_dir, PathBuf::from("./data/"));
+        assert_eq!(options.memtable_max_entries, 1024);
+        assert_eq!(options.memtable_max_bytes, None);
+        assert_eq!(options.wal_filename, String::from("wal"));
+        assert!(!options.create_if_missing);
+        assert!(!options.error_if_exists);
+        assert_eq!(options.durability, Durability::Always);
+        assert_eq!(options.block_size, 4096);
+        assert_eq!(options.bloom_bits_per_key, 10);
+        assert_eq!(options.max_levels, 7);
+        assert_eq!(options.level0_compaction_trigger, 4);
+        assert_eq!(options.level1_max_bytes, 10 * 1024 * 1024);
+        assert_eq!(options.level_size_multiplier, 10);
+        assert_eq!(options.target_file_size, 2 * 1024 * 1024);
+        assert_eq!(options.background_threads, 1);
+        assert!(!options.read_only);
+        assert_eq!(options.compression, Compression::None);
+        assert_eq!(options.compression_level, 6);
+    }
+
+    #[test]
+    fn test_setters() {
+        let options = KVSOptions::new()
+            .data_dir("/tmp/kvsd")
+            .memtable_max_entries(10)
+            .memtable_max_bytes(100)
+            .wal_filename("log")
+            .create_if_missing(true)
+            .error_if_exists(true)
+            .durability(Durability::Never)
+            .block_size(1024)
+            .bloom_bits_per_key(0)
+            .max_levels(3)
+            .level0_compaction_trigger(2)
+            .level1_max_bytes(1000)
+            .level_size_multiplier(5)
+            .target_file_size(100)
+            .background_threads(4)
+            .read_only(true)
+            .compression(Compression::LzHuffman)
+            .compression_level(9);
+        assert_eq!(options.data_dir, PathBuf::from("/tmp/kvsd"));
+        assert_eq!(options.memtable_max_entries, 10);
+        assert_eq!(options.memtable_max_bytes, Some(100));
+        assert_eq!(options.wal_filename, String::from("log"));
+        assert!(options.create_if_missing);
+        assert!(options.error_if_exists);
+        assert_eq!(options.durability, Durability::Never);
+        assert_eq!(options.block_size, 1024);
+        assert_eq!(options.bloom_bits_per_key, 0);
+        assert_eq!(options.max_levels, 3);
+        assert_eq!(options.level0_compaction_trigger, 2);
+        assert_eq!(options.level1_max_bytes, 1000);
+        assert_eq!(options.level_size_multiplier, 5);
+        assert_eq!(options.target_file_size, 100);
+        assert_eq!(options.background_threads, 4);
+        assert!(options.read_only);
+        assert_eq!(options.compression, Compression::LzHuffman);
+        assert_eq!(options.compression_level, 9);
+
+        // 範囲外のレベルは丸められるケース
+        assert_eq!(KVSOptions::new().compression_level(0).compression_level, 1);
+        assert_eq!(KVSOptions::new().compression_level(20).compression_level, 9);
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::Duration,
+};
+
+use crate::error::ProtocolError;
+
+/// The version of the protocol written by this library.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The largest size of a frame after its length in bytes.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// The size of the length at the start of a frame.
+const LENGTH_SIZE: usize = 4;
+
+/// A request sent from a client to the server.
+///
+/// Requests and responses are sent as frames:
+///
+/// - A frame is: length (4 bytes) | version (1 byte) | message
+///   The length is the size of the version and the message, and is at most `MAX_FRAME_SIZE`.
+/// - A request message is: command (1 byte) | argument count (1 byte) | arguments
+///   An argument is: length (4 bytes) | bytes
+/// - A response message is: status (1 byte) | body
+///   The body is the value for `OK`, empty for `NOT_FOUND` and the error message for `ERROR`.
+///
+/// Keys and values are arbitrary bytes and are never split or truncated.
+/// All numbers are big-endian.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// Gets the value of a key.
+    Get { key: Vec<u8> },
+    /// Puts a value.
+    Put { key: Vec<u8>, value: Vec<u8> },
+    /// Puts a value that expires after a time to live, sent in seconds.
+    PutWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    /// Deletes a key.
+    Delete { key: Vec<u8> },
+    /// Gets the seconds left before the value of a key expires, or `-1` if it never expires.
+    Ttl { key: Vec<u8> },
+    /// Replaces the value of a key only if it equals the expected value.
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Puts a value only if the key does not exist.
+    PutIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    /// Deletes a key only if its value equals the expected value.
+    DeleteIfEquals { key: Vec<u8>, expected: Vec<u8> },
+}
+
+/// The status of a response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// The request succeeded.
+    Ok = 0,
+    /// The key was not found.
+    NotFound = 1,
+    /// The request failed.
+    Error = 2,
+}
+
+/// A response sent from the server to a client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The request succeeded, with the value if the request reads one.
+    ///
+    /// Conditional writes answer `1` if they were applied and `0` if not.
+    Ok(Vec<u8>),
+    /// The key was not found.
+    NotFound,
+    /// The request failed with the message.
+    Error(String),
+}
+
+impl Request {
+    /// Reads a request from a connection.
+    ///
+    /// Returns `None` if the connection was closed before a new frame started.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The connection to read from.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Request>, ProtocolError> {
+        let message: Vec<u8> = match read_frame(reader)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        Request::decode(&message).map(Some)
+    }
+
+    /// Writes the request to a connection.
+    ///
+    /// The writer is not flushed, so that several requests can be sent together.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The connection to write to.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        write_frame(writer, &self.encode())
+    }
+
+    /// Returns the name of the command, used in logs.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Put { .. } => "put",
+            Request::PutWithTtl { .. } => "setex",
+            Request::Delete { .. } => "delete",
+            Request::Ttl { .. } => "ttl",
+            Request::CompareAndSwap { .. } => "cas",
+            Request::PutIfAbsent { .. } => "setnx",
+            Request::DeleteIfEquals { .. } => "delifeq",
+        }
+    }
+
+    /// Encodes the request into a message.
+    fn encode(&self) -> Vec<u8> {
+        let secs: [u8; 8];
+        let (command, args): (u8, Vec<&[u8]>) = match self {
+            Request::Get { key } => (1, vec![key]),
+            Request::Put { key, value } => (2, vec![key, value]),
+            Request::PutWithTtl { key, value, ttl } => {
+                secs = ttl.as_secs().to_be_bytes();
+                (3, vec![key, value, &secs])
+            }
+            Request::Delete { key } => (4, vec![key]),
+            Request::Ttl { key } => (5, vec![key]),
+            Request::CompareAndSwap {
+                key,
+                expected,
+                value,
+            } => (6, vec![key, expected, value]),
+            Request::PutIfAbsent { key, value } => (7, vec![key, value]),
+            Request::DeleteIfEquals { key, expected } => (8, vec![key, expected]),
+        };
+        encode_request(command, &args)
+    }
+
+    /// Decodes a request from a message.
+    fn decode(message: &[u8]) -> Result<Request, ProtocolError> {
+        let (command, count): (u8, u8) = match message {
+            [command, count, ..] => (*command, *count),
+            _ => return Err(invalid("The request has no command.")),
+        };
+
+        let mut offset: usize = 2;
+        let mut args: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..count {
+            let len: usize = match message.get(offset..offset + LENGTH_SIZE) {
+                Some(b) => u32::from_be_bytes(b.try_into().unwrap()) as usize,
+                None => return Err(invalid("An argument is truncated.")),
+            };
+            offset += LENGTH_SIZE;
+            match message.get(offset..offset + len) {
+                Some(arg) => args.push(arg.to_vec()),
+                None => return Err(invalid("An argument is truncated.")),
+            }
+            offset += len;
+        }
+        if offset != message.len() {
+            return Err(invalid("The request has bytes after its arguments."));
+        }
+
+        let expected_count: usize = match command {
+            1 | 4 | 5 => 1,
+            2 | 7 | 8 => 2,
+            3 | 6 => 3,
+            _ => return Err(invalid(&format!("The command {command} is not defined."))),
+        };
+        if args.len() != expected_count {
+            return Err(invalid(&format!(
+                "The command {command} takes {expected_count} arguments but got {}.",
+                args.len()
+            )));
+        }
+
+        let mut args = args.into_iter();
+        let mut next = || args.next().unwrap();
+        let request: Request = match command {
+            1 => Request::Get { key: next() },
+            2 => Request::Put {
+                key: next(),
+                value: next(),
+            },
+            3 => {
+                let (key, value, ttl) = (next(), next(), next());
+                let secs: [u8; 8] = match ttl.try_into() {
+                    Ok(secs) => secs,
+                    Err(_) => return Err(invalid("The TTL must be 8 bytes.")),
+                };
+                Request::PutWithTtl {
+                    key,
+                    value,
+                    ttl: Duration::from_secs(u64::from_be_bytes(secs)),
+                }
+            }
+            4 => Request::Delete { key: next() },
+            5 => Request::Ttl { key: next() },
+            6 => Request::CompareAndSwap {
+                key: next(),
+                expected: next(),
+                value: next(),
+            },
+            7 => Request::PutIfAbsent {
+                key: next(),
+                value: next(),
+            },
+            _ => Request::DeleteIfEquals {
+                key: next(),
+                expected: next(),
+            },
+        };
+        Ok(request)
+    }
+}
+
+impl Response {
+    /// Reads a response from a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The connection to read from.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Response, ProtocolError> {
+        let message: Vec<u8> = match read_frame(reader)? {
+            Some(message) => message,
+            None => {
+                return Err(ProtocolError::FailedIO(
+                    "The connection was closed before the response.".to_string(),
+                ))
+            }
+        };
+        Response::decode(message)
+    }
+
+    /// Writes the response to a connection.
+    ///
+    /// The writer is not flushed, so that several responses can be sent together.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The connection to write to.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        write_frame(writer, &self.encode())
+    }
+
+    /// Returns the status of the response.
+    pub fn status(&self) -> Status {
+        match self {
+            Response::Ok(_) => Status::Ok,
+            Response::NotFound => Status::NotFound,
+            Response::Error(_) => Status::Error,
+        }
+    }
+
+    /// Encodes the response into a message.
+    fn encode(&self) -> Vec<u8> {
+        let body: &[u8] = match self {
+            Response::Ok(value) => value,
+            Response::NotFound => &[],
+            Response::Error(msg) => msg.as_bytes(),
+        };
+        [&[self.status() as u8], body].concat()
+    }
+
+    /// Decodes a response from a message.
+    fn decode(mut message: Vec<u8>) -> Result<Response, ProtocolError> {
+        if message.is_empty() {
+            return Err(invalid("The response has no status."));
+        }
+        let body: Vec<u8> = message.split_off(1);
+        match message[0] {
+            0 => Ok(Response::Ok(body)),
+            1 => Ok(Response::NotFound),
+            2 => Ok(Response::Error(String::from_utf8_lossy(&body).to_string())),
+            status => Err(invalid(&format!("The status {status} is not defined."))),
+        }
+    }
+}
+
+/// Encodes a request message from its command and arguments.
+fn encode_request(command: u8, args: &[&[u8]]) -> Vec<u8> {
+    let mut message: Vec<u8> = vec![command, args.len() as u8];
+    for arg in args {
+        message.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+        message.extend_from_slice(arg);
+    }
+    message
+}
+
+/// Reads a frame and returns its message.
+///
+/// Returns `None` if the connection was closed before the frame started.
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, ProtocolError> {
+    let mut len_bytes: [u8; LENGTH_SIZE] = [0; LENGTH_SIZE];
+    // 1 バイトも読めずに閉じられたときだけ、正常な切断とみなす
+    let mut read: usize = 0;
+    while read < LENGTH_SIZE {
+        match reader.read(&mut len_bytes[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(failed_io("The connection was closed in a frame.")),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(failed_io("The connection timed out waiting for a frame."))
+            }
+            Err(e) => return Err(failed_io(&e.to_string())),
+        }
+    }
+
+    let len: usize = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+    if len == 0 {
+        return Err(invalid("The frame has no version."));
+    }
+
+    // 長さだけ送って来ない相手のために、先に確保せず届いた分だけ伸ばす
+    let mut frame: Vec<u8> = Vec::new();
+    if let Err(e) = reader.take(len as u64).read_to_end(&mut frame) {
+        return Err(failed_io(&e.to_string()));
+    }
+    if frame.len() < len {
+        return Err(failed_io("The connection was closed in a frame."));
+    }
+    if frame[0] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(frame[0]));
+    }
+    Ok(Some(frame.split_off(1)))
+}
+
+/// Writes a message as a frame.
+fn write_frame<W: Write>(writer: &mut W, message: &[u8]) -> Result<(), ProtocolError> {
+    let len: usize = message.len() + 1;
+    if len > MAX_FRAME_SIZE {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+
+    let frame: Vec<u8> = [
+        &(len as u32).to_be_bytes()[..],
+        &[PROTOCOL_VERSION],
+        message,
+    ]
+    .concat();
+    writer
+        .write_all(&frame)
+        .map_err(|e| failed_io(&e.to_string()))
+}
+
+/// Creates an error for an invalid message.
+fn invalid(msg: &str) -> ProtocolError {
+    ProtocolError::InvalidMessage(msg.to_string())
+}
+
+/// Creates an error for a failed read or write.
+fn failed_io(msg: &str) -> ProtocolError {
+    ProtocolError::FailedIO(msg.to_string())
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::*;
+
+    /// Writes a request and reads it back.
+    fn round_trip(request: &Request) -> Request {
+        let mut bytes: Vec<u8> = Vec::new();
+        request.write_to(&mut bytes).unwrap();
+        Request::read_from(&mut bytes.as_slice()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_request() {
+        let requests: Vec<Request> = vec![
+            Request::Get {
+                key: b"k1".to_vec(),
+            },
+            Request::Put {
+                key: b"k1".to_vec(),
+                value: b"a value with spaces".to_vec(),
+            },
+            Request::PutWithTtl {
+                key: b"session".to_vec(),
+                value: b"token".to_vec(),
+                ttl: Duration::from_secs(60),
+            },
+            Request::Delete {
+                key: b"k1".to_vec(),
+            },
+            Request::Ttl {
+                key: b"k1".to_vec(),
+            },
+            Request::CompareAndSwap {
+                key: b"k1".to_vec(),
+                expected: Vec::new(),
+                value: b"v2".to_vec(),
+            },
+            Request::PutIfAbsent {
+                key: b"k1".to_vec(),
+                value: b"v1".to_vec(),
+            },
+            Request::DeleteIfEquals {
+                key: b"k1".to_vec(),
+                expected: b"v1".to_vec(),
+            },
+        ];
+        for request in &requests {
+            assert_eq!(&round_trip(request), request);
+        }
+
+        // 1 KB を超える値や任意のバイト列も切り詰められないケース
+        let request: Request = Request::Put {
+            key: vec![0, 0xff, b' ', b'\n'],
+            value: vec![0; 10_000],
+        };
+        assert_eq!(round_trip(&request), request);
+
+        // 複数のリクエストを続けて読むケース
+        let mut bytes: Vec<u8> = Vec::new();
+        for request in &requests {
+            request.write_to(&mut bytes).unwrap();
+        }
+        let mut reader: &[u8] = bytes.as_slice();
+        for request in &requests {
+            assert_eq!(&Request::read_from(&mut reader).unwrap().unwrap(), request);
+        }
+        assert_eq!(Request::read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_response() {
+        for response in [
+            Response::Ok(b"value".to_vec()),
+            Response::Ok(Vec::new()),
+            Response::NotFound,
+            Response::Error("IOError: something failed.".to_string()),
+        ] {
+            let mut bytes: Vec<u8> = Vec::new();
+            response.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes[LENGTH_SIZE + 1], response.status() as u8);
+            assert_eq!(
+                Response::read_from(&mut bytes.as_slice()).unwrap(),
+                response
+            );
+        }
+
+        // 応答の前に接続が閉じられたケース
+        assert!(matches!(
+            Response::read_from(&mut [].as_slice()),
+            Err(ProtocolError::FailedIO(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        let mut bytes: Vec<u8> = Vec::new();
+        Request::Get {
+            key: b"k1".to_vec(),
+        }
+        .write_to(&mut bytes)
+        .unwrap();
+
+        // フレームの途中で切れたケース
+        assert!(matches!(
+            Request::read_from(&mut &bytes[..bytes.len() - 1]),
+            Err(ProtocolError::FailedIO(_))
+        ));
+        assert!(matches!(
+            Request::read_from(&mut &bytes[..2]),
+            Err(ProtocolError::FailedIO(_))
+        ));
+
+        // 未対応のバージョンのケース
+        let mut other: Vec<u8> = bytes.clone();
+        other[LENGTH_SIZE] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Request::read_from(&mut other.as_slice()),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        // 長さだけ大きく、中身が届かないフレームのケース
+        struct Recorder<'a> {
+            bytes: &'a [u8],
+            largest_buf: usize,
+        }
+        impl Read for Recorder<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.largest_buf = self.largest_buf.max(buf.len());
+                self.bytes.read(buf)
+            }
+        }
+        let claimed: Vec<u8> = [
+            &(MAX_FRAME_SIZE as u32).to_be_bytes()[..],
+            &[PROTOCOL_VERSION, 1, 0],
+        ]
+        .concat();
+        let mut recorder: Recorder = Recorder {
+            bytes: &claimed,
+            largest_buf: 0,
+        };
+        assert!(matches!(
+            Request::read_from(&mut recorder),
+            Err(ProtocolError::FailedIO(_))
+        ));
+        assert!(recorder.largest_buf < 1024 * 1024);
+
+        // 大きすぎるフレームのケース
+        let large: Vec<u8> = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes().to_vec();
+        assert_eq!(
+            Request::read_from(&mut large.as_slice()),
+            Err(ProtocolError::FrameTooLarge(MAX_FRAME_SIZE + 1))
+        );
+
+        // 未定義のコマンドと引数の数が違うケース
+        for message in [vec![9, 0], vec![1, 0], vec![1, 1, 0, 0, 0, 5, b'k'], vec![]] {
+            let mut bytes: Vec<u8> = Vec::new();
+            write_frame(&mut bytes, &message).unwrap();
+            assert!(matches!(
+                Request::read_from(&mut bytes.as_slice()),
+                Err(ProtocolError::InvalidMessage(_))
+            ));
+        }
+    }
+}
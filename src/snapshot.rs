@@ -0,0 +1,260 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Bound, RangeBounds},
+    sync::{atomic::Ordering, Mutex, MutexGuard},
+};
+
+use crate::{error::KVSError, iterator::KVSIterator, value::Value, KVS};
+
+/// The sequence numbers of the live snapshots of a store.
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    /// The number of live snapshots at each sequence number.
+    seqs: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    /// Returns the sequence numbers of the live snapshots in ascending order.
+    pub fn sequences(&self) -> Vec<u64> {
+        self.lock().keys().copied().collect()
+    }
+
+    /// Runs a write with the sequence numbers of the live snapshots in ascending order.
+    ///
+    /// No snapshot can be taken until the write finishes, so a new snapshot sees
+    /// either all of the write or none of it, and never a version the write dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `write` - The write, which gets the sequence numbers.
+    pub fn write<T>(&self, write: impl FnOnce(&[u64]) -> T) -> T {
+        let seqs = self.lock();
+        let sequences: Vec<u64> = seqs.keys().copied().collect();
+        write(&sequences)
+    }
+
+    /// Locks the sequence numbers.
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, usize>> {
+        match self.seqs.lock() {
+            Ok(seqs) => seqs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Unregisters a snapshot at a sequence number.
+    fn release(&self, seq: u64) {
+        let mut seqs = self.lock();
+        if let Some(count) = seqs.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                seqs.remove(&seq);
+            }
+        }
+    }
+}
+
+/// A consistent view of a `KVS` as of the moment it was taken.
+///
+/// Reads through a snapshot see only the writes made before it was taken.
+/// The store keeps the versions a snapshot can read until the snapshot is dropped.
+/// A snapshot holds a clone of the `KVS`, so the store stays open while the snapshot lives.
+///
+/// ```no_run
+/// use kvsd::KVS;
+///
+/// let kvs = KVS::new().unwrap();
+/// kvs.put("k1", "v1").unwrap();
+/// let snapshot = kvs.snapshot();
+/// kvs.put("k1", "v2").unwrap();
+///
+/// assert_eq!(snapshot.get("k1").unwrap().unwrap().to_string(), "v1");
+/// ```
+pub struct Snapshot {
+    /// The largest sequence number the snapshot sees.
+    seq: u64,
+    /// The store the snapshot was taken from.
+    kvs: KVS,
+}
+
+impl Snapshot {
+    /// Creates a new `Snapshot` at the last visible sequence number of a store
+    /// and registers it in the store's list.
+    ///
+    /// The sequence number is read while the list is locked, so that no write is half applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `kvs` - The store to take the snapshot from.
+    pub(crate) fn latest(kvs: &KVS) -> Self {
+        let mut seqs = kvs.inner.snapshots.lock();
+        let seq: u64 = kvs.inner.visible_seq.load(Ordering::SeqCst);
+        *seqs.entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            kvs: kvs.clone(),
+        }
+    }
+
+    /// Returns the largest sequence number the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Retrieves a value by its key as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get(&self, key: &str) -> Result<Option<Value>, KVSError> {
+        self.get_bytes(key.as_bytes())
+    }
+
+    /// Retrieves a value by its binary key as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Value>, KVSError> {
+        self.kvs.get_at(key, self.seq)
+    }
+
+    /// Returns an iterator over the key-value pairs whose keys are in the range as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of keys, e.g. `"a".."c"` or `b"a".to_vec()..`.
+    pub fn scan<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<KVSIterator, KVSError> {
+        self.kvs.iter(crate::key_range(&range), None, self.clone())
+    }
+
+    /// Returns an iterator over all the key-value pairs as of the snapshot, in key order.
+    ///
+    /// # Arguments
+    ///
+    pub fn scan_all(&self) -> Result<KVSIterator, KVSError> {
+        self.kvs
+            .iter((Bound::Unbounded, Bound::Unbounded), None, self.clone())
+    }
+
+    /// Returns an iterator over the key-value pairs whose binary keys are in the range as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of keys, e.g. `&b"a"[..]..&b"c"[..]`.
+    pub fn scan_bytes<'k, R>(&self, range: R) -> Result<KVSIterator, KVSError>
+    where
+        R: RangeBounds<&'k [u8]>,
+    {
+        self.kvs.iter(crate::key_range(&range), None, self.clone())
+    }
+
+    /// Returns an iterator over the key-value pairs whose keys start with the prefix as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The prefix of the keys.
+    pub fn prefix(&self, prefix: &str) -> Result<KVSIterator, KVSError> {
+        self.prefix_bytes(prefix.as_bytes())
+    }
+
+    /// Returns an iterator over the key-value pairs whose binary keys start with the prefix as of the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The prefix of the keys.
+    pub fn prefix_bytes(&self, prefix: &[u8]) -> Result<KVSIterator, KVSError> {
+        let range = crate::prefix_range(prefix);
+        self.kvs.iter(range, Some(prefix.to_vec()), self.clone())
+    }
+}
+
+impl Clone for Snapshot {
+    /// Registers another snapshot at the same sequence number.
+    fn clone(&self) -> Self {
+        *self.kvs.inner.snapshots.lock().entry(self.seq).or_insert(0) += 1;
+        Snapshot {
+            seq: self.seq,
+            kvs: self.kvs.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot").field("seq", &self.seq).finish()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.kvs.inner.snapshots.release(self.seq);
+    }
+}
+
+/// Returns `true` if a version of a key is still read by the latest reads or by a snapshot.
+///
+/// A version is read at sequence numbers from its own up to the next newer version of the key.
+/// The newest version is always read.
+///
+/// # Arguments
+///
+/// * `seq` - The sequence number of the version.
+/// * `newer_seq` - The sequence number of the next newer version of the key, if any.
+/// * `snapshots` - The sequence numbers of the live snapshots in ascending order.
+pub fn is_visible(seq: u64, newer_seq: Option<u64>, snapshots: &[u64]) -> bool {
+    let newer_seq: u64 = match newer_seq {
+        Some(newer_seq) => newer_seq,
+        None => return true,
+    };
+    let i: usize = snapshots.partition_point(|s| *s < seq);
+    snapshots.get(i).is_some_and(|s| *s < newer_seq)
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{snapshot::*, test_util, KVSOptions};
+
+    #[test]
+    fn test_snapshot_list() {
+        let data_dir: PathBuf = test_util::temp_dir("snapshot_list");
+        let kvs: KVS = KVSOptions::new().data_dir(&data_dir).open().unwrap();
+        let list: Arc<SnapshotList> = Arc::clone(&kvs.inner.snapshots);
+        for i in 0..3 {
+            kvs.put(&format!("k{i}"), "v").unwrap();
+        }
+        let s2 = kvs.snapshot();
+        kvs.put("k3", "v").unwrap();
+        kvs.put("k4", "v").unwrap();
+        let s1 = kvs.snapshot();
+        let s3 = kvs.snapshot();
+        assert_eq!(list.sequences(), vec![3, 5]);
+
+        drop(s1);
+        assert_eq!(list.sequences(), vec![3, 5]);
+        drop(s3);
+        assert_eq!(list.sequences(), vec![3]);
+        assert_eq!(s2.seq(), 3);
+        drop(s2);
+        assert!(list.sequences().is_empty());
+    }
+
+    #[test]
+    fn test_is_visible() {
+        // 最新のバージョンは常に読まれる
+        assert!(is_visible(5, None, &[]));
+
+        // 古いバージョンは間にスナップショットがある場合だけ読まれる
+        assert!(!is_visible(2, Some(5), &[]));
+        assert!(is_visible(2, Some(5), &[2]));
+        assert!(is_visible(2, Some(5), &[1, 4]));
+        assert!(!is_visible(2, Some(5), &[1, 5, 6]));
+    }
+}
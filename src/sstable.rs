@@ -1,111 +1,750 @@
 use std::{
-    collections::{BTreeMap, HashMap},
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Bound,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    vec,
 };
 
 use crate::{
+    bloom::{self, BloomFilter},
+    compression::{self, Compression},
     error::{ConvertError, IOError, KVSError},
-    file_io::{get_filesize, read_key_value, write_key_value},
+    file_io::{decode_key_value, encode_key_value},
+    iterator::Entry,
+    memtable::Memtable,
+    options::KVSOptions,
     value::Value,
 };
 
+/// The magic number at the end of every SSTable file.
+pub(crate) const MAGIC: u64 = 0x4B56_5344_5353_5442; // "KVSDSSTB"
+/// The version of the SSTable format.
+///
+/// Files of any other version are not read. The data files of the earlier release,
+/// which have no trailer, are rewritten by `KVSOptions::upgrade`.
+const FORMAT_VERSION: u32 = 1;
+/// The size of the trailer:
+/// index offset (8) | index size (8) | meta offset (8) | meta size (8) | filter offset (8) | filter size (8) | version (4) | magic (8)
+const TRAILER_SIZE: usize = 60;
+
 /// Represents a Sorted String Table (SSTable).
+///
+/// The layout of the data file is:
+///
+/// ```text
+/// | data block | data block | ... | index block | meta block | filter block | trailer |
+/// ```
+///
+/// - A data block holds entries in key order and is about `block_size` bytes before compression.
+///   An entry is: sequence number (8 bytes) | key-value pair
+///   If the table is compressed, each block is compressed on its own by `compression::compress_block`.
+/// - The index block holds the last key, the offset and the size of every data block.
+/// - The meta block holds the number of entries, the smallest and largest keys, the level,
+///   the largest sequence number, the compression and the size of the data blocks before compression.
+/// - The filter block holds a bloom filter of the keys. It is empty when filters are disabled.
+/// - The trailer holds the positions of the other blocks, the format version and a magic number.
+///
+/// Opening an SSTable reads only the trailer, the meta block, the index block and the filter block.
 #[derive(Debug)]
 pub struct SSTable {
     /// The path to the data file.
     pub data_path: PathBuf,
-    /// The in-memory index of keys to their offsets in the data file.
-    index: HashMap<String, usize>,
+    /// The sparse index with one entry per data block, ordered by key.
+    index: Vec<BlockHandle>,
+    /// The metadata of the table.
+    meta: Metadata,
+    /// The bloom filter of the keys, if the table has one.
+    filter: Option<BloomFilter>,
+    /// The size of the data file in bytes.
+    file_size: u64,
+    /// Whether the table was replaced by a compaction, so that its file is removed when it is dropped.
+    obsolete: AtomicBool,
+}
+
+/// The position of a data block and the last key in it.
+#[derive(Debug, Clone, PartialEq)]
+struct BlockHandle {
+    /// The largest key in the block.
+    last_key: Vec<u8>,
+    /// The offset of the block in the data file.
+    offset: u64,
+    /// The size of the block in bytes.
+    size: u64,
+}
+
+/// The metadata of an SSTable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// The number of entries in the table.
+    pub entry_count: u64,
+    /// The smallest key in the table.
+    pub min_key: Vec<u8>,
+    /// The largest key in the table.
+    pub max_key: Vec<u8>,
+    /// The level of the table in the LSM tree.
+    pub level: u32,
+    /// The largest sequence number of the entries in the table.
+    pub max_seq: u64,
+    /// The compression of the data blocks.
+    ///
+    /// Blocks that did not get smaller are stored uncompressed whatever this says.
+    pub compression: Compression,
+    /// The total size of the data blocks before compression in bytes.
+    pub raw_data_size: u64,
 }
 
 impl SSTable {
-    /// Creates a new SSTable from a memtable.
+    /// Creates a new SSTable in level 0 from a memtable.
     ///
     /// # Arguments
     ///
     /// * `data_dir` - The directory to store the data file in.
     /// * `memtable` - The memtable to create the SSTable from.
     /// * `filename` - The name of the data file.
+    /// * `options` - The options of the store.
     pub fn create(
         data_dir: &Path,
-        memtable: &BTreeMap<String, Value>,
+        memtable: &Memtable,
         filename: &str,
+        options: &KVSOptions,
     ) -> Result<Self, IOError> {
-        let mut data_path: PathBuf = data_dir.to_path_buf();
-        data_path.push(format!("{filename}.dat"));
-        let mut data_writer: BufWriter<File> = get_bufwriter(&data_path)?;
-
-        let mut pointer: usize = 0;
-        let mut index: HashMap<String, usize> = HashMap::new();
-        for (k, v) in memtable.iter() {
-            index.insert(k.to_string(), pointer);
-            pointer = write_key_value(&mut data_writer, k, v)?;
+        let mut builder: SSTableBuilder = SSTableBuilder::new(data_dir, filename, 0, options)?;
+        for (k, seq, v) in memtable.iter() {
+            builder.add(k, seq, v)?;
         }
-
-        Ok(SSTable { data_path, index })
+        builder.finish()
     }
 
     /// Loads an SSTable from a file.
     ///
+    /// Only the trailer, the meta block and the index block are read.
+    ///
     /// # Arguments
     ///
     /// * `path` - The path to the data file.
     pub fn from_file(path: PathBuf) -> Result<Self, KVSError> {
         let mut buf_reader: BufReader<File> = get_bufreader(&path)?;
-        let file_size: usize = get_filesize(&path)?;
+        let file_size: u64 = match buf_reader.get_ref().metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                return Err(KVSError::FailedIO(IOError::FailedGetFileSize(
+                    path,
+                    e.to_string(),
+                )))
+            }
+        };
 
-        let mut offset: usize = 0;
-        let mut index: HashMap<String, usize> = HashMap::new();
+        let trailer: Vec<u8> = read_tail(&mut buf_reader, TRAILER_SIZE)
+            .map_err(|_| invalid(&path, "The file is smaller than the trailer."))?;
+        let magic: u64 = read_u64(&trailer, 52);
+        if magic != MAGIC {
+            return Err(invalid(&path, "The magic number does not match."));
+        }
+        let version: u32 = u32::from_be_bytes(trailer[48..52].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(invalid(
+                &path,
+                &format!("The version {version} is not supported."),
+            ));
+        }
 
-        while offset < file_size {
-            let (key_bytes, value_bytes) = read_key_value(&mut buf_reader, offset)?;
+        let index_handle: (u64, u64) = (read_u64(&trailer, 0), read_u64(&trailer, 8));
+        let meta_handle: (u64, u64) = (read_u64(&trailer, 16), read_u64(&trailer, 24));
+        // 壊れた位置から大きな領域を確保しないよう、読む前にファイルに収まるか確かめる
+        let blocks_end: u64 = file_size - TRAILER_SIZE as u64;
+        check_handle(&path, "index", index_handle, blocks_end)?;
+        check_handle(&path, "meta", meta_handle, blocks_end)?;
 
-            let key: String = match String::from_utf8(key_bytes) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(KVSError::FailedConvert(ConvertError::FailedBytesToString(
-                        e.to_string(),
-                    )))
-                }
-            };
+        let index_bytes: Vec<u8> = read_at(&mut buf_reader, index_handle.0, index_handle.1)?;
+        let index: Vec<BlockHandle> = match decode_index(&index_bytes) {
+            Some(index) => index,
+            None => return Err(invalid(&path, "The index block is broken.")),
+        };
+        for handle in index.iter() {
+            check_handle(&path, "data", (handle.offset, handle.size), index_handle.0)?;
+        }
 
-            let key_len = key.len();
-            let value_len = Value::from_bytes(value_bytes)?.len();
-            index.insert(key, offset);
+        let meta_bytes: Vec<u8> = read_at(&mut buf_reader, meta_handle.0, meta_handle.1)?;
+        let meta: Metadata = match decode_meta(&meta_bytes) {
+            Some(meta) => meta,
+            None => return Err(invalid(&path, "The meta block is broken.")),
+        };
 
-            offset += key_len + value_len + 9;
-        }
+        let filter_handle: (u64, u64) = (read_u64(&trailer, 32), read_u64(&trailer, 40));
+        check_handle(&path, "filter", filter_handle, blocks_end)?;
+        let filter_bytes: Vec<u8> = read_at(&mut buf_reader, filter_handle.0, filter_handle.1)?;
+        let filter: Option<BloomFilter> = BloomFilter::from_bytes(&filter_bytes);
 
         Ok(SSTable {
             data_path: path,
             index,
+            meta,
+            filter,
+            file_size,
+            obsolete: AtomicBool::new(false),
         })
     }
 
-    /// Gets a value from the SSTable by its key.
+    /// Returns the name of the data file.
+    pub fn filename(&self) -> String {
+        match self.data_path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::new(),
+        }
+    }
+
+    /// Returns the metadata of the SSTable.
+    pub fn meta(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// Returns the size of the data file in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the total size of the data blocks in the file in bytes, after compression.
+    pub fn data_size(&self) -> u64 {
+        self.index.last().map_or(0, |h| h.offset + h.size)
+    }
+
+    /// Returns the bloom filter of the keys, if the table has one.
+    pub fn filter(&self) -> Option<&BloomFilter> {
+        self.filter.as_ref()
+    }
+
+    /// Marks the table as replaced, so that its data file is removed once the table is dropped.
+    ///
+    /// Readers that still hold the table can keep reading it until then.
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::Release);
+    }
+
+    /// Gets the newest version of a key whose sequence number is at most `seq`,
+    /// together with its sequence number.
+    ///
+    /// Usually one data block is read. More are read only when the versions of the key
+    /// continue into the next block.
     ///
     /// # Arguments
     ///
     /// * `key` - The key to get.
-    pub fn get(&self, key: &str) -> Result<Option<Value>, KVSError> {
-        let pointer = match self.index.get(key) {
-            Some(p) => *p,
-            None => return Ok(None),
-        };
+    /// * `seq` - The largest sequence number to read.
+    pub fn get(&self, key: &[u8], seq: u64) -> Result<Option<(u64, Value)>, KVSError> {
+        if self.meta.entry_count == 0 || key < self.meta.min_key.as_slice() {
+            return Ok(None);
+        }
 
+        let i: usize = self.index.partition_point(|h| h.last_key.as_slice() < key);
         let mut buf_reader: BufReader<File> = get_bufreader(&self.data_path)?;
+        for handle in self.index[i..].iter() {
+            for (k, s, v) in read_block(&mut buf_reader, handle, self.meta.compression)? {
+                if key < k.as_slice() {
+                    return Ok(None);
+                }
+                if k == key && s <= seq {
+                    return Ok(Some((s, v)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over the entries whose keys are in the range, in key order.
+    ///
+    /// Data blocks are read one at a time as the iterator advances.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The start and end bounds of the keys.
+    pub fn range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Result<SSTableIterator, IOError> {
+        let reader: BufReader<File> = get_bufreader(&self.data_path)?;
 
-        let (_, bytes) = read_key_value(&mut buf_reader, pointer)?;
-        let value: Value = Value::from_bytes(bytes)?;
+        // 範囲の先頭のキーを含むブロックから読み始める
+        let first: usize = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                self.index.partition_point(|h| h.last_key < *start)
+            }
+            Bound::Unbounded => 0,
+        };
+        let blocks: Vec<BlockHandle> = self.index[first..].to_vec();
 
-        Ok(Some(value))
+        Ok(SSTableIterator {
+            reader,
+            blocks: blocks.into_iter(),
+            entries: Vec::new().into_iter(),
+            range,
+            compression: self.meta.compression,
+        })
     }
+}
 
-    /// Returns a list of all keys in the SSTable.
-    pub fn keys(&self) -> Vec<&String> {
-        self.index.keys().collect::<Vec<&String>>()
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        // 削除に失敗したファイルは、次に開いたときにマニフェストにないファイルとして消される
+        if self.obsolete.load(Ordering::Acquire) {
+            let _ = std::fs::remove_file(&self.data_path);
+        }
+    }
+}
+
+/// Writes an SSTable one key-value pair at a time.
+///
+/// Keys must be added in ascending order, and the versions of a key from newest to oldest.
+/// Only the current data block and the index are kept in memory.
+pub struct SSTableBuilder {
+    /// The path to the data file.
+    data_path: PathBuf,
+    /// The writer of the data file.
+    writer: BufWriter<File>,
+    /// The target size of a data block in bytes.
+    block_size: usize,
+    /// The data block being built.
+    block: Vec<u8>,
+    /// The last key added to the data block being built.
+    last_key: Vec<u8>,
+    /// The offset where the data block being built starts.
+    offset: u64,
+    /// The handles of the finished data blocks.
+    index: Vec<BlockHandle>,
+    /// The number of entries added.
+    entry_count: u64,
+    /// The first key added.
+    min_key: Option<Vec<u8>>,
+    /// The level of the table in the LSM tree.
+    level: u32,
+    /// The largest sequence number added.
+    max_seq: u64,
+    /// The number of bits per key of the bloom filter. 0 disables the filter.
+    bloom_bits_per_key: usize,
+    /// The hashes of the keys added, used to build the bloom filter.
+    key_hashes: Vec<u64>,
+    /// The compression of the data blocks.
+    compression: Compression,
+    /// The compression level from 1 (fastest) to 9 (smallest).
+    compression_level: u32,
+    /// The total size of the finished data blocks before compression.
+    raw_data_size: u64,
+}
+
+impl SSTableBuilder {
+    /// Creates a new `SSTableBuilder`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` - The directory to store the data file in.
+    /// * `filename` - The name of the data file.
+    /// * `level` - The level of the table in the LSM tree.
+    /// * `options` - The options of the store.
+    pub fn new(
+        data_dir: &Path,
+        filename: &str,
+        level: u32,
+        options: &KVSOptions,
+    ) -> Result<Self, IOError> {
+        let mut data_path: PathBuf = data_dir.to_path_buf();
+        data_path.push(format!("{filename}.dat"));
+        let writer: BufWriter<File> = get_bufwriter(&data_path)?;
+
+        Ok(SSTableBuilder {
+            data_path,
+            writer,
+            block_size: options.block_size,
+            block: Vec::new(),
+            last_key: Vec::new(),
+            offset: 0,
+            index: Vec::new(),
+            entry_count: 0,
+            min_key: None,
+            level,
+            max_seq: 0,
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            key_hashes: Vec::new(),
+            compression: options.compression,
+            compression_level: options.compression_level,
+            raw_data_size: 0,
+        })
+    }
+
+    /// Adds a key-value pair to the table.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key, which must not be smaller than the keys added before.
+    /// * `seq` - The sequence number of the write, smaller than that of the version added before.
+    /// * `value` - The value.
+    pub fn add(&mut self, key: &[u8], seq: u64, value: &Value) -> Result<(), IOError> {
+        // 同じキーの古いバージョンはフィルタに重ねて登録しない
+        let is_new_key: bool = self.entry_count == 0 || self.last_key != key;
+        if self.min_key.is_none() {
+            self.min_key = Some(key.to_vec());
+        }
+
+        self.block.extend(seq.to_be_bytes());
+        self.block.extend(encode_key_value(key, value));
+        self.max_seq = self.max_seq.max(seq);
+        self.last_key = key.to_vec();
+        self.entry_count += 1;
+        if 0 < self.bloom_bits_per_key && is_new_key {
+            self.key_hashes.push(bloom::hash(key));
+        }
+
+        if self.block_size <= self.block.len() {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the approximate size of the data file so far in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Compresses the data block being built and writes it to the file.
+    fn finish_block(&mut self) -> Result<(), IOError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        // 圧縮しないテーブルのブロックには、圧縮の種類の 1 バイトも付けない
+        let bytes: Vec<u8> = match self.compression {
+            Compression::None => std::mem::take(&mut self.block),
+            compression => {
+                compression::compress_block(compression, self.compression_level, &self.block)
+            }
+        };
+        write_bytes(&mut self.writer, &bytes)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            size: bytes.len() as u64,
+        });
+        self.offset += bytes.len() as u64;
+        self.raw_data_size += match self.compression {
+            Compression::None => bytes.len() as u64,
+            _ => self.block.len() as u64,
+        };
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the index block, the meta block, the filter block and the trailer, and syncs the file.
+    pub fn finish(mut self) -> Result<SSTable, IOError> {
+        self.finish_block()?;
+
+        let meta: Metadata = Metadata {
+            entry_count: self.entry_count,
+            min_key: self.min_key.take().unwrap_or_default(),
+            max_key: self.last_key.clone(),
+            level: self.level,
+            max_seq: self.max_seq,
+            compression: self.compression,
+            raw_data_size: self.raw_data_size,
+        };
+
+        let filter: Option<BloomFilter> = match self.bloom_bits_per_key {
+            0 => None,
+            bits_per_key => Some(BloomFilter::build(&self.key_hashes, bits_per_key)),
+        };
+
+        let index_bytes: Vec<u8> = encode_index(&self.index);
+        let meta_bytes: Vec<u8> = encode_meta(&meta);
+        let filter_bytes: Vec<u8> = filter.as_ref().map(|f| f.to_bytes()).unwrap_or_default();
+        let index_offset: u64 = self.offset;
+        let meta_offset: u64 = index_offset + index_bytes.len() as u64;
+        let filter_offset: u64 = meta_offset + meta_bytes.len() as u64;
+
+        let trailer: Vec<u8> = [
+            &index_offset.to_be_bytes()[..],
+            &(index_bytes.len() as u64).to_be_bytes(),
+            &meta_offset.to_be_bytes(),
+            &(meta_bytes.len() as u64).to_be_bytes(),
+            &filter_offset.to_be_bytes(),
+            &(filter_bytes.len() as u64).to_be_bytes(),
+            &FORMAT_VERSION.to_be_bytes(),
+            &MAGIC.to_be_bytes(),
+        ]
+        .concat();
+
+        write_bytes(&mut self.writer, &index_bytes)?;
+        write_bytes(&mut self.writer, &meta_bytes)?;
+        write_bytes(&mut self.writer, &filter_bytes)?;
+        write_bytes(&mut self.writer, &trailer)?;
+
+        if let Err(e) = self.writer.flush() {
+            return Err(IOError::FailedWriteBytes(e.to_string()));
+        }
+        // WAL を消す前にデータファイルをディスクに書き込む
+        if let Err(e) = self.writer.get_ref().sync_all() {
+            return Err(IOError::FailedSync(e.to_string()));
+        }
+
+        Ok(SSTable {
+            data_path: self.data_path,
+            index: self.index,
+            meta,
+            filter,
+            file_size: filter_offset + filter_bytes.len() as u64 + trailer.len() as u64,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+}
+
+/// An iterator over the entries of an SSTable in key order.
+pub struct SSTableIterator {
+    /// The reader of the data file.
+    reader: BufReader<File>,
+    /// The data blocks left to read.
+    blocks: vec::IntoIter<BlockHandle>,
+    /// The entries of the current data block left to return.
+    entries: vec::IntoIter<(Vec<u8>, u64, Value)>,
+    /// The start and end bounds of the keys.
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    /// The compression of the data blocks.
+    compression: Compression,
+}
+
+impl Iterator for SSTableIterator {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, seq, value)) = self.entries.next() {
+                let after_start: bool = match &self.range.0 {
+                    Bound::Included(start) => *start <= key,
+                    Bound::Excluded(start) => *start < key,
+                    Bound::Unbounded => true,
+                };
+                if !after_start {
+                    continue;
+                }
+
+                let before_end: bool = match &self.range.1 {
+                    Bound::Included(end) => key <= *end,
+                    Bound::Excluded(end) => key < *end,
+                    Bound::Unbounded => true,
+                };
+                if !before_end {
+                    self.blocks = Vec::new().into_iter();
+                    self.entries = Vec::new().into_iter();
+                    return None;
+                }
+
+                return Some(Ok((key, seq, value)));
+            }
+
+            let handle: BlockHandle = self.blocks.next()?;
+            match read_block(&mut self.reader, &handle, self.compression) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Reads a data block, decompresses it and decodes its entries.
+///
+/// Blocks of tables without compression are read as they are.
+fn read_block(
+    buf_reader: &mut BufReader<File>,
+    handle: &BlockHandle,
+    compression: Compression,
+) -> Result<Vec<(Vec<u8>, u64, Value)>, KVSError> {
+    let mut bytes: Vec<u8> = read_at(buf_reader, handle.offset, handle.size)?;
+    if compression != Compression::None {
+        bytes = match compression::decompress_block(&bytes) {
+            Some(bytes) => bytes,
+            None => {
+                return Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(
+                    "A data block cannot be decompressed.".to_string(),
+                )))
+            }
+        };
+    }
+
+    let mut offset: usize = 0;
+    let mut entries: Vec<(Vec<u8>, u64, Value)> = Vec::new();
+    while offset < bytes.len() {
+        let seq: u64 = match bytes.get(offset..offset + 8) {
+            Some(b) => {
+                offset += 8;
+                u64::from_be_bytes(b.try_into().unwrap())
+            }
+            None => {
+                return Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(
+                    "A data block is truncated.".to_string(),
+                )))
+            }
+        };
+        let (key_bytes, value_bytes, next) = match decode_key_value(&bytes, offset) {
+            Some(decoded) => decoded,
+            None => {
+                return Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(
+                    "A data block is truncated.".to_string(),
+                )))
+            }
+        };
+
+        entries.push((key_bytes, seq, Value::from_bytes(value_bytes)?));
+        offset = next;
+    }
+    Ok(entries)
+}
+
+/// Returns the name of the data file of an SSTable, without the extension.
+///
+/// # Arguments
+///
+/// * `number` - A number taken from the sequence of the store, so that no two tables share it.
+pub fn filename_for(number: u64) -> String {
+    format!("{number:06}")
+}
+
+/// Encodes the index block.
+///
+/// The format of each entry is: key length (8 bytes) | last key | offset (8 bytes) | size (8 bytes)
+fn encode_index(index: &[BlockHandle]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for handle in index {
+        bytes.extend(encode_bytes(&handle.last_key));
+        bytes.extend(handle.offset.to_be_bytes());
+        bytes.extend(handle.size.to_be_bytes());
+    }
+    bytes
+}
+
+/// Decodes the index block.
+fn decode_index(bytes: &[u8]) -> Option<Vec<BlockHandle>> {
+    let mut offset: usize = 0;
+    let mut index: Vec<BlockHandle> = Vec::new();
+    while offset < bytes.len() {
+        let (last_key, next) = decode_bytes(bytes, offset)?;
+        let block_offset: u64 = u64::from_be_bytes(bytes.get(next..next + 8)?.try_into().ok()?);
+        let size: u64 = u64::from_be_bytes(bytes.get(next + 8..next + 16)?.try_into().ok()?);
+        index.push(BlockHandle {
+            last_key,
+            offset: block_offset,
+            size,
+        });
+        offset = next + 16;
+    }
+    Some(index)
+}
+
+/// Encodes the meta block.
+///
+/// The format is: entry count (8 bytes) | min key length (8 bytes) | min key | max key length (8 bytes) | max key | level (4 bytes) | max seq (8 bytes) | compression (1 byte) | raw data size (8 bytes)
+fn encode_meta(meta: &Metadata) -> Vec<u8> {
+    [
+        meta.entry_count.to_be_bytes().to_vec(),
+        encode_bytes(&meta.min_key),
+        encode_bytes(&meta.max_key),
+        meta.level.to_be_bytes().to_vec(),
+        meta.max_seq.to_be_bytes().to_vec(),
+        vec![meta.compression.id()],
+        meta.raw_data_size.to_be_bytes().to_vec(),
+    ]
+    .concat()
+}
+
+/// Decodes the meta block.
+fn decode_meta(bytes: &[u8]) -> Option<Metadata> {
+    let entry_count: u64 = u64::from_be_bytes(bytes.get(0..8)?.try_into().ok()?);
+    let (min_key, offset) = decode_bytes(bytes, 8)?;
+    let (max_key, offset) = decode_bytes(bytes, offset)?;
+
+    let level: u32 = u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?);
+    let max_seq: u64 = u64::from_be_bytes(bytes.get(offset + 4..offset + 12)?.try_into().ok()?);
+    let compression: Compression = Compression::from_id(*bytes.get(offset + 12)?)?;
+    let raw_data_size: u64 =
+        u64::from_be_bytes(bytes.get(offset + 13..offset + 21)?.try_into().ok()?);
+
+    Some(Metadata {
+        entry_count,
+        min_key,
+        max_key,
+        level,
+        max_seq,
+        compression,
+        raw_data_size,
+    })
+}
+
+/// Encodes bytes with an 8-byte length prefix.
+fn encode_bytes(b: &[u8]) -> Vec<u8> {
+    [&b.len().to_be_bytes(), b].concat()
+}
+
+/// Decodes bytes with an 8-byte length prefix at a given offset.
+fn decode_bytes(bytes: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+    let length: usize = usize::from_be_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?);
+    let end: usize = (offset + 8).checked_add(length)?;
+    Some((bytes.get(offset + 8..end)?.to_vec(), end))
+}
+
+/// Reads a big-endian `u64` at a given offset.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns an error telling that an SSTable file is invalid.
+fn invalid(path: &Path, msg: &str) -> KVSError {
+    KVSError::FailedConvert(ConvertError::InvalidSSTable(format!("{path:?}: {msg}")))
+}
+
+/// Checks that a block lies within the given end of the file.
+///
+/// # Arguments
+///
+/// * `path` - The path to the data file.
+/// * `name` - The name of the block, used in the error message.
+/// * `handle` - The offset and the size of the block.
+/// * `end` - The offset the block must end at or before.
+fn check_handle(path: &Path, name: &str, handle: (u64, u64), end: u64) -> Result<(), KVSError> {
+    match handle.0.checked_add(handle.1) {
+        Some(block_end) if block_end <= end => Ok(()),
+        _ => Err(invalid(
+            path,
+            &format!("The {name} block is outside the file."),
+        )),
+    }
+}
+
+/// Reads bytes at a given offset.
+fn read_at(buf_reader: &mut BufReader<File>, offset: u64, size: u64) -> Result<Vec<u8>, IOError> {
+    if let Err(e) = buf_reader.seek(SeekFrom::Start(offset)) {
+        return Err(IOError::FailedSeek(e.to_string()));
+    }
+    read_exact(buf_reader, size as usize)
+}
+
+/// Reads the given number of bytes at the end of the file.
+fn read_tail(buf_reader: &mut BufReader<File>, size: usize) -> Result<Vec<u8>, IOError> {
+    if let Err(e) = buf_reader.seek(SeekFrom::End(-(size as i64))) {
+        return Err(IOError::FailedSeek(e.to_string()));
+    }
+    read_exact(buf_reader, size)
+}
+
+/// Reads exactly the given number of bytes.
+fn read_exact(buf_reader: &mut BufReader<File>, size: usize) -> Result<Vec<u8>, IOError> {
+    let mut bytes: Vec<u8> = vec![0; size];
+    match buf_reader.read_exact(&mut bytes) {
+        Ok(_) => Ok(bytes),
+        Err(e) => Err(IOError::FailedReadFile(e.to_string())),
+    }
+}
+
+/// Writes all bytes to a buffered writer.
+fn write_bytes(buf_writer: &mut BufWriter<File>, bytes: &[u8]) -> Result<(), IOError> {
+    match buf_writer.write_all(bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(IOError::FailedWriteBytes(e.to_string())),
     }
 }
 
@@ -124,3 +763,253 @@ fn get_bufreader(path: &PathBuf) -> Result<BufReader<File>, IOError> {
         Err(e) => Err(IOError::FailedOpenFile(path.clone(), e.to_string())),
     }
 }
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::sstable::*;
+
+    fn memtable(n: usize) -> Memtable {
+        let mut memtable: Memtable = Memtable::new();
+        for i in 0..n {
+            let value: Value = Value::new(format!("value{i}"), i % 7 == 0);
+            memtable.insert(format!("key{i:03}").as_bytes(), i as u64 + 1, value, &[]);
+        }
+        memtable
+    }
+
+    #[test]
+    fn test_create_get() {
+        let data_dir: PathBuf = crate::test_util::temp_dir("sstable_get");
+        let options: KVSOptions = KVSOptions::new().block_size(64);
+        let memtable: Memtable = memtable(100);
+
+        let sstable: SSTable = SSTable::create(&data_dir, &memtable, "1", &options).unwrap();
+        assert!(sstable.index.len() > 1);
+        assert_eq!(
+            &sstable.meta,
+            &Metadata {
+                entry_count: 100,
+                min_key: b"key000".to_vec(),
+                max_key: b"key099".to_vec(),
+                level: 0,
+                max_seq: 100,
+                compression: Compression::None,
+                raw_data_size: sstable.data_size(),
+            }
+        );
+
+        for (k, seq, v) in memtable.iter() {
+            assert_eq!(sstable.get(k, u64::MAX).unwrap(), Some((seq, v.clone())));
+            assert_eq!(sstable.get(k, seq - 1).unwrap(), None);
+        }
+        assert_eq!(sstable.get(b"aaa", u64::MAX).unwrap(), None);
+        assert_eq!(sstable.get(b"key0505", u64::MAX).unwrap(), None);
+        assert_eq!(sstable.get(b"zzz", u64::MAX).unwrap(), None);
+    }
+
+    #[test]
+    fn test_from_file() {
+        let data_dir: PathBuf = crate::test_util::temp_dir("sstable_from_file");
+        let options: KVSOptions = KVSOptions::new().block_size(100);
+        let memtable: Memtable = memtable(50);
+
+        let created: SSTable = SSTable::create(&data_dir, &memtable, "1", &options).unwrap();
+        let loaded: SSTable = SSTable::from_file(created.data_path.clone()).unwrap();
+        assert_eq!(loaded.index, created.index);
+        assert_eq!(loaded.meta, created.meta);
+        assert_eq!(loaded.filter, created.filter);
+        assert_eq!(loaded.file_size, created.file_size);
+        assert_eq!(
+            loaded.get(b"key042", u64::MAX).unwrap(),
+            memtable
+                .get(b"key042", u64::MAX)
+                .map(|(seq, v)| (seq, v.clone()))
+        );
+
+        // 空の SSTable
+        let empty: SSTable = SSTable::create(&data_dir, &Memtable::new(), "2", &options).unwrap();
+        let loaded: SSTable = SSTable::from_file(empty.data_path.clone()).unwrap();
+        assert_eq!(loaded.meta.entry_count, 0);
+        assert_eq!(loaded.get(b"key000", u64::MAX).unwrap(), None);
+
+        // フィルタを無効にしたケース
+        let options: KVSOptions = options.bloom_bits_per_key(0);
+        let created: SSTable = SSTable::create(&data_dir, &memtable, "3", &options).unwrap();
+        assert!(created.filter().is_none());
+        let loaded: SSTable = SSTable::from_file(created.data_path.clone()).unwrap();
+        assert!(loaded.filter().is_none());
+        assert_eq!(
+            loaded.get(b"key042", u64::MAX).unwrap(),
+            memtable
+                .get(b"key042", u64::MAX)
+                .map(|(seq, v)| (seq, v.clone()))
+        );
+
+        // SSTable ではないファイル
+        let path: PathBuf = data_dir.join("4.dat");
+        std::fs::write(&path, b"not an sstable").unwrap();
+        assert!(SSTable::from_file(path).is_err());
+
+        // 他のバージョンのファイル
+        let bytes: Vec<u8> = std::fs::read(&created.data_path).unwrap();
+        let trailer: usize = bytes.len() - TRAILER_SIZE;
+        let mut other: Vec<u8> = bytes.clone();
+        other[trailer + 48..trailer + 52].copy_from_slice(&4u32.to_be_bytes());
+        let path: PathBuf = data_dir.join("5.dat");
+        std::fs::write(&path, other).unwrap();
+        assert!(matches!(
+            SSTable::from_file(path),
+            Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(_)))
+        ));
+
+        // トレーラーやインデックスの位置がファイルの外を指すケース
+        let index_offset: usize = read_u64(&bytes, trailer) as usize;
+        let broken: [(usize, u64); 4] = [
+            (trailer + 8, u64::MAX),
+            (trailer + 24, 1 << 40),
+            (trailer + 32, bytes.len() as u64),
+            (index_offset + 8 + 6 + 8, u64::MAX - 1),
+        ];
+        for (i, (at, value)) in broken.into_iter().enumerate() {
+            let mut broken: Vec<u8> = bytes.clone();
+            broken[at..at + 8].copy_from_slice(&value.to_be_bytes());
+            let path: PathBuf = data_dir.join(format!("broken{i}.dat"));
+            std::fs::write(&path, broken).unwrap();
+            assert!(matches!(
+                SSTable::from_file(path),
+                Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let data_dir: PathBuf = crate::test_util::temp_dir("sstable_filter");
+        let memtable: Memtable = memtable(100);
+        let sstable: SSTable =
+            SSTable::create(&data_dir, &memtable, "1", &KVSOptions::new()).unwrap();
+
+        let filter: &BloomFilter = sstable.filter().unwrap();
+        for (k, _, _) in memtable.iter() {
+            assert!(filter.may_contain(k));
+        }
+        let false_positives: usize = (0..1000)
+            .filter(|i| filter.may_contain(format!("other{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 50);
+    }
+
+    #[test]
+    fn test_range() {
+        let data_dir: PathBuf = crate::test_util::temp_dir("sstable_range");
+        let options: KVSOptions = KVSOptions::new().block_size(64);
+        let memtable: Memtable = memtable(100);
+        let sstable: SSTable = SSTable::create(&data_dir, &memtable, "1", &options).unwrap();
+
+        let all: Vec<(Vec<u8>, u64, Value)> = sstable
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        let expected: Vec<(Vec<u8>, u64, Value)> = memtable
+            .iter()
+            .map(|(k, seq, v)| (k.to_vec(), seq, v.clone()))
+            .collect();
+        assert_eq!(all, expected);
+
+        let range = (
+            Bound::Excluded(b"key010".to_vec()),
+            Bound::Included(b"key020".to_vec()),
+        );
+        let keys: Vec<Vec<u8>> = sstable
+            .range(range)
+            .unwrap()
+            .map(|e| e.unwrap().0)
+            .collect();
+        let expected: Vec<Vec<u8>> = (11..=20)
+            .map(|i| format!("key{i:03}").into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_compression() {
+        let data_dir: PathBuf = crate::test_util::temp_dir("sstable_compression");
+        let mut memtable: Memtable = Memtable::new();
+        for i in 0..200 {
+            let value: String = format!(r#"{{"id":{i},"name":"user{i}","active":true}}"#);
+            memtable.insert(
+                format!("user{i:03}").as_bytes(),
+                i + 1,
+                Value::new(value, false),
+                &[],
+            );
+        }
+
+        for (name, compression) in [("1", Compression::Lz), ("2", Compression::LzHuffman)] {
+            let options: KVSOptions = KVSOptions::new().block_size(512).compression(compression);
+            let created: SSTable = SSTable::create(&data_dir, &memtable, name, &options).unwrap();
+            assert_eq!(created.meta().compression, compression);
+            assert!(created.data_size() < created.meta().raw_data_size);
+
+            let loaded: SSTable = SSTable::from_file(created.data_path.clone()).unwrap();
+            assert_eq!(loaded.meta, created.meta);
+            let all: Vec<(Vec<u8>, u64, Value)> = loaded
+                .range((Bound::Unbounded, Bound::Unbounded))
+                .unwrap()
+                .map(|e| e.unwrap())
+                .collect();
+            let expected: Vec<(Vec<u8>, u64, Value)> = memtable
+                .iter()
+                .map(|(k, seq, v)| (k.to_vec(), seq, v.clone()))
+                .collect();
+            assert_eq!(all, expected);
+            assert_eq!(
+                loaded
+                    .get(b"user123", u64::MAX)
+                    .unwrap()
+                    .map(|(seq, _)| seq),
+                Some(124)
+            );
+        }
+
+        // 圧縮しても小さくならないデータはそのまま書かれるケース
+        let mut memtable: Memtable = Memtable::new();
+        let mut state: u64 = 1;
+        for i in 0..50 {
+            let value: Vec<u8> = (0..1024)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 56) as u8
+                })
+                .collect();
+            memtable.insert(
+                format!("blob{i:02}").as_bytes(),
+                i + 1,
+                Value::new(value, false),
+                &[],
+            );
+        }
+        let options: KVSOptions = KVSOptions::new().compression(Compression::LzHuffman);
+        let created: SSTable = SSTable::create(&data_dir, &memtable, "3", &options).unwrap();
+        assert_eq!(
+            created.data_size(),
+            created.meta().raw_data_size + created.index.len() as u64
+        );
+        let loaded: SSTable = SSTable::from_file(created.data_path.clone()).unwrap();
+        assert_eq!(
+            loaded.get(b"blob07", u64::MAX).unwrap(),
+            memtable
+                .get(b"blob07", u64::MAX)
+                .map(|(seq, v)| (seq, v.clone()))
+        );
+
+        // 破損したブロック
+        let mut bytes: Vec<u8> = std::fs::read(&created.data_path).unwrap();
+        bytes[0] = 0xff;
+        std::fs::write(&created.data_path, bytes).unwrap();
+        assert!(loaded.get(b"blob00", u64::MAX).is_err());
+    }
+}
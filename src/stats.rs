@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The counters of a `KVS`, updated while it is used.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    /// The number of SSTable lookups the bloom filter let through.
+    bloom_filter_hits: AtomicU64,
+    /// The number of SSTable lookups skipped because the bloom filter ruled the key out.
+    bloom_filter_misses: AtomicU64,
+    /// The number of SSTable lookups the bloom filter let through that did not find the key.
+    bloom_filter_false_positives: AtomicU64,
+}
+
+impl Statistics {
+    /// Records that the bloom filter let a lookup through.
+    pub fn record_bloom_filter_hit(&self) {
+        self.bloom_filter_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the bloom filter ruled a key out.
+    pub fn record_bloom_filter_miss(&self) {
+        self.bloom_filter_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a lookup the bloom filter let through did not find the key.
+    pub fn record_bloom_filter_false_positive(&self) {
+        self.bloom_filter_false_positives
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> KVSStats {
+        KVSStats {
+            bloom_filter_hits: self.bloom_filter_hits.load(Ordering::Relaxed),
+            bloom_filter_misses: self.bloom_filter_misses.load(Ordering::Relaxed),
+            bloom_filter_false_positives: self.bloom_filter_false_positives.load(Ordering::Relaxed),
+            ..KVSStats::default()
+        }
+    }
+}
+
+/// The statistics of a `KVS` at one moment.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KVSStats {
+    /// The number of SSTable lookups the bloom filter let through.
+    pub bloom_filter_hits: u64,
+    /// The number of SSTable lookups skipped without any I/O because the bloom filter ruled the key out.
+    pub bloom_filter_misses: u64,
+    /// The number of SSTable lookups the bloom filter let through that did not find the key.
+    pub bloom_filter_false_positives: u64,
+    /// The total size of the data blocks of the live SSTables before compression in bytes.
+    pub uncompressed_bytes: u64,
+    /// The total size of the data blocks of the live SSTables as stored in bytes.
+    pub compressed_bytes: u64,
+}
+
+impl KVSStats {
+    /// Returns how many times smaller the data blocks are stored than their original size.
+    ///
+    /// Returns 1.0 when there are no SSTables.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+    }
+}
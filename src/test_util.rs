@@ -0,0 +1,20 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates an empty directory for a test under the system temporary directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let n: usize = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let mut path: PathBuf = std::env::temp_dir();
+    path.push(format!("kvsd-test-{}-{name}-{n}", std::process::id()));
+
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
+    fs::create_dir_all(&path).unwrap();
+    path
+}
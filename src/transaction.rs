@@ -0,0 +1,240 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use crate::{
+    batch::WriteBatch,
+    error::KVSError,
+    snapshot::Snapshot,
+    value::{self, Value},
+};
+
+/// A read-modify-write over several keys that is applied atomically or not at all.
+///
+/// Reads see the store as of the moment the transaction began, plus the transaction's own writes.
+/// Writes are buffered until `KVS::commit`, which fails with `KVSError::TransactionConflict`
+/// if any key the transaction read has been written since it began.
+///
+/// ```no_run
+/// use kvsd::{KVSError, KVS};
+///
+/// let kvs = KVS::new().unwrap();
+/// loop {
+///     let mut txn = kvs.transaction();
+///     let from: i64 = txn.get("from").unwrap().unwrap().to_string().parse().unwrap();
+///     let to: i64 = txn.get("to").unwrap().unwrap().to_string().parse().unwrap();
+///     txn.put("from", &(from - 10).to_string());
+///     txn.put("to", &(to + 10).to_string());
+///
+///     match kvs.commit(txn) {
+///         Err(KVSError::TransactionConflict(_)) => continue,
+///         result => break result.unwrap(),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Transaction {
+    /// The view of the store as of the moment the transaction began.
+    snapshot: Snapshot,
+    /// The keys read from the store, which must not change before the commit.
+    reads: BTreeSet<Vec<u8>>,
+    /// The puts and deletes buffered until the commit.
+    writes: WriteBatch,
+}
+
+impl Transaction {
+    /// Creates a new `Transaction` that reads from a snapshot.
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        Transaction {
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: WriteBatch::new(),
+        }
+    }
+
+    /// Retrieves a value by its key.
+    ///
+    /// A key written by the transaction reads its buffered value.
+    /// Any other key is read as of the moment the transaction began and is validated at the commit.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get(&mut self, key: &str) -> Result<Option<Value>, KVSError> {
+        self.get_bytes(key.as_bytes())
+    }
+
+    /// Retrieves a value by its binary key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to retrieve.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Value>, KVSError> {
+        if let Some((_, value)) = self.writes.entries().iter().rev().find(|(k, _)| k == key) {
+            return match value.is_deleted() || value.is_expired(value::now_millis()) {
+                true => Ok(None),
+                false => Ok(Some(value.clone())),
+            };
+        }
+
+        self.reads.insert(key.to_vec());
+        self.snapshot.get_bytes(key)
+    }
+
+    /// Buffers a put of a key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    pub fn put(&mut self, k: &str, v: &str) -> &mut Self {
+        self.put_bytes(k.as_bytes(), v.as_bytes())
+    }
+
+    /// Buffers a put of a binary key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    pub fn put_bytes(&mut self, k: &[u8], v: &[u8]) -> &mut Self {
+        self.writes.put_bytes(k, v);
+        self
+    }
+
+    /// Buffers a put of a key-value pair that expires after a time to live.
+    ///
+    /// The time to live starts when the put is buffered, not when the transaction commits.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl(&mut self, k: &str, v: &str, ttl: Duration) -> &mut Self {
+        self.put_with_ttl_bytes(k.as_bytes(), v.as_bytes(), ttl)
+    }
+
+    /// Buffers a put of a binary key-value pair that expires after a time to live.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key.
+    /// * `v` - The value.
+    /// * `ttl` - How long the value lives.
+    pub fn put_with_ttl_bytes(&mut self, k: &[u8], v: &[u8], ttl: Duration) -> &mut Self {
+        self.writes.put_with_ttl_bytes(k, v, ttl);
+        self
+    }
+
+    /// Buffers a delete of a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key to delete.
+    pub fn delete(&mut self, k: &str) -> &mut Self {
+        self.delete_bytes(k.as_bytes())
+    }
+
+    /// Buffers a delete of a binary key.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The key to delete.
+    pub fn delete_bytes(&mut self, k: &[u8]) -> &mut Self {
+        self.writes.delete_bytes(k);
+        self
+    }
+
+    /// Returns the largest sequence number the transaction reads.
+    pub(crate) fn seq(&self) -> u64 {
+        self.snapshot.seq()
+    }
+
+    /// Returns the keys read from the store.
+    pub(crate) fn reads(&self) -> &BTreeSet<Vec<u8>> {
+        &self.reads
+    }
+
+    /// Consumes the transaction and returns its buffered writes.
+    pub(crate) fn into_writes(self) -> WriteBatch {
+        self.writes
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{test_util, transaction::*, KVSOptions, KVS};
+
+    #[test]
+    fn test_commit() {
+        let data_dir: PathBuf = test_util::temp_dir("transaction_commit");
+        let kvs: KVS = KVSOptions::new().data_dir(&data_dir).open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+
+        let mut txn: Transaction = kvs.transaction();
+        assert_eq!(txn.get("k1").unwrap(), Some(Value::new("v1", false)));
+        txn.put("k1", "v2").delete("k2").put("k3", "v3");
+
+        // 自分の書き込みはコミット前でも読める
+        assert_eq!(txn.get("k1").unwrap(), Some(Value::new("v2", false)));
+        assert_eq!(txn.get("k2").unwrap(), None);
+        assert_eq!(txn.reads().len(), 1);
+        assert_eq!(kvs.get("k3").unwrap(), None);
+
+        kvs.commit(txn).unwrap();
+        assert_eq!(kvs.get("k1").unwrap(), Some(Value::new("v2", false)));
+        assert_eq!(kvs.get("k3").unwrap(), Some(Value::new("v3", false)));
+
+        // 有効期限つきの書き込みのケース
+        let mut txn: Transaction = kvs.transaction();
+        txn.put_with_ttl("k1", "v3", Duration::from_secs(60))
+            .put_with_ttl("k3", "gone", Duration::ZERO);
+        assert!(txn.get("k1").unwrap().unwrap().ttl().is_some());
+        assert_eq!(txn.get("k3").unwrap(), None);
+        kvs.commit(txn).unwrap();
+        assert!(kvs.get("k1").unwrap().unwrap().ttl().unwrap() <= Duration::from_secs(60));
+        assert_eq!(kvs.get("k3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_commit_conflict() {
+        let data_dir: PathBuf = test_util::temp_dir("transaction_conflict");
+        let kvs: KVS = KVSOptions::new().data_dir(&data_dir).open().unwrap();
+        kvs.put("k1", "v1").unwrap();
+
+        // 読んだキーが書き換えられたケース
+        let mut txn: Transaction = kvs.transaction();
+        txn.get("k1").unwrap();
+        txn.put("k2", "v2");
+        kvs.put("k1", "other").unwrap();
+        assert!(matches!(
+            kvs.commit(txn),
+            Err(KVSError::TransactionConflict(key)) if key == b"k1"
+        ));
+        assert_eq!(kvs.get("k2").unwrap(), None);
+
+        // 読んだキーが削除されたケース (flush 後も検出する)
+        let mut txn: Transaction = kvs.transaction();
+        txn.get("k1").unwrap();
+        kvs.delete("k1").unwrap();
+        kvs.flush().unwrap();
+        assert!(kvs.commit(txn).is_err());
+
+        // 存在しなかったキーが書き込まれたケース
+        let mut txn: Transaction = kvs.transaction();
+        assert_eq!(txn.get("k9").unwrap(), None);
+        kvs.put("k9", "v9").unwrap();
+        assert!(kvs.commit(txn).is_err());
+
+        // 読んでいないキーへの書き込みは衝突しない
+        let mut txn: Transaction = kvs.transaction();
+        txn.get("k9").unwrap();
+        txn.put("k9", "mine");
+        kvs.put("k8", "v8").unwrap();
+        kvs.commit(txn).unwrap();
+        assert_eq!(kvs.get("k9").unwrap(), Some(Value::new("mine", false)));
+    }
+}
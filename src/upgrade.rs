@@ -0,0 +1,475 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{ConvertError, IOError, KVSError},
+    manifest::{self, Manifest},
+    options::{Durability, KVSOptions},
+    sstable,
+    value::Value,
+    wal, WriteBatch, KVS,
+};
+
+/// The directory the new store is written to during an upgrade.
+const NEW_TMP_DIR: &str = "upgrade.tmp";
+/// The directory the new store is moved to once it is complete.
+const NEW_DIR: &str = "upgrade.new";
+/// The directory the files of the earlier release are moved to during an upgrade.
+const OLD_TMP_DIR: &str = "upgrade.old.tmp";
+/// The directory the files of the earlier release are moved to once all of them are there.
+const OLD_DIR: &str = "upgrade.old";
+/// The number of entries written to the new store in one batch.
+const BATCH_ENTRIES: usize = 1024;
+
+/// The files of the earlier release in a data directory.
+struct LegacyFiles {
+    /// The data files, ordered from oldest to newest.
+    tables: Vec<PathBuf>,
+    /// The WAL, if it exists and is not empty.
+    wal: Option<PathBuf>,
+}
+
+impl LegacyFiles {
+    /// Returns `true` if there are no files of the earlier release.
+    fn is_empty(&self) -> bool {
+        self.tables.is_empty() && self.wal.is_none()
+    }
+
+    /// Returns all the files, the WAL last.
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.tables.iter().chain(self.wal.iter())
+    }
+}
+
+/// Returns `true` if the data directory holds files of the earlier release that must be upgraded
+/// before it is opened, or an upgrade that was interrupted.
+///
+/// # Arguments
+///
+/// * `data_dir` - The data directory.
+/// * `wal_filename` - The name of the WAL file.
+pub fn needs_upgrade(data_dir: &Path, wal_filename: &str) -> Result<bool, KVSError> {
+    if Manifest::exists(data_dir) {
+        return Ok(false);
+    }
+    if [NEW_TMP_DIR, NEW_DIR, OLD_TMP_DIR, OLD_DIR]
+        .iter()
+        .any(|dir| data_dir.join(dir).is_dir())
+    {
+        return Ok(true);
+    }
+    Ok(!legacy_files(data_dir, wal_filename)?.is_empty())
+}
+
+/// Rewrites a data directory of the earlier release in the current format.
+///
+/// The entries of the data files and the WAL are written to a new store in a directory
+/// inside the data directory. Then the old files are moved aside, the new files are moved
+/// into the data directory with the manifest last, and the old files are removed.
+/// Each step can be run again, so an upgrade interrupted by a crash resumes where it stopped.
+///
+/// Returns `false` if there was nothing to upgrade.
+///
+/// # Arguments
+///
+/// * `options` - The options the store will be opened with.
+pub fn upgrade(options: &KVSOptions) -> Result<bool, KVSError> {
+    let data_dir: &Path = &options.data_dir;
+    if !data_dir.is_dir() {
+        return Err(KVSError::FailedIO(IOError::DirectoryNotFound(
+            data_dir.to_path_buf(),
+        )));
+    }
+    let _lock: File = crate::lock_data_dir(data_dir, false)?;
+
+    if !needs_upgrade(data_dir, &options.wal_filename)? {
+        // マニフェストを移した後に止まったなら、残ったディレクトリだけを消す
+        remove_dir(&data_dir.join(NEW_DIR))?;
+        remove_dir(&data_dir.join(OLD_DIR))?;
+        return Ok(false);
+    }
+
+    let old_dir: PathBuf = data_dir.join(OLD_DIR);
+    if !old_dir.is_dir() {
+        if !data_dir.join(NEW_DIR).is_dir() {
+            build(options)?;
+        }
+        move_legacy_files(data_dir, &options.wal_filename)?;
+    }
+    install(data_dir)?;
+
+    remove_dir(&data_dir.join(NEW_DIR))?;
+    remove_dir(&old_dir)?;
+    Ok(true)
+}
+
+/// Writes the entries of the files of the earlier release to a new store,
+/// and moves it to `NEW_DIR` once it is complete.
+fn build(options: &KVSOptions) -> Result<(), KVSError> {
+    let data_dir: &Path = &options.data_dir;
+    let legacy: LegacyFiles = legacy_files(data_dir, &options.wal_filename)?;
+
+    // 古いファイルを古い順に読み、後のファイルの値で上書きする
+    let mut entries: BTreeMap<Vec<u8>, Value> = BTreeMap::new();
+    for table in legacy.tables.iter() {
+        entries.extend(read_legacy_file(table, false)?);
+    }
+    if let Some(wal) = &legacy.wal {
+        entries.extend(read_legacy_file(wal, true)?);
+    }
+
+    // 前回の途中までの書き込みは捨てて作り直す
+    let tmp_dir: PathBuf = data_dir.join(NEW_TMP_DIR);
+    remove_dir(&tmp_dir)?;
+    let mut new_options: KVSOptions = options.clone();
+    new_options.data_dir = tmp_dir.clone();
+    new_options.create_if_missing = true;
+    new_options.error_if_exists = false;
+    new_options.read_only = false;
+    new_options.background_threads = 0;
+    new_options.durability = Durability::Never;
+    let kvs: KVS = new_options.open()?;
+    let mut batch: WriteBatch = WriteBatch::new();
+    for (key, value) in entries.iter().filter(|(_, value)| !value.is_deleted()) {
+        batch.put_bytes(key, value.as_bytes());
+        if batch.len() == BATCH_ENTRIES {
+            kvs.write(std::mem::take(&mut batch))?;
+        }
+    }
+    kvs.write(batch)?;
+    kvs.flush()?;
+    drop(kvs);
+    manifest::sync_dir(&tmp_dir)?;
+
+    rename(&tmp_dir, &data_dir.join(NEW_DIR))?;
+    manifest::sync_dir(data_dir)?;
+    Ok(())
+}
+
+/// Moves the files of the earlier release to `OLD_TMP_DIR`, and then the directory to `OLD_DIR`.
+fn move_legacy_files(data_dir: &Path, wal_filename: &str) -> Result<(), KVSError> {
+    let tmp_dir: PathBuf = data_dir.join(OLD_TMP_DIR);
+    if let Err(e) = fs::create_dir_all(&tmp_dir) {
+        return Err(KVSError::FailedIO(IOError::FailedCreateDirectory(
+            tmp_dir,
+            e.to_string(),
+        )));
+    }
+    for path in legacy_files(data_dir, wal_filename)?.paths() {
+        if let Some(filename) = path.file_name() {
+            rename(path, &tmp_dir.join(filename))?;
+        }
+    }
+    manifest::sync_dir(&tmp_dir)?;
+    rename(&tmp_dir, &data_dir.join(OLD_DIR))?;
+    manifest::sync_dir(data_dir)?;
+    Ok(())
+}
+
+/// Moves the files of the new store from `NEW_DIR` into the data directory, the manifest last,
+/// so that the data directory is opened as the new store only once every file is there.
+fn install(data_dir: &Path) -> Result<(), KVSError> {
+    let new_dir: PathBuf = data_dir.join(NEW_DIR);
+    let mut manifest_path: Option<PathBuf> = None;
+    for path in list_files(&new_dir)? {
+        if path.file_name() == Some(manifest::MANIFEST_FILENAME.as_ref()) {
+            manifest_path = Some(path);
+            continue;
+        }
+        if let Some(filename) = path.file_name() {
+            rename(&path, &data_dir.join(filename))?;
+        }
+    }
+    manifest::sync_dir(data_dir)?;
+
+    if let Some(path) = manifest_path {
+        rename(&path, &data_dir.join(manifest::MANIFEST_FILENAME))?;
+        manifest::sync_dir(data_dir)?;
+    }
+    Ok(())
+}
+
+/// Finds the files of the earlier release in the data directory.
+///
+/// A data file is of the earlier release if it does not end with the magic number of an SSTable,
+/// and the WAL if it is not empty and does not start with the magic number of a WAL.
+fn legacy_files(data_dir: &Path, wal_filename: &str) -> Result<LegacyFiles, KVSError> {
+    let mut tables: Vec<PathBuf> = Vec::new();
+    for path in crate::get_data_files(data_dir)? {
+        if read_edge(&path, SeekFrom::End(-8))? != sstable::MAGIC.to_be_bytes() {
+            tables.push(path);
+        }
+    }
+    // 以前のリリースのファイル名は作成時刻の秒数
+    tables.sort_by_key(|path| (crate::table_number(path), path.clone()));
+
+    let wal_path: PathBuf = data_dir.join(wal_filename);
+    let wal: Option<PathBuf> = match wal_path.is_file() {
+        true => {
+            // ヘッダーが途中までしかない WAL は今の形式として扱う
+            let head: Vec<u8> = read_edge(&wal_path, SeekFrom::Start(0))?;
+            let legacy: bool = !wal::MAGIC.to_be_bytes().starts_with(&head);
+            legacy.then_some(wal_path)
+        }
+        false => None,
+    };
+    Ok(LegacyFiles { tables, wal })
+}
+
+/// Reads the entries of a data file or a WAL of the earlier release.
+///
+/// Both are a sequence of records in the format:
+/// key length (8 bytes) | key | value length (8 bytes) | value | flag (1 byte) | flag (1 byte)
+///
+/// The value length counts the first flag, which is 1 for a deleted value.
+/// A torn record at the end of a WAL is ignored, since its write never completed.
+///
+/// # Arguments
+///
+/// * `path` - The path to the file.
+/// * `is_wal` - Whether the file is a WAL.
+fn read_legacy_file(path: &Path, is_wal: bool) -> Result<Vec<(Vec<u8>, Value)>, KVSError> {
+    let bytes: Vec<u8> = read_file(path)?;
+    let mut entries: Vec<(Vec<u8>, Value)> = Vec::new();
+    let mut offset: usize = 0;
+    while offset < bytes.len() {
+        let (key, value_bytes, next) = match decode_legacy_record(&bytes, offset) {
+            Some(decoded) => decoded,
+            None if is_wal => break,
+            None => {
+                return Err(KVSError::FailedConvert(ConvertError::InvalidSSTable(
+                    format!("{path:?}: The record at offset {offset} is truncated."),
+                )))
+            }
+        };
+        if value_bytes.is_empty() {
+            return Err(KVSError::FailedConvert(ConvertError::FailedBytesToValue(
+                format!("{path:?}: The value at offset {offset} has no flag."),
+            )));
+        }
+        entries.push((key.to_vec(), Value::from_bytes(value_bytes.to_vec())?));
+        offset = next;
+    }
+    Ok(entries)
+}
+
+/// Decodes a record of the earlier release at a given offset.
+///
+/// Returns the key, the value with its first flag, and the offset of the next record,
+/// or `None` if the record is truncated.
+fn decode_legacy_record(bytes: &[u8], offset: usize) -> Option<(&[u8], &[u8], usize)> {
+    let (key, next) = read_bytes(bytes, offset)?;
+    let (value, next) = read_bytes(bytes, next)?;
+    // 2 つ目のフラグは 1 つ目と同じなので読み飛ばす
+    bytes.get(next)?;
+    Some((key, value, next + 1))
+}
+
+/// Reads bytes with an 8-byte length prefix at a given offset and returns them with the offset after them.
+fn read_bytes(bytes: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let length: u64 = u64::from_be_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?);
+    let start: usize = offset + 8;
+    let end: usize = start.checked_add(usize::try_from(length).ok()?)?;
+    Some((bytes.get(start..end)?, end))
+}
+
+/// Reads a whole file.
+fn read_file(path: &Path) -> Result<Vec<u8>, KVSError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => Err(KVSError::FailedIO(IOError::FailedReadFile(format!(
+            "{path:?}: {e}"
+        )))),
+    }
+}
+
+/// Reads up to 8 bytes of a file from a position, or nothing if the position is before the start.
+///
+/// # Arguments
+///
+/// * `path` - The path to the file.
+/// * `from` - The position to read from, e.g. `SeekFrom::End(-8)` for the last 8 bytes.
+fn read_edge(path: &Path, from: SeekFrom) -> Result<Vec<u8>, KVSError> {
+    let failed =
+        |e: std::io::Error| KVSError::FailedIO(IOError::FailedReadFile(format!("{path:?}: {e}")));
+    let mut file: File = File::open(path).map_err(failed)?;
+    let len: u64 = file.metadata().map_err(failed)?.len();
+    if matches!(from, SeekFrom::End(offset) if len < offset.unsigned_abs()) {
+        return Ok(Vec::new());
+    }
+    file.seek(from).map_err(failed)?;
+    let mut bytes: Vec<u8> = Vec::new();
+    file.take(8).read_to_end(&mut bytes).map_err(failed)?;
+    Ok(bytes)
+}
+
+/// Lists the files in a directory.
+fn list_files(dir: &Path) -> Result<Vec<PathBuf>, KVSError> {
+    let entries: fs::ReadDir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            return Err(KVSError::FailedIO(IOError::FailedGetFilePath(
+                dir.to_path_buf(),
+                e.to_string(),
+            )))
+        }
+    };
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) if entry.path().is_file() => files.push(entry.path()),
+            Ok(_) => {}
+            Err(e) => {
+                return Err(KVSError::FailedIO(IOError::FailedGetFilePath(
+                    dir.to_path_buf(),
+                    e.to_string(),
+                )))
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Renames a file or a directory.
+fn rename(from: &Path, to: &Path) -> Result<(), KVSError> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(KVSError::FailedIO(IOError::FailedRenameFile(
+            from.to_path_buf(),
+            e.to_string(),
+        ))),
+    }
+}
+
+/// Removes a directory and everything in it, if it exists.
+fn remove_dir(dir: &Path) -> Result<(), KVSError> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    match fs::remove_dir_all(dir) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(KVSError::FailedIO(IOError::FailedRemoveFile(
+            dir.to_path_buf(),
+            e.to_string(),
+        ))),
+    }
+}
+
+// ----- test -----
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util, upgrade::*};
+
+    /// Encodes a record of the earlier release.
+    fn legacy_record(key: &str, value: &str, deleted: bool) -> Vec<u8> {
+        [
+            &(key.len() as u64).to_be_bytes()[..],
+            key.as_bytes(),
+            &(value.len() as u64 + 1).to_be_bytes(),
+            value.as_bytes(),
+            &[deleted as u8, deleted as u8],
+        ]
+        .concat()
+    }
+
+    /// Writes a data directory of the earlier release.
+    fn legacy_dir(name: &str) -> PathBuf {
+        let data_dir: PathBuf = test_util::temp_dir(name);
+        let old: Vec<u8> = [
+            legacy_record("k1", "v1", false),
+            legacy_record("k2", "v2", false),
+            legacy_record("k3", "v3", false),
+        ]
+        .concat();
+        fs::write(data_dir.join("1700000000.dat"), old).unwrap();
+        let new: Vec<u8> = [
+            legacy_record("k1", "new", false),
+            legacy_record("k2", "", true),
+        ]
+        .concat();
+        fs::write(data_dir.join("1700000100.dat"), new).unwrap();
+        let mut wal: Vec<u8> = [
+            legacy_record("k3", "wal", false),
+            legacy_record("k4", "v4", false),
+        ]
+        .concat();
+        // 書きかけのレコード
+        wal.extend(&legacy_record("k5", "v5", false)[..10]);
+        fs::write(data_dir.join("wal"), wal).unwrap();
+        data_dir
+    }
+
+    /// Asserts that the store holds the entries of `legacy_dir`.
+    fn assert_upgraded(options: &KVSOptions) {
+        let kvs: KVS = options.open().unwrap();
+        let entries: Vec<(Vec<u8>, Value)> = kvs.scan_all().unwrap().map(|e| e.unwrap()).collect();
+        let expected: Vec<(Vec<u8>, Value)> = [("k1", "new"), ("k3", "wal"), ("k4", "v4")]
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), Value::new(v, false)))
+            .collect();
+        assert_eq!(entries, expected);
+        drop(kvs);
+        for dir in [NEW_TMP_DIR, NEW_DIR, OLD_TMP_DIR, OLD_DIR] {
+            assert!(!options.data_dir.join(dir).exists());
+        }
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let data_dir: PathBuf = legacy_dir("upgrade");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+
+        // 以前のリリースのディレクトリは、書き換えるまで開けないケース
+        assert!(matches!(options.open(), Err(KVSError::UpgradeRequired(_))));
+        assert!(matches!(
+            options.clone().read_only(true).open(),
+            Err(KVSError::UpgradeRequired(_))
+        ));
+
+        // 前回の書きかけの新しいストアは作り直すケース
+        fs::create_dir(data_dir.join(NEW_TMP_DIR)).unwrap();
+        fs::write(data_dir.join(NEW_TMP_DIR).join("1.dat"), b"broken").unwrap();
+        assert!(upgrade(&options).unwrap());
+        assert_upgraded(&options);
+        assert!(!data_dir.join("1700000000.dat").exists());
+
+        // 書き換え済みのディレクトリには何もしないケース
+        assert!(!upgrade(&options).unwrap());
+        assert_upgraded(&options);
+
+        // 空のディレクトリには何もしないケース
+        let empty: KVSOptions = KVSOptions::new().data_dir(test_util::temp_dir("upgrade_empty"));
+        assert!(!upgrade(&empty).unwrap());
+        assert!(empty.open().is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_resume() {
+        // 古いファイルを移している途中で止まったケース
+        let data_dir: PathBuf = legacy_dir("upgrade_resume_old");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        build(&options).unwrap();
+        fs::create_dir(data_dir.join(OLD_TMP_DIR)).unwrap();
+        let moved: PathBuf = data_dir.join(OLD_TMP_DIR).join("1700000000.dat");
+        fs::rename(data_dir.join("1700000000.dat"), moved).unwrap();
+        assert!(matches!(options.open(), Err(KVSError::UpgradeRequired(_))));
+        assert!(upgrade(&options).unwrap());
+        assert_upgraded(&options);
+
+        // 新しいファイルを移している途中で止まったケース
+        let data_dir: PathBuf = legacy_dir("upgrade_resume_new");
+        let options: KVSOptions = KVSOptions::new().data_dir(&data_dir);
+        build(&options).unwrap();
+        move_legacy_files(&data_dir, "wal").unwrap();
+        let wal: PathBuf = data_dir.join(NEW_DIR).join("wal");
+        fs::rename(wal, data_dir.join("wal")).unwrap();
+        assert!(matches!(options.open(), Err(KVSError::UpgradeRequired(_))));
+        assert!(upgrade(&options).unwrap());
+        assert_upgraded(&options);
+    }
+}
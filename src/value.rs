@@ -1,13 +1,25 @@
 use crate::error::ConvertError;
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The flag byte of a live value.
+const FLAG_LIVE: u8 = 0;
+/// The flag byte of a deleted value.
+const FLAG_DELETED: u8 = 1;
+/// The flag byte of a live value followed by its expiry time.
+const FLAG_EXPIRING: u8 = 2;
 
 /// Represents a value in the key-value store.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Value {
     /// The value itself.
-    value: String,
+    value: Vec<u8>,
     /// Whether the value has been deleted.
     is_delete: bool,
+    /// When the value expires, in milliseconds since the UNIX epoch.
+    expires_at: Option<u64>,
 }
 
 impl Value {
@@ -15,18 +27,51 @@ impl Value {
     ///
     /// # Arguments
     ///
-    /// * `string` - The string value.
+    /// * `value` - The value, as a string or bytes.
     /// * `is_del` - A flag indicating if the value is deleted.
-    pub fn new(string: &str, is_del: bool) -> Self {
+    pub fn new<V: AsRef<[u8]>>(value: V, is_del: bool) -> Self {
         Value {
-            value: string.to_string(),
+            value: value.as_ref().to_vec(),
             is_delete: is_del,
+            expires_at: None,
         }
     }
 
+    /// Creates a new live `Value` that expires at a given time.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value, as a string or bytes.
+    /// * `expires_at` - When the value expires, in milliseconds since the UNIX epoch.
+    pub fn with_expiry<V: AsRef<[u8]>>(value: V, expires_at: u64) -> Self {
+        Value {
+            value: value.as_ref().to_vec(),
+            is_delete: false,
+            expires_at: Some(expires_at),
+        }
+    }
+
+    /// Returns the value as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns the value as a string slice, or `None` if it is not valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+
+    /// Consumes the `Value` and returns its bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.value
+    }
+
     /// Returns the length of the value in bytes, including metadata.
     pub fn len(&self) -> usize {
-        self.value.len() + 9
+        match self.expires_at {
+            Some(_) => self.value.len() + 17,
+            None => self.value.len() + 9,
+        }
     }
 
     /// Returns `true` if the value is marked as deleted.
@@ -34,41 +79,105 @@ impl Value {
         self.is_delete
     }
 
+    /// Returns when the value expires, in milliseconds since the UNIX epoch, if it does.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns `true` if the value has expired at a given time.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time in milliseconds since the UNIX epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns the time left until the value expires, or `None` if it never expires.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now_millis())))
+    }
+
     /// Converts the `Value` to a byte vector.
+    ///
+    /// The format is: length (8 bytes) | value | expires_at (8 bytes, only if it expires) | flag (1 byte)
+    ///
+    /// The flag is 0 for a live value, 1 for a deleted value and 2 for a live value that expires.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let value = self.value.as_bytes();
-        let value_len = (self.value.len() + 1).to_be_bytes();
-        let is_del = u8::from(self.is_delete).to_be_bytes();
-        [&value_len, value, &is_del].concat()
+        let value: &[u8] = &self.value;
+        let (expires_at, flag): (Vec<u8>, u8) = match (self.is_delete, self.expires_at) {
+            (true, _) => (Vec::new(), FLAG_DELETED),
+            (false, Some(expires_at)) => (expires_at.to_be_bytes().to_vec(), FLAG_EXPIRING),
+            (false, None) => (Vec::new(), FLAG_LIVE),
+        };
+        let value_len = (self.value.len() + expires_at.len() + 1).to_be_bytes();
+        [&value_len, value, &expires_at, &[flag]].concat()
     }
 
     /// Creates a `Value` from a byte vector.
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ConvertError> {
-        // 0 ~ length-1 までが value 本体
-        let value: String = match String::from_utf8(bytes[0..bytes.len() - 1].to_vec()) {
-            Ok(s) => s,
-            Err(e) => return Err(ConvertError::FailedBytesToValue(e.to_string())),
+        // 最後1バイトがフラグ (0: 有効, 1: 削除済み, 2: 有効期限つき)
+        let flag: u8 = match bytes.last() {
+            Some(flag) => *flag,
+            None => {
+                return Err(ConvertError::FailedBytesToValue(
+                    "The bytes of Value are empty.".to_string(),
+                ))
+            }
         };
-
-        // 最後1バイトがtrue か false (1ならtrue)
-        let is_delete: bool = match bytes[bytes.len() - 1] {
-            0 => false,
-            1 => true,
+        let (is_delete, expires_len): (bool, usize) = match flag {
+            FLAG_LIVE => (false, 0),
+            FLAG_DELETED => (true, 0),
+            FLAG_EXPIRING => (false, 8),
             _ => {
                 return Err(ConvertError::FailedBytesToValue(format!(
-                    "Invalid value '{}' is read. is_delete expect '0' or '1'",
-                    bytes[bytes.len() - 1]
+                    "Invalid value '{flag}' is read. is_delete expect '0', '1' or '2'"
                 )))
             }
         };
 
-        Ok(Value { value, is_delete })
+        // フラグの直前の 8 バイトが有効期限
+        let value_end: usize = match (bytes.len() - 1).checked_sub(expires_len) {
+            Some(end) => end,
+            None => {
+                return Err(ConvertError::FailedBytesToValue(
+                    "The bytes of Value end before the expiry time.".to_string(),
+                ))
+            }
+        };
+        let expires_at: Option<u64> = match flag {
+            FLAG_EXPIRING => {
+                let mut expires_bytes: [u8; 8] = [0u8; 8];
+                expires_bytes.copy_from_slice(&bytes[value_end..bytes.len() - 1]);
+                Some(u64::from_be_bytes(expires_bytes))
+            }
+            _ => None,
+        };
+
+        // 0 ~ value_end までが value 本体
+        let value: Vec<u8> = bytes[0..value_end].to_vec();
+
+        Ok(Value {
+            value,
+            is_delete,
+            expires_at,
+        })
     }
 }
 
+/// Returns the current time in milliseconds since the UNIX epoch.
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Displays the value as a string, replacing bytes that are not valid UTF-8.
 impl Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.value)
+        write!(f, "{}", String::from_utf8_lossy(&self.value))
     }
 }
 
@@ -81,22 +190,64 @@ mod tests {
     #[test]
     fn test_value_new() {
         let t = Value {
-            value: "value".to_string(),
+            value: b"value".to_vec(),
             is_delete: true,
+            expires_at: None,
         };
         assert_eq!(Value::new("value", true), t);
 
         let f = Value {
-            value: "value".to_string(),
+            value: b"value".to_vec(),
             is_delete: false,
+            expires_at: None,
         };
         assert_eq!(Value::new("value", false), f);
+
+        let e = Value {
+            value: b"value".to_vec(),
+            is_delete: false,
+            expires_at: Some(100),
+        };
+        assert_eq!(Value::with_expiry("value", 100), e);
     }
 
     #[test]
     fn test_len() {
         let value = Value::new("value", false);
         assert_eq!(value.len(), 14);
+        let value = Value::with_expiry("value", 100);
+        assert_eq!(value.len(), 22);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let value = Value::with_expiry("v", 100);
+        assert_eq!(value.expires_at(), Some(100));
+        assert!(!value.is_expired(99));
+        assert!(value.is_expired(100));
+        assert_eq!(value.ttl(), Some(Duration::ZERO));
+
+        let value = Value::with_expiry("v", now_millis() + 60_000);
+        assert!(Duration::from_secs(59) < value.ttl().unwrap());
+
+        // 有効期限のない値は期限切れにならない
+        let value = Value::new("v", false);
+        assert!(!value.is_expired(u64::MAX));
+        assert_eq!(value.ttl(), None);
+    }
+
+    #[test]
+    fn test_as_bytes() {
+        let value = Value::new("value", false);
+        assert_eq!(value.as_bytes(), b"value");
+        assert_eq!(value.as_str(), Some("value"));
+        assert_eq!(value.into_bytes(), b"value".to_vec());
+
+        // UTF-8 でない値
+        let value = Value::new([0xFF, 0x00, 0x80], false);
+        assert_eq!(value.as_bytes(), &[0xFF, 0x00, 0x80]);
+        assert_eq!(value.as_str(), None);
+        assert_eq!(value.to_string(), "\u{FFFD}\0\u{FFFD}");
     }
 
     #[test]
@@ -125,6 +276,15 @@ mod tests {
         ];
         let t = Value::new("value", false);
         assert_eq!(t.to_bytes(), v);
+
+        let v: Vec<u8> = vec![
+            0, 0, 0, 0, 0, 0, 0, 14, // 14 (length of value)
+            118, 97, 108, 117, 101, // value
+            0, 0, 0, 0, 0, 0, 1, 0, // 256 (expires_at)
+            2, // expiring
+        ];
+        let t = Value::with_expiry("value", 256);
+        assert_eq!(t.to_bytes(), v);
     }
 
     #[test]
@@ -136,6 +296,22 @@ mod tests {
         let f = Value::new("test", false);
         let bytes: Vec<u8> = vec![116, 101, 115, 116, 0];
         assert_eq!(Value::from_bytes(bytes).unwrap(), f);
+
+        let e = Value::with_expiry("test", 256);
+        let bytes: Vec<u8> = vec![116, 101, 115, 116, 0, 0, 0, 0, 0, 0, 1, 0, 2];
+        assert_eq!(Value::from_bytes(bytes).unwrap(), e);
+
+        // UTF-8 でない値もそのまま読める
+        let bytes: Vec<u8> = vec![0xFF, 0x80, 0];
+        assert_eq!(
+            Value::from_bytes(bytes).unwrap(),
+            Value::new([0xFF, 0x80], false)
+        );
+
+        // 不正なフラグと短すぎるバイト列
+        assert!(Value::from_bytes(vec![116, 3]).is_err());
+        assert!(Value::from_bytes(vec![0, 1, 2]).is_err());
+        assert!(Value::from_bytes(Vec::new()).is_err());
     }
 
     #[test]
@@ -1,103 +1,434 @@
 use std::{
-    collections::BTreeMap,
     fs::{File, OpenOptions},
-    io::{BufReader, BufWriter},
+    io::{BufReader, Read, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread,
+    time::Duration,
 };
 
 use crate::{
+    batch::WriteBatch,
     error::{ConvertError, IOError, KVSError},
-    file_io::{get_filesize, read_key_value, write_key_value},
-    value::Value,
+    log, manifest,
+    memtable::Memtable,
+    options::Durability,
 };
 
+/// The magic number at the start of a WAL file.
+pub(crate) const MAGIC: u64 = 0x4B56_5344_5741_4C00; // "KVSDWAL\0"
+
+/// The version of the WAL format.
+const FORMAT_VERSION: u32 = 1;
+
+/// The size of the file header: magic (8 bytes) | version (4 bytes)
+const FILE_HEADER_SIZE: usize = 12;
+
 /// Represents a Write-Ahead Log (WAL).
-#[derive(Debug, PartialEq)]
+///
+/// The file starts with a header of the magic number and the format version,
+/// followed by records. Each record holds one `WriteBatch`, framed by `log::encode_record`:
+/// length (8 bytes) | crc (4 bytes) | batch bytes
+///
+/// The WAL keeps one file handle open for its lifetime and can be written from several threads.
+/// With `Durability::Always`, writers that arrive while an fsync is running wait for it
+/// and are then synced together by the next fsync (group commit).
+///
+/// A record that fails to be written is cut off the file. If that fails too, or an fsync fails,
+/// the WAL is poisoned and refuses every later write, since it can no longer tell
+/// which records are on the disk.
+#[derive(Debug)]
 pub struct WriteAheadLog {
     /// The path to the WAL file.
     path: PathBuf,
+    /// When the WAL is synced to the disk.
+    durability: Durability,
+    /// The state shared with writers and the periodic sync thread.
+    shared: Arc<Shared>,
+}
+
+/// The state of a WAL shared between threads.
+#[derive(Debug)]
+struct Shared {
+    /// The file opened in append mode and the counters of written and synced records.
+    state: Mutex<State>,
+    /// Notified when an fsync finishes.
+    synced: Condvar,
+    /// A second handle to the file, used to fsync without holding the lock.
+    sync_file: File,
+}
+
+/// The mutable state of a WAL.
+#[derive(Debug)]
+struct State {
+    /// The file opened in append mode.
+    file: File,
+    /// The length of the file up to the end of the last record written.
+    len: u64,
+    /// The error that poisoned the WAL, if any.
+    error: Option<String>,
+    /// The number of records written.
+    written: u64,
+    /// The number of records known to be on the disk.
+    synced: u64,
+    /// Whether a thread is running an fsync.
+    syncing: bool,
+    /// The number of fsyncs run.
+    syncs: u64,
 }
 
 impl WriteAheadLog {
     /// Creates a new `WriteAheadLog`.
     ///
+    /// A new file gets its header, and the file and the directory are synced before it is used.
+    ///
     /// # Arguments
     ///
     /// * `data_dir` - The directory to store the WAL file in.
     /// * `filename` - The name of the WAL file.
-    pub fn new(data_dir: &Path, filename: &str) -> Result<Self, IOError> {
+    /// * `durability` - When the WAL is synced to the disk.
+    pub fn new(data_dir: &Path, filename: &str, durability: Durability) -> Result<Self, IOError> {
         let mut path: PathBuf = data_dir.to_path_buf();
         path.push(filename);
 
-        if !path.exists() {
-            if let Err(e) = File::create(&path) {
-                return Err(IOError::FailedCreateFile(path, e.to_string()));
+        let mut file: File = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(f) => f,
+            Err(e) => return Err(IOError::FailedCreateFile(path, e.to_string())),
+        };
+        let len: u64 = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(IOError::FailedGetFileSize(path, e.to_string())),
+        };
+        let mut len: u64 = len;
+        if len == 0 {
+            if let Err(e) = file.write_all(&file_header()) {
+                return Err(IOError::FailedWriteBytes(e.to_string()));
             }
+            len = FILE_HEADER_SIZE as u64;
+
+            // ヘッダのない WAL が残らないように、ヘッダとディレクトリのエントリを書き込みより先に同期する
+            if let Err(e) = file.sync_all() {
+                return Err(IOError::FailedSync(e.to_string()));
+            }
+            manifest::sync_dir(data_dir)?;
         }
+        let sync_file: File = match file.try_clone() {
+            Ok(f) => f,
+            Err(e) => return Err(IOError::FailedOpenFile(path, e.to_string())),
+        };
 
-        Ok(WriteAheadLog { path })
+        let shared: Arc<Shared> = Arc::new(Shared {
+            state: Mutex::new(State {
+                file,
+                len,
+                error: None,
+                written: 0,
+                synced: 0,
+                syncing: false,
+                syncs: 0,
+            }),
+            synced: Condvar::new(),
+            sync_file,
+        });
+
+        if let Durability::Periodic(interval) = durability {
+            spawn_periodic_sync(Arc::downgrade(&shared), interval);
+        }
+
+        Ok(WriteAheadLog {
+            path,
+            durability,
+            shared,
+        })
     }
 
-    /// Writes a key-value pair to the WAL.
+    /// Writes a batch to the WAL as a single record.
+    ///
+    /// With `Durability::Always`, this returns after the record is synced to the disk.
+    /// If the sync fails, the record may or may not be on the disk, and the WAL is poisoned.
     ///
     /// # Arguments
     ///
-    /// * `key` - The key to write.
-    /// * `value` - The value to write.
-    pub fn write(&mut self, key: &str, value: &Value) -> Result<usize, IOError> {
-        let mut writer: BufWriter<File> = match OpenOptions::new().append(true).open(&self.path) {
-            Ok(f) => BufWriter::new(f),
-            Err(e) => return Err(IOError::FailedOpenFile(self.path.clone(), e.to_string())),
-        };
-        write_key_value(&mut writer, key, value)
-    }
+    /// * `seq` - The sequence number of the first entry of the batch.
+    /// * `batch` - The batch to write.
+    pub fn write(&self, seq: u64, batch: &WriteBatch) -> Result<usize, IOError> {
+        let record: Vec<u8> = log::encode_record(&batch.to_bytes(seq));
 
-    /// Clears the WAL.
-    pub fn clear(&mut self) -> Result<(), IOError> {
-        match File::create(&self.path) {
-            Ok(f) => match f.set_len(0) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(IOError::FailedTruncateWAL(e.to_string())),
-            },
-            Err(e) => Err(IOError::FailedOpenFile(self.path.clone(), e.to_string())),
+        let mut state: MutexGuard<State> = self.shared.lock();
+        state.check()?;
+        if let Err(e) = state.file.write_all(&record) {
+            // 書きかけのレコードの後ろに次のレコードを書かないように、書く前の長さに戻す
+            let len: u64 = state.len;
+            if let Err(truncate_error) = state.file.set_len(len) {
+                state.error = Some(truncate_error.to_string());
+            }
+            return Err(IOError::FailedWriteBytes(e.to_string()));
         }
-    }
+        state.len += record.len() as u64;
+        state.written += 1;
 
-    /// Recovers the memtable from the WAL.
-    pub fn recovery(&mut self) -> Result<BTreeMap<String, Value>, KVSError> {
-        let mut buf_reader: BufReader<File> = match File::open(&self.path) {
-            Ok(f) => BufReader::new(f),
-            Err(e) => {
-                return Err(KVSError::FailedIO(IOError::FailedOpenFile(
-                    self.path.clone(),
-                    e.to_string(),
-                )))
-            }
-        };
+        if self.durability == Durability::Always {
+            let ticket: u64 = state.written;
+            self.shared.sync_until(state, ticket)?;
+        }
+        Ok(record.len())
+    }
 
-        let file_size: usize = get_filesize(&self.path)?;
+    /// Syncs all written records to the disk.
+    pub fn sync(&self) -> Result<(), IOError> {
+        let state: MutexGuard<State> = self.shared.lock();
+        let ticket: u64 = state.written;
+        self.shared.sync_until(state, ticket)
+    }
 
-        let mut offset: usize = 0;
-        let mut btm: BTreeMap<String, Value> = BTreeMap::new();
-        while offset < file_size {
-            let (key_bytes, value_bytes) = read_key_value(&mut buf_reader, offset)?;
+    /// Recovers the memtable from the WAL.
+    ///
+    /// Batches are replayed as a whole.
+    /// Replay stops at the first record that is torn or fails its checksum,
+    /// and the WAL is truncated after the last good record.
+    ///
+    /// A file that is not a WAL of this format, or whose first record is corrupt,
+    /// is an error and is left as it is, since nothing in it can be trusted to be a torn tail.
+    pub fn recovery(&mut self) -> Result<Recovery, KVSError> {
+        let (recovery, end) = read_records(&self.path)?;
 
-            let key: String = match String::from_utf8(key_bytes) {
-                Ok(s) => s,
+        // 壊れた末尾を切り詰める
+        if recovery.dropped_bytes > 0 {
+            let file: File = match OpenOptions::new().write(true).open(&self.path) {
+                Ok(f) => f,
                 Err(e) => {
-                    return Err(KVSError::FailedConvert(ConvertError::FailedBytesToString(
+                    return Err(KVSError::FailedIO(IOError::FailedOpenFile(
+                        self.path.clone(),
                         e.to_string(),
                     )))
                 }
             };
+            if let Err(e) = file.set_len(end as u64) {
+                return Err(KVSError::FailedIO(IOError::FailedTruncateWAL(
+                    e.to_string(),
+                )));
+            }
+        }
+
+        let mut state: MutexGuard<State> = self.shared.lock();
+        state.len = end as u64;
+        // ヘッダーの書き込み中に止まったファイルは、空にしたのでヘッダーから書き直す
+        if end == 0 {
+            if let Err(e) = state.file.write_all(&file_header()) {
+                return Err(KVSError::FailedIO(IOError::FailedWriteBytes(e.to_string())));
+            }
+            state.len = FILE_HEADER_SIZE as u64;
+        }
+
+        Ok(recovery)
+    }
+
+    /// Recovers the memtable from a WAL file without changing the file.
+    ///
+    /// Replay stops at the first record that is torn or fails its checksum, like `recovery`,
+    /// but the bad tail is left in place. A missing file is read as an empty WAL.
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` - The directory the WAL file is in.
+    /// * `filename` - The name of the WAL file.
+    pub fn replay(data_dir: &Path, filename: &str) -> Result<Recovery, KVSError> {
+        let path: PathBuf = data_dir.join(filename);
+        if !path.exists() {
+            return Ok(Recovery {
+                memtable: Memtable::new(),
+                last_seq: 0,
+                dropped_bytes: 0,
+            });
+        }
+        let (recovery, _) = read_records(&path)?;
+        Ok(recovery)
+    }
+}
+
+/// Returns the header written at the start of a WAL file.
+fn file_header() -> Vec<u8> {
+    [&MAGIC.to_be_bytes()[..], &FORMAT_VERSION.to_be_bytes()[..]].concat()
+}
+
+/// Reads the records of a WAL file into a memtable.
+///
+/// Returns the recovery and the offset just after the last good record.
+fn read_records(path: &Path) -> Result<(Recovery, usize), KVSError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let read_result = match File::open(path) {
+        Ok(f) => BufReader::new(f).read_to_end(&mut bytes),
+        Err(e) => {
+            return Err(KVSError::FailedIO(IOError::FailedOpenFile(
+                path.to_path_buf(),
+                e.to_string(),
+            )))
+        }
+    };
+    if let Err(e) = read_result {
+        return Err(KVSError::FailedIO(IOError::FailedReadFile(e.to_string())));
+    }
+
+    let mut memtable: Memtable = Memtable::new();
+    let mut last_seq: u64 = 0;
+
+    // ヘッダーが途中までしかないファイルは、何も書かれていない WAL として扱う
+    let header: Vec<u8> = file_header();
+    if bytes.len() < FILE_HEADER_SIZE && header.starts_with(&bytes) {
+        let recovery: Recovery = Recovery {
+            memtable,
+            last_seq,
+            dropped_bytes: bytes.len(),
+        };
+        return Ok((recovery, 0));
+    }
+    if bytes.get(..8) != Some(&header[..8]) {
+        return Err(invalid_wal(
+            path,
+            "The file does not start with the magic number. It may have been written by an older version.",
+        ));
+    }
+    let version: u32 =
+        u32::from_be_bytes(bytes[8..FILE_HEADER_SIZE].try_into().unwrap_or_default());
+    if version != FORMAT_VERSION {
+        return Err(invalid_wal(
+            path,
+            &format!("The format version {version} is not supported."),
+        ));
+    }
+
+    let mut offset: usize = FILE_HEADER_SIZE;
+    while let Some((seq, batch, next)) = decode_batch(&bytes, offset) {
+        for (i, (key, value)) in batch.entries().iter().enumerate() {
+            memtable.insert(key, seq + i as u64, value.clone(), &[]);
+        }
+        last_seq = last_seq.max(seq + (batch.len() as u64).saturating_sub(1));
+        offset = next;
+    }
+
+    // 最初のレコードが途中で切れたのではなく壊れているなら、末尾として切り詰めずにエラーにする
+    if offset == FILE_HEADER_SIZE && offset < bytes.len() && !log::is_torn(&bytes, offset) {
+        return Err(invalid_wal(path, "The first record is corrupt."));
+    }
+
+    let recovery: Recovery = Recovery {
+        memtable,
+        last_seq,
+        dropped_bytes: bytes.len() - offset,
+    };
+    Ok((recovery, offset))
+}
+
+/// Returns the error for an invalid WAL file.
+fn invalid_wal(path: &Path, msg: &str) -> KVSError {
+    KVSError::FailedConvert(ConvertError::InvalidWAL(format!("{path:?}: {msg}")))
+}
+
+impl State {
+    /// Returns an error if the WAL is poisoned.
+    fn check(&self) -> Result<(), IOError> {
+        match &self.error {
+            Some(msg) => Err(IOError::WALPoisoned(msg.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    /// Locks the state.
+    ///
+    /// The state is only counters and a file handle, so it is still usable after a panic.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Waits until the record with the given ticket is synced to the disk.
+    ///
+    /// If no fsync is running, this thread becomes the leader and syncs every record
+    /// written so far, including the records of writers that are waiting.
+    fn sync_until<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        ticket: u64,
+    ) -> Result<(), IOError> {
+        loop {
+            if ticket <= state.synced {
+                return Ok(());
+            }
+            state.check()?;
+
+            if state.syncing {
+                state = match self.synced.wait(state) {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                continue;
+            }
 
-            let value: Value = Value::from_bytes(value_bytes)?;
+            state.syncing = true;
+            let target: u64 = state.written;
+            drop(state);
 
-            offset += key.len() + value.len() + 9;
-            btm.insert(key, value);
+            let result = self.sync_file.sync_data();
+
+            state = self.lock();
+            state.syncing = false;
+            state.syncs += 1;
+            // fsync に失敗した後は、それまでのレコードが書けたかどうか分からないので使わない
+            match &result {
+                Ok(()) => state.synced = state.synced.max(target),
+                Err(e) => state.error = Some(e.to_string()),
+            }
+            self.synced.notify_all();
+
+            if let Err(e) = result {
+                return Err(IOError::FailedSync(e.to_string()));
+            }
         }
+    }
+}
+
+/// Spawns a thread that syncs the WAL at a fixed interval until the WAL is dropped.
+fn spawn_periodic_sync(shared: Weak<Shared>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
 
-        Ok(btm)
+        let shared: Arc<Shared> = match shared.upgrade() {
+            Some(s) => s,
+            None => return,
+        };
+        let state: MutexGuard<State> = shared.lock();
+        if state.synced < state.written {
+            let ticket: u64 = state.written;
+            // 失敗すると WAL が使えなくなり、次の書き込みがエラーを返す
+            let _ = shared.sync_until(state, ticket);
+        }
+    });
+}
+
+/// The result of recovering the memtable from the WAL.
+#[derive(Debug, PartialEq)]
+pub struct Recovery {
+    /// The recovered memtable.
+    pub memtable: Memtable,
+    /// The largest sequence number in the WAL, or 0 if it is empty.
+    pub last_seq: u64,
+    /// The number of bytes dropped from the tail of the WAL.
+    pub dropped_bytes: usize,
+}
+
+/// Decodes the record at a given offset.
+///
+/// Returns the sequence number of the first entry, the batch and the offset of the next record,
+/// or `None` if the record is torn or corrupt.
+fn decode_batch(bytes: &[u8], offset: usize) -> Option<(u64, WriteBatch, usize)> {
+    let (batch_bytes, next) = log::decode_record(bytes, offset)?;
+    match WriteBatch::from_bytes(batch_bytes) {
+        Ok((seq, batch)) => Some((seq, batch, next)),
+        Err(_) => None,
     }
 }
 
@@ -105,14 +436,281 @@ impl WriteAheadLog {
 
 #[cfg(test)]
 mod tests {
+    use crate::value::Value;
     use crate::wal::*;
 
     #[test]
     fn test_wal_new() {
-        let path: PathBuf = PathBuf::from("data");
-        let wal = WriteAheadLog {
-            path: PathBuf::from("data/wal"),
-        };
-        assert_eq!(WriteAheadLog::new(&path, "wal").unwrap(), wal);
+        let path: PathBuf = crate::test_util::temp_dir("wal_new");
+        let wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+        assert_eq!(wal.path, path.join("wal"));
+        assert_eq!(std::fs::read(&wal.path).unwrap(), file_header());
+
+        // 既存のファイルにはヘッダーを書き足さない
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1");
+        let size: usize = wal.write(1, &batch).unwrap();
+        let wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+        let len: u64 = std::fs::metadata(&wal.path).unwrap().len();
+        assert_eq!(len, (FILE_HEADER_SIZE + size) as u64);
+    }
+
+    #[test]
+    fn test_durability() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_durability");
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1");
+
+        // Never は fsync しない
+        let wal = WriteAheadLog::new(&path, "never", Durability::Never).unwrap();
+        wal.write(1, &batch).unwrap();
+        assert_eq!(wal.shared.lock().syncs, 0);
+
+        // Always は書き込みごとに fsync する
+        let wal = WriteAheadLog::new(&path, "always", Durability::Always).unwrap();
+        wal.write(1, &batch).unwrap();
+        wal.write(1, &batch).unwrap();
+        let state = wal.shared.lock();
+        assert_eq!((state.syncs, state.synced), (2, 2));
+        drop(state);
+
+        // Periodic はバックグラウンドで fsync する
+        let interval: Duration = Duration::from_millis(10);
+        let wal = WriteAheadLog::new(&path, "periodic", Durability::Periodic(interval)).unwrap();
+        wal.write(1, &batch).unwrap();
+        for _ in 0..100 {
+            if wal.shared.lock().synced == 1 {
+                break;
+            }
+            thread::sleep(interval);
+        }
+        assert_eq!(wal.shared.lock().synced, 1);
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_poisoned");
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1");
+        let wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+        wal.write(1, &batch).unwrap();
+
+        // 書き込みに失敗し、書きかけのレコードも切り詰められないケース
+        let read_only: File = File::open(path.join("wal")).unwrap();
+        let file: File = std::mem::replace(&mut wal.shared.lock().file, read_only);
+        assert!(matches!(
+            wal.write(2, &batch),
+            Err(IOError::FailedWriteBytes(_))
+        ));
+
+        // 以降の書き込みと同期はすべて失敗する
+        wal.shared.lock().file = file;
+        assert!(matches!(wal.write(3, &batch), Err(IOError::WALPoisoned(_))));
+        assert!(matches!(wal.sync(), Err(IOError::WALPoisoned(_))));
+
+        // 開き直すと、失敗する前のレコードだけが残っている
+        let mut wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!((recovery.dropped_bytes, recovery.last_seq), (0, 1));
+        wal.write(2, &batch).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_group_commit");
+        let wal = Arc::new(WriteAheadLog::new(&path, "wal", Durability::Always).unwrap());
+
+        let handles: Vec<thread::JoinHandle<()>> = (0..8)
+            .map(|i| {
+                let wal = Arc::clone(&wal);
+                thread::spawn(move || {
+                    for j in 0..20 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(&format!("k{i}-{j}"), "v");
+                        wal.write((i * 20 + j + 1) as u64, &batch).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(wal.shared.lock().synced, 160);
+        let mut wal = Arc::into_inner(wal).unwrap();
+        assert_eq!(wal.recovery().unwrap().memtable.len(), 160);
+
+        // fsync の最中に書き込んだレコードは、次の 1 回の fsync でまとめて同期するケース
+        let path: PathBuf = crate::test_util::temp_dir("wal_group_commit_shared");
+        let wal = Arc::new(WriteAheadLog::new(&path, "wal", Durability::Always).unwrap());
+        wal.shared.lock().syncing = true;
+        let handles: Vec<thread::JoinHandle<()>> = (0..8)
+            .map(|i| {
+                let wal = Arc::clone(&wal);
+                thread::spawn(move || {
+                    let mut batch = WriteBatch::new();
+                    batch.put(&format!("k{i}"), "v");
+                    wal.write(i + 1, &batch).unwrap();
+                })
+            })
+            .collect();
+        while wal.shared.lock().written < 8 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut state = wal.shared.lock();
+        state.syncing = false;
+        wal.shared.synced.notify_all();
+        drop(state);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let state = wal.shared.lock();
+        assert_eq!((state.synced, state.syncs), (8, 1));
+    }
+
+    #[test]
+    fn test_write_recovery() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_recovery");
+        let mut wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1").put("k2", "v2");
+        wal.write(1, &batch).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete("k1").put("k3", "v3");
+        let size: usize = wal.write(3, &batch).unwrap();
+
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!(recovery.dropped_bytes, 0);
+        assert_eq!(recovery.last_seq, 4);
+        let btm = recovery.memtable;
+        assert_eq!(btm.get(b"k1", u64::MAX), Some((3, &Value::new("", true))));
+        assert_eq!(
+            btm.get(b"k2", u64::MAX),
+            Some((2, &Value::new("v2", false)))
+        );
+        assert_eq!(
+            btm.get(b"k3", u64::MAX),
+            Some((4, &Value::new("v3", false)))
+        );
+
+        // 最後のバッチが途中までしか書き込まれていないケース
+        let file: File = OpenOptions::new()
+            .write(true)
+            .open(path.join("wal"))
+            .unwrap();
+        let file_size: u64 = file.metadata().unwrap().len();
+        file.set_len(file_size - (size as u64) / 2).unwrap();
+
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!(recovery.dropped_bytes, size - size / 2);
+        assert_eq!(recovery.last_seq, 2);
+        assert_eq!(
+            recovery.memtable.get(b"k1", u64::MAX),
+            Some((1, &Value::new("v1", false)))
+        );
+        assert_eq!(recovery.memtable.get(b"k3", u64::MAX), None);
+        assert_eq!(file.metadata().unwrap().len(), file_size - size as u64);
+    }
+
+    #[test]
+    fn test_recovery_corrupt_record() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_corrupt");
+        let mut wal = WriteAheadLog::new(&path, "wal", Durability::Never).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1");
+        let good_size: usize = wal.write(1, &batch).unwrap();
+        batch.put("k2", "v2");
+        let bad_size: usize = wal.write(2, &batch).unwrap();
+
+        // 2つ目のレコードの値を1バイト書き換える
+        let mut bytes: Vec<u8> = std::fs::read(path.join("wal")).unwrap();
+        let last: usize = bytes.len() - 2;
+        bytes[last] ^= 0xFF;
+        // 末尾にゴミを追加する
+        bytes.extend([0xFF; 5]);
+        std::fs::write(path.join("wal"), &bytes).unwrap();
+
+        // replay はファイルを変えない
+        let recovery: Recovery = WriteAheadLog::replay(&path, "wal").unwrap();
+        assert_eq!(recovery.dropped_bytes, bad_size + 5);
+        assert_eq!(recovery.memtable.len(), 1);
+        assert_eq!(std::fs::read(path.join("wal")).unwrap(), bytes);
+        let recovery: Recovery = WriteAheadLog::replay(&path, "missing").unwrap();
+        assert!(recovery.memtable.is_empty());
+
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!(recovery.dropped_bytes, bad_size + 5);
+        assert_eq!(recovery.memtable.len(), 1);
+        assert_eq!(
+            recovery.memtable.get(b"k1", u64::MAX),
+            Some((1, &Value::new("v1", false)))
+        );
+
+        // 切り詰めた後は何も落とさない
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!(recovery.dropped_bytes, 0);
+        assert_eq!(
+            std::fs::metadata(path.join("wal")).unwrap().len(),
+            (FILE_HEADER_SIZE + good_size) as u64
+        );
+    }
+
+    #[test]
+    fn test_recovery_invalid_file() {
+        let path: PathBuf = crate::test_util::temp_dir("wal_invalid");
+        let mut batch = WriteBatch::new();
+        batch.put("k1", "v1");
+
+        // ヘッダーのない古い形式のファイルはエラーにし、切り詰めないケース
+        let old: Vec<u8> = [&2usize.to_be_bytes()[..], b"k1", &[0; 20]].concat();
+        std::fs::write(path.join("old"), &old).unwrap();
+        let mut wal = WriteAheadLog::new(&path, "old", Durability::Never).unwrap();
+        assert!(matches!(
+            wal.recovery(),
+            Err(KVSError::FailedConvert(ConvertError::InvalidWAL(_)))
+        ));
+        assert!(WriteAheadLog::replay(&path, "old").is_err());
+        assert_eq!(std::fs::read(path.join("old")).unwrap(), old);
+
+        // 対応していないバージョンのケース
+        let mut newer: Vec<u8> = file_header();
+        newer[FILE_HEADER_SIZE - 1] += 1;
+        std::fs::write(path.join("newer"), &newer).unwrap();
+        let mut wal = WriteAheadLog::new(&path, "newer", Durability::Never).unwrap();
+        assert!(wal.recovery().is_err());
+
+        // 最初のレコードが壊れているケース
+        let wal = WriteAheadLog::new(&path, "corrupt", Durability::Never).unwrap();
+        wal.write(1, &batch).unwrap();
+        wal.write(2, &batch).unwrap();
+        let mut bytes: Vec<u8> = std::fs::read(path.join("corrupt")).unwrap();
+        bytes[FILE_HEADER_SIZE + log::HEADER_SIZE] ^= 0xFF;
+        std::fs::write(path.join("corrupt"), &bytes).unwrap();
+        let mut wal = WriteAheadLog::new(&path, "corrupt", Durability::Never).unwrap();
+        assert!(wal.recovery().is_err());
+        assert_eq!(std::fs::read(path.join("corrupt")).unwrap(), bytes);
+
+        // 最初のレコードが途中で切れたケースは末尾として落とす
+        let wal = WriteAheadLog::new(&path, "torn", Durability::Never).unwrap();
+        let size: usize = wal.write(1, &batch).unwrap();
+        let file: File = OpenOptions::new()
+            .write(true)
+            .open(path.join("torn"))
+            .unwrap();
+        file.set_len((FILE_HEADER_SIZE + size - 1) as u64).unwrap();
+        let mut wal = WriteAheadLog::new(&path, "torn", Durability::Never).unwrap();
+        assert_eq!(wal.recovery().unwrap().dropped_bytes, size - 1);
+
+        // ヘッダーの途中で切れたケースは空の WAL として書き直す
+        std::fs::write(path.join("header"), &file_header()[..5]).unwrap();
+        let mut wal = WriteAheadLog::new(&path, "header", Durability::Never).unwrap();
+        assert_eq!(wal.recovery().unwrap().dropped_bytes, 5);
+        wal.write(1, &batch).unwrap();
+        let recovery: Recovery = wal.recovery().unwrap();
+        assert_eq!((recovery.dropped_bytes, recovery.memtable.len()), (0, 1));
     }
 }